@@ -0,0 +1,78 @@
+//! Finding the paper color of a scanned document, so the padding around it
+//! can match instead of showing as a band of the wrong white.
+
+use image::{GenericImageView, Rgba};
+
+use crate::config::{Color, ProcessConfig};
+use crate::decode::open_image;
+use crate::error::ProcessError;
+
+// Positions sampled along each edge, and how deep into the margin
+const SAMPLES_PER_EDGE: u32 = 256;
+const SAMPLE_DEPTHS: u32 = 4;
+
+/// The color along the edges of `image`: the per-channel median of pixels
+/// sampled from a band around it, so a stray mark or a corner of the
+/// drawing doesn't sway it. `None` if the edges are all transparent.
+pub fn detect_background<I>(image: &I) -> Option<Color>
+where
+    I: GenericImageView<Pixel = Rgba<u8>>,
+{
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    // The outer 2% of the shorter side
+    let band = (width.min(height) / 50).max(1);
+    let mut samples: [Vec<u8>; 3] = Default::default();
+    let mut sample = |x: u32, y: u32| {
+        let Rgba([r, g, b, a]) = image.get_pixel(x, y);
+        if a > 0 {
+            samples[0].push(r);
+            samples[1].push(g);
+            samples[2].push(b);
+        }
+    };
+    for depth in 0..SAMPLE_DEPTHS.min(band) {
+        let inset = depth * band / SAMPLE_DEPTHS.min(band);
+        for i in 0..SAMPLES_PER_EDGE {
+            let x = (i * width / SAMPLES_PER_EDGE).min(width - 1);
+            let y = (i * height / SAMPLES_PER_EDGE).min(height - 1);
+            sample(x, inset);
+            sample(x, height - 1 - inset);
+            sample(inset, y);
+            sample(width - 1 - inset, y);
+        }
+    }
+
+    let [r, g, b] = samples.map(|mut channel| {
+        channel.sort_unstable();
+        channel.get(channel.len() / 2).copied()
+    });
+    Some(Color::rgb(r?, g?, b?))
+}
+
+/// The background color of a run: the one detected in `image` when
+/// `auto_background` is set and the edges aren't transparent, otherwise
+/// `config.background_color`.
+pub(crate) fn run_background<I>(config: &ProcessConfig, image: &I) -> Color
+where
+    I: GenericImageView<Pixel = Rgba<u8>>,
+{
+    if !config.auto_background {
+        return config.background_color;
+    }
+    match detect_background(image) {
+        Some(color) => {
+            tracing::info!(path = %config.image_path, %color, "Detected background color");
+            color
+        }
+        None => config.background_color,
+    }
+}
+
+/// Decode `config.image_path` and detect its paper color, to suggest as the
+/// background. Decoding the image makes this blocking.
+pub fn suggest_background(config: &ProcessConfig) -> Result<Option<Color>, ProcessError> {
+    Ok(detect_background(&open_image(config)?))
+}
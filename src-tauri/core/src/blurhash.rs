@@ -0,0 +1,145 @@
+//! Blurhash placeholders: a tile boiled down to a few cosine components and
+//! written as a short string, which a viewer decodes into a blurred stand-in
+//! while the real tile loads. See https://blurha.sh for the format.
+
+use image::RgbaImage;
+use std::f32::consts::PI;
+
+use crate::linear::{linear_table, to_srgb};
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+// Components along a tile's longer side; the shorter side gets as many as
+// keep them roughly square
+const COMPONENTS: u32 = 4;
+
+/// The blurhash of `tile`. Alpha is ignored, as the format has no room for
+/// it; transparent padding counts with the color it was given.
+pub(crate) fn blurhash(tile: &RgbaImage) -> String {
+    let (width, height) = tile.dimensions();
+    let (components_x, components_y) = if width >= height {
+        (
+            COMPONENTS,
+            (COMPONENTS * height / width.max(1)).clamp(1, COMPONENTS),
+        )
+    } else {
+        (
+            (COMPONENTS * width / height).clamp(1, COMPONENTS),
+            COMPONENTS,
+        )
+    };
+
+    // Each component's cosine along each axis, worked out once
+    let basis = |components: u32, size: u32| -> Vec<Vec<f32>> {
+        (0..components)
+            .map(|i| {
+                (0..size)
+                    .map(|p| (PI * i as f32 * p as f32 / size as f32).cos())
+                    .collect()
+            })
+            .collect()
+    };
+    let basis_x = basis(components_x, width);
+    let basis_y = basis(components_y, height);
+
+    let table = linear_table();
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for cos_y in &basis_y {
+        for cos_x in &basis_x {
+            let mut sum = [0.0f32; 3];
+            for (x, y, pixel) in tile.enumerate_pixels() {
+                let weight = cos_x[x as usize] * cos_y[y as usize];
+                for (total, &channel) in sum.iter_mut().zip(&pixel.0[..3]) {
+                    *total += weight * table[channel as usize];
+                }
+            }
+            let normalisation = if factors.is_empty() { 1.0 } else { 2.0 };
+            let scale = normalisation / (width * height).max(1) as f32;
+            factors.push(sum.map(|total| total * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    push_base83(&mut hash, (components_x - 1) + (components_y - 1) * 9, 1);
+
+    let (dc, ac) = factors
+        .split_first()
+        .expect("there is always a DC component");
+    let max_ac = ac.iter().flatten().fold(0.0f32, |max, v| max.max(v.abs()));
+    let ac_scale = if ac.is_empty() {
+        push_base83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let quantised = (max_ac * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        push_base83(&mut hash, quantised, 1);
+        (quantised + 1) as f32 / 166.0
+    };
+
+    let dc = dc.map(|channel| to_srgb(channel) as u32);
+    push_base83(&mut hash, (dc[0] << 16) | (dc[1] << 8) | dc[2], 4);
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            let value = value / ac_scale;
+            (value.signum() * value.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        push_base83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    hash
+}
+
+/// Append `value` as `digits` base-83 characters, most significant first.
+fn push_base83(hash: &mut String, value: u32, digits: u32) {
+    for digit in (0..digits).rev() {
+        let index = (value / 83u32.pow(digit)) % 83;
+        hash.push(BASE83[index as usize] as char);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    // Expected hashes are worked out as the reference encoder at
+    // https://blurha.sh works them out
+
+    #[test]
+    fn hashes_flat_tiles() {
+        let blue = RgbaImage::from_pixel(8, 8, Rgba([90, 140, 200, 255]));
+        assert_eq!(blurhash(&blue), "UNAVYTyGfQyGyGkDfQkDfQfQfQfQyGkDfQkD");
+        // Alpha is left out
+        let clear = RgbaImage::from_pixel(8, 8, Rgba([90, 140, 200, 0]));
+        assert_eq!(blurhash(&clear), blurhash(&blue));
+    }
+
+    #[test]
+    fn hashes_detail() {
+        let split = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 3 {
+                Rgba([200, 40, 90, 255])
+            } else {
+                Rgba([20, 180, 240, 255])
+            }
+        });
+        assert_eq!(blurhash(&split), "U#E:u}_~:O$+tTr=r=oMfQfQfQfQtTr=r=oM");
+    }
+
+    #[test]
+    fn keeps_components_roughly_square() {
+        // 4 by 2 components, for a tile twice as wide as it is tall
+        let strip = RgbaImage::from_fn(16, 8, |x, y| {
+            let blue = if (x / 4) % 2 == 1 { 128 } else { 32 };
+            Rgba([(x * 16) as u8, (255 - y * 32) as u8, blue, 255])
+        });
+        assert_eq!(blurhash(&strip), "C~GvjtBisUkPubSxjtf$");
+
+        // 1 by 4, for a tile a pixel wide
+        let tall = RgbaImage::new(1, 64);
+        let hash = blurhash(&tall);
+        assert_eq!(&hash[..1], "R");
+        assert_eq!(hash.len(), 6 + 2 * 3);
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+use crate::processor::until_cancelled;
+use crate::progress::StatusNotifier;
+use crate::sink::TileSink;
+
+/// Keeps a run from hammering a server that is down. After `threshold`
+/// consecutive uploads fail on the server's side it opens: every upload
+/// holds while one of them probes the server each `probe_interval`, and
+/// they all carry on once it answers again.
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    probe_interval: Duration,
+    failures: AtomicU32,
+    open: AtomicBool,
+    // Held by the upload probing the server; the others queue behind it
+    probe: Mutex<()>,
+}
+
+impl CircuitBreaker {
+    /// The breaker for `config`'s run, or `None` if it is turned off.
+    pub(crate) fn new(config: &ProcessConfig) -> Option<Self> {
+        (config.breaker_threshold > 0).then(|| Self {
+            threshold: config.breaker_threshold,
+            probe_interval: Duration::from_secs(config.breaker_probe_secs.max(1)),
+            failures: AtomicU32::new(0),
+            open: AtomicBool::new(false),
+            probe: Mutex::new(()),
+        })
+    }
+
+    pub(crate) fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn record_success(&self) {
+        self.failures.store(0, Ordering::Release);
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::AcqRel) + 1;
+        if failures >= self.threshold && !self.open.swap(true, Ordering::AcqRel) {
+            tracing::warn!(
+                failures,
+                "Server keeps failing, holding uploads until it recovers"
+            );
+        }
+    }
+
+    /// Hold while the breaker is open, probing `sink` until it answers.
+    /// Returns whether there was anything to wait for.
+    pub(crate) async fn wait_until_closed(
+        &self,
+        sink: &dyn TileSink,
+        cancel: &CancellationToken,
+        status: &StatusNotifier,
+    ) -> Result<bool, ProcessError> {
+        if !self.is_open() {
+            return Ok(false);
+        }
+
+        let _probe = until_cancelled(cancel, async { Ok(self.probe.lock().await) }).await?;
+        while self.is_open() {
+            status.waiting_for_server(self.probe_interval).await;
+            until_cancelled(cancel, async {
+                tokio::time::sleep(self.probe_interval).await;
+                Ok(())
+            })
+            .await?;
+
+            match until_cancelled(cancel, sink.probe()).await {
+                Ok(()) => {
+                    self.failures.store(0, Ordering::Release);
+                    self.open.store(false, Ordering::Release);
+                    tracing::info!("Server is back, resuming uploads");
+                    status.server_back().await;
+                }
+                Err(ProcessError::Cancelled) => return Err(ProcessError::Cancelled),
+                Err(e) => tracing::debug!("Server still unavailable: {}", e),
+            }
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,202 @@
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+
+// Bumped whenever the cache layout or tile rendering changes, so stale
+// tiles are never served
+const CACHE_VERSION: &[u8] = b"tile-cache-v4";
+
+/// Encoded tiles kept on disk, so running the same image again (against
+/// another server, or after a failure) skips the resize and encode work.
+///
+/// Tiles are filed under a key made from the image's contents and every
+/// setting that changes the encoded bytes, as `{key}/{zoom}/{x}_{y}.{ext}`.
+/// A background tile left out of the upload is an empty file.
+#[derive(Debug, Clone)]
+pub struct TileCache {
+    dir: PathBuf,
+}
+
+impl TileCache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The cached tiles of `config`'s image with its encoding settings.
+    pub(crate) fn for_image(&self, config: &ProcessConfig) -> Result<ImageCache, ProcessError> {
+        // Every stitched image's contents too, after `image_path`'s
+        let stitched = config.stitch.iter().flat_map(|stitch| &stitch.images);
+        let mut image_hasher = Sha256::new();
+        for path in iter::once(&config.image_path).chain(stitched.map(|image| &image.path)) {
+            let mut file = File::open(path)
+                .map_err(|e| ProcessError::io("Failed to read image for the tile cache", e))?;
+            std::io::copy(&mut file, &mut image_hasher)
+                .map_err(|e| ProcessError::io("Failed to read image for the tile cache", e))?;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(CACHE_VERSION);
+        hasher.update(image_hasher.finalize());
+        hasher.update(config.tile_size.key());
+        hasher.update(config.tile_format.extension().as_bytes());
+        hasher.update([
+            config.jpeg_quality,
+            config.webp_quality,
+            config.background_color.r,
+            config.background_color.g,
+            config.background_color.b,
+            config.skip_background_tiles as u8,
+            config.effective_padding() as u8,
+            config.upscale as u8,
+            config.resize_filter as u8,
+        ]);
+        hasher.update(config.anchor.key());
+        if config.overlap > 0 {
+            hasher.update(b"overlap:");
+            hasher.update(config.overlap.to_le_bytes());
+        }
+        // Only hashed when set, so raster images keep their cached tiles
+        if let Some(alpha) = config.background_color.a {
+            hasher.update(b"background-alpha:");
+            hasher.update([alpha]);
+        }
+        if let Some(dpi) = config.render_dpi {
+            hasher.update(b"render-dpi:");
+            hasher.update(dpi.to_le_bytes());
+        }
+        if let Some(size) = config.render_size {
+            hasher.update(b"render-size:");
+            hasher.update(size.to_le_bytes());
+        }
+        if !config.auto_orient {
+            hasher.update(b"no-auto-orient");
+        }
+        if !config.convert_to_srgb {
+            hasher.update(b"no-srgb");
+        }
+        if config.pdf_page != 1 {
+            hasher.update(b"pdf-page:");
+            hasher.update(config.pdf_page.to_le_bytes());
+        }
+        if config.gif_frame != 1 {
+            hasher.update(b"gif-frame:");
+            hasher.update(config.gif_frame.to_le_bytes());
+        }
+        if let Some(stitch) = &config.stitch {
+            hasher.update(b"stitch:");
+            hasher.update(stitch.key());
+        }
+        if config.rotate != 0.0 {
+            hasher.update(b"rotate:");
+            hasher.update(config.rotate.to_le_bytes());
+        }
+        if config.flip_horizontal || config.flip_vertical {
+            hasher.update(b"flip:");
+            hasher.update([config.flip_horizontal as u8, config.flip_vertical as u8]);
+        }
+        if let Some(crop) = config.crop {
+            hasher.update(b"crop:");
+            hasher.update(crop.key());
+        }
+        if config.debug_overlay {
+            hasher.update(b"debug-overlay");
+        }
+        // A level's quality counts from the deepest level, which a max zoom
+        // override moves
+        if let Some(ramp) = config.quality_ramp_key() {
+            hasher.update(b"quality-ramp:");
+            hasher.update(ramp);
+            hasher.update(
+                config
+                    .max_zoom_override
+                    .map_or(0, |zoom| zoom + 1)
+                    .to_le_bytes(),
+            );
+        }
+        if config.auto_trim {
+            hasher.update(b"trim:");
+            hasher.update([config.trim_tolerance]);
+        }
+        if config.linear_resize {
+            hasher.update(b"linear-resize");
+        }
+        if let Some(tone) = config.tone_key() {
+            hasher.update(b"tone:");
+            hasher.update(tone);
+        }
+        if config.sharpen_amount > 0.0 {
+            hasher.update(b"sharpen:");
+            hasher.update(config.sharpen_amount.to_le_bytes());
+            hasher.update(config.sharpen_radius.to_le_bytes());
+        }
+        if let Some(key) = config.color_key {
+            hasher.update(b"color-key:");
+            hasher.update([key.r, key.g, key.b, config.color_key_tolerance]);
+        }
+
+        Ok(ImageCache {
+            dir: self.dir.join(format!("{:x}", hasher.finalize())),
+            extension: config.tile_format.extension(),
+        })
+    }
+
+    /// Delete every cached tile.
+    pub fn clear(&self) -> Result<(), ProcessError> {
+        match fs::remove_dir_all(&self.dir) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(ProcessError::io("Failed to clear the tile cache", e)),
+        }
+    }
+}
+
+/// The cached tiles of one image and set of encoding settings.
+#[derive(Debug, Clone)]
+pub(crate) struct ImageCache {
+    dir: PathBuf,
+    extension: &'static str,
+}
+
+impl ImageCache {
+    fn path(&self, zoom_level: u32, x: u32, y: u32) -> PathBuf {
+        self.dir
+            .join(zoom_level.to_string())
+            .join(format!("{}_{}.{}", x, y, self.extension))
+    }
+
+    /// Whether the tile is cached as a skipped background tile, or `None`
+    /// if it isn't cached at all.
+    pub(crate) fn is_background(&self, zoom_level: u32, x: u32, y: u32) -> Option<bool> {
+        fs::metadata(self.path(zoom_level, x, y))
+            .ok()
+            .map(|metadata| metadata.len() == 0)
+    }
+
+    /// A cached tile: its data, or `None` for a skipped background tile.
+    pub(crate) fn get(&self, zoom_level: u32, x: u32, y: u32) -> Option<Option<Vec<u8>>> {
+        let data = fs::read(self.path(zoom_level, x, y)).ok()?;
+        Some((!data.is_empty()).then_some(data))
+    }
+
+    /// Cache a tile, `None` marking a skipped background tile. Failures
+    /// only cost a re-encode next time, so they are logged and dropped.
+    pub(crate) fn store(&self, zoom_level: u32, x: u32, y: u32, data: Option<&[u8]>) {
+        let path = self.path(zoom_level, x, y);
+        // Written aside and renamed, so a crash can't leave half a tile
+        let temp = path.with_extension("tmp");
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&temp, data.unwrap_or_default()))
+            .and_then(|()| fs::rename(&temp, &path));
+        if let Err(e) = result {
+            tracing::debug!(zoom_level, x, y, "Failed to cache tile: {}", e);
+        }
+    }
+}
@@ -0,0 +1,1240 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::ProcessError;
+use crate::network::NetworkSettings;
+
+// Image types offered by the file picker and picked up by batch folder scans
+pub const IMAGE_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "webp", "tif", "tiff", "svg", "svgz", "pdf", "heic", "heif",
+];
+
+/// Everything one upload run needs: the image, where it goes, and how it
+/// is tiled and encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessConfig {
+    pub image_path: String,
+    pub server_address: String,
+    pub layout_key: String,
+    pub secret: String,
+    // Layout path to upload under instead of a fresh UUID, so a re-run
+    // overwrites the same tiles and other systems can know the path up front
+    #[serde(default)]
+    pub layout_path: Option<String>,
+    // Layer of the layout this image is, e.g. "base"; a layer is finalized
+    // through `layer_endpoint` rather than replacing the whole layout
+    #[serde(default)]
+    pub layer: Option<String>,
+    // Further images aligned with this one, e.g. electrical and HVAC
+    // overlays, uploaded in the same job as layers of the same layout key
+    #[serde(default)]
+    pub layers: Vec<LayerImage>,
+    pub background_color: Color,
+    // Tile width and height in pixels; a single number for square tiles
+    pub tile_size: TileSize,
+    // Pixels each tile shares with its neighbours on every side, as Deep
+    // Zoom viewers that blend tile seams expect; 0 for edge-to-edge tiles
+    #[serde(default)]
+    pub overlap: u32,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    // How the secret is sent to the server
+    #[serde(default)]
+    pub auth: AuthMode,
+    // Timeouts and proxy, sent as top-level fields
+    #[serde(flatten)]
+    pub network: NetworkSettings,
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+    #[serde(default)]
+    pub tile_format: TileFormat,
+    #[serde(default = "default_webp_quality")]
+    pub webp_quality: u8,
+    // Lower JPEG and WebP quality for the zoom levels further out, which
+    // are mostly glanced at on the way in; empty keeps one quality for all
+    #[serde(default)]
+    pub quality_ramp: Vec<QualityStep>,
+    // Most the whole layout may come to, in MB; the run encodes at the
+    // highest JPEG or WebP quality that keeps it under
+    #[serde(default)]
+    pub size_budget_mb: Option<u64>,
+    // Threads used to crop and encode tiles, 0 means one per CPU core
+    #[serde(default)]
+    pub encode_threads: usize,
+    // Leave out tiles that are nothing but background; the viewer has to
+    // treat missing tiles as empty for this to be safe
+    #[serde(default)]
+    pub skip_background_tiles: bool,
+    // How each zoom level is padded out around the image
+    #[serde(default)]
+    pub padding: PaddingMode,
+    // Where the image sits on the padded canvas
+    #[serde(default)]
+    pub anchor: PaddingAnchor,
+    // How levels larger than the source image are produced
+    #[serde(default)]
+    pub upscale: UpscaleMode,
+    // Resampling used to resize the zoom levels
+    #[serde(default)]
+    pub resize_filter: ResizeFilter,
+    // Resize in linear light rather than on the stored sRGB values, so fine
+    // hatching and text don't darken on the low zoom levels
+    #[serde(default)]
+    pub linear_resize: bool,
+    // Unsharp mask strength applied to each downscaled zoom level, so thin
+    // walls and labels stay crisp; 0 leaves the levels as resized
+    #[serde(default)]
+    pub sharpen_amount: f32,
+    // Radius in pixels (the blur's sigma) the unsharp mask works at
+    #[serde(default = "default_sharpen_radius")]
+    pub sharpen_radius: f32,
+    // Resolution vector input (SVG, PDF) is rasterized at, 96 unless set
+    #[serde(default)]
+    pub render_dpi: Option<f32>,
+    // Longer side in pixels to rasterize vector input to, instead of
+    // `render_dpi`
+    #[serde(default)]
+    pub render_size: Option<u32>,
+    // Turn photos upright as their EXIF orientation says before tiling
+    #[serde(default = "default_auto_orient")]
+    pub auto_orient: bool,
+    // Convert images with an embedded ICC profile, e.g. AdobeRGB scans, to
+    // sRGB before tiling
+    #[serde(default = "default_convert_to_srgb")]
+    pub convert_to_srgb: bool,
+    // Page of a PDF to tile, counted from 1
+    #[serde(default = "default_pdf_page")]
+    pub pdf_page: u32,
+    // Frame of an animated GIF to tile, counted from 1, as the animation
+    // shows it with the frames before it drawn underneath
+    #[serde(default = "default_gif_frame")]
+    pub gif_frame: u32,
+    // Further images composited with this one into a single canvas before
+    // anything else, e.g. the separately scanned wings of a campus
+    #[serde(default)]
+    pub stitch: Option<Stitch>,
+    // Degrees the image is turned clockwise before tiling; other angles
+    // than quarter turns straighten skewed scans
+    #[serde(default)]
+    pub rotate: f64,
+    // Mirror the image left to right, after rotating it
+    #[serde(default)]
+    pub flip_horizontal: bool,
+    // Mirror the image top to bottom, after rotating it
+    #[serde(default)]
+    pub flip_vertical: bool,
+    // Part of the image to tile, leaving out e.g. title blocks, legends and
+    // scan margins
+    #[serde(default)]
+    pub crop: Option<CropRect>,
+    // Use the color along the image's edges as the background instead of
+    // `background_color`, so padding matches the scan's paper
+    #[serde(default)]
+    pub auto_background: bool,
+    // Make pixels of this color transparent, e.g. a plan's white paper for
+    // tiles laid over a styled map; needs PNG or WebP tiles
+    #[serde(default)]
+    pub color_key: Option<Color>,
+    // How far, per channel, a pixel may be from `color_key` and still go
+    #[serde(default = "default_color_key_tolerance")]
+    pub color_key_tolerance: u8,
+    // Tone adjustments for dark or washed-out scans, applied once before
+    // the levels are made: brightness is added, -1 to 1 of full scale
+    #[serde(default)]
+    pub brightness: f32,
+    // Contrast around mid-gray, 1 leaves it as it is
+    #[serde(default = "default_contrast")]
+    pub contrast: f32,
+    // Gamma, above 1 lightens the midtones and below 1 darkens them
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+    // Turn the image into shades of gray
+    #[serde(default)]
+    pub grayscale: bool,
+    // Strip borders of the scan's margin color, so deep levels aren't
+    // spent on empty paper
+    #[serde(default)]
+    pub auto_trim: bool,
+    // How far, per channel, a margin pixel may be from the margin color
+    #[serde(default = "default_trim_tolerance")]
+    pub trim_tolerance: u8,
+    // Stop the pyramid at this zoom level, leaving out deeper levels
+    #[serde(default)]
+    pub max_zoom_override: Option<u32>,
+    // Leave out the zoom levels above this one, for viewers that never zoom
+    // out that far
+    #[serde(default)]
+    pub min_zoom: u32,
+    // Where the tiles go
+    #[serde(default)]
+    pub output: OutputTarget,
+    // Run the whole pipeline but keep the tiles off the server
+    #[serde(default)]
+    pub dry_run: bool,
+    // Folder a dry run writes its tiles to; they are discarded when unset
+    #[serde(default)]
+    pub dry_run_output: Option<String>,
+    // Keep going when a tile fails, retry the failed tiles once at the end
+    // and report the ones that still fail, instead of stopping at the first
+    #[serde(default)]
+    pub continue_on_error: bool,
+    // Server path that deletes an unfinished layout path's tiles, e.g.
+    // `/LayoutUtil/DeleteTiles`; without one they can only be recorded
+    #[serde(default)]
+    pub cleanup_endpoint: Option<String>,
+    // Remove the tiles of a failed or cancelled run through
+    // `cleanup_endpoint` straight away, giving up the chance to resume it
+    #[serde(default)]
+    pub rollback_on_failure: bool,
+    // Upload only the tiles whose content changed since the last upload to
+    // this layout key, reusing its layout path
+    #[serde(default)]
+    pub incremental: bool,
+    // Part of the image, in pixels of the image as tiled, that changed
+    // since the layout was uploaded; only the tiles showing it are redone,
+    // over the existing layout path
+    #[serde(default)]
+    pub update_region: Option<CropRect>,
+    // Reuse encoded tiles of earlier runs of the same image and settings,
+    // and keep this run's for later
+    #[serde(default)]
+    pub tile_cache: bool,
+    // Fail a tile whose upload the server doesn't confirm with the same
+    // SHA-256, echoed in `X-Checksum-SHA256` or the ETag
+    #[serde(default)]
+    pub verify_checksums: bool,
+    // Server path the upload manifest (tile counts, checksum digest) is
+    // POSTed to as JSON before the layout is finalized
+    #[serde(default)]
+    pub manifest_endpoint: Option<String>,
+    // Add a blurhash of every tile to the upload manifest, for viewers to
+    // show in its place while it loads
+    #[serde(default)]
+    pub tile_placeholders: bool,
+    // Draw each tile's boundary and `zoom/x/y` onto it, to check a server's
+    // coordinate mapping in the viewer; not for production layouts
+    #[serde(default)]
+    pub debug_overlay: bool,
+    // Server path a JPEG overview of the layout is uploaded to on finalize,
+    // for list views to show without fetching tiles
+    #[serde(default)]
+    pub thumbnail_endpoint: Option<String>,
+    // Longest side of that overview in pixels
+    #[serde(default = "default_thumbnail_size")]
+    pub thumbnail_size: u32,
+    // Consecutive server failures (unreachable, timed out, 5xx) after which
+    // uploads hold until the server answers again; 0 turns this off
+    #[serde(default)]
+    pub breaker_threshold: u32,
+    // How often a held run checks whether the server is back
+    #[serde(default = "default_breaker_probe_secs")]
+    pub breaker_probe_secs: u64,
+    // Server path taking several tiles per multipart request, e.g.
+    // `/LayoutUtil/UploadTiles`; tiles go one per request without one
+    #[serde(default)]
+    pub batch_endpoint: Option<String>,
+    // Tiles sent per request to `batch_endpoint`
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    // Server path storing a tile as a copy of an earlier one with the same
+    // content, e.g. `/LayoutUtil/ReferenceTile`, so repeated tiles are
+    // uploaded once; without one they are all sent in full
+    #[serde(default)]
+    pub tile_ref_endpoint: Option<String>,
+    // Where each tile is sent, relative to the server address unless it is
+    // a full URL; see `sink::DEFAULT_TILE_URL_TEMPLATE` for the placeholders
+    #[serde(default)]
+    pub tile_url_template: Option<String>,
+    #[serde(default)]
+    pub tile_upload_method: TileUploadMethod,
+    // Server path that points the layout at the uploaded tiles, instead of
+    // `/api/Location/LocationLayout/UpdatePath`
+    #[serde(default)]
+    pub finalize_endpoint: Option<String>,
+    #[serde(default)]
+    pub finalize_method: FinalizeMethod,
+    // Server path that points one layer of a layout at its tiles, instead
+    // of `/api/Location/LocationLayout/UpdateLayerPath`
+    #[serde(default)]
+    pub layer_endpoint: Option<String>,
+    // Sent along with `LayoutKey`, `LayoutPath` and `MaxZoom` on finalize
+    #[serde(default)]
+    pub finalize_params: BTreeMap<String, String>,
+    // Further servers every tile is also uploaded to and finalized on, so
+    // e.g. production and DR stay in sync from one run
+    #[serde(default)]
+    pub mirrors: Vec<ServerTarget>,
+    // Tile the coarse levels first, so viewers get an overview within
+    // seconds; each level is then resized from the source image
+    #[serde(default)]
+    pub low_zoom_first: bool,
+    // Move the decoded image to a temp file and render each level a row of
+    // tiles at a time, for images whose levels are too large to hold in
+    // memory; the image is still decoded whole once
+    #[serde(default)]
+    pub low_memory: bool,
+    // Most memory, in MB, a run's estimate may come to before it switches
+    // to low-memory mode, or is refused if even that needs more
+    #[serde(default)]
+    pub memory_limit_mb: Option<u64>,
+    // File the run's summary is written to as JSON, or a folder to write
+    // `{layout_path}.summary.json` into
+    #[serde(default)]
+    pub summary_path: Option<String>,
+    // Page showing the finalized layout, relative to the server address
+    // unless it is a full URL; see `sink::DEFAULT_VIEWER_URL_TEMPLATE`
+    #[serde(default)]
+    pub viewer_url_template: Option<String>,
+    // Where the live layout's tiles are fetched from for a visual diff,
+    // relative to the server address unless it is a full URL; takes the
+    // placeholders of `tile_url_template`
+    #[serde(default)]
+    pub published_tile_url_template: Option<String>,
+}
+
+/// Width and height of a tile in pixels. Written as a single number when
+/// the tiles are square, as configs always had it, and as
+/// `{ "width": 512, "height": 256 }` otherwise; both forms load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "TileSizeValue", into = "TileSizeValue")]
+pub struct TileSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TileSizeValue {
+    Square(u32),
+    Sized { width: u32, height: u32 },
+}
+
+impl From<TileSizeValue> for TileSize {
+    fn from(value: TileSizeValue) -> Self {
+        match value {
+            TileSizeValue::Square(side) => TileSize::square(side),
+            TileSizeValue::Sized { width, height } => TileSize { width, height },
+        }
+    }
+}
+
+impl From<TileSize> for TileSizeValue {
+    fn from(size: TileSize) -> Self {
+        if size.is_square() {
+            TileSizeValue::Square(size.width)
+        } else {
+            TileSizeValue::Sized {
+                width: size.width,
+                height: size.height,
+            }
+        }
+    }
+}
+
+impl TileSize {
+    pub const fn square(side: u32) -> Self {
+        TileSize {
+            width: side,
+            height: side,
+        }
+    }
+
+    pub fn is_square(self) -> bool {
+        self.width == self.height
+    }
+
+    /// Bytes telling tile sizes apart in the manifest and tile cache keys.
+    /// Square tiles hash as the single size did, so their keys still match.
+    pub(crate) fn key(self) -> Vec<u8> {
+        let mut key = self.width.to_le_bytes().to_vec();
+        if !self.is_square() {
+            key.extend(b"x");
+            key.extend(self.height.to_le_bytes());
+        }
+        key
+    }
+}
+
+impl fmt::Display for TileSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// A color, written as `#RRGGBB` or `#RRGGBBAA` (or the short `#RGB` and
+/// `#RGBA`), a CSS color name, or `r,g,b`. Configs from before it was a
+/// string, with `[r, g, b]` arrays, still load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ColorValue", into = "String")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    // Only used by tile formats with an alpha channel; when not given,
+    // their padding is left transparent
+    pub a: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorValue {
+    Text(String),
+    Rgb([u8; 3]),
+    Rgba([u8; 4]),
+}
+
+impl TryFrom<ColorValue> for Color {
+    type Error = String;
+
+    fn try_from(value: ColorValue) -> Result<Self, String> {
+        match value {
+            ColorValue::Text(text) => text.parse(),
+            ColorValue::Rgb([r, g, b]) => Ok(Color::rgb(r, g, b)),
+            ColorValue::Rgba([r, g, b, a]) => Ok(Color {
+                r,
+                g,
+                b,
+                a: Some(a),
+            }),
+        }
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.to_string()
+    }
+}
+
+// The CSS basic colors, plus the whites and greys paper tends to be
+const NAMED_COLORS: &[(&str, [u8; 3])] = &[
+    ("black", [0, 0, 0]),
+    ("silver", [192, 192, 192]),
+    ("gray", [128, 128, 128]),
+    ("grey", [128, 128, 128]),
+    ("white", [255, 255, 255]),
+    ("maroon", [128, 0, 0]),
+    ("red", [255, 0, 0]),
+    ("purple", [128, 0, 128]),
+    ("fuchsia", [255, 0, 255]),
+    ("green", [0, 128, 0]),
+    ("lime", [0, 255, 0]),
+    ("olive", [128, 128, 0]),
+    ("yellow", [255, 255, 0]),
+    ("navy", [0, 0, 128]),
+    ("blue", [0, 0, 255]),
+    ("teal", [0, 128, 128]),
+    ("aqua", [0, 255, 255]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgrey", [211, 211, 211]),
+    ("gainsboro", [220, 220, 220]),
+    ("whitesmoke", [245, 245, 245]),
+    ("snow", [255, 250, 250]),
+    ("ivory", [255, 255, 240]),
+    ("linen", [250, 240, 230]),
+    ("beige", [245, 245, 220]),
+    ("floralwhite", [255, 250, 240]),
+    ("oldlace", [253, 245, 230]),
+];
+
+impl Color {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b, a: None }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        match self.a {
+            Some(a) => write!(f, "{:02x}", a),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let invalid = || {
+            format!(
+                "invalid color '{}', expected #RRGGBB, #RRGGBBAA, a color name or r,g,b",
+                value
+            )
+        };
+        if let Some(hex) = value.strip_prefix('#') {
+            let digits: Vec<u8> = hex
+                .chars()
+                .map(|c| c.to_digit(16).map(|d| d as u8))
+                .collect::<Option<_>>()
+                .ok_or_else(invalid)?;
+            let channels: Vec<u8> = match digits.len() {
+                // #RGB and #RGBA repeat each digit
+                3 | 4 => digits.iter().map(|d| d * 17).collect(),
+                6 | 8 => digits
+                    .chunks(2)
+                    .map(|pair| pair[0] * 16 + pair[1])
+                    .collect(),
+                _ => return Err(invalid()),
+            };
+            return Ok(Color {
+                r: channels[0],
+                g: channels[1],
+                b: channels[2],
+                a: channels.get(3).copied(),
+            });
+        }
+        if value.contains(',') {
+            let parts = value
+                .split(',')
+                .map(|part| part.trim().parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?;
+            return match parts[..] {
+                [r, g, b] => Ok(Color::rgb(r, g, b)),
+                [r, g, b, a] => Ok(Color {
+                    r,
+                    g,
+                    b,
+                    a: Some(a),
+                }),
+                _ => Err(invalid()),
+            };
+        }
+        let name = value.to_ascii_lowercase();
+        if name == "transparent" {
+            return Ok(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: Some(0),
+            });
+        }
+        NAMED_COLORS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|&(_, [r, g, b])| Color::rgb(r, g, b))
+            .ok_or_else(invalid)
+    }
+}
+
+/// Destination of a run's tiles.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum OutputTarget {
+    /// Upload to `server_address`
+    #[default]
+    Server,
+    /// Export to a local folder, with a `layout.json` describing the pyramid
+    Directory { path: String },
+    /// Export to a folder in Deep Zoom (`.dzi`) format
+    Dzi { path: String },
+    /// Export into a single MBTiles (SQLite) file
+    Mbtiles { path: String },
+    /// Pack the folder export into one TAR file, uploaded later with
+    /// `upload_package` from a machine that can reach the server
+    Package { path: String },
+    /// Upload to an S3-compatible bucket, then finalize with
+    /// `server_address` pointing the layout at the bucket
+    S3(S3Settings),
+    /// Write `{zoom}/{x}/{y}.{ext}` plus `layout.json` into a folder on an
+    /// SFTP server
+    Sftp(SftpSettings),
+}
+
+/// Another server a run uploads to, with its own layout key and secret.
+/// Everything else, routes included, is shared with the main server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerTarget {
+    pub server_address: String,
+    pub layout_key: String,
+    #[serde(default)]
+    pub secret: String,
+    #[serde(default)]
+    pub auth: AuthMode,
+}
+
+/// Bucket and credentials of an S3-compatible object store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct S3Settings {
+    // e.g. `https://s3.eu-west-1.amazonaws.com` or a MinIO address; the
+    // bucket is addressed in the path
+    pub endpoint: String,
+    pub bucket: String,
+    // Key prefix tiles are stored under, before `{layout_key}/{path}`
+    #[serde(default)]
+    pub prefix: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    // Address the tiles are served from, when not the bucket itself (a CDN
+    // in front of it, say); handed to the server on finalize
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+/// An SFTP drop folder and how to log in to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SftpSettings {
+    pub host: String,
+    #[serde(default = "default_sftp_port")]
+    pub port: u16,
+    pub username: String,
+    pub auth: SftpAuth,
+    // Folder the pyramid is written into, relative to the login folder
+    // unless absolute; created if missing
+    #[serde(default)]
+    pub remote_dir: String,
+    // SHA-256 of the server's host key, as hex or as the `SHA256:` base64
+    // `ssh-keygen -lf` prints; when set, any other key is refused. Without
+    // it the key has to match `~/.ssh/known_hosts`
+    #[serde(default)]
+    pub host_key_sha256: Option<String>,
+    // Skip checking the host key against `~/.ssh/known_hosts`, for servers
+    // that aren't in it; any key is then accepted
+    #[serde(default)]
+    pub accept_any_host_key: bool,
+}
+
+impl SftpSettings {
+    /// Settings that weaken the connection's security, worded for the user.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.accept_any_host_key && self.host_key_sha256.is_none() {
+            warnings.push(
+                "SFTP host key checks were disabled; the server's identity was not verified"
+                    .to_string(),
+            );
+        }
+        warnings
+    }
+}
+
+/// How the SFTP login is proven.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SftpAuth {
+    Password {
+        password: String,
+    },
+    /// A private key file, in OpenSSH or PEM format
+    Key {
+        path: String,
+        #[serde(default)]
+        passphrase: Option<String>,
+    },
+}
+
+/// Where requests carry the secret.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum AuthMode {
+    /// As a query parameter (`__sc__` on uploads, `apikey` on finalize),
+    /// which ends up in server access logs
+    #[default]
+    Query,
+    /// As `Authorization: Bearer <secret>`
+    Bearer,
+    /// As the value of a custom header
+    Header { name: String },
+}
+
+/// How a tile is sent to its upload URL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileUploadMethod {
+    /// POST as a multipart form with the tile in a `file` part
+    #[default]
+    Post,
+    /// PUT with the tile as the raw request body
+    Put,
+}
+
+/// The canvas each zoom level is padded out to around the image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaddingMode {
+    /// Each side rounded up to whole tiles, so a portrait or panoramic
+    /// image doesn't carry a band of empty tiles
+    #[default]
+    Tiles,
+    /// A square of `2^zoom` tiles per side, the full pyramid viewers that
+    /// assume one expect
+    Square,
+    /// No padding: the tiles of the last column and row are cut to the
+    /// image, so they can be smaller than `tile_size`
+    None,
+}
+
+/// Where the image sits on a zoom level's padded canvas.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PaddingAnchor {
+    /// Centred, the padding split evenly between the sides
+    #[default]
+    Center,
+    /// In the top-left corner, all padding right and below
+    TopLeft,
+    /// `x` and `y` are the share of the horizontal and vertical padding
+    /// placed left of and above the image, from 0 to 1
+    Custom { x: f64, y: f64 },
+}
+
+impl PaddingAnchor {
+    /// Share of the horizontal and vertical padding before the image.
+    pub fn fractions(self) -> (f64, f64) {
+        match self {
+            PaddingAnchor::Center => (0.5, 0.5),
+            PaddingAnchor::TopLeft => (0.0, 0.0),
+            PaddingAnchor::Custom { x, y } => (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0)),
+        }
+    }
+
+    /// Bytes telling anchors apart in the manifest and tile cache keys.
+    pub(crate) fn key(self) -> Vec<u8> {
+        let (x, y) = self.fractions();
+        [x.to_le_bytes(), y.to_le_bytes()].concat()
+    }
+}
+
+/// A rectangle of the image in pixels, from its top-left corner, as it
+/// stands once turned upright, rotated and flipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropRect {
+    /// Bytes telling crops apart in the manifest and tile cache keys.
+    pub(crate) fn key(self) -> Vec<u8> {
+        [self.x, self.y, self.width, self.height]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+}
+
+/// A further layer of a layered upload: an image the same size as
+/// `image_path`, tiled the same way and uploaded as the layer `name`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerImage {
+    pub name: String,
+    pub image_path: String,
+}
+
+/// Images stitched onto one canvas with `image_path`, which is then
+/// prepared and tiled as if it were a single image. Gaps between the
+/// images are left transparent and filled like padding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stitch {
+    // In order after `image_path`
+    pub images: Vec<StitchImage>,
+    pub placement: StitchPlacement,
+}
+
+/// One further image of a stitch. `x` and `y` are only read with
+/// `StitchPlacement::Offsets`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StitchImage {
+    pub path: String,
+    #[serde(default)]
+    pub x: i64,
+    #[serde(default)]
+    pub y: i64,
+}
+
+/// How the images of a stitch are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum StitchPlacement {
+    /// Rows of `columns` images, `image_path` first, left to right and top
+    /// to bottom. Each column is as wide as its widest image and each row
+    /// as tall as its tallest, the images in their cells' top-left corners.
+    Grid { columns: u32 },
+    /// Each image at its `x`, `y` pixel offset from the top-left corner of
+    /// `image_path`, which may be negative for images above or left of it
+    Offsets,
+}
+
+impl Stitch {
+    /// Bytes telling stitches apart in the manifest and tile cache keys,
+    /// besides the images' own contents.
+    pub(crate) fn key(&self) -> Vec<u8> {
+        let mut key = match self.placement {
+            StitchPlacement::Grid { columns } => {
+                [b"grid".as_slice(), &columns.to_le_bytes()].concat()
+            }
+            StitchPlacement::Offsets => b"offsets".to_vec(),
+        };
+        for image in &self.images {
+            key.extend(image.path.as_bytes());
+            key.push(0);
+            if self.placement == StitchPlacement::Offsets {
+                key.extend(image.x.to_le_bytes());
+                key.extend(image.y.to_le_bytes());
+            }
+        }
+        key
+    }
+}
+
+/// A JPEG and WebP quality for the zoom levels at least `levels_below_max`
+/// levels out from the deepest one, down to the next step of the ramp.
+/// Counting from the deepest level lets a ramp such as "70 from one level
+/// out" fit any image, whatever its zoom levels come to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QualityStep {
+    pub levels_below_max: u32,
+    pub quality: u8,
+}
+
+/// How zoom levels larger than the source image are produced. The deepest
+/// level is usually somewhat larger, as its side is a power of two tiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpscaleMode {
+    /// Enlarged with `resize_filter`, Lanczos unless another is picked
+    #[default]
+    Lanczos,
+    /// Enlarged by repeating pixels, keeping hard edges such as line art
+    Nearest,
+    /// Kept at native resolution, with the extra space padded
+    None,
+}
+
+/// Resampling filter for resizing the zoom levels, fastest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    /// Picks the closest pixel; blocky, but by far the fastest, for drafts
+    Nearest,
+    /// Linear, soft
+    Triangle,
+    /// Cubic, sharper than triangle
+    CatmullRom,
+    /// Smooth, blurring fine lines
+    Gaussian,
+    /// Sharpest, for photographic site maps
+    #[default]
+    #[serde(alias = "lanczos")]
+    Lanczos3,
+}
+
+/// How the finalize request carries its parameters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinalizeMethod {
+    /// GET with the parameters in the query string
+    #[default]
+    Get,
+    /// POST with the parameters as a JSON object
+    Post,
+}
+
+/// Encoding of the generated tiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileFormat {
+    #[default]
+    Jpeg,
+    /// Keeps the alpha channel, for layouts with transparent backgrounds
+    Png,
+    /// Lossy WebP, noticeably smaller than JPEG at the same quality
+    Webp,
+}
+
+impl TileFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => "image/jpeg",
+            TileFormat::Png => "image/png",
+            TileFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Png => "png",
+            TileFormat::Webp => "webp",
+        }
+    }
+
+    pub fn supports_alpha(self) -> bool {
+        !matches!(self, TileFormat::Jpeg)
+    }
+}
+
+impl ProcessConfig {
+    /// A config with every optional setting at its default, as if only the
+    /// required fields had been sent from the frontend.
+    pub fn new(
+        image_path: String,
+        server_address: String,
+        layout_key: String,
+        secret: String,
+    ) -> Self {
+        Self {
+            image_path,
+            server_address,
+            layout_key,
+            secret,
+            layout_path: None,
+            layer: None,
+            layers: Vec::new(),
+            background_color: Color::rgb(0, 0, 0),
+            tile_size: TileSize::square(256),
+            overlap: 0,
+            concurrency: default_concurrency(),
+            pool_size: default_pool_size(),
+            keep_alive_secs: default_keep_alive_secs(),
+            auth: AuthMode::default(),
+            network: NetworkSettings::default(),
+            jpeg_quality: default_jpeg_quality(),
+            tile_format: TileFormat::default(),
+            webp_quality: default_webp_quality(),
+            quality_ramp: Vec::new(),
+            size_budget_mb: None,
+            encode_threads: 0,
+            skip_background_tiles: false,
+            padding: PaddingMode::default(),
+            anchor: PaddingAnchor::default(),
+            upscale: UpscaleMode::default(),
+            resize_filter: ResizeFilter::default(),
+            linear_resize: false,
+            sharpen_amount: 0.0,
+            sharpen_radius: default_sharpen_radius(),
+            render_dpi: None,
+            render_size: None,
+            auto_orient: default_auto_orient(),
+            convert_to_srgb: default_convert_to_srgb(),
+            pdf_page: default_pdf_page(),
+            gif_frame: default_gif_frame(),
+            stitch: None,
+            rotate: 0.0,
+            flip_horizontal: false,
+            flip_vertical: false,
+            crop: None,
+            auto_background: false,
+            auto_trim: false,
+            color_key: None,
+            color_key_tolerance: default_color_key_tolerance(),
+            brightness: 0.0,
+            contrast: default_contrast(),
+            gamma: default_gamma(),
+            grayscale: false,
+            trim_tolerance: default_trim_tolerance(),
+            max_zoom_override: None,
+            min_zoom: 0,
+            output: OutputTarget::default(),
+            dry_run: false,
+            dry_run_output: None,
+            continue_on_error: false,
+            cleanup_endpoint: None,
+            rollback_on_failure: false,
+            incremental: false,
+            update_region: None,
+            tile_cache: false,
+            verify_checksums: false,
+            manifest_endpoint: None,
+            tile_placeholders: false,
+            debug_overlay: false,
+            thumbnail_endpoint: None,
+            thumbnail_size: default_thumbnail_size(),
+            breaker_threshold: 0,
+            breaker_probe_secs: default_breaker_probe_secs(),
+            batch_endpoint: None,
+            tile_ref_endpoint: None,
+            batch_size: default_batch_size(),
+            tile_url_template: None,
+            tile_upload_method: TileUploadMethod::default(),
+            finalize_endpoint: None,
+            finalize_method: FinalizeMethod::default(),
+            layer_endpoint: None,
+            finalize_params: BTreeMap::new(),
+            mirrors: Vec::new(),
+            low_zoom_first: false,
+            low_memory: false,
+            memory_limit_mb: None,
+            summary_path: None,
+            viewer_url_template: None,
+            published_tile_url_template: None,
+        }
+    }
+
+    /// How the levels are padded. A Deep Zoom descriptor describes a square
+    /// canvas of `2^max_zoom` tiles a side, so DZI exports are always
+    /// padded square whatever `padding` says.
+    pub fn effective_padding(&self) -> PaddingMode {
+        match self.output {
+            OutputTarget::Dzi { .. } => PaddingMode::Square,
+            _ => self.padding,
+        }
+    }
+
+    /// Tiles handed to the sink at once. Only server uploads with a batch
+    /// endpoint group them, and not when every tile's checksum has to be
+    /// confirmed on its own.
+    pub fn upload_batch_size(&self) -> usize {
+        let batching = self.batch_endpoint.is_some()
+            && self.output == OutputTarget::Server
+            && !self.dry_run
+            && !self.verify_checksums;
+        if batching {
+            self.batch_size.max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Bytes telling tone adjustments apart in the manifest and tile cache
+    /// keys, or `None` when the image's tones are left as they are.
+    pub(crate) fn tone_key(&self) -> Option<Vec<u8>> {
+        let unchanged =
+            self.brightness == 0.0 && self.contrast == 1.0 && self.gamma == 1.0 && !self.grayscale;
+        (!unchanged).then(|| {
+            [self.brightness, self.contrast, self.gamma]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .chain([self.grayscale as u8])
+                .collect()
+        })
+    }
+
+    /// The quality `quality_ramp` gives `zoom_level` of a pyramid whose
+    /// deepest level is `max_zoom`, or `None` to keep the format's own.
+    pub(crate) fn ramp_quality(&self, zoom_level: u32, max_zoom: u32) -> Option<u8> {
+        let below_max = max_zoom.saturating_sub(zoom_level);
+        self.quality_ramp
+            .iter()
+            .filter(|step| step.levels_below_max <= below_max)
+            .max_by_key(|step| step.levels_below_max)
+            .map(|step| step.quality)
+    }
+
+    /// Bytes telling quality ramps apart in the manifest and tile cache
+    /// keys, or `None` when every level has the same quality.
+    pub(crate) fn quality_ramp_key(&self) -> Option<Vec<u8>> {
+        if self.quality_ramp.is_empty() {
+            return None;
+        }
+        let mut steps = self.quality_ramp.clone();
+        steps.sort_by_key(|step| step.levels_below_max);
+        Some(
+            steps
+                .iter()
+                .flat_map(|step| {
+                    step.levels_below_max
+                        .to_le_bytes()
+                        .into_iter()
+                        .chain([step.quality])
+                })
+                .collect(),
+        )
+    }
+
+    /// This config with every credential cleared: the secrets of the server
+    /// and its mirrors, the proxy and client certificate passwords, and the
+    /// S3 and SFTP logins. Whatever is written to disk goes through this;
+    /// the credentials are filled in again from the credential store.
+    /// Optional passwords that were set are left empty rather than `None`,
+    /// so only those are looked up again.
+    pub fn without_secrets(&self) -> Self {
+        let mut config = ProcessConfig {
+            secret: String::new(),
+            network: self.network.without_secrets(),
+            ..self.clone()
+        };
+        for mirror in &mut config.mirrors {
+            mirror.secret.clear();
+        }
+        match &mut config.output {
+            OutputTarget::S3(s3) => s3.secret_access_key.clear(),
+            OutputTarget::Sftp(sftp) => match &mut sftp.auth {
+                SftpAuth::Password { password } => password.clear(),
+                SftpAuth::Key {
+                    passphrase: Some(passphrase),
+                    ..
+                } => passphrase.clear(),
+                SftpAuth::Key { .. } => {}
+            },
+            _ => {}
+        }
+        config
+    }
+
+    /// The layout path a new run uploads under: `layout_path` if given,
+    /// otherwise a fresh UUID, which a region update can't use. The path
+    /// ends up in tile URLs, so it may only hold letters, digits, `-` and
+    /// `_`.
+    pub fn resolve_layout_path(&self) -> Result<String, ProcessError> {
+        let Some(layout_path) = &self.layout_path else {
+            // A fresh path would hold nothing but the updated tiles
+            if self.update_region.is_some() {
+                return Err(ProcessError::InvalidConfig(
+                    "A region update needs the layout path of the layout to update".to_string(),
+                ));
+            }
+            return Ok(uuid::Uuid::new_v4().to_string());
+        };
+        let valid = !layout_path.is_empty()
+            && layout_path.len() <= MAX_LAYOUT_PATH_LEN
+            && layout_path
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Invalid layout path '{}': use 1 to {} letters, digits, '-' or '_'",
+                layout_path, MAX_LAYOUT_PATH_LEN
+            )));
+        }
+        Ok(layout_path.clone())
+    }
+}
+
+const MAX_LAYOUT_PATH_LEN: usize = 64;
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_pool_size() -> usize {
+    16
+}
+
+fn default_keep_alive_secs() -> u64 {
+    90
+}
+
+// Matches the image crate's own default, so existing runs look the same
+fn default_jpeg_quality() -> u8 {
+    75
+}
+
+fn default_webp_quality() -> u8 {
+    80
+}
+
+fn default_thumbnail_size() -> u32 {
+    512
+}
+
+fn default_breaker_probe_secs() -> u64 {
+    15
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+fn default_batch_size() -> usize {
+    32
+}
+
+fn default_auto_orient() -> bool {
+    true
+}
+
+fn default_convert_to_srgb() -> bool {
+    true
+}
+
+fn default_sharpen_radius() -> f32 {
+    1.0
+}
+
+fn default_contrast() -> f32 {
+    1.0
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+fn default_color_key_tolerance() -> u8 {
+    16
+}
+
+fn default_trim_tolerance() -> u8 {
+    16
+}
+
+fn default_pdf_page() -> u32 {
+    1
+}
+
+fn default_gif_frame() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Result<Color, String> {
+        value.parse()
+    }
+
+    fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color {
+            r,
+            g,
+            b,
+            a: Some(a),
+        }
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse("#1a2B3c"), Ok(Color::rgb(0x1a, 0x2b, 0x3c)));
+        assert_eq!(parse("#1a2b3c80"), Ok(rgba(0x1a, 0x2b, 0x3c, 0x80)));
+        assert_eq!(parse("#f0a"), Ok(Color::rgb(0xff, 0x00, 0xaa)));
+        assert_eq!(parse("#f0a8"), Ok(rgba(0xff, 0x00, 0xaa, 0x88)));
+        assert_eq!(parse("  #ffffff "), Ok(Color::rgb(255, 255, 255)));
+        for invalid in ["#", "#ff", "#fffff", "#fffffffff", "#ggg", "ffffff"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_channel_lists_and_names() {
+        assert_eq!(parse("255, 128,0"), Ok(Color::rgb(255, 128, 0)));
+        assert_eq!(parse("1,2,3,4"), Ok(rgba(1, 2, 3, 4)));
+        assert!(parse("1,2").is_err());
+        assert!(parse("1,2,3,4,5").is_err());
+        assert!(parse("256,0,0").is_err());
+        assert!(parse("-1,0,0").is_err());
+
+        assert_eq!(parse("White"), Ok(Color::rgb(255, 255, 255)));
+        assert_eq!(parse("grey"), parse("gray"));
+        assert_eq!(parse("transparent"), Ok(rgba(0, 0, 0, 0)));
+        assert!(parse("blurple").is_err());
+    }
+
+    #[test]
+    fn colors_round_trip_through_configs() {
+        for color in [Color::rgb(0x12, 0xab, 0xef), rgba(1, 2, 3, 4)] {
+            assert_eq!(parse(&color.to_string()), Ok(color));
+            let json = serde_json::to_value(color).unwrap();
+            assert_eq!(serde_json::from_value::<Color>(json).unwrap(), color);
+        }
+        assert_eq!(Color::rgb(255, 0, 16).to_string(), "#ff0010");
+
+        // Arrays from configs saved before colors were strings
+        let old: Color = serde_json::from_str("[10, 20, 30]").unwrap();
+        assert_eq!(old, Color::rgb(10, 20, 30));
+        let old: Color = serde_json::from_str("[10, 20, 30, 40]").unwrap();
+        assert_eq!(old, rgba(10, 20, 30, 40));
+    }
+}
@@ -0,0 +1,160 @@
+use reqwest::Url;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use crate::config::AuthMode;
+use crate::error::ProcessError;
+use crate::network::{self, NetworkSettings};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of `test_connection`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionReport {
+    pub ok: bool,
+    // Time until the server answered; `None` if it never did
+    pub latency_ms: Option<u64>,
+    pub status: Option<u16>,
+    // Why the check failed: "invalid_url", "invalid_config", "unreachable",
+    // "timeout", "proxy", "certificate", "unauthorized" or "server_error"
+    pub reason: Option<&'static str>,
+    pub message: String,
+    // Settings that weaken the connection, such as skipped certificate checks
+    pub warnings: Vec<String>,
+}
+
+impl ConnectionReport {
+    fn failed(reason: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            latency_ms: None,
+            status: None,
+            reason: Some(reason),
+            message: message.into(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// Check that the server is reachable and accepts the secret, without
+/// uploading anything.
+///
+/// The probe is a GET on the layout's tile upload route with the secret
+/// attached the same way tile uploads attach it. That route only takes
+/// POSTed tiles, so any answer other than 401/403 or a server error means
+/// the server is up and took the secret. The secret is sent the way `auth`
+/// says and requests go through the proxy in `network`, like uploads do.
+pub async fn test_connection(
+    server_address: &str,
+    layout_key: &str,
+    secret: &str,
+    auth: &AuthMode,
+    network: &NetworkSettings,
+) -> Result<ConnectionReport, ProcessError> {
+    let server = match Url::parse(server_address.trim_end_matches('/')) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => url,
+        Ok(_) => {
+            return Ok(ConnectionReport::failed(
+                "invalid_url",
+                "Server address must be an http:// or https:// URL",
+            ))
+        }
+        Err(e) => {
+            return Ok(ConnectionReport::failed(
+                "invalid_url",
+                format!("Invalid server address: {}", e),
+            ))
+        }
+    };
+    if layout_key.trim().is_empty() || secret.trim().is_empty() {
+        return Ok(ConnectionReport::failed(
+            "invalid_config",
+            "Layout key and secret are required",
+        ));
+    }
+    if let Err(e) = auth.validate() {
+        return Ok(ConnectionReport::failed("invalid_config", e.to_string()));
+    }
+
+    let client = network
+        .client_builder()?
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .map_err(|e| ProcessError::http("Failed to create HTTP client", e))?;
+
+    let url = format!(
+        "{}/LayoutUtil/UploadTile/{}",
+        server.as_str().trim_end_matches('/'),
+        layout_key
+    );
+
+    let started = Instant::now();
+    let response = auth
+        .authorize(client.get(&url), "__sc__", secret)
+        .send()
+        .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    let response = match response {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() => {
+            return Ok(ConnectionReport::failed(
+                "timeout",
+                "The server did not respond in time",
+            ))
+        }
+        Err(e) if network::is_certificate_error(&e) => {
+            return Ok(ConnectionReport::failed(
+                "certificate",
+                format!(
+                    "The server's certificate is not trusted; add its CA bundle: {}",
+                    e
+                ),
+            ))
+        }
+        Err(e) if network::is_proxy_error(&e) => {
+            return Ok(ConnectionReport::failed(
+                "proxy",
+                format!("The proxy refused the request: {}", e),
+            ))
+        }
+        Err(e) => {
+            return Ok(ConnectionReport::failed(
+                "unreachable",
+                format!("Could not reach the server: {}", e),
+            ))
+        }
+    };
+
+    let status = response.status();
+    let (ok, reason, message) = if status == 401 || status == 403 {
+        (
+            false,
+            Some("unauthorized"),
+            format!("The server rejected the secret ({})", status),
+        )
+    } else if status == 407 {
+        (
+            false,
+            Some("proxy"),
+            "The proxy asked for credentials (407)".to_string(),
+        )
+    } else if status.is_server_error() {
+        (
+            false,
+            Some("server_error"),
+            format!("The server returned an error ({})", status),
+        )
+    } else {
+        (true, None, format!("Connected in {} ms", latency_ms))
+    };
+
+    Ok(ConnectionReport {
+        ok,
+        latency_ms: Some(latency_ms),
+        status: Some(status.as_u16()),
+        reason,
+        message,
+        warnings: network.warnings(),
+    })
+}
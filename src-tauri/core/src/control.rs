@@ -0,0 +1,65 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::ProcessError;
+use crate::progress::ProgressReporter;
+
+/// Holds a job between tiles while set. Uploads already in flight finish;
+/// no new ones start until the job is resumed.
+#[derive(Debug, Clone)]
+pub struct PauseToken {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for PauseToken {
+    fn default() -> Self {
+        Self {
+            paused: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl PauseToken {
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+}
+
+/// How the owner of a job steers it while it runs.
+#[derive(Debug, Clone, Default)]
+pub struct JobControl {
+    pub cancel: CancellationToken,
+    pub pause: PauseToken,
+}
+
+impl JobControl {
+    /// Called between tiles: fails once the job is cancelled, and waits
+    /// here for as long as it is paused.
+    pub(crate) async fn checkpoint(
+        &self,
+        reporter: &mut ProgressReporter,
+    ) -> Result<(), ProcessError> {
+        if self.cancel.is_cancelled() {
+            return Err(ProcessError::Cancelled);
+        }
+        if !self.pause.is_paused() {
+            return Ok(());
+        }
+
+        reporter.paused().await;
+        let mut paused = self.pause.paused.subscribe();
+        tokio::select! {
+            _ = paused.wait_for(|paused| !*paused) => Ok(()),
+            _ = self.cancel.cancelled() => Err(ProcessError::Cancelled),
+        }
+    }
+}
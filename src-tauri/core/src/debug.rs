@@ -0,0 +1,95 @@
+//! The `debug_overlay` diagnostics: every tile gets its boundary and its
+//! `zoom/x/y` drawn on, so a new server deployment's coordinate mapping
+//! can be checked by eye in the viewer.
+
+use image::{Rgba, RgbaImage};
+
+use crate::config::TileSize;
+
+const BORDER: Rgba<u8> = Rgba([255, 0, 0, 255]);
+const LABEL_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LABEL_TEXT: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+// 5x7 glyphs, one row per byte from the top, the leftmost column in bit 4
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const DIGITS: [[u8; 7]; 10] = [
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+];
+const SLASH: [u8; 7] = [0x01, 0x01, 0x02, 0x04, 0x08, 0x10, 0x10];
+
+/// Draw the boundary of the tile at `x`, `y` of `zoom_level` and its
+/// `zoom/x/y` label onto `tile`, whose own area starts `inset` pixels in
+/// (the overlap it shares with its left and top neighbours).
+pub(crate) fn draw_overlay(
+    tile: &mut RgbaImage,
+    zoom_level: u32,
+    x: u32,
+    y: u32,
+    tile_size: TileSize,
+    inset: (u32, u32),
+) {
+    let (left, top) = inset;
+    let right = (left + tile_size.width).min(tile.width());
+    let bottom = (top + tile_size.height).min(tile.height());
+    if left >= right || top >= bottom {
+        return;
+    }
+    // Big enough to read on large tiles, never wider than a small one
+    let scale = (tile_size.width / 128).max(1);
+
+    for px in left..right {
+        tile.put_pixel(px, top, BORDER);
+        tile.put_pixel(px, bottom - 1, BORDER);
+    }
+    for py in top..bottom {
+        tile.put_pixel(left, py, BORDER);
+        tile.put_pixel(right - 1, py, BORDER);
+    }
+
+    let label = format!("{}/{}/{}", zoom_level, x, y);
+    let advance = (GLYPH_WIDTH + 1) * scale;
+    let padding = 2 * scale;
+    let origin_x = left + 2 + padding;
+    let origin_y = top + 2 + padding;
+    let box_right = (origin_x + advance * label.len() as u32 + padding).min(right - 1);
+    let box_bottom = (origin_y + GLYPH_HEIGHT * scale + padding).min(bottom - 1);
+    for py in top + 2..box_bottom {
+        for px in left + 2..box_right {
+            tile.put_pixel(px, py, LABEL_BACKGROUND);
+        }
+    }
+
+    for (i, character) in label.chars().enumerate() {
+        let glyph = match character.to_digit(10) {
+            Some(digit) => &DIGITS[digit as usize],
+            None => &SLASH,
+        };
+        let glyph_x = origin_x + i as u32 * advance;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = glyph_x + column * scale + dx;
+                        let py = origin_y + row as u32 * scale + dy;
+                        if px < box_right && py < box_bottom {
+                            tile.put_pixel(px, py, LABEL_TEXT);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,554 @@
+//! Reading the source image. Everything that decodes or measures
+//! `config.image_path` goes through here, so every path accepts the same
+//! formats, and sees the images of `config.stitch` composited with it.
+
+use image::codecs::gif::GifDecoder;
+use image::error::{DecodingError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{
+    imageops, AnimationDecoder, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader,
+    Limits, RgbaImage,
+};
+#[cfg(feature = "heif")]
+use libheif_rs::{color_profile_types, ColorProfile, ColorSpace, HeifContext, LibHeif, RgbChroma};
+use pdfium_render::prelude::{PdfDocument, PdfPage, PdfPageIndex, PdfRenderConfig, Pdfium, Pixels};
+use resvg::{tiny_skia, usvg};
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+use crate::icc;
+use crate::prepare::{prepare, prepared_size};
+use crate::stitch;
+
+/// What the header of the source image says, read without decoding pixels.
+#[derive(Debug, Clone)]
+pub struct ImageHeader {
+    pub width: u32,
+    pub height: u32,
+    // Detected from the file's contents, e.g. "Png"
+    pub format: String,
+    // Pixel layout as stored in the file, e.g. "Rgb8"
+    pub color_type: String,
+    // Bytes per pixel once decoded
+    pub bytes_per_pixel: u8,
+}
+
+fn reader(config: &ProcessConfig) -> Result<ImageReader<BufReader<File>>, ProcessError> {
+    ImageReader::open(&config.image_path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| ProcessError::io(format!("Failed to read {}", config.image_path), e))
+}
+
+/// Resolution vector input is rasterized at when `render_dpi` isn't set.
+/// SVG lengths are CSS pixels, 96 to the inch, so this keeps an SVG's own
+/// size.
+pub const DEFAULT_RENDER_DPI: f32 = 96.0;
+
+/// Read the header of `config.image_path`. The size is the image's once
+/// prepared for tiling, e.g. cropped, but not trimmed: the margins
+/// `auto_trim` takes off need the pixels, so with it the size is an upper
+/// bound. Runs and estimates count their tiles from the prepared image.
+pub fn read_header(config: &ProcessConfig) -> Result<ImageHeader, ProcessError> {
+    let mut header = decoded_header(config)?;
+    (header.width, header.height) = prepared_size(config, header.width, header.height)?;
+    Ok(header)
+}
+
+fn decoded_header(config: &ProcessConfig) -> Result<ImageHeader, ProcessError> {
+    let Some(stitch) = &config.stitch else {
+        return file_header(config);
+    };
+    let headers = stitch::parts(config, stitch)
+        .iter()
+        .map(file_header)
+        .collect::<Result<Vec<_>, _>>()?;
+    let sizes: Vec<_> = headers
+        .iter()
+        .map(|header| (header.width, header.height))
+        .collect();
+    let arrangement = stitch::arrange(stitch, &sizes)?;
+    // Described as the first image, but the canvas is always RGBA
+    let first = headers.into_iter().next().expect("a stitch has image_path");
+    Ok(ImageHeader {
+        width: arrangement.width,
+        height: arrangement.height,
+        bytes_per_pixel: 4,
+        ..first
+    })
+}
+
+fn file_header(config: &ProcessConfig) -> Result<ImageHeader, ProcessError> {
+    if is_svg(config) {
+        return Ok(vector_header("Svg", svg_size(config, &parse_svg(config)?)));
+    }
+    if is_pdf(config) {
+        return Ok(vector_header("Pdf", pdf_size(config)?));
+    }
+    if is_heif(config) {
+        return heif_header(config);
+    }
+    let reader = reader(config)?;
+    let format = reader
+        .format()
+        .map(|format| format!("{:?}", format))
+        .unwrap_or_default();
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| ProcessError::image(format!("Failed to read {}", config.image_path), e))?;
+    let (width, height) = match orientation(config, &mut decoder) {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => {
+            let (width, height) = decoder.dimensions();
+            (height, width)
+        }
+        _ => decoder.dimensions(),
+    };
+    Ok(ImageHeader {
+        width,
+        height,
+        format,
+        color_type: format!("{:?}", decoder.original_color_type()),
+        bytes_per_pixel: decoder.color_type().bytes_per_pixel(),
+    })
+}
+
+/// Decode `config.image_path`, telling the format from its contents, and
+/// turn it upright as its EXIF orientation says unless `auto_orient` is
+/// off. Colors are converted to sRGB from an embedded ICC profile unless
+/// `convert_to_srgb` is off. It is then prepared for tiling, see
+/// `prepare`.
+///
+/// The decoders' default limit of 512 MB per allocation would turn away
+/// the large TIFF scans this is meant for, so it is lifted; the run's
+/// memory is held to `memory_limit_mb` instead.
+pub fn open_image(config: &ProcessConfig) -> Result<DynamicImage, ProcessError> {
+    prepare(decode(config)?, config)
+}
+
+fn decode(config: &ProcessConfig) -> Result<DynamicImage, ProcessError> {
+    let Some(stitch) = &config.stitch else {
+        return decode_file(config);
+    };
+    let parts = stitch::parts(config, stitch);
+    let sizes = parts
+        .iter()
+        .map(|part| file_header(part).map(|header| (header.width, header.height)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let arrangement = stitch::arrange(stitch, &sizes)?;
+    let mut canvas = RgbaImage::new(arrangement.width, arrangement.height);
+    // One image decoded at a time, so only it and the canvas are held;
+    // later images are drawn over earlier ones where they overlap
+    for (part, (x, y)) in parts.iter().zip(arrangement.positions) {
+        let image = decode_file(part)?.into_rgba8();
+        imageops::overlay(&mut canvas, &image, x as i64, y as i64);
+    }
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+fn decode_file(config: &ProcessConfig) -> Result<DynamicImage, ProcessError> {
+    if is_svg(config) {
+        return render_svg(config).map(DynamicImage::ImageRgba8);
+    }
+    if is_pdf(config) {
+        return render_pdf(config).map(DynamicImage::ImageRgba8);
+    }
+    if is_heif(config) {
+        return decode_heif(config);
+    }
+    let mut reader = reader(config)?;
+    if reader.format() == Some(ImageFormat::Gif) {
+        return decode_gif(config);
+    }
+    reader.no_limits();
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| ProcessError::image("Failed to open image", e))?;
+    let orientation = orientation(config, &mut decoder);
+    let icc_profile = if config.convert_to_srgb {
+        decoder.icc_profile().unwrap_or_else(|e| {
+            tracing::warn!(path = %config.image_path, error = %e, "Failed to read ICC profile");
+            None
+        })
+    } else {
+        None
+    };
+    let mut image = DynamicImage::from_decoder(decoder)
+        .map_err(|e| ProcessError::image("Failed to open image", e))?;
+    if let Some(icc_profile) = icc_profile {
+        image = icc::to_srgb(image, &icc_profile, &config.image_path);
+    }
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn open_gif(path: &str) -> Result<GifDecoder<BufReader<File>>, ProcessError> {
+    let file =
+        File::open(path).map_err(|e| ProcessError::io(format!("Failed to read {}", path), e))?;
+    let mut decoder = GifDecoder::new(BufReader::new(file))
+        .map_err(|e| ProcessError::image(format!("Failed to read {}", path), e))?;
+    decoder
+        .set_limits(Limits::no_limits())
+        .map_err(|e| ProcessError::image("Failed to open image", e))?;
+    Ok(decoder)
+}
+
+/// Frames in the GIF at `path`, 1 for a still image. Every frame is
+/// decoded to count them, which makes this blocking.
+pub fn gif_frames(path: &str) -> Result<u32, ProcessError> {
+    open_gif(path)?
+        .into_frames()
+        .try_fold(0, |count, frame| frame.map(|_| count + 1))
+        .map_err(|e| ProcessError::image("Failed to decode GIF frame", e))
+}
+
+/// Frame `gif_frame` of the GIF at `config.image_path`, flattened as the
+/// animation shows it: each frame is drawn over the ones before it as
+/// their disposal says, so a frame that only updates part of the picture
+/// still comes out whole.
+fn decode_gif(config: &ProcessConfig) -> Result<DynamicImage, ProcessError> {
+    let mut frames = open_gif(&config.image_path)?.into_frames();
+    let Some(frame) = frames.nth(config.gif_frame.saturating_sub(1) as usize) else {
+        return Err(ProcessError::InvalidConfig(format!(
+            "Frame {} is out of range, {} has frames 1 to {}",
+            config.gif_frame,
+            config.image_path,
+            gif_frames(&config.image_path)?
+        )));
+    };
+    let frame = frame.map_err(|e| ProcessError::image("Failed to decode GIF frame", e))?;
+    if config.gif_frame == 1 && frames.next().is_some() {
+        tracing::info!(path = %config.image_path, "Tiling the first frame of an animated GIF");
+    }
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+/// How the image has to be turned to stand upright, going by its EXIF
+/// orientation. Phones store photos as the sensor saw them and set this
+/// instead of rotating the pixels.
+fn orientation(config: &ProcessConfig, decoder: &mut impl ImageDecoder) -> Orientation {
+    if !config.auto_orient {
+        return Orientation::NoTransforms;
+    }
+    decoder.orientation().unwrap_or_else(|e| {
+        tracing::warn!(path = %config.image_path, error = %e, "Failed to read EXIF orientation");
+        Orientation::NoTransforms
+    })
+}
+
+fn has_extension(config: &ProcessConfig, extensions: &[&str]) -> bool {
+    Path::new(&config.image_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+fn is_svg(config: &ProcessConfig) -> bool {
+    has_extension(config, &["svg", "svgz"])
+}
+
+fn is_pdf(config: &ProcessConfig) -> bool {
+    has_extension(config, &["pdf"])
+}
+
+fn is_heif(config: &ProcessConfig) -> bool {
+    has_extension(config, &["heic", "heif"])
+}
+
+fn format_error(
+    format: &str,
+    message: &str,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> ProcessError {
+    ProcessError::image(
+        message,
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name(format.to_string()),
+            e,
+        )),
+    )
+}
+
+/// Pixels per unit a drawing `width` by `height` units is rasterized at:
+/// `render_size` over its longer side if set, otherwise `render_dpi` over
+/// the units to the inch.
+fn render_scale(config: &ProcessConfig, width: f32, height: f32, units_per_inch: f32) -> f32 {
+    match config.render_size {
+        Some(size) => size as f32 / width.max(height),
+        None => config.render_dpi.unwrap_or(DEFAULT_RENDER_DPI) / units_per_inch,
+    }
+}
+
+fn render_dimensions(
+    config: &ProcessConfig,
+    width: f32,
+    height: f32,
+    units_per_inch: f32,
+) -> (u32, u32) {
+    let scale = render_scale(config, width, height, units_per_inch);
+    (
+        ((width * scale).ceil() as u32).max(1),
+        ((height * scale).ceil() as u32).max(1),
+    )
+}
+
+fn vector_header(format: &str, (width, height): (u32, u32)) -> ImageHeader {
+    ImageHeader {
+        width,
+        height,
+        format: format.to_string(),
+        color_type: "Rgba8".to_string(),
+        bytes_per_pixel: 4,
+    }
+}
+
+// SVG lengths are CSS pixels
+const SVG_UNITS_PER_INCH: f32 = 96.0;
+
+fn parse_svg(config: &ProcessConfig) -> Result<usvg::Tree, ProcessError> {
+    let data = std::fs::read(&config.image_path)
+        .map_err(|e| ProcessError::io(format!("Failed to read {}", config.image_path), e))?;
+    // Images and fonts the drawing refers to are looked up next to it
+    let mut options = usvg::Options {
+        resources_dir: Path::new(&config.image_path)
+            .parent()
+            .map(Path::to_path_buf),
+        ..Default::default()
+    };
+    options.fontdb_mut().load_system_fonts();
+    usvg::Tree::from_data(&data, &options)
+        .map_err(|e| format_error("SVG", &format!("Failed to read {}", config.image_path), e))
+}
+
+fn svg_size(config: &ProcessConfig, tree: &usvg::Tree) -> (u32, u32) {
+    let size = tree.size();
+    render_dimensions(config, size.width(), size.height(), SVG_UNITS_PER_INCH)
+}
+
+/// Rasterize the SVG at `config.image_path` at the configured resolution.
+fn render_svg(config: &ProcessConfig) -> Result<RgbaImage, ProcessError> {
+    let tree = parse_svg(config)?;
+    let size = tree.size();
+    let scale = render_scale(config, size.width(), size.height(), SVG_UNITS_PER_INCH);
+    let (width, height) = svg_size(config, &tree);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| {
+        format_error(
+            "SVG",
+            "Failed to render SVG",
+            format!(
+                "{}x{} pixels is too large to rasterize, lower render_dpi or render_size",
+                width, height
+            ),
+        )
+    })?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia keeps premultiplied alpha, the rest of the pipeline doesn't
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        pixels.extend_from_slice(&[color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+    RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| ProcessError::Internal("Rasterized SVG has the wrong size".to_string()))
+}
+
+// PDF lengths are points
+const PDF_UNITS_PER_INCH: f32 = 72.0;
+
+/// One page of a PDF, in points (72 to the inch).
+#[derive(Debug, Clone, Serialize)]
+pub struct PdfPageInfo {
+    // Counted from 1, as `pdf_page` takes it
+    pub number: u32,
+    pub width_pt: f32,
+    pub height_pt: f32,
+}
+
+/// Load PDFium, the library PDFs are rendered with, from next to the
+/// executable or else from the system.
+fn pdfium() -> Result<Pdfium, ProcessError> {
+    let bundled = std::env::current_exe().ok().and_then(|exe| {
+        exe.parent()
+            .map(Pdfium::pdfium_platform_library_name_at_path)
+    });
+    let bindings = bundled
+        .map(Pdfium::bind_to_library)
+        .filter(|bindings| bindings.is_ok())
+        .unwrap_or_else(Pdfium::bind_to_system_library)
+        .map_err(|e| format_error("PDF", "Failed to load PDFium, which PDF input needs", e))?;
+    Ok(Pdfium::new(bindings))
+}
+
+fn open_pdf<'a>(pdfium: &'a Pdfium, path: &str) -> Result<PdfDocument<'a>, ProcessError> {
+    pdfium
+        .load_pdf_from_file(path, None)
+        .map_err(|e| format_error("PDF", &format!("Failed to read {}", path), e))
+}
+
+/// The pages of the PDF at `path` and their sizes.
+pub fn pdf_pages(path: &str) -> Result<Vec<PdfPageInfo>, ProcessError> {
+    let pdfium = pdfium()?;
+    let document = open_pdf(&pdfium, path)?;
+    Ok(document
+        .pages()
+        .iter()
+        .enumerate()
+        .map(|(index, page)| PdfPageInfo {
+            number: index as u32 + 1,
+            width_pt: page.width().value,
+            height_pt: page.height().value,
+        })
+        .collect())
+}
+
+/// The `pdf_page` of `document`, and its size rasterized.
+fn pdf_page<'a>(
+    config: &ProcessConfig,
+    document: &'a PdfDocument,
+) -> Result<(PdfPage<'a>, (u32, u32)), ProcessError> {
+    let pages = document.pages();
+    let count = pages.len() as u32;
+    if config.pdf_page == 0 || config.pdf_page > count {
+        return Err(ProcessError::InvalidConfig(format!(
+            "Page {} is out of range, {} has pages 1 to {}",
+            config.pdf_page, config.image_path, count
+        )));
+    }
+    let page = pages
+        .get((config.pdf_page - 1) as PdfPageIndex)
+        .map_err(|e| format_error("PDF", "Failed to read PDF page", e))?;
+    let size = render_dimensions(
+        config,
+        page.width().value,
+        page.height().value,
+        PDF_UNITS_PER_INCH,
+    );
+    Ok((page, size))
+}
+
+fn pdf_size(config: &ProcessConfig) -> Result<(u32, u32), ProcessError> {
+    let pdfium = pdfium()?;
+    let document = open_pdf(&pdfium, &config.image_path)?;
+    pdf_page(config, &document).map(|(_, size)| size)
+}
+
+/// Rasterize page `pdf_page` of the PDF at `config.image_path` at the
+/// configured resolution.
+fn render_pdf(config: &ProcessConfig) -> Result<RgbaImage, ProcessError> {
+    let pdfium = pdfium()?;
+    let document = open_pdf(&pdfium, &config.image_path)?;
+    let (page, (width, height)) = pdf_page(config, &document)?;
+    let bitmap = page
+        .render_with_config(
+            &PdfRenderConfig::new()
+                .set_target_width(width as Pixels)
+                .set_target_height(height as Pixels),
+        )
+        .map_err(|e| format_error("PDF", "Failed to render PDF page", e))?;
+    RgbaImage::from_raw(
+        bitmap.width() as u32,
+        bitmap.height() as u32,
+        bitmap.as_rgba_bytes(),
+    )
+    .ok_or_else(|| ProcessError::Internal("Rasterized PDF page has the wrong size".to_string()))
+}
+
+#[cfg(feature = "heif")]
+fn open_heif(config: &ProcessConfig) -> Result<libheif_rs::ImageHandle, ProcessError> {
+    HeifContext::read_from_file(&config.image_path)
+        .and_then(|context| context.primary_image_handle())
+        .map_err(|e| format_error("HEIF", &format!("Failed to read {}", config.image_path), e))
+}
+
+#[cfg(feature = "heif")]
+fn heif_header(config: &ProcessConfig) -> Result<ImageHeader, ProcessError> {
+    let handle = open_heif(config)?;
+    let bytes_per_pixel = if handle.has_alpha_channel() { 4 } else { 3 };
+    Ok(ImageHeader {
+        width: handle.width(),
+        height: handle.height(),
+        format: "Heif".to_string(),
+        color_type: if bytes_per_pixel == 4 {
+            "Rgba8"
+        } else {
+            "Rgb8"
+        }
+        .to_string(),
+        bytes_per_pixel,
+    })
+}
+
+/// Decode the primary image of the HEIC/HEIF file at `config.image_path`,
+/// keeping its alpha channel if it has one. libheif applies the file's
+/// rotation and mirroring as it decodes; an embedded ICC profile is
+/// converted from like other formats'.
+#[cfg(feature = "heif")]
+fn decode_heif(config: &ProcessConfig) -> Result<DynamicImage, ProcessError> {
+    let handle = open_heif(config)?;
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha {
+        RgbChroma::Rgba
+    } else {
+        RgbChroma::Rgb
+    };
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(|e| format_error("HEIF", "Failed to decode HEIF image", e))?;
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| ProcessError::Internal("Decoded HEIF image has no pixels".to_string()))?;
+
+    // Rows are padded out to `stride`
+    let row_bytes = plane.width as usize * if has_alpha { 4 } else { 3 };
+    let mut pixels = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_bytes]);
+    }
+    let image = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        image::RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    let image = image.ok_or_else(|| {
+        ProcessError::Internal("Decoded HEIF image has the wrong size".to_string())
+    })?;
+
+    // NCLX profiles only name a color space, which libheif already decoded
+    // to RGB
+    let icc_profile = handle.color_profile_raw().filter(|profile| {
+        config.convert_to_srgb && profile.profile_type() != color_profile_types::NCLX
+    });
+    Ok(match icc_profile {
+        Some(profile) => icc::to_srgb(image, &profile.data, &config.image_path),
+        None => image,
+    })
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_header(config: &ProcessConfig) -> Result<ImageHeader, ProcessError> {
+    Err(heif_unsupported(config))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(config: &ProcessConfig) -> Result<DynamicImage, ProcessError> {
+    Err(heif_unsupported(config))
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_unsupported(config: &ProcessConfig) -> ProcessError {
+    ProcessError::UnsupportedFormat(format!(
+        "{} is a HEIC/HEIF image, which this build can't read: it was built without the `heif` feature",
+        config.image_path
+    ))
+}
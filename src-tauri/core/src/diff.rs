@@ -0,0 +1,296 @@
+//! Visual diff: a new rendering of a layout compared with the tiles the
+//! server publishes for it now, so the user can see what an upload would
+//! change before overwriting the live layout. One zoom level is compared, a
+//! low one unless the caller picks another, and its changes are painted
+//! onto a heatmap of the level.
+
+use base64::Engine;
+use image::imageops::{self, FilterType};
+use image::{ImageFormat, Rgba, RgbaImage};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::io::Cursor;
+use tokio::task::JoinSet;
+
+use crate::background::run_background;
+use crate::config::{AuthMode, ProcessConfig};
+use crate::decode::open_image;
+use crate::error::ProcessError;
+use crate::processor::{is_background_tile, tile_bounds, TileProcessor};
+use crate::sink::absolute_template;
+
+/// Most tiles the level compared by default has: the deepest level within
+/// it is fetched, which keeps a diff to a few dozen requests.
+pub const DEFAULT_DIFF_TILES: u32 = 64;
+
+// How far a channel may move, out of 255, before a pixel counts as
+// changed; re-encoding alone moves JPEG and WebP pixels a little
+const PIXEL_THRESHOLD: u8 = 24;
+
+const CHANGED_COLOR: Rgba<u8> = Rgba([255, 48, 48, 255]);
+const MISSING_COLOR: Rgba<u8> = Rgba([255, 176, 0, 255]);
+
+/// How one tile of the compared level differs from the published one.
+#[derive(Debug, Clone, Serialize)]
+pub struct TileDiff {
+    pub x: u32,
+    pub y: u32,
+    // Share of the tile's pixels that changed, from 0 to 1
+    pub difference: f64,
+    pub changed: bool,
+    // The server has no such tile, so all of it is new
+    pub missing: bool,
+}
+
+/// One zoom level of a new rendering compared with the published layout.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayoutDiff {
+    // The published layout compared against
+    pub layout_path: String,
+    pub zoom_level: u32,
+    pub tiles_compared: u32,
+    pub tiles_changed: u32,
+    pub tiles_missing: u32,
+    // Share of the level's pixels that changed, from 0 to 1
+    pub changed_fraction: f64,
+    pub tiles: Vec<TileDiff>,
+    // The new level dimmed to grey, changed pixels in red and tiles the
+    // server lacks in amber, as a base64 PNG
+    pub heatmap: String,
+}
+
+impl LayoutDiff {
+    /// The heatmap as PNG bytes.
+    pub fn heatmap_png(&self) -> Result<Vec<u8>, ProcessError> {
+        base64::engine::general_purpose::STANDARD
+            .decode(&self.heatmap)
+            .map_err(|e| ProcessError::Internal(format!("Invalid heatmap encoding: {}", e)))
+    }
+}
+
+impl TileProcessor {
+    /// Compare level `zoom_level` of `config`'s rendering with the tiles
+    /// fetched through `published_tile_url_template`, or by default the
+    /// deepest level of at most `DEFAULT_DIFF_TILES` tiles. The published
+    /// layout is `config.layout_path`, otherwise the path of the last
+    /// upload to the layout key. The heatmap fits within `max_size`.
+    ///
+    /// New tiles are encoded and decoded again as a run would, so only
+    /// changes a viewer could see count. Decoding the image blocks.
+    pub async fn diff_published(
+        &self,
+        config: &ProcessConfig,
+        zoom_level: Option<u32>,
+        max_size: u32,
+    ) -> Result<LayoutDiff, ProcessError> {
+        let template = config
+            .published_tile_url_template
+            .as_deref()
+            .ok_or_else(|| {
+                ProcessError::InvalidConfig(
+                    "A visual diff needs the published tile URL template to fetch the live tiles"
+                        .to_string(),
+                )
+            })?;
+        let recorded = match (&config.layout_path, &self.tile_hashes) {
+            (None, Some(store)) => store.load(config)?.map(|previous| previous.layout_path),
+            _ => None,
+        };
+        let layout_path = config.layout_path.clone().or(recorded).ok_or_else(|| {
+            ProcessError::InvalidConfig(
+                "No upload to this layout key is recorded; give the layout path to compare against"
+                    .to_string(),
+            )
+        })?;
+
+        let img = open_image(config)?.into_rgba8();
+        let config = &ProcessConfig {
+            background_color: run_background(config, &img),
+            ..config.clone()
+        };
+        let (img_width, img_height) = img.dimensions();
+        let zoom_range = self.zoom_range(img_width, img_height);
+        let zoom_level = match zoom_level {
+            Some(zoom_level) if !zoom_range.contains(&zoom_level) => {
+                return Err(ProcessError::InvalidConfig(format!(
+                    "Zoom level {} is out of range, the image has levels {} to {}",
+                    zoom_level,
+                    zoom_range.start,
+                    zoom_range.end - 1
+                )))
+            }
+            Some(zoom_level) => zoom_level,
+            None => zoom_range
+                .clone()
+                .rev()
+                .find(|&level| self.level_tiles(level, img_width, img_height) <= DEFAULT_DIFF_TILES)
+                .unwrap_or(zoom_range.start),
+        };
+
+        let background = self.background(config);
+        let padded = self.padded_level(config, &img, zoom_level, background);
+        let encoder = self.level_encoder(config, &img, zoom_level)?;
+        drop(img);
+
+        // Fetch the published tiles a few at a time, as many as the run
+        // would upload at once
+        let url_template = absolute_template(config, template)
+            .replace("{layout_key}", &config.layout_key)
+            .replace("{path}", &layout_path)
+            .replace("{zoom}", &zoom_level.to_string());
+        let grid = self.level_grid(zoom_level, img_width, img_height);
+        let concurrency = config.concurrency.max(1);
+        let mut fetches = JoinSet::new();
+        let mut published = Vec::with_capacity(grid.len());
+        for &(x, y) in &grid {
+            if fetches.len() >= concurrency {
+                published.push(joined(fetches.join_next().await)?);
+            }
+            let url = url_template
+                .replace("{x}", &x.to_string())
+                .replace("{y}", &y.to_string());
+            let client = self.client.clone();
+            let auth = config.auth.clone();
+            let secret = config.secret.clone();
+            fetches.spawn(async move {
+                let tile = fetch_tile(&client, &auth, &secret, &url).await?;
+                Ok::<_, ProcessError>(((x, y), tile))
+            });
+        }
+        while let Some(result) = fetches.join_next().await {
+            published.push(joined(Some(result))?);
+        }
+        published.sort_by_key(|&((x, y), _)| (y, x));
+
+        let mut heatmap = dimmed(&padded);
+        let mut tiles = Vec::with_capacity(published.len());
+        let (mut pixels, mut pixels_changed) = (0u64, 0u64);
+        for ((x, y), old) in published {
+            let (left, top, width, height) = tile_bounds(x, y, self.tile_size, self.overlap);
+            let tile = imageops::crop_imm(&padded, left, top, width, height).to_image();
+            let (width, height) = tile.dimensions();
+            // A background tile the run leaves out isn't missing
+            let skipped = config.skip_background_tiles && is_background_tile(&tile, background);
+            let data = encoder.encode(tile)?;
+            let new = image::load_from_memory(&data)
+                .map_err(|e| ProcessError::image("Failed to decode a rendered tile", e))?
+                .into_rgba8();
+            let area = u64::from(width) * u64::from(height);
+            pixels += area;
+
+            let (changed, missing) = match old {
+                None if skipped => (0, false),
+                None => (area, true),
+                Some(old) if old.dimensions() != new.dimensions() => (area, false),
+                Some(old) => {
+                    let mut changed = 0;
+                    for (i, j, pixel) in new.enumerate_pixels() {
+                        if differs(pixel, old.get_pixel(i, j)) {
+                            heatmap.put_pixel(left + i, top + j, CHANGED_COLOR);
+                            changed += 1;
+                        }
+                    }
+                    (changed, false)
+                }
+            };
+            if missing || (changed == area && changed > 0) {
+                let color = if missing {
+                    MISSING_COLOR
+                } else {
+                    CHANGED_COLOR
+                };
+                for j in top..top + height {
+                    for i in left..left + width {
+                        heatmap.put_pixel(i, j, color);
+                    }
+                }
+            }
+            pixels_changed += changed;
+            tiles.push(TileDiff {
+                x,
+                y,
+                difference: changed as f64 / area.max(1) as f64,
+                changed: changed > 0,
+                missing,
+            });
+        }
+
+        let max_size = max_size.max(1);
+        if heatmap.width() > max_size || heatmap.height() > max_size {
+            let scale = max_size as f64 / heatmap.width().max(heatmap.height()) as f64;
+            let width = ((heatmap.width() as f64 * scale).round() as u32).max(1);
+            let height = ((heatmap.height() as f64 * scale).round() as u32).max(1);
+            heatmap = imageops::resize(&heatmap, width, height, FilterType::Triangle);
+        }
+        let mut png = Vec::new();
+        heatmap
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .map_err(|e| ProcessError::image("Failed to encode heatmap", e))?;
+
+        Ok(LayoutDiff {
+            layout_path,
+            zoom_level,
+            tiles_compared: tiles.len() as u32,
+            tiles_changed: tiles.iter().filter(|tile| tile.changed).count() as u32,
+            tiles_missing: tiles.iter().filter(|tile| tile.missing).count() as u32,
+            changed_fraction: pixels_changed as f64 / pixels.max(1) as f64,
+            tiles,
+            heatmap: base64::engine::general_purpose::STANDARD.encode(&png),
+        })
+    }
+}
+
+type Fetched = ((u32, u32), Option<RgbaImage>);
+
+fn joined(
+    result: Option<Result<Result<Fetched, ProcessError>, tokio::task::JoinError>>,
+) -> Result<Fetched, ProcessError> {
+    result
+        .expect("only joined while fetches are running")
+        .map_err(|e| ProcessError::Internal(format!("Tile fetch task failed: {}", e)))?
+}
+
+/// The published tile at `url`, or `None` if the server has none.
+async fn fetch_tile(
+    client: &reqwest::Client,
+    auth: &AuthMode,
+    secret: &str,
+    url: &str,
+) -> Result<Option<RgbaImage>, ProcessError> {
+    let response = auth
+        .authorize(client.get(url), "__sc__", secret)
+        .send()
+        .await
+        .map_err(|e| ProcessError::http("Failed to fetch a published tile", e))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let data = response
+        .error_for_status()
+        .map_err(|e| ProcessError::http("Failed to fetch a published tile", e))?
+        .bytes()
+        .await
+        .map_err(|e| ProcessError::http("Failed to fetch a published tile", e))?;
+    let tile = image::load_from_memory(&data).map_err(|e| {
+        ProcessError::image(format!("The published tile at {} isn't an image", url), e)
+    })?;
+    Ok(Some(tile.into_rgba8()))
+}
+
+/// Whether any channel of `new` moved past `PIXEL_THRESHOLD` from `old`.
+fn differs(new: &Rgba<u8>, old: &Rgba<u8>) -> bool {
+    new.0
+        .iter()
+        .zip(old.0)
+        .any(|(&new, old)| new.abs_diff(old) > PIXEL_THRESHOLD)
+}
+
+/// `level` in dim grey, flattened onto black, for changes to stand out on.
+fn dimmed(level: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(level.width(), level.height(), |x, y| {
+        let [r, g, b, a] = level.get_pixel(x, y).0;
+        let luma = (u32::from(r) * 299 + u32::from(g) * 587 + u32::from(b) * 114) / 1000;
+        let grey = (48 + luma * u32::from(a) / 255 / 2) as u8;
+        Rgba([grey, grey, grey, 255])
+    })
+}
@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use image::imageops::FilterType;
+use std::path::PathBuf;
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+use crate::processor::TileEncoder;
+use crate::sink::{Tile, TileSink, UploadManifest};
+
+/// Exports the pyramid in Deep Zoom format: `{name}.dzi` next to a
+/// `{name}_files/{level}/{col}_{row}.{ext}` folder, viewable with
+/// OpenSeadragon and other Deep Zoom consumers.
+///
+/// The described image is the padded square canvas, `2^max_zoom` tiles a
+/// side (DZI exports are always padded square, see
+/// `ProcessConfig::effective_padding`), so our zoom `z` is Deep Zoom level
+/// `z + ceil(log2(tile_size))`.
+/// The levels below that, smaller than one tile, are downscaled from the
+/// zoom 0 tile when the export finishes.
+pub struct DziSink {
+    root: PathBuf,
+    name: String,
+    tile_size: u32,
+    overlap: u32,
+    encoder: TileEncoder,
+}
+
+impl DziSink {
+    pub fn new(root: PathBuf, config: &ProcessConfig) -> Self {
+        Self {
+            root,
+            name: config.layout_key.clone(),
+            // Only square tiles are let through to Deep Zoom
+            tile_size: config.tile_size.width,
+            overlap: config.overlap,
+            encoder: TileEncoder::new(config),
+        }
+    }
+
+    fn files_dir(&self) -> PathBuf {
+        self.root.join(format!("{}_files", self.name))
+    }
+
+    /// Deep Zoom level of our zoom 0.
+    fn level_offset(&self) -> u32 {
+        self.tile_size.next_power_of_two().trailing_zeros()
+    }
+
+    fn tile_path(&self, level: u32, col: u32, row: u32) -> PathBuf {
+        self.files_dir().join(level.to_string()).join(format!(
+            "{}_{}.{}",
+            col,
+            row,
+            self.encoder.format.extension()
+        ))
+    }
+
+    async fn write(&self, level: u32, col: u32, row: u32, data: &[u8]) -> Result<(), ProcessError> {
+        let path = self.tile_path(level, col, row);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| ProcessError::io("Failed to create tile folder", e))?;
+        }
+        tokio::fs::write(&path, data)
+            .await
+            .map_err(|e| ProcessError::io("Failed to write tile", e))
+    }
+
+    /// Fill in the sub-tile levels by halving the zoom 0 tile down to 1x1.
+    async fn write_small_levels(&self) -> Result<(), ProcessError> {
+        let offset = self.level_offset();
+        let top = self.tile_path(offset, 0, 0);
+        let data = match tokio::fs::read(&top).await {
+            Ok(data) => data,
+            // Skipped as background; the viewer starts at the first tiled level
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(ProcessError::io("Failed to read zoom 0 tile", e)),
+        };
+        let mut img = image::load_from_memory(&data)
+            .map_err(|e| ProcessError::image("Failed to decode zoom 0 tile", e))?
+            .to_rgba8();
+
+        for level in (0..offset).rev() {
+            let size = img.width().div_ceil(2).max(1);
+            img = image::imageops::resize(&img, size, size, FilterType::Lanczos3);
+            let data = self.encoder.encode(img.clone())?;
+            self.write(level, 0, 0, &data).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TileSink for DziSink {
+    async fn put_tile(&self, _layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        self.write(
+            tile.zoom_level + self.level_offset(),
+            tile.x / self.tile_size,
+            tile.y / self.tile_size,
+            &tile.data,
+        )
+        .await
+    }
+
+    async fn finish(
+        &self,
+        _layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), ProcessError> {
+        self.write_small_levels().await?;
+
+        let size = 2_u32.pow(manifest.max_zoom) * self.tile_size;
+        let descriptor = format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" ",
+                "Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n",
+                "  <Size Width=\"{}\" Height=\"{}\"/>\n",
+                "</Image>\n"
+            ),
+            self.encoder.format.extension(),
+            self.overlap,
+            self.tile_size,
+            size,
+            size
+        );
+        tokio::fs::write(self.root.join(format!("{}.dzi", self.name)), descriptor)
+            .await
+            .map_err(|e| ProcessError::io("Failed to write DZI descriptor", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{TileFormat, TileSize};
+    use image::{Rgba, RgbaImage};
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn lays_out_deep_zoom_levels() {
+        let root = std::env::temp_dir().join(format!("dzi-{}", std::process::id()));
+        let mut config = ProcessConfig::new(
+            "plan.png".to_string(),
+            "http://localhost".to_string(),
+            "plan".to_string(),
+            String::new(),
+        );
+        // Not a power of two, so the levels below it don't halve evenly
+        config.tile_size = TileSize::square(250);
+        config.tile_format = TileFormat::Png;
+        let sink = DziSink::new(root.clone(), &config);
+        assert_eq!(sink.level_offset(), 8);
+
+        let data = TileEncoder::new(&config)
+            .encode(RgbaImage::from_pixel(250, 250, Rgba([200, 40, 90, 255])))
+            .unwrap();
+        // Tile positions are in pixels
+        for (zoom_level, x, y) in [(0, 0, 0), (1, 250, 0), (1, 0, 250)] {
+            let tile = Tile {
+                zoom_level,
+                x,
+                y,
+                format: TileFormat::Png,
+                data: data.clone(),
+                placeholder: None,
+            };
+            sink.put_tile("", tile).await.unwrap();
+        }
+        let manifest = UploadManifest {
+            layout_key: "plan".to_string(),
+            layout_path: String::new(),
+            min_zoom: 0,
+            max_zoom: 1,
+            tile_size: config.tile_size,
+            overlap: 0,
+            tile_format: TileFormat::Png,
+            image_width: None,
+            image_height: None,
+            image_offset_x: None,
+            image_offset_y: None,
+            image_offsets: BTreeMap::new(),
+            tile_count: 3,
+            tiles_per_zoom: BTreeMap::new(),
+            checksums_digest: String::new(),
+            placeholders: None,
+            thumbnail: None,
+            app_version: String::new(),
+        };
+        sink.finish("", &manifest).await.unwrap();
+
+        let descriptor = std::fs::read_to_string(root.join("plan.dzi")).unwrap();
+        assert!(descriptor.contains("TileSize=\"250\""), "{}", descriptor);
+        assert!(
+            descriptor.contains("<Size Width=\"500\" Height=\"500\"/>"),
+            "{}",
+            descriptor
+        );
+        let files = root.join("plan_files");
+        for tile in ["8/0_0.png", "9/1_0.png", "9/0_1.png"] {
+            assert!(files.join(tile).is_file(), "{}", tile);
+        }
+        // The levels smaller than a tile, each half the one above it
+        for (level, side) in [125, 63, 32, 16, 8, 4, 2, 1].into_iter().enumerate() {
+            let level = 7 - level as u32;
+            let path = files.join(level.to_string()).join("0_0.png");
+            let (width, height) = image::image_dimensions(&path).unwrap();
+            assert_eq!((width, height), (side, side), "level {}", level);
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use thiserror::Error;
+
+use crate::network;
+
+/// Why a run, or a command around it, failed.
+///
+/// Serializes as `{ code, message, context, status }` so the frontend can
+/// branch on `code` and show `message`; `tiles_failed` adds `failed_tiles`.
+#[derive(Debug, Error)]
+pub enum ProcessError {
+    #[error("{0}")]
+    InvalidConfig(String),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("{context}: {source}")]
+    Image {
+        context: String,
+        #[source]
+        source: image::ImageError,
+    },
+
+    /// The image is in a format this build can't read.
+    #[error("{0}")]
+    UnsupportedFormat(String),
+
+    /// The server turned the secret down (401 or 403).
+    #[error("{context}: server rejected the secret ({status})")]
+    Unauthorized { context: String, status: u16 },
+
+    /// The server accepted the connection, or never did, but didn't answer
+    /// within the configured timeouts.
+    #[error("{context}: the server did not respond in time")]
+    Timeout {
+        context: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    /// The proxy refused the request or couldn't be reached; the server
+    /// itself was never asked.
+    #[error("{context}: proxy error: {source}")]
+    Proxy {
+        context: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    #[error("{context}: {source}")]
+    Http {
+        context: String,
+        status: Option<u16>,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    /// The server rate-limited the request (429), asking to wait
+    /// `retry_after` seconds if it said how long.
+    #[error("{context}: throttled by the server (429)")]
+    Throttled {
+        context: String,
+        retry_after: Option<u64>,
+    },
+
+    /// The SFTP server couldn't be reached, refused the login, or failed a
+    /// file operation.
+    #[error("{context}: {source}")]
+    Sftp {
+        context: String,
+        #[source]
+        source: ssh2::Error,
+    },
+
+    /// The server stored a tile whose checksum differs from the one sent.
+    #[error("{context}: the server's checksum {} does not match {expected}", .received.as_deref().unwrap_or("(none)"))]
+    ChecksumMismatch {
+        context: String,
+        expected: String,
+        received: Option<String>,
+    },
+
+    /// Even in low-memory mode the run would need more memory than
+    /// `memory_limit_mb` allows.
+    #[error("The image needs about {needed_mb} MB of memory even in low-memory mode, more than the {limit_mb} MB limit")]
+    MemoryLimit { needed_mb: u64, limit_mb: u64 },
+
+    #[error("Invalid manifest: {0}")]
+    Manifest(String),
+
+    #[error("No interrupted upload found for this image and layout")]
+    NoResumableUpload,
+
+    #[error("Processing cancelled")]
+    Cancelled,
+
+    #[error("Unknown job: {0}")]
+    JobNotFound(String),
+
+    #[error("Unknown server profile: {0}")]
+    ProfileNotFound(String),
+
+    #[error("This image is already being uploaded to layout '{0}'")]
+    AlreadyRunning(String),
+
+    /// A job was asked for something its current state doesn't allow,
+    /// such as resuming a job that isn't paused.
+    #[error("{0}")]
+    InvalidState(String),
+
+    /// Tiles that failed in a continue-on-error run and again on the retry
+    /// pass. The layout is left unfinalized and the manifest kept, so a
+    /// resumed run sends just these.
+    #[error("{} tiles could not be uploaded", .tiles.len())]
+    TilesFailed { tiles: Vec<FailedTile> },
+
+    #[error("{failed} of {total} layouts failed: {}", .errors.join("; "))]
+    Batch {
+        failed: usize,
+        total: usize,
+        errors: Vec<String>,
+    },
+
+    /// The platform credential store couldn't be read or written.
+    #[error("Credential store error: {0}")]
+    SecretStore(String),
+
+    /// A worker task panicked or was torn down.
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl ProcessError {
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Self::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn sftp(context: impl Into<String>, source: ssh2::Error) -> Self {
+        Self::Sftp {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn image(context: impl Into<String>, source: image::ImageError) -> Self {
+        Self::Image {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Wrap a request failure, telling a rejected secret, a timeout and a
+    /// proxy failure apart from other HTTP errors.
+    pub fn http(context: impl Into<String>, source: reqwest::Error) -> Self {
+        let context = context.into();
+        let status = source.status().map(|status| status.as_u16());
+        match status {
+            Some(status @ (401 | 403)) => Self::Unauthorized { context, status },
+            _ if source.is_timeout() => Self::Timeout { context, source },
+            _ if network::is_proxy_error(&source) => Self::Proxy { context, source },
+            _ => Self::Http {
+                context,
+                status,
+                source,
+            },
+        }
+    }
+
+    /// Stable identifier for the kind of failure.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidConfig(_) => "invalid_config",
+            Self::Io { .. } => "io",
+            Self::Image { .. } => "image",
+            Self::UnsupportedFormat(_) => "unsupported_format",
+            Self::Unauthorized { .. } => "unauthorized",
+            Self::Timeout { .. } => "timeout",
+            Self::Proxy { .. } => "proxy",
+            Self::Http { .. } => "http",
+            Self::Throttled { .. } => "throttled",
+            Self::Sftp { .. } => "sftp",
+            Self::ChecksumMismatch { .. } => "checksum_mismatch",
+            Self::MemoryLimit { .. } => "memory_limit",
+            Self::Manifest(_) => "manifest",
+            Self::NoResumableUpload => "no_resumable_upload",
+            Self::Cancelled => "cancelled",
+            Self::JobNotFound(_) => "job_not_found",
+            Self::ProfileNotFound(_) => "profile_not_found",
+            Self::AlreadyRunning(_) => "already_running",
+            Self::InvalidState(_) => "invalid_state",
+            Self::TilesFailed { .. } => "tiles_failed",
+            Self::Batch { .. } => "batch",
+            Self::SecretStore(_) => "secret_store",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    /// What was being done when the error happened, where that is known.
+    pub fn context(&self) -> Option<&str> {
+        match self {
+            Self::Io { context, .. }
+            | Self::Image { context, .. }
+            | Self::Unauthorized { context, .. }
+            | Self::Timeout { context, .. }
+            | Self::Proxy { context, .. }
+            | Self::Http { context, .. }
+            | Self::Throttled { context, .. }
+            | Self::Sftp { context, .. }
+            | Self::ChecksumMismatch { context, .. } => Some(context),
+            Self::JobNotFound(job_id) => Some(job_id),
+            Self::ProfileNotFound(id) => Some(id),
+            Self::AlreadyRunning(layout_key) => Some(layout_key),
+            _ => None,
+        }
+    }
+
+    /// Whether the server looks down rather than refusing this request: it
+    /// couldn't be reached, didn't answer in time, or answered with a 5xx.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Timeout { .. } | Self::Proxy { .. } => true,
+            Self::Http { status, .. } => status.is_none_or(|status| status >= 500),
+            _ => false,
+        }
+    }
+
+    /// HTTP status returned by the server, if it answered at all.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Unauthorized { status, .. } => Some(*status),
+            Self::Http { status, .. } => *status,
+            Self::Throttled { .. } => Some(429),
+            _ => None,
+        }
+    }
+}
+
+/// A tile that could not be stored, and the last error it got.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedTile {
+    pub zoom_level: u32,
+    pub x: u32,
+    pub y: u32,
+    pub error: String,
+}
+
+impl Serialize for ProcessError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ProcessError", 5)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("context", &self.context())?;
+        state.serialize_field("status", &self.status())?;
+        // Only `tiles_failed` carries the per-tile report
+        match self {
+            Self::TilesFailed { tiles } => state.serialize_field("failed_tiles", tiles)?,
+            _ => state.skip_field("failed_tiles")?,
+        }
+        state.end()
+    }
+}
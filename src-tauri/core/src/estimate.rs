@@ -0,0 +1,331 @@
+use image::RgbaImage;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use crate::config::{ProcessConfig, TileSize};
+use crate::decode::{open_image, read_header};
+use crate::error::ProcessError;
+use crate::processor::TileProcessor;
+use crate::strips::STRIP_ROWS;
+use crate::summary::SummaryBuilder;
+
+// Rough link assumptions for the duration estimate; the real figure depends
+// on the network and the server
+const ASSUMED_UPLOAD_BYTES_PER_SEC: f64 = 2.0 * 1024.0 * 1024.0;
+const ASSUMED_REQUEST_OVERHEAD: Duration = Duration::from_millis(50);
+
+// Where tiles are sampled to see how an image encodes: the centre and the
+// middle of each quadrant
+const SAMPLE_POINTS: [(f64, f64); 5] = [
+    (0.5, 0.5),
+    (0.25, 0.25),
+    (0.75, 0.25),
+    (0.25, 0.75),
+    (0.75, 0.75),
+];
+
+/// Top-left corners of the sample tiles of an image `width` by `height`.
+pub(crate) fn sample_origins(
+    width: u32,
+    height: u32,
+    tile_size: TileSize,
+) -> impl Iterator<Item = (u32, u32)> {
+    SAMPLE_POINTS.into_iter().map(move |(fx, fy)| {
+        (
+            ((width as f64 * fx) as u32).saturating_sub(tile_size.width / 2),
+            ((height as f64 * fy) as u32).saturating_sub(tile_size.height / 2),
+        )
+    })
+}
+
+/// Full-resolution tiles from around `image`, cut at `sample_origins`.
+pub(crate) fn sample_tiles(image: &RgbaImage, tile_size: TileSize) -> Vec<RgbaImage> {
+    sample_origins(image.width(), image.height(), tile_size)
+        .map(|(x, y)| {
+            image::imageops::crop_imm(image, x, y, tile_size.width, tile_size.height).to_image()
+        })
+        .collect()
+}
+
+/// Tiles generated at one zoom level.
+#[derive(Debug, Clone, Serialize)]
+pub struct LevelEstimate {
+    pub zoom_level: u32,
+    pub tiles: u32,
+}
+
+/// What a job would produce, worked out before anything is uploaded.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobEstimate {
+    pub width: u32,
+    pub height: u32,
+    pub zoom_levels: u32,
+    pub levels: Vec<LevelEstimate>,
+    pub total_tiles: u32,
+    // Average encoded size of the sample tiles
+    pub sample_tile_bytes: u64,
+    // JPEG or WebP quality `size_budget_mb` would pick
+    pub budget_quality: Option<u8>,
+    pub estimated_bytes: u64,
+    pub estimated_seconds: u64,
+}
+
+/// What an image is and how many tiles it makes, read from its header
+/// without decoding the pixels.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    // Detected from the file's contents, e.g. "Png"
+    pub format: String,
+    // Pixel layout as stored in the file, e.g. "Rgb8"
+    pub color_type: String,
+    pub file_size: u64,
+    pub zoom_levels: u32,
+    pub total_tiles: u32,
+    pub memory: MemoryEstimate,
+}
+
+/// Peak memory of a run in bytes, normally and in low-memory mode, worked
+/// out from the image's size and pixel format. Buffers are counted, the
+/// allocator's and the upload queue's overhead isn't.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MemoryEstimate {
+    pub normal_bytes: u64,
+    pub low_memory_bytes: u64,
+}
+
+const MB: u64 = 1024 * 1024;
+
+impl TileProcessor {
+    /// Describe `config.image_path`, stitched images and all, and the
+    /// pyramid it would make. Only the headers are read, so this is quick even for huge images;
+    /// with `auto_trim` the size and counts are those before trimming.
+    pub fn image_info(&self, config: &ProcessConfig) -> Result<ImageInfo, ProcessError> {
+        let stitched = config.stitch.iter().flat_map(|stitch| &stitch.images);
+        let file_size = std::iter::once(&config.image_path)
+            .chain(stitched.map(|image| &image.path))
+            .map(|path| std::fs::metadata(path).map(|metadata| metadata.len()))
+            .sum::<Result<u64, _>>()
+            .map_err(|e| ProcessError::io("Failed to read image", e))?;
+        let header = read_header(config)?;
+        let (width, height) = (header.width, header.height);
+
+        Ok(ImageInfo {
+            width,
+            height,
+            format: header.format,
+            color_type: header.color_type,
+            file_size,
+            zoom_levels: self.zoom_range(width, height).len() as u32,
+            total_tiles: self.count_tiles(width, height),
+            memory: self.memory_estimate(
+                width,
+                height,
+                header.bytes_per_pixel,
+                config.low_zoom_first,
+            ),
+        })
+    }
+
+    /// Peak memory of tiling a `width` by `height` image that decodes to
+    /// `bytes_per_pixel`.
+    pub fn memory_estimate(
+        &self,
+        width: u32,
+        height: u32,
+        bytes_per_pixel: u8,
+        low_zoom_first: bool,
+    ) -> MemoryEstimate {
+        let rgba = |width: u32, height: u32| width as u64 * height as u64 * 4;
+        let decoded = width as u64 * height as u64 * bytes_per_pixel as u64;
+
+        // Normally the decoded image is converted to RGBA and kept, and each
+        // level is resized from the one before it and padded, all in memory
+        let mut normal = decoded + rgba(width, height);
+        // A low-memory run still decodes the whole image and spills it a
+        // strip at a time; then it holds a strip of the level it resizes from
+        // and one padded band, which the encoder may still be reading from
+        let mut low_memory = decoded + rgba(width, STRIP_ROWS);
+        let mut previous: Option<(u32, u32)> = None;
+        for zoom_level in self.zoom_range(width, height).rev() {
+            let size = self.level_size(zoom_level, width, height);
+            let scaled = rgba(size.width, size.height);
+            let padded = rgba(size.padded_width, size.padded_height);
+            let previous_bytes = previous.map_or(0, |(w, h)| rgba(w, h));
+            normal = normal.max(rgba(width, height) + previous_bytes + scaled + padded);
+
+            let source_width = match previous {
+                Some((previous_width, _)) if previous_width < width => previous_width,
+                _ => width,
+            };
+            let band = rgba(size.padded_width, self.tile_size.height);
+            low_memory = low_memory.max(rgba(source_width, STRIP_ROWS * 2) + 2 * band);
+
+            if !low_zoom_first {
+                previous = Some((size.width, size.height));
+            }
+        }
+        MemoryEstimate {
+            normal_bytes: normal,
+            low_memory_bytes: low_memory,
+        }
+    }
+
+    /// Hold a run to `config.memory_limit_mb`: switch it to low-memory mode
+    /// when it wouldn't fit otherwise, and refuse it when it wouldn't fit
+    /// either way. Returns whether to run in low-memory mode.
+    pub(crate) fn check_memory(
+        &self,
+        config: &ProcessConfig,
+        summary: &mut SummaryBuilder,
+    ) -> Result<bool, ProcessError> {
+        let Some(limit_mb) = config.memory_limit_mb else {
+            return Ok(config.low_memory);
+        };
+        let header = read_header(config)?;
+        let estimate = self.memory_estimate(
+            header.width,
+            header.height,
+            header.bytes_per_pixel,
+            config.low_zoom_first,
+        );
+        let normal_mb = estimate.normal_bytes.div_ceil(MB);
+        let low_memory_mb = estimate.low_memory_bytes.div_ceil(MB);
+
+        if !config.low_memory && normal_mb <= limit_mb {
+            return Ok(false);
+        }
+        if low_memory_mb > limit_mb {
+            return Err(ProcessError::MemoryLimit {
+                needed_mb: low_memory_mb,
+                limit_mb,
+            });
+        }
+        if !config.low_memory {
+            let warning = format!(
+                "Needs about {} MB of memory, more than the {} MB limit; ran in low-memory mode",
+                normal_mb, limit_mb
+            );
+            tracing::warn!(
+                normal_mb,
+                low_memory_mb,
+                limit_mb,
+                "Switching to low-memory mode"
+            );
+            summary.warn(warning);
+        }
+        Ok(true)
+    }
+
+    /// Estimate the tile count, upload size and duration of `config`.
+    ///
+    /// The size comes from encoding a few full-resolution tiles spread over
+    /// the image, so it reflects the actual content and encoder settings.
+    /// Low zoom levels are mostly padding and encode smaller, so the total
+    /// errs on the high side. Decoding the image makes this blocking.
+    pub fn estimate(&self, config: &ProcessConfig) -> Result<JobEstimate, ProcessError> {
+        // Counted from the prepared image rather than the header, which
+        // doesn't show what `auto_trim` takes off
+        let img = open_image(config)?.into_rgba8();
+        let (width, height) = img.dimensions();
+        let zoom_range = self.zoom_range(width, height);
+        let zoom_levels = zoom_range.len() as u32;
+        let deepest_zoom = zoom_range.end - 1;
+        let levels: Vec<LevelEstimate> = zoom_range
+            .map(|zoom_level| LevelEstimate {
+                zoom_level,
+                tiles: self.level_tiles(zoom_level, width, height),
+            })
+            .collect();
+        let total_tiles = levels.iter().map(|level| level.tiles).sum();
+
+        let samples = sample_tiles(&img, self.tile_size);
+        let budget_quality = self.budget_quality(config, &samples, total_tiles)?;
+
+        // The samples are full-resolution tiles, encoded as the deepest
+        // level's are
+        let encoder = self
+            .encoder
+            .with_quality(budget_quality)
+            .with_quality(config.ramp_quality(deepest_zoom, deepest_zoom));
+
+        let started = Instant::now();
+        let mut sample_bytes = 0;
+        for tile in samples.iter().cloned() {
+            sample_bytes += encoder.encode(tile)?.len() as u64;
+        }
+        let encode_time = started.elapsed() / samples.len() as u32;
+        let sample_tile_bytes = sample_bytes / samples.len() as u64;
+        let estimated_bytes = sample_tile_bytes * total_tiles as u64;
+
+        // Encoding and uploading overlap, so whichever is slower sets the pace
+        let threads = self.encode_pool.current_num_threads().max(1) as f64;
+        let encode_secs = encode_time.as_secs_f64() * total_tiles as f64 / threads;
+        let upload_secs = estimated_bytes as f64 / ASSUMED_UPLOAD_BYTES_PER_SEC
+            + ASSUMED_REQUEST_OVERHEAD.as_secs_f64() * total_tiles as f64
+                / config.concurrency.max(1) as f64;
+
+        Ok(JobEstimate {
+            width,
+            height,
+            zoom_levels,
+            levels,
+            total_tiles,
+            sample_tile_bytes,
+            budget_quality,
+            estimated_bytes,
+            estimated_seconds: encode_secs.max(upload_secs).ceil() as u64,
+        })
+    }
+
+    /// The highest JPEG or WebP quality at which `total_tiles` tiles
+    /// encoding like `samples` stay within `config.size_budget_mb`, or
+    /// `None` without a budget. Low zoom levels are mostly padding and
+    /// encode smaller than the samples, so the layout lands under budget.
+    pub(crate) fn budget_quality(
+        &self,
+        config: &ProcessConfig,
+        samples: &[RgbaImage],
+        total_tiles: u32,
+    ) -> Result<Option<u8>, ProcessError> {
+        let Some(budget_mb) = config.size_budget_mb else {
+            return Ok(None);
+        };
+        let budget = budget_mb * MB;
+        let projected = |quality: u8| -> Result<u64, ProcessError> {
+            let encoder = self.encoder.with_quality(Some(quality));
+            let mut bytes = 0;
+            for tile in samples.iter().cloned() {
+                bytes += encoder.encode(tile)?.len() as u64;
+            }
+            Ok(bytes / samples.len().max(1) as u64 * total_tiles as u64)
+        };
+
+        let smallest = projected(1)?;
+        if smallest > budget {
+            return Err(ProcessError::InvalidConfig(format!(
+                "The layout comes to about {} MB even at quality 1, over the {} MB size budget",
+                smallest.div_ceil(MB),
+                budget_mb
+            )));
+        }
+        // Tiles grow with quality, so the search only keeps the qualities
+        // that still fit
+        let (mut low, mut high) = (1u8, 100);
+        while low < high {
+            let quality = low + (high - low).div_ceil(2);
+            if projected(quality)? <= budget {
+                low = quality;
+            } else {
+                high = quality - 1;
+            }
+        }
+        tracing::info!(
+            budget_mb,
+            quality = low,
+            "Picked the quality for the size budget"
+        );
+        Ok(Some(low))
+    }
+}
@@ -0,0 +1,85 @@
+//! Converting images with an embedded ICC profile to sRGB, the color space
+//! viewers display tiles in.
+
+use image::{DynamicImage, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use rayon::prelude::*;
+
+/// `image` converted from the color space `icc` describes to sRGB.
+///
+/// An unreadable profile, or one this can't convert from, is logged and
+/// the image kept as decoded. A profile that maps to sRGB unchanged, as
+/// the sRGB profiles many tools embed do, leaves the image untouched.
+pub(crate) fn to_srgb(image: DynamicImage, icc: &[u8], path: &str) -> DynamicImage {
+    let profile = match ColorProfile::new_from_slice(icc) {
+        Ok(profile) => profile,
+        Err(e) => {
+            tracing::warn!(path = %path, error = %e, "Ignoring unreadable ICC profile");
+            return image;
+        }
+    };
+    // Decoders hand out CMYK images already converted to RGB, so their
+    // profile no longer describes the pixels
+    let (layout, channels) = match profile.color_space {
+        DataColorSpace::Rgb => (Layout::Rgba, 4),
+        DataColorSpace::Gray => (Layout::GrayAlpha, 2),
+        other => {
+            tracing::warn!(path = %path, color_space = ?other, "Ignoring ICC profile of unsupported color space");
+            return image;
+        }
+    };
+    let transform = match profile.create_transform_8bit(
+        layout,
+        &ColorProfile::new_srgb(),
+        Layout::Rgba,
+        TransformOptions::default(),
+    ) {
+        Ok(transform) => transform,
+        Err(e) => {
+            tracing::warn!(path = %path, error = %e, "Ignoring ICC profile that can't be converted to sRGB");
+            return image;
+        }
+    };
+
+    // Ramps of gray and each primary; a profile that leaves them all as
+    // they are is sRGB, and converting would only cost time and rounding
+    if layout == Layout::Rgba {
+        let probe: Vec<u8> = (0..=255u8)
+            .flat_map(|v| [v, v, v, 255, v, 0, 0, 255, 0, v, 0, 255, 0, 0, v, 255])
+            .collect();
+        let mut converted = vec![0; probe.len()];
+        if transform.transform(&probe, &mut converted).is_ok() && converted == probe {
+            return image;
+        }
+    }
+
+    let (width, height) = (image.width(), image.height());
+    if width == 0 || height == 0 {
+        return image;
+    }
+    let source = match layout {
+        Layout::Rgba => image.into_rgba8().into_raw(),
+        _ => image.into_luma_alpha8().into_raw(),
+    };
+    let mut converted = RgbaImage::new(width, height);
+    let row = width as usize;
+    let failed = converted
+        .par_chunks_mut(row * 4)
+        .zip(source.par_chunks(row * channels))
+        .map(|(dst, src)| transform.transform(src, dst))
+        .find_any(|result| result.is_err());
+    if let Some(Err(e)) = failed {
+        tracing::warn!(path = %path, error = %e, "Failed to convert image to sRGB");
+        // Keep the pixels as decoded
+        return DynamicImage::ImageRgba8(match layout {
+            Layout::Rgba => RgbaImage::from_raw(width, height, source)
+                .expect("the buffer came from an image of this size"),
+            _ => DynamicImage::ImageLumaA8(
+                image::GrayAlphaImage::from_raw(width, height, source)
+                    .expect("the buffer came from an image of this size"),
+            )
+            .into_rgba8(),
+        });
+    }
+    DynamicImage::ImageRgba8(converted)
+}
@@ -0,0 +1,415 @@
+use std::fs;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::task::JoinSet;
+
+use crate::config::{ProcessConfig, TileFormat, TileSize};
+use crate::control::JobControl;
+use crate::error::ProcessError;
+use crate::local::{PyramidManifest, PYRAMID_MANIFEST};
+use crate::manifest::JobManifest;
+use crate::processor::{
+    report_cancelled, until_cancelled, upload_result, TileProcessor, UploadOutcome, Uploader,
+};
+use crate::progress::ProgressReporter;
+use crate::sink::Tile;
+use crate::summary::{save_summary, RunSummary, SummaryBuilder};
+
+/// A tile file found in an existing pyramid, with the coordinates the
+/// server expects (pixel offsets within its zoom level).
+#[derive(Debug, Clone)]
+pub struct PyramidTile {
+    pub zoom_level: u32,
+    pub x: u32,
+    pub y: u32,
+    pub format: TileFormat,
+    pub path: PathBuf,
+    // Byte offset and length of the tile within `path`, when that is a
+    // package rather than the tile's own file
+    pub range: Option<(u64, u64)>,
+}
+
+/// Tiles of a pre-generated pyramid, ready to be uploaded.
+#[derive(Debug, Clone)]
+pub struct ScannedPyramid {
+    pub tiles: Vec<PyramidTile>,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub tile_size: TileSize,
+    // Pixels each tile shares with its neighbours
+    pub overlap: u32,
+}
+
+pub(crate) fn tile_format(path: &Path) -> Option<TileFormat> {
+    match path.extension()?.to_str()?.to_lowercase().as_str() {
+        "jpg" | "jpeg" => Some(TileFormat::Jpeg),
+        "png" => Some(TileFormat::Png),
+        "webp" => Some(TileFormat::Webp),
+        _ => None,
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>, ProcessError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| ProcessError::io("Failed to read pyramid", e))? {
+        paths.push(
+            entry
+                .map_err(|e| ProcessError::io("Failed to read pyramid", e))?
+                .path(),
+        );
+    }
+    Ok(paths)
+}
+
+pub(crate) fn number(path: &Path) -> Option<u32> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Find the tiles of a pyramid folder. Three layouts are understood:
+///
+/// - our own export, `{zoom}/{x}/{y}.{ext}` plus `layout.json`, where `x`
+///   and `y` are already pixel offsets
+/// - a plain XYZ folder such as gdal2tiles `--xyz` or vips `--layout google`
+///   output after renaming, `{zoom}/{col}/{row}.{ext}`, tiled at
+///   `tile_size`
+/// - a Deep Zoom `{name}.dzi` next to its `{name}_files` folder
+pub fn scan_pyramid(dir: &Path, tile_size: TileSize) -> Result<ScannedPyramid, ProcessError> {
+    let dzi = read_dir(dir)?
+        .into_iter()
+        .find(|path| path.extension().is_some_and(|ext| ext == "dzi"));
+    if let Some(descriptor) = dzi {
+        return scan_dzi(&descriptor);
+    }
+
+    // Our export already stores pixel offsets; anything else counts tiles
+    let (tile_size, overlap, scale) = match fs::read_to_string(dir.join(PYRAMID_MANIFEST)) {
+        Ok(json) => {
+            let manifest: PyramidManifest = serde_json::from_str(&json)
+                .map_err(|e| ProcessError::Manifest(format!("{}: {}", PYRAMID_MANIFEST, e)))?;
+            (manifest.tile_size, manifest.overlap, TileSize::square(1))
+        }
+        Err(_) => (tile_size, 0, tile_size),
+    };
+
+    let mut tiles = Vec::new();
+    for zoom_dir in read_dir(dir)?.into_iter().filter(|path| path.is_dir()) {
+        let Some(zoom_level) = number(&zoom_dir) else {
+            continue;
+        };
+        for x_dir in read_dir(&zoom_dir)?
+            .into_iter()
+            .filter(|path| path.is_dir())
+        {
+            let Some(x) = number(&x_dir) else {
+                continue;
+            };
+            for path in read_dir(&x_dir)? {
+                if let (Some(y), Some(format)) = (number(&path), tile_format(&path)) {
+                    tiles.push(PyramidTile {
+                        zoom_level,
+                        x: x * scale.width,
+                        y: y * scale.height,
+                        format,
+                        path,
+                        range: None,
+                    });
+                }
+            }
+        }
+    }
+
+    finish_scan(tiles, tile_size, overlap)
+}
+
+fn dzi_attribute(descriptor: &str, name: &str) -> Option<u32> {
+    let start = descriptor.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = start + descriptor[start..].find('"')?;
+    descriptor[start..end].parse().ok()
+}
+
+fn scan_dzi(descriptor_path: &Path) -> Result<ScannedPyramid, ProcessError> {
+    let descriptor = fs::read_to_string(descriptor_path)
+        .map_err(|e| ProcessError::io("Failed to read DZI descriptor", e))?;
+    let invalid = |what: &str| ProcessError::Manifest(format!("DZI descriptor {}", what));
+
+    let tile_size =
+        dzi_attribute(&descriptor, "TileSize").ok_or_else(|| invalid("has no TileSize"))?;
+    let overlap = dzi_attribute(&descriptor, "Overlap").unwrap_or(0);
+
+    let stem = descriptor_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let files_dir = descriptor_path.with_file_name(format!("{}_files", stem));
+
+    // Levels smaller than one tile have no zoom level of their own
+    let offset = tile_size.next_power_of_two().trailing_zeros();
+
+    let mut tiles = Vec::new();
+    for level_dir in read_dir(&files_dir)?
+        .into_iter()
+        .filter(|path| path.is_dir())
+    {
+        let Some(level) = number(&level_dir).filter(|level| *level >= offset) else {
+            continue;
+        };
+        for path in read_dir(&level_dir)? {
+            let Some(format) = tile_format(&path) else {
+                continue;
+            };
+            let stem = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("");
+            let Some((col, row)) = stem.split_once('_') else {
+                continue;
+            };
+            if let (Ok(col), Ok(row)) = (col.parse::<u32>(), row.parse::<u32>()) {
+                tiles.push(PyramidTile {
+                    zoom_level: level - offset,
+                    x: col * tile_size,
+                    y: row * tile_size,
+                    format,
+                    path,
+                    range: None,
+                });
+            }
+        }
+    }
+
+    finish_scan(tiles, TileSize::square(tile_size), overlap)
+}
+
+pub(crate) fn finish_scan(
+    mut tiles: Vec<PyramidTile>,
+    tile_size: TileSize,
+    overlap: u32,
+) -> Result<ScannedPyramid, ProcessError> {
+    if tiles.is_empty() {
+        return Err(ProcessError::InvalidConfig(
+            "No tiles found in the pyramid folder".to_string(),
+        ));
+    }
+    // Low zoom levels first, so a viewer has something to show early
+    tiles.sort_by_key(|tile| (tile.zoom_level, tile.y, tile.x));
+    let min_zoom = tiles[0].zoom_level;
+    let max_zoom = tiles.iter().map(|tile| tile.zoom_level).max().unwrap_or(0);
+
+    Ok(ScannedPyramid {
+        tiles,
+        min_zoom,
+        max_zoom,
+        tile_size,
+        overlap,
+    })
+}
+
+/// The bytes of a tile, from its own file or its span of a package.
+async fn read_tile(tile: &PyramidTile) -> Result<Vec<u8>, ProcessError> {
+    let read = async {
+        let Some((offset, len)) = tile.range else {
+            return tokio::fs::read(&tile.path).await;
+        };
+        let mut file = tokio::fs::File::open(&tile.path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = vec![0; len as usize];
+        file.read_exact(&mut data).await?;
+        Ok(data)
+    };
+    read.await
+        .map_err(|e| ProcessError::io("Failed to read tile", e))
+}
+
+/// Read a batch of tiles and store them, from an upload task.
+async fn send_batch(
+    uploader: Uploader,
+    pyramid_tiles: Vec<PyramidTile>,
+    keep_failed: bool,
+) -> Result<Vec<UploadOutcome>, ProcessError> {
+    let mut tiles = Vec::with_capacity(pyramid_tiles.len());
+    for pyramid_tile in pyramid_tiles {
+        let data = read_tile(&pyramid_tile).await?;
+        let PyramidTile {
+            zoom_level,
+            x,
+            y,
+            format,
+            ..
+        } = pyramid_tile;
+        tiles.push(Tile {
+            zoom_level,
+            x,
+            y,
+            format,
+            data,
+            // Imported tiles are sent as they are, without decoding them
+            placeholder: None,
+        });
+    }
+    uploader.send(tiles, keep_failed).await
+}
+
+impl TileProcessor {
+    /// Send the tiles of an already generated pyramid to `config.output`
+    /// and finalize the layout, skipping tiles already in `manifest`.
+    pub async fn upload_pyramid(
+        &self,
+        pyramid: ScannedPyramid,
+        config: &ProcessConfig,
+        manifest: &mut JobManifest,
+        reporter: &mut ProgressReporter,
+        control: JobControl,
+    ) -> Result<RunSummary, ProcessError> {
+        let result = self
+            .send_pyramid(pyramid, config, manifest, reporter, &control)
+            .await;
+        save_summary(&result, config);
+        report_cancelled(result, reporter).await
+    }
+
+    async fn send_pyramid(
+        &self,
+        pyramid: ScannedPyramid,
+        config: &ProcessConfig,
+        manifest: &mut JobManifest,
+        reporter: &mut ProgressReporter,
+        control: &JobControl,
+    ) -> Result<RunSummary, ProcessError> {
+        let mut summary = SummaryBuilder::new();
+        let sink = self.sink_for(config)?;
+        let layout_path = manifest.layout_path.clone();
+        let total_tiles = pyramid.tiles.len() as u32;
+        let concurrency = config.concurrency.max(1);
+
+        let mut current_tile = 0;
+        let mut bytes_uploaded = 0;
+        let mut uploads: JoinSet<Result<Vec<UploadOutcome>, ProcessError>> = JoinSet::new();
+        let uploader = Uploader::new(sink.clone(), layout_path.clone(), config, control, reporter);
+        let batch_size = config.upload_batch_size();
+        let mut batch = Vec::new();
+        let mut failed = Vec::new();
+
+        for pyramid_tile in pyramid.tiles {
+            control.checkpoint(reporter).await?;
+
+            if manifest.contains(pyramid_tile.zoom_level, pyramid_tile.x, pyramid_tile.y) {
+                current_tile += 1;
+                continue;
+            }
+
+            let zoom_level = pyramid_tile.zoom_level;
+            batch.push(pyramid_tile);
+            if batch.len() < batch_size {
+                continue;
+            }
+
+            if uploads.len() >= concurrency {
+                if let Some(result) = uploads.join_next().await {
+                    for outcome in upload_result(result)? {
+                        match outcome {
+                            UploadOutcome::Uploaded(done) => {
+                                manifest.record(
+                                    done.zoom_level,
+                                    done.x,
+                                    done.y,
+                                    &done.checksum,
+                                    done.placeholder.as_deref(),
+                                )?;
+                                summary.uploaded(done.zoom_level, done.bytes, &done.checksum);
+                                bytes_uploaded += done.bytes;
+                            }
+                            UploadOutcome::Failed(tile) => failed.push(tile),
+                        }
+                        current_tile += 1;
+                    }
+                    reporter
+                        .update(current_tile, total_tiles, zoom_level, 0, bytes_uploaded)
+                        .await;
+                }
+            }
+
+            let tiles = std::mem::take(&mut batch);
+            uploads.spawn(send_batch(
+                uploader.clone(),
+                tiles,
+                config.continue_on_error,
+            ));
+        }
+        // The last, partly filled batch
+        if !batch.is_empty() {
+            uploads.spawn(send_batch(
+                uploader.clone(),
+                batch,
+                config.continue_on_error,
+            ));
+        }
+
+        while let Some(result) = uploads.join_next().await {
+            let mut zoom_level = 0;
+            for outcome in upload_result(result)? {
+                zoom_level = match outcome {
+                    UploadOutcome::Uploaded(done) => {
+                        manifest.record(
+                            done.zoom_level,
+                            done.x,
+                            done.y,
+                            &done.checksum,
+                            done.placeholder.as_deref(),
+                        )?;
+                        summary.uploaded(done.zoom_level, done.bytes, &done.checksum);
+                        bytes_uploaded += done.bytes;
+                        done.zoom_level
+                    }
+                    UploadOutcome::Failed(tile) => {
+                        let zoom_level = tile.zoom_level;
+                        failed.push(tile);
+                        zoom_level
+                    }
+                };
+                current_tile += 1;
+            }
+            reporter
+                .update(current_tile, total_tiles, zoom_level, 0, bytes_uploaded)
+                .await;
+        }
+
+        summary.phases().tiles_secs = summary.lap();
+
+        let retry = uploader
+            .retry_failed(failed, manifest, reporter, control)
+            .await?;
+        for tile in &retry.uploaded {
+            summary.uploaded(tile.zoom_level, tile.bytes, &tile.checksum);
+        }
+        summary.phases().retry_secs = summary.lap();
+        if !retry.failed.is_empty() {
+            return Err(ProcessError::TilesFailed {
+                tiles: retry.failed,
+            });
+        }
+
+        if control.cancel.is_cancelled() {
+            return Err(ProcessError::Cancelled);
+        }
+
+        let upload = manifest.upload_manifest(
+            config,
+            pyramid.min_zoom,
+            pyramid.max_zoom,
+            pyramid.tile_size,
+            pyramid.overlap,
+            None,
+        );
+        until_cancelled(&control.cancel, sink.finish(&layout_path, &upload)).await?;
+        summary.phases().finalize_secs = summary.lap();
+
+        Ok(summary.finish(
+            layout_path,
+            pyramid.min_zoom,
+            pyramid.max_zoom,
+            uploader.retries(),
+            config,
+        ))
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+
+/// Content hashes of the tiles a layout key was last uploaded with, so an
+/// incremental run can send only the tiles that changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TileHashes {
+    pub layout_path: String,
+    pub max_zoom: u32,
+    // SHA-256 of each encoded tile, keyed by `zoom/x/y`
+    hashes: HashMap<String, String>,
+}
+
+impl TileHashes {
+    pub fn new(layout_path: String) -> Self {
+        Self {
+            layout_path,
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, zoom_level: u32, x: u32, y: u32) -> bool {
+        self.hashes.contains_key(&key(zoom_level, x, y))
+    }
+
+    /// Whether the tile at these coordinates was last stored as `hash`.
+    pub fn matches(&self, zoom_level: u32, x: u32, y: u32, hash: &str) -> bool {
+        self.hashes
+            .get(&key(zoom_level, x, y))
+            .is_some_and(|previous| previous == hash)
+    }
+
+    pub fn insert(&mut self, zoom_level: u32, x: u32, y: u32, hash: String) {
+        self.hashes.insert(key(zoom_level, x, y), hash);
+    }
+
+    /// Forget a tile whose stored content isn't known, so the next run
+    /// sends it again.
+    pub fn forget(&mut self, zoom_level: u32, x: u32, y: u32) {
+        self.hashes.remove(&key(zoom_level, x, y));
+    }
+}
+
+fn key(zoom_level: u32, x: u32, y: u32) -> String {
+    format!("{}/{}/{}", zoom_level, x, y)
+}
+
+/// SHA-256 of an encoded tile, as hex.
+pub fn tile_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// One `TileHashes` file per server and layout key.
+#[derive(Debug, Clone)]
+pub struct TileHashStore {
+    dir: PathBuf,
+}
+
+impl TileHashStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, config: &ProcessConfig) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(config.server_address.trim_end_matches('/').as_bytes());
+        hasher.update([0]);
+        hasher.update(config.layout_key.as_bytes());
+        // Only hashed for layers, so existing hashes still match
+        if let Some(layer) = &config.layer {
+            hasher.update(b"\0layer:");
+            hasher.update(layer.as_bytes());
+        }
+        self.dir.join(format!("{:x}.json", hasher.finalize()))
+    }
+
+    /// The hashes of the last completed upload to `config`'s layout key.
+    pub fn load(&self, config: &ProcessConfig) -> Result<Option<TileHashes>, ProcessError> {
+        match fs::read(self.path(config)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| ProcessError::Manifest(format!("tile hashes: {}", e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ProcessError::io("Failed to read tile hashes", e)),
+        }
+    }
+
+    /// Replace the hashes of `config`'s layout key once a run finalized it.
+    pub fn save(&self, config: &ProcessConfig, hashes: &TileHashes) -> Result<(), ProcessError> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| ProcessError::io("Failed to create tile hash directory", e))?;
+        let path = self.path(config);
+        let contents = serde_json::to_vec(hashes)
+            .map_err(|e| ProcessError::Internal(format!("Failed to encode tile hashes: {}", e)))?;
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, contents)
+            .map_err(|e| ProcessError::io("Failed to write tile hashes", e))?;
+        fs::rename(&temp, &path).map_err(|e| ProcessError::io("Failed to write tile hashes", e))
+    }
+
+    /// The layout path a new run of `config` uploads under. An incremental
+    /// run or a region update keeps the path of the last upload so
+    /// unchanged tiles stay valid; otherwise it is
+    /// `ProcessConfig::resolve_layout_path`.
+    pub fn layout_path(&self, config: &ProcessConfig) -> Result<String, ProcessError> {
+        let reuse = config.incremental || config.update_region.is_some();
+        if reuse && config.layout_path.is_none() {
+            if let Some(previous) = self.load(config)? {
+                return Ok(previous.layout_path);
+            }
+        }
+        config.resolve_layout_path()
+    }
+}
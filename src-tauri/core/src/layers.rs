@@ -0,0 +1,91 @@
+//! Layered layouts: aligned images, such as a floor plan with its
+//! electrical and HVAC overlays, uploaded in one job as separate layers of
+//! one layout key. Each layer is a run of its own, with its own layout
+//! path, finalized through the server's layer endpoint.
+
+use std::collections::HashSet;
+use std::iter;
+
+use crate::config::ProcessConfig;
+use crate::decode::read_header;
+use crate::error::ProcessError;
+
+/// What `image_path` is called in a layered upload unless `layer` names it.
+pub const DEFAULT_BASE_LAYER: &str = "base";
+
+/// The run of each layer `config` uploads: `config` itself without further
+/// layers, otherwise one per layer, `image_path` first. A given
+/// `layout_path` gets the layer's name appended, so layers never share
+/// tiles. The images must come out the same size once prepared, or the
+/// layers wouldn't line up in the viewer.
+pub fn layer_runs(config: &ProcessConfig) -> Result<Vec<ProcessConfig>, ProcessError> {
+    if config.layers.is_empty() {
+        return Ok(vec![config.clone()]);
+    }
+    let base_layer = config
+        .layer
+        .clone()
+        .unwrap_or_else(|| DEFAULT_BASE_LAYER.to_string());
+    let layers = iter::once((base_layer, config.image_path.clone())).chain(
+        config
+            .layers
+            .iter()
+            .map(|layer| (layer.name.clone(), layer.image_path.clone())),
+    );
+
+    let mut names = HashSet::new();
+    let mut runs = Vec::with_capacity(config.layers.len() + 1);
+    for (layer, image_path) in layers {
+        check_layer_name(&layer)?;
+        if !names.insert(layer.clone()) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Layer '{}' is given more than once",
+                layer
+            )));
+        }
+        runs.push(ProcessConfig {
+            image_path,
+            layout_path: config
+                .layout_path
+                .as_ref()
+                .map(|layout_path| format!("{}-{}", layout_path, layer)),
+            layer: Some(layer),
+            layers: Vec::new(),
+            ..config.clone()
+        });
+    }
+
+    let base = read_header(&runs[0])?;
+    for run in &runs[1..] {
+        let header = read_header(run)?;
+        if (header.width, header.height) != (base.width, base.height) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Layer '{}' is {}x{} but '{}' is {}x{}; layers must be the same size to line up",
+                run.layer.as_deref().unwrap_or_default(),
+                header.width,
+                header.height,
+                runs[0].layer.as_deref().unwrap_or_default(),
+                base.width,
+                base.height
+            )));
+        }
+    }
+    Ok(runs)
+}
+
+/// Layer names are sent to the server and end up in layout paths, so they
+/// may only hold letters, digits, `-` and `_`.
+pub(crate) fn check_layer_name(name: &str) -> Result<(), ProcessError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ProcessError::InvalidConfig(format!(
+            "Invalid layer name '{}': use letters, digits, '-' or '_'",
+            name
+        )))
+    }
+}
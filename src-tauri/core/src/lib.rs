@@ -0,0 +1,64 @@
+//! Tiling and upload pipeline shared by the desktop app and the headless CLI.
+//!
+//! An image is cut into a pyramid of zoom levels by [`TileProcessor`], and
+//! each encoded tile is handed to a [`TileSink`]. [`HttpSink`] uploads to an
+//! iviva server, [`LocalDirectorySink`] and [`DziSink`] export to a folder
+//! and [`MbtilesSink`] to a single MBTiles file; other sinks can be
+//! injected through [`TileProcessor::process_tiles_to`]. Progress goes to a
+//! [`ProgressListener`] and finished tiles are journaled in a
+//! [`JobManifest`] so an interrupted run can be resumed. A running job is
+//! paused or cancelled through its [`JobControl`].
+
+pub mod background;
+mod blurhash;
+pub mod breaker;
+pub mod cache;
+pub mod config;
+pub mod connection;
+pub mod control;
+mod debug;
+pub mod decode;
+pub mod diff;
+pub mod dzi;
+pub mod error;
+pub mod estimate;
+mod icc;
+pub mod import;
+pub mod incremental;
+pub mod layers;
+mod linear;
+pub mod local;
+pub mod manifest;
+pub mod mbtiles;
+pub mod mirror;
+pub mod network;
+pub mod orphans;
+pub mod package;
+mod prepare;
+pub mod preview;
+pub mod processor;
+pub mod progress;
+pub mod s3;
+pub mod sftp;
+pub mod sink;
+mod stitch;
+mod strips;
+pub mod summary;
+
+pub use config::{OutputTarget, ProcessConfig, TileFormat};
+pub use control::{JobControl, PauseToken};
+pub use dzi::DziSink;
+pub use error::{FailedTile, ProcessError};
+pub use local::LocalDirectorySink;
+pub use manifest::JobManifest;
+pub use mbtiles::MbtilesSink;
+pub use mirror::MirrorSink;
+pub use network::{ClientCertificate, NetworkSettings, ProxySettings};
+pub use orphans::{OrphanStore, OrphanedLayout};
+pub use package::PackageSink;
+pub use processor::TileProcessor;
+pub use progress::{ProgressEvent, ProgressListener, ProgressReporter};
+pub use s3::S3Sink;
+pub use sftp::SftpSink;
+pub use sink::{DryRunSink, HttpSink, ImagePlacement, Tile, TileSink, UploadManifest};
+pub use summary::RunSummary;
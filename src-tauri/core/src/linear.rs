@@ -0,0 +1,80 @@
+//! Resizing in linear light. Averaging sRGB values as they are stored
+//! darkens fine dark-on-light detail such as hatching and text as it is
+//! shrunk; averaging the light they stand for keeps a level's overall
+//! density true to the document.
+
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+
+/// Each sRGB channel value as linear light, from 0 to 1.
+pub(crate) fn linear_table() -> [f32; 256] {
+    std::array::from_fn(|value| {
+        let value = value as f32 / 255.0;
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+/// The sRGB channel value nearest linear light `value`, clamped to 0 to 1.
+pub(crate) fn to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round() as u8
+}
+
+/// An alpha value from 0 to 1 as a byte.
+pub(crate) fn alpha_byte(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// `source` resized to `width` by `height` in linear light, weighed exactly
+/// as `imageops::resize` weighs it. Alpha is resized as it is.
+pub(crate) fn resize_linear(
+    source: &RgbaImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> RgbaImage {
+    // `imageops::resize` copies an image it isn't asked to resize
+    if (width, height) == source.dimensions() {
+        return source.clone();
+    }
+    let table = linear_table();
+    let linear: Vec<f32> = source
+        .as_raw()
+        .par_chunks(4)
+        .flat_map_iter(|pixel| {
+            [
+                table[pixel[0] as usize],
+                table[pixel[1] as usize],
+                table[pixel[2] as usize],
+                pixel[3] as f32 / 255.0,
+            ]
+        })
+        .collect();
+    let linear: Rgba32FImage = ImageBuffer::from_raw(source.width(), source.height(), linear)
+        .expect("the buffer came from an image of this size");
+
+    let resized = imageops::resize(&linear, width, height, filter);
+    let pixels: Vec<u8> = resized
+        .as_raw()
+        .par_chunks(4)
+        .flat_map_iter(|pixel| {
+            [
+                to_srgb(pixel[0]),
+                to_srgb(pixel[1]),
+                to_srgb(pixel[2]),
+                alpha_byte(pixel[3]),
+            ]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels).expect("the buffer has the resized size")
+}
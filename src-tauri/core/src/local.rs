@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{ProcessConfig, TileFormat, TileSize};
+use crate::error::ProcessError;
+use crate::sink::{Tile, TileSink, UploadManifest};
+
+/// Name of the file describing an exported pyramid.
+pub const PYRAMID_MANIFEST: &str = "layout.json";
+
+/// Written next to an exported pyramid with what the server needs to
+/// finalize the layout once the tiles have been transferred.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PyramidManifest {
+    pub layout_key: String,
+    pub layout_path: String,
+    // Left out of manifests written before `min_zoom` existed
+    #[serde(default)]
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub tile_size: TileSize,
+    // Left out of manifests written before tiles could overlap
+    #[serde(default)]
+    pub overlap: u32,
+    pub tile_format: TileFormat,
+    // Blurhash of each tile keyed by `zoom/x/y`, on `tile_placeholders` runs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub placeholders: BTreeMap<String, String>,
+}
+
+/// Write a tile under `root` as `{zoom}/{x}/{y}.{ext}`, the same layout the
+/// server's upload route uses.
+pub(crate) async fn write_tile(root: &Path, tile: &Tile) -> Result<(), ProcessError> {
+    let dir = root
+        .join(tile.zoom_level.to_string())
+        .join(tile.x.to_string());
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| ProcessError::io("Failed to create tile folder", e))?;
+    tokio::fs::write(
+        dir.join(format!("{}.{}", tile.y, tile.format.extension())),
+        &tile.data,
+    )
+    .await
+    .map_err(|e| ProcessError::io("Failed to write tile", e))
+}
+
+/// Exports the pyramid to a folder instead of uploading it, for sites
+/// where the tiles have to be carried over to the server by hand.
+pub struct LocalDirectorySink {
+    root: PathBuf,
+    layout_key: String,
+    tile_size: TileSize,
+    tile_format: TileFormat,
+}
+
+impl LocalDirectorySink {
+    pub fn new(root: PathBuf, config: &ProcessConfig) -> Self {
+        Self {
+            root,
+            layout_key: config.layout_key.clone(),
+            tile_size: config.tile_size,
+            tile_format: config.tile_format,
+        }
+    }
+}
+
+#[async_trait]
+impl TileSink for LocalDirectorySink {
+    async fn put_tile(&self, _layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        write_tile(&self.root, &tile).await
+    }
+
+    async fn finish(&self, layout_path: &str, upload: &UploadManifest) -> Result<(), ProcessError> {
+        let manifest = PyramidManifest {
+            layout_key: self.layout_key.clone(),
+            layout_path: layout_path.to_string(),
+            min_zoom: upload.min_zoom,
+            max_zoom: upload.max_zoom,
+            tile_size: self.tile_size,
+            overlap: upload.overlap,
+            tile_format: self.tile_format,
+            placeholders: upload.placeholders.clone().unwrap_or_default(),
+        };
+        let json = serde_json::to_string_pretty(&manifest)
+            .map_err(|e| ProcessError::Internal(format!("Failed to serialize manifest: {}", e)))?;
+        tokio::fs::write(self.root.join(PYRAMID_MANIFEST), json)
+            .await
+            .map_err(|e| ProcessError::io("Failed to write pyramid manifest", e))
+    }
+}
@@ -0,0 +1,523 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{OutputTarget, ProcessConfig, TileSize, TileUploadMethod};
+use crate::error::ProcessError;
+use crate::sink::{ImagePlacement, UploadManifest};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestHeader {
+    layout_path: String,
+    config_hash: String,
+    // The run's settings without credentials, so the job can be listed and
+    // continued after a restart. Missing from older manifests.
+    #[serde(default)]
+    config: Option<ProcessConfig>,
+    #[serde(default)]
+    started_at: u64,
+}
+
+/// An interrupted run found on disk by `JobManifest::list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResumableJob {
+    // Name of the manifest, stable across restarts
+    pub id: String,
+    pub layout_path: String,
+    // What to pass back to resume the run; the secret has to be filled in
+    pub config: ProcessConfig,
+    pub tiles_uploaded: u32,
+    // Seconds since the Unix epoch
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+/// Journal of an upload in progress, kept on disk so an interrupted job can
+/// be resumed. The file is JSON lines: a header followed by one
+/// `[zoom,x,y,"sha256"]` entry per uploaded tile, appended as each upload
+/// completes (older manifests have `[zoom,x,y]`). Tiles with a placeholder
+/// have it as a fifth element.
+pub struct JobManifest {
+    pub layout_path: String,
+    // Checksum of each uploaded tile; empty where an older manifest had none
+    uploaded: HashMap<(u32, u32, u32), String>,
+    // Blurhash of each uploaded tile that has one
+    placeholders: HashMap<(u32, u32, u32), String>,
+    path: PathBuf,
+    file: File,
+}
+
+impl JobManifest {
+    /// Start a fresh manifest for `config`, replacing any previous one.
+    pub fn create(
+        dir: &Path,
+        config: &ProcessConfig,
+        layout_path: String,
+    ) -> Result<Self, ProcessError> {
+        let config_hash = config_hash(config)?;
+        let path = dir.join(format!("{}.jsonl", config_hash));
+
+        let header = ManifestHeader {
+            layout_path: layout_path.clone(),
+            config_hash,
+            config: Some(config.without_secrets()),
+            started_at: unix_time(SystemTime::now()),
+        };
+        let header =
+            serde_json::to_string(&header).map_err(|e| ProcessError::Manifest(e.to_string()))?;
+
+        let mut file =
+            File::create(&path).map_err(|e| ProcessError::io("Failed to create manifest", e))?;
+        writeln!(file, "{}", header)
+            .map_err(|e| ProcessError::io("Failed to write manifest", e))?;
+
+        Ok(Self {
+            layout_path,
+            uploaded: HashMap::new(),
+            placeholders: HashMap::new(),
+            path,
+            file,
+        })
+    }
+
+    /// Load the manifest left behind by an earlier run with the same config.
+    pub fn load(dir: &Path, config: &ProcessConfig) -> Result<Option<Self>, ProcessError> {
+        let config_hash = config_hash(config)?;
+        let path = dir.join(format!("{}.jsonl", config_hash));
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ProcessError::io("Failed to read manifest", e)),
+        };
+
+        let mut lines = contents.lines();
+        let header = read_header(lines.next())?;
+
+        if header.config_hash != config_hash {
+            return Err(ProcessError::Manifest(
+                "does not match the current configuration".to_string(),
+            ));
+        }
+
+        // A crash mid-write can leave a truncated last line, so skip anything
+        // that doesn't parse rather than refusing to resume.
+        let mut uploaded = HashMap::new();
+        let mut placeholders = HashMap::new();
+        for (zoom_level, x, y, checksum, placeholder) in lines.filter_map(parse_entry) {
+            uploaded.insert((zoom_level, x, y), checksum);
+            if let Some(placeholder) = placeholder {
+                placeholders.insert((zoom_level, x, y), placeholder);
+            }
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| ProcessError::io("Failed to open manifest", e))?;
+
+        Ok(Some(Self {
+            layout_path: header.layout_path,
+            uploaded,
+            placeholders,
+            path,
+            file,
+        }))
+    }
+
+    /// Every interrupted run in `dir` that can still be resumed: manifests
+    /// whose image is unchanged, newest first. Manifests that can't be read
+    /// are left out.
+    pub fn list(dir: &Path) -> Result<Vec<ResumableJob>, ProcessError> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ProcessError::io("Failed to read manifest directory", e)),
+        };
+
+        let mut jobs = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| ProcessError::io("Failed to read manifest directory", e))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "jsonl") {
+                if let Some(job) = resumable_job(&path) {
+                    jobs.push(job);
+                }
+            }
+        }
+        jobs.sort_by_key(|job| std::cmp::Reverse(job.updated_at));
+        Ok(jobs)
+    }
+
+    pub fn uploaded_count(&self) -> u32 {
+        self.uploaded.len() as u32
+    }
+
+    pub fn contains(&self, zoom_level: u32, x: u32, y: u32) -> bool {
+        self.uploaded.contains_key(&(zoom_level, x, y))
+    }
+
+    /// Mark a tile as uploaded with its SHA-256 and placeholder, if it has
+    /// one, and append it to the journal.
+    pub fn record(
+        &mut self,
+        zoom_level: u32,
+        x: u32,
+        y: u32,
+        checksum: &str,
+        placeholder: Option<&str>,
+    ) -> Result<(), ProcessError> {
+        if self
+            .uploaded
+            .insert((zoom_level, x, y), checksum.to_string())
+            .is_none()
+        {
+            // Blurhash characters need no escaping in JSON
+            let entry = match placeholder {
+                Some(placeholder) => format!(
+                    "[{},{},{},\"{}\",\"{}\"]",
+                    zoom_level, x, y, checksum, placeholder
+                ),
+                None => format!("[{},{},{},\"{}\"]", zoom_level, x, y, checksum),
+            };
+            writeln!(self.file, "{}", entry)
+                .map_err(|e| ProcessError::io("Failed to update manifest", e))?;
+        }
+        if let Some(placeholder) = placeholder {
+            self.placeholders
+                .insert((zoom_level, x, y), placeholder.to_string());
+        }
+        Ok(())
+    }
+
+    /// Describe the finished upload for the sink to finalize: tile counts
+    /// per zoom level and a digest over every tile's checksum, so a server
+    /// can check it got them all. `image_size` is left out for pyramids
+    /// generated elsewhere.
+    pub fn upload_manifest(
+        &self,
+        config: &ProcessConfig,
+        min_zoom: u32,
+        max_zoom: u32,
+        tile_size: TileSize,
+        overlap: u32,
+        image: Option<ImagePlacement>,
+    ) -> UploadManifest {
+        let mut tiles_per_zoom = BTreeMap::new();
+        for &(zoom_level, _, _) in self.uploaded.keys() {
+            *tiles_per_zoom.entry(zoom_level).or_insert(0) += 1;
+        }
+
+        // Sorted so the digest doesn't depend on upload order
+        let mut entries: Vec<_> = self.uploaded.iter().collect();
+        entries.sort_unstable_by_key(|(&coordinates, _)| coordinates);
+        let mut hasher = Sha256::new();
+        for ((zoom_level, x, y), checksum) in entries {
+            hasher.update(format!("{}/{}/{}:{}\n", zoom_level, x, y, checksum));
+        }
+
+        UploadManifest {
+            layout_key: config.layout_key.clone(),
+            layout_path: self.layout_path.clone(),
+            min_zoom,
+            max_zoom,
+            tile_size,
+            overlap,
+            tile_format: config.tile_format,
+            image_width: image.as_ref().map(|image| image.width),
+            image_height: image.as_ref().map(|image| image.height),
+            image_offset_x: image.as_ref().map(|image| image.offset_x),
+            image_offset_y: image.as_ref().map(|image| image.offset_y),
+            image_offsets: image.map(|image| image.level_offsets).unwrap_or_default(),
+            tile_count: self.uploaded_count(),
+            tiles_per_zoom,
+            checksums_digest: format!("{:x}", hasher.finalize()),
+            placeholders: (!self.placeholders.is_empty()).then(|| {
+                self.placeholders
+                    .iter()
+                    .map(|((zoom_level, x, y), placeholder)| {
+                        (format!("{}/{}/{}", zoom_level, x, y), placeholder.clone())
+                    })
+                    .collect()
+            }),
+            thumbnail: None,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Delete the manifest once the job has been finalized.
+    pub fn remove(self) -> Result<(), ProcessError> {
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(|e| ProcessError::io("Failed to remove manifest", e))
+    }
+}
+
+/// A journal entry: `[zoom,x,y,"sha256","blurhash"]`, `[zoom,x,y,"sha256"]`
+/// without a placeholder, or `[zoom,x,y]` from before checksums were kept.
+fn parse_entry(line: &str) -> Option<(u32, u32, u32, String, Option<String>)> {
+    serde_json::from_str::<(u32, u32, u32, String, String)>(line)
+        .ok()
+        .map(|(zoom_level, x, y, checksum, placeholder)| {
+            (zoom_level, x, y, checksum, Some(placeholder))
+        })
+        .or_else(|| {
+            serde_json::from_str::<(u32, u32, u32, String)>(line)
+                .ok()
+                .map(|(zoom_level, x, y, checksum)| (zoom_level, x, y, checksum, None))
+        })
+        .or_else(|| {
+            serde_json::from_str::<(u32, u32, u32)>(line)
+                .ok()
+                .map(|(zoom_level, x, y)| (zoom_level, x, y, String::new(), None))
+        })
+}
+
+fn read_header(line: Option<&str>) -> Result<ManifestHeader, ProcessError> {
+    let line = line.ok_or_else(|| ProcessError::Manifest("file is empty".to_string()))?;
+    serde_json::from_str(line).map_err(|e| ProcessError::Manifest(e.to_string()))
+}
+
+fn resumable_job(path: &Path) -> Option<ResumableJob> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+    let header = read_header(lines.next()).ok()?;
+    let config = header.config?;
+
+    // The image was edited or removed since, so its tiles would not match
+    if config_hash(&config).ok()? != header.config_hash {
+        return None;
+    }
+
+    let tiles_uploaded = lines.filter_map(parse_entry).count() as u32;
+    let updated_at = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map(unix_time)
+        .unwrap_or(header.started_at);
+
+    Some(ResumableJob {
+        id: header.config_hash,
+        layout_path: header.layout_path,
+        config,
+        tiles_uploaded,
+        started_at: header.started_at,
+        updated_at,
+    })
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Hash everything that determines which tiles get generated and where they
+/// go. The secret is left out so a rotated key doesn't invalidate a resume.
+fn config_hash(config: &ProcessConfig) -> Result<String, ProcessError> {
+    let metadata = fs::metadata(&config.image_path)
+        .map_err(|e| ProcessError::io("Failed to read image metadata", e))?;
+    let modified = metadata.modified().map(unix_time).unwrap_or(0);
+
+    // Strings are NUL-terminated so adjacent fields can't run into each other
+    let mut hasher = Sha256::new();
+    hasher.update(config.image_path.as_bytes());
+    hasher.update([0]);
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(modified.to_le_bytes());
+    hasher.update(config.server_address.trim_end_matches('/').as_bytes());
+    hasher.update([0]);
+    hasher.update(config.layout_key.as_bytes());
+    hasher.update([0]);
+    // Only hashed when set, so manifests from before the option still match
+    if let Some(layout_path) = &config.layout_path {
+        hasher.update(b"layout-path:");
+        hasher.update(layout_path.as_bytes());
+        hasher.update([0]);
+    }
+    if let Some(layer) = &config.layer {
+        hasher.update(b"layer:");
+        hasher.update(layer.as_bytes());
+        hasher.update([0]);
+    }
+    // Tiles sent to another route or with another method land elsewhere
+    if let Some(template) = &config.tile_url_template {
+        hasher.update(b"tile-url:");
+        hasher.update(template.as_bytes());
+        hasher.update([0]);
+    }
+    if config.tile_upload_method != TileUploadMethod::default() {
+        hasher.update(b"tile-upload-method:");
+        hasher.update([config.tile_upload_method as u8]);
+    }
+    hasher.update([
+        config.background_color.r,
+        config.background_color.g,
+        config.background_color.b,
+    ]);
+    if let Some(alpha) = config.background_color.a {
+        hasher.update(b"background-alpha:");
+        hasher.update([alpha]);
+    }
+    if config.auto_background {
+        hasher.update(b"auto-background\0");
+    }
+    hasher.update(config.tile_size.key());
+    if config.overlap > 0 {
+        hasher.update(b"\0overlap:");
+        hasher.update(config.overlap.to_le_bytes());
+    }
+    if config.debug_overlay {
+        hasher.update(b"\0debug-overlay");
+    }
+    // A run resumed with them would be missing the earlier tiles' ones
+    if config.tile_placeholders {
+        hasher.update(b"\0placeholders");
+    }
+    hasher.update([
+        config.effective_padding() as u8,
+        config.upscale as u8,
+        config.resize_filter as u8,
+    ]);
+    if let Some(max_zoom) = config.max_zoom_override {
+        hasher.update(b"\0max-zoom:");
+        hasher.update(max_zoom.to_le_bytes());
+    }
+    if config.min_zoom > 0 {
+        hasher.update(b"\0min-zoom:");
+        hasher.update(config.min_zoom.to_le_bytes());
+    }
+    if let Some(dpi) = config.render_dpi {
+        hasher.update(b"\0render-dpi:");
+        hasher.update(dpi.to_le_bytes());
+    }
+    if let Some(size) = config.render_size {
+        hasher.update(b"\0render-size:");
+        hasher.update(size.to_le_bytes());
+    }
+    if !config.auto_orient {
+        hasher.update(b"\0no-auto-orient");
+    }
+    if !config.convert_to_srgb {
+        hasher.update(b"\0no-srgb");
+    }
+    if config.pdf_page != 1 {
+        hasher.update(b"\0pdf-page:");
+        hasher.update(config.pdf_page.to_le_bytes());
+    }
+    if config.gif_frame != 1 {
+        hasher.update(b"\0gif-frame:");
+        hasher.update(config.gif_frame.to_le_bytes());
+    }
+    if let Some(stitch) = &config.stitch {
+        hasher.update(b"\0stitch:");
+        hasher.update(stitch.key());
+        for image in &stitch.images {
+            let metadata = fs::metadata(&image.path)
+                .map_err(|e| ProcessError::io("Failed to read image metadata", e))?;
+            let modified = metadata.modified().map(unix_time).unwrap_or(0);
+            hasher.update(metadata.len().to_le_bytes());
+            hasher.update(modified.to_le_bytes());
+        }
+    }
+    if config.rotate != 0.0 {
+        hasher.update(b"\0rotate:");
+        hasher.update(config.rotate.to_le_bytes());
+    }
+    if config.flip_horizontal || config.flip_vertical {
+        hasher.update(b"\0flip:");
+        hasher.update([config.flip_horizontal as u8, config.flip_vertical as u8]);
+    }
+    if let Some(crop) = config.crop {
+        hasher.update(b"\0crop:");
+        hasher.update(crop.key());
+    }
+    if let Some(region) = config.update_region {
+        hasher.update(b"\0update-region:");
+        hasher.update(region.key());
+    }
+    if let Some(ramp) = config.quality_ramp_key() {
+        hasher.update(b"\0quality-ramp:");
+        hasher.update(ramp);
+    }
+    if let Some(budget) = config.size_budget_mb {
+        hasher.update(b"\0size-budget:");
+        hasher.update(budget.to_le_bytes());
+    }
+    if config.auto_trim {
+        hasher.update(b"\0trim:");
+        hasher.update([config.trim_tolerance]);
+    }
+    if config.linear_resize {
+        hasher.update(b"\0linear-resize");
+    }
+    if let Some(tone) = config.tone_key() {
+        hasher.update(b"\0tone:");
+        hasher.update(tone);
+    }
+    if config.sharpen_amount > 0.0 {
+        hasher.update(b"\0sharpen:");
+        hasher.update(config.sharpen_amount.to_le_bytes());
+        hasher.update(config.sharpen_radius.to_le_bytes());
+    }
+    if let Some(key) = config.color_key {
+        hasher.update(b"\0color-key:");
+        hasher.update([key.r, key.g, key.b, config.color_key_tolerance]);
+    }
+    hasher.update(config.anchor.key());
+    hasher.update([config.jpeg_quality, config.webp_quality]);
+    hasher.update(config.tile_format.extension().as_bytes());
+    match &config.output {
+        OutputTarget::Server => {}
+        OutputTarget::Directory { path } => {
+            hasher.update(b"\0dir:");
+            hasher.update(path.as_bytes());
+        }
+        OutputTarget::Dzi { path } => {
+            hasher.update(b"\0dzi:");
+            hasher.update(path.as_bytes());
+        }
+        OutputTarget::Mbtiles { path } => {
+            hasher.update(b"\0mbtiles:");
+            hasher.update(path.as_bytes());
+        }
+        OutputTarget::Package { path } => {
+            hasher.update(b"\0package:");
+            hasher.update(path.as_bytes());
+        }
+        OutputTarget::Sftp(sftp) => {
+            hasher.update(b"\0sftp:");
+            for part in [&sftp.host, &sftp.username, &sftp.remote_dir] {
+                hasher.update(part.as_bytes());
+                hasher.update(b"\0");
+            }
+            hasher.update(sftp.port.to_le_bytes());
+        }
+        OutputTarget::S3(s3) => {
+            hasher.update(b"\0s3:");
+            for part in [&s3.endpoint, &s3.bucket, &s3.prefix] {
+                hasher.update(part.as_bytes());
+                hasher.update(b"\0");
+            }
+        }
+    }
+    for mirror in config
+        .mirrors
+        .iter()
+        .filter(|_| config.output == OutputTarget::Server)
+    {
+        hasher.update(b"\0mirror:");
+        hasher.update(mirror.server_address.trim_end_matches('/').as_bytes());
+        hasher.update([0]);
+        hasher.update(mirror.layout_key.as_bytes());
+    }
+    // Kept apart so a dry run never shares a manifest with a real upload
+    if config.dry_run {
+        hasher.update(b"\0dry-run");
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,189 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{ProcessConfig, TileFormat};
+use crate::error::ProcessError;
+use crate::sink::{Tile, TileSink, UploadManifest};
+
+fn sqlite_error(context: &str, e: rusqlite::Error) -> ProcessError {
+    ProcessError::Internal(format!("{}: {}", context, e))
+}
+
+/// Exports the pyramid into a single MBTiles (SQLite) file, for GIS tooling
+/// and anywhere one portable artifact beats thousands of loose files.
+///
+/// MBTiles rows follow the TMS scheme, counted from the bottom, so `y` is
+/// flipped on the way in.
+pub struct MbtilesSink {
+    // SQLite calls block, so they run on the blocking pool one at a time
+    connection: Arc<Mutex<Connection>>,
+    name: String,
+    tile_size: u32,
+    tile_format: TileFormat,
+}
+
+impl MbtilesSink {
+    /// Open or create the file at `path`. Tiles already in it are kept, so a
+    /// resumed export carries on where it stopped.
+    pub fn open(path: &Path, config: &ProcessConfig) -> Result<Self, ProcessError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| ProcessError::io("Failed to create MBTiles folder", e))?;
+        }
+
+        let connection =
+            Connection::open(path).map_err(|e| sqlite_error("Failed to open MBTiles file", e))?;
+        connection
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 PRAGMA synchronous = NORMAL;
+                 CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+                 CREATE UNIQUE INDEX IF NOT EXISTS metadata_name ON metadata (name);
+                 CREATE TABLE IF NOT EXISTS tiles (
+                     zoom_level INTEGER,
+                     tile_column INTEGER,
+                     tile_row INTEGER,
+                     tile_data BLOB
+                 );
+                 CREATE UNIQUE INDEX IF NOT EXISTS tile_index
+                     ON tiles (zoom_level, tile_column, tile_row);",
+            )
+            .map_err(|e| sqlite_error("Failed to set up MBTiles file", e))?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            name: config.layout_key.clone(),
+            // Only square tiles are let through to MBTiles
+            tile_size: config.tile_size.width,
+            tile_format: config.tile_format,
+        })
+    }
+
+    async fn with_connection<F>(&self, f: F) -> Result<(), ProcessError>
+    where
+        F: FnOnce(&mut Connection) -> Result<(), ProcessError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| ProcessError::Internal("MBTiles connection poisoned".to_string()))?;
+            f(&mut connection)
+        })
+        .await
+        .map_err(|e| ProcessError::Internal(format!("MBTiles task failed: {}", e)))?
+    }
+}
+
+#[async_trait]
+impl TileSink for MbtilesSink {
+    async fn put_tile(&self, _layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        let column = tile.x / self.tile_size;
+        let row = (1_u32 << tile.zoom_level) - 1 - tile.y / self.tile_size;
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![tile.zoom_level, column, row, tile.data],
+                )
+                .map(|_| ())
+                .map_err(|e| sqlite_error("Failed to write tile", e))
+        })
+        .await
+    }
+
+    async fn finish(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), ProcessError> {
+        let metadata = [
+            ("name", self.name.clone()),
+            ("description", format!("Layout path {}", layout_path)),
+            ("format", self.tile_format.extension().to_string()),
+            ("type", "baselayer".to_string()),
+            ("version", "1.0".to_string()),
+            ("minzoom", manifest.min_zoom.to_string()),
+            ("maxzoom", manifest.max_zoom.to_string()),
+        ];
+        self.with_connection(move |connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(|e| sqlite_error("Failed to write metadata", e))?;
+            for (name, value) in metadata {
+                transaction
+                    .execute(
+                        "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                        params![name, value],
+                    )
+                    .map_err(|e| sqlite_error("Failed to write metadata", e))?;
+            }
+            transaction
+                .commit()
+                .map_err(|e| sqlite_error("Failed to write metadata", e))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(zoom_level: u32, x: u32, y: u32) -> Tile {
+        Tile {
+            zoom_level,
+            x,
+            y,
+            format: TileFormat::Png,
+            data: format!("{}/{}/{}", zoom_level, x, y).into_bytes(),
+            placeholder: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn rows_count_from_the_bottom() {
+        let path = std::env::temp_dir().join(format!("tms-{}.mbtiles", std::process::id()));
+        let config = ProcessConfig::new(
+            "plan.png".to_string(),
+            "http://localhost".to_string(),
+            "plan".to_string(),
+            String::new(),
+        );
+        let sink = MbtilesSink::open(&path, &config).unwrap();
+        // Tile positions are in pixels, with rows counted from the top
+        for (zoom_level, x, y) in [(0, 0, 0), (2, 0, 0), (2, 256, 768), (2, 768, 256)] {
+            sink.put_tile("", tile(zoom_level, x, y)).await.unwrap();
+        }
+
+        let mut rows: Vec<(u32, u32, u32, Vec<u8>)> = {
+            let connection = sink.connection.lock().unwrap();
+            let mut statement = connection
+                .prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")
+                .unwrap();
+            statement
+                .query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        rows.sort();
+        let expected: Vec<(u32, u32, u32, Vec<u8>)> = vec![
+            (0, 0, 0, b"0/0/0".to_vec()),
+            (2, 0, 3, b"2/0/0".to_vec()),
+            (2, 1, 0, b"2/256/768".to_vec()),
+            (2, 3, 2, b"2/768/256".to_vec()),
+        ];
+        assert_eq!(rows, expected);
+
+        drop(sink);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
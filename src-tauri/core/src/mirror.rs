@@ -0,0 +1,86 @@
+use async_trait::async_trait;
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+use crate::sink::{HttpSink, Tile, TileSink, UploadManifest};
+
+/// Uploads every tile to the main server and each of `config.mirrors`, and
+/// finalizes the layout on all of them.
+///
+/// A tile counts as stored once every server has it; if one of them fails
+/// the tile fails, and a retry sends it to all of them again, which the
+/// upload route takes as an overwrite.
+pub struct MirrorSink {
+    servers: Vec<(String, HttpSink)>,
+}
+
+impl MirrorSink {
+    pub fn new(client: reqwest::Client, config: &ProcessConfig) -> Self {
+        let mut servers = vec![(
+            config.server_address.clone(),
+            HttpSink::new(client.clone(), config),
+        )];
+        for mirror in &config.mirrors {
+            let mut mirror_config = config.clone();
+            mirror_config.server_address = mirror.server_address.clone();
+            mirror_config.layout_key = mirror.layout_key.clone();
+            mirror_config.secret = mirror.secret.clone();
+            mirror_config.auth = mirror.auth.clone();
+            servers.push((
+                mirror.server_address.clone(),
+                HttpSink::new(client.clone(), &mirror_config),
+            ));
+        }
+        Self { servers }
+    }
+}
+
+#[async_trait]
+impl TileSink for MirrorSink {
+    async fn put_tile(&self, layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        self.put_tiles(layout_path, vec![tile]).await
+    }
+
+    async fn put_tiles(&self, layout_path: &str, tiles: Vec<Tile>) -> Result<(), ProcessError> {
+        for (server, sink) in &self.servers {
+            if let Err(e) = sink.put_tiles(layout_path, tiles.clone()).await {
+                tracing::warn!(server = %server, tiles = tiles.len(), error = %e, "Mirror upload failed");
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalizes on every server even after one fails, so the others are
+    /// switched over; the first failure is returned.
+    async fn finish(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), ProcessError> {
+        let mut first_error = None;
+        for (server, sink) in &self.servers {
+            if let Err(e) = sink.finish(layout_path, manifest).await {
+                tracing::error!(server = %server, error = %e, "Finalize failed on mirror");
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Only reports the tiles discarded if every server could remove them.
+    async fn discard(&self, layout_path: &str) -> Result<bool, ProcessError> {
+        let mut discarded = true;
+        for (_, sink) in &self.servers {
+            discarded &= sink.discard(layout_path).await?;
+        }
+        Ok(discarded)
+    }
+
+    async fn probe(&self) -> Result<(), ProcessError> {
+        for (_, sink) in &self.servers {
+            sink.probe().await?;
+        }
+        Ok(())
+    }
+}
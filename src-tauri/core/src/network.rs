@@ -0,0 +1,290 @@
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Certificate, ClientBuilder, Identity, Proxy, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::config::AuthMode;
+use crate::error::ProcessError;
+use crate::processor::USER_AGENT;
+
+/// How requests reach the server, shared by uploads and `test_connection`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkSettings {
+    // Timeouts, 0 meaning no limit: establishing a connection, waiting
+    // between reads of a response, and one request from start to end
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64,
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    // Proxy every request goes through; takes precedence over the system one
+    #[serde(default)]
+    pub proxy: Option<ProxySettings>,
+    // Pick up HTTP_PROXY / HTTPS_PROXY / NO_PROXY when no proxy is set
+    #[serde(default = "default_use_system_proxy")]
+    pub use_system_proxy: bool,
+    // PEM file of extra CAs to trust, for servers with an internal CA
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+    // Skip certificate checks entirely; only ever set on explicit request
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    // Certificate presented to servers that require mutual TLS
+    #[serde(default)]
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// A client certificate and its private key, for mutual TLS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ClientCertificate {
+    /// A `.p12` / `.pfx` archive holding both
+    Pkcs12 {
+        path: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Separate PEM files; the key has to be PKCS#8 (`BEGIN PRIVATE KEY`)
+    Pem { cert_path: String, key_path: String },
+}
+
+/// An HTTP, HTTPS or SOCKS5 proxy, optionally with a username and
+/// password. `socks5h://` resolves host names on the proxy, for gateways
+/// that are the only way to reach the server's DNS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxySettings {
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: default_connect_timeout_secs(),
+            read_timeout_secs: default_read_timeout_secs(),
+            request_timeout_secs: default_request_timeout_secs(),
+            proxy: None,
+            use_system_proxy: default_use_system_proxy(),
+            ca_bundle: None,
+            accept_invalid_certs: false,
+            client_certificate: None,
+        }
+    }
+}
+
+impl NetworkSettings {
+    /// These settings with the proxy and client certificate passwords
+    /// cleared, for writing to disk; ones that were set are left empty
+    /// rather than `None`.
+    pub fn without_secrets(&self) -> Self {
+        let mut settings = self.clone();
+        if let Some(password) = settings
+            .proxy
+            .as_mut()
+            .and_then(|proxy| proxy.password.as_mut())
+        {
+            password.clear();
+        }
+        if let Some(ClientCertificate::Pkcs12 {
+            password: Some(password),
+            ..
+        }) = &mut settings.client_certificate
+        {
+            password.clear();
+        }
+        settings
+    }
+
+    /// A client builder with the user agent, timeouts and proxy applied.
+    pub(crate) fn client_builder(&self) -> Result<ClientBuilder, ProcessError> {
+        let mut builder = reqwest::Client::builder().user_agent(USER_AGENT);
+
+        // Without these a server that stops answering holds an upload slot
+        // forever
+        if self.connect_timeout_secs > 0 {
+            builder = builder.connect_timeout(Duration::from_secs(self.connect_timeout_secs));
+        }
+        if self.read_timeout_secs > 0 {
+            builder = builder.read_timeout(Duration::from_secs(self.read_timeout_secs));
+        }
+        if self.request_timeout_secs > 0 {
+            builder = builder.timeout(Duration::from_secs(self.request_timeout_secs));
+        }
+
+        match &self.proxy {
+            Some(settings) => builder = builder.proxy(settings.proxy()?),
+            None if !self.use_system_proxy => builder = builder.no_proxy(),
+            None => {}
+        }
+
+        if let Some(path) = self.ca_bundle.as_deref().filter(|path| !path.is_empty()) {
+            let pem = std::fs::read(path)
+                .map_err(|e| ProcessError::io(format!("Failed to read CA bundle {}", path), e))?;
+            let certificates = Certificate::from_pem_bundle(&pem).map_err(|e| {
+                ProcessError::InvalidConfig(format!("Invalid CA bundle {}: {}", path, e))
+            })?;
+            if certificates.is_empty() {
+                return Err(ProcessError::InvalidConfig(format!(
+                    "CA bundle {} contains no certificates",
+                    path
+                )));
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if self.accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(certificate) = &self.client_certificate {
+            builder = builder.identity(certificate.identity()?);
+        }
+
+        Ok(builder)
+    }
+
+    /// Settings that weaken the connection's security, worded for the user.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.accept_invalid_certs {
+            warnings.push(
+                "Certificate checks were disabled; the server's identity was not verified"
+                    .to_string(),
+            );
+        }
+        warnings
+    }
+}
+
+impl AuthMode {
+    /// Attach `secret` to `request`; `query_param` names the parameter the
+    /// endpoint expects in `Query` mode.
+    pub(crate) fn authorize(
+        &self,
+        request: RequestBuilder,
+        query_param: &str,
+        secret: &str,
+    ) -> RequestBuilder {
+        match self {
+            AuthMode::Query => request.query(&[(query_param, secret)]),
+            AuthMode::Bearer => request.bearer_auth(secret),
+            AuthMode::Header { name } => request.header(name.as_str(), secret),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ProcessError> {
+        if let AuthMode::Header { name } = self {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || name.eq_ignore_ascii_case(AUTHORIZATION.as_str())
+            {
+                return Err(ProcessError::InvalidConfig(format!(
+                    "'{}' can't be used as the secret header; use bearer mode for Authorization",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProxySettings {
+    fn proxy(&self) -> Result<Proxy, ProcessError> {
+        let url = self.url.trim();
+        let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+        if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h")) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Proxy URL must start with http://, https://, socks5:// or socks5h://, got '{}'",
+                self.url
+            )));
+        }
+
+        let mut proxy = Proxy::all(url).map_err(|e| {
+            ProcessError::InvalidConfig(format!("Invalid proxy URL '{}': {}", self.url, e))
+        })?;
+        if let Some(username) = self.username.as_deref().filter(|name| !name.is_empty()) {
+            proxy = proxy.basic_auth(username, self.password.as_deref().unwrap_or_default());
+        }
+        Ok(proxy)
+    }
+}
+
+impl ClientCertificate {
+    fn identity(&self) -> Result<Identity, ProcessError> {
+        let read = |path: &str| {
+            std::fs::read(path).map_err(|e| {
+                ProcessError::io(format!("Failed to read client certificate {}", path), e)
+            })
+        };
+        let invalid = |e: reqwest::Error| {
+            ProcessError::InvalidConfig(format!("Invalid client certificate: {}", e))
+        };
+
+        match self {
+            ClientCertificate::Pkcs12 { path, password } => {
+                Identity::from_pkcs12_der(&read(path)?, password.as_deref().unwrap_or_default())
+                    .map_err(invalid)
+            }
+            ClientCertificate::Pem {
+                cert_path,
+                key_path,
+            } => Identity::from_pkcs8_pem(&read(cert_path)?, &read(key_path)?).map_err(invalid),
+        }
+    }
+}
+
+/// Whether a failed request was turned away by the proxy rather than the
+/// server. reqwest has no dedicated error kind for this, so it is read off
+/// a 407 status or the tunnel or SOCKS error underneath; other errors that
+/// merely mention a proxy, such as a server's own message, don't count.
+pub(crate) fn is_proxy_error(error: &reqwest::Error) -> bool {
+    if error.status().is_some_and(|status| status == 407) {
+        return true;
+    }
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        let message = cause.to_string().to_lowercase();
+        // hyper-util's `TunnelError` and reqwest's `SocksProxyError`
+        if message.contains("tunnel error")
+            || message.contains("socks error")
+            || message.contains("socks proxy")
+        {
+            return true;
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// Whether a request failed because the server's certificate wasn't
+/// trusted, which a CA bundle would fix.
+pub(crate) fn is_certificate_error(error: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        if cause.to_string().to_lowercase().contains("certificate") {
+            return true;
+        }
+        source = cause.source();
+    }
+    false
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_read_timeout_secs() -> u64 {
+    30
+}
+
+// Generous enough for a large tile over a slow link
+fn default_request_timeout_secs() -> u64 {
+    120
+}
+
+fn default_use_system_proxy() -> bool {
+    true
+}
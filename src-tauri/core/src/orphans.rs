@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{OutputTarget, ProcessConfig};
+use crate::error::ProcessError;
+use crate::manifest::JobManifest;
+use crate::processor::TileProcessor;
+
+pub const ORPHANS_FILE: &str = "orphans.json";
+
+// Jobs finish on their own tasks, so reads and writes of the file are
+// serialized across the process
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A layout path that received tiles but was never finalized, because its
+/// run failed or was cancelled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrphanedLayout {
+    pub layout_path: String,
+    // The run's settings without credentials, needed to reach the server
+    pub config: ProcessConfig,
+    pub tiles_uploaded: u32,
+    // Seconds since the Unix epoch
+    pub recorded_at: u64,
+}
+
+/// What `cleanup_orphan` did with one orphaned layout path.
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanCleanup {
+    pub layout_path: String,
+    pub server_address: String,
+    pub layout_key: String,
+    // Whether the server removed the tiles and the record was dropped
+    pub removed: bool,
+    pub message: String,
+}
+
+/// Layout paths left behind on servers, kept as a JSON file next to the
+/// resume manifests so they can be cleaned up later.
+pub struct OrphanStore {
+    path: PathBuf,
+}
+
+impl OrphanStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(ORPHANS_FILE),
+        }
+    }
+
+    /// Every recorded orphan, oldest first.
+    pub fn list(&self) -> Result<Vec<OrphanedLayout>, ProcessError> {
+        let _lock = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        self.read()
+    }
+
+    /// Note `orphan`, replacing an earlier record of the same layout path.
+    pub fn record(&self, orphan: OrphanedLayout) -> Result<(), ProcessError> {
+        let _lock = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut orphans = self.read()?;
+        orphans.retain(|existing| existing.layout_path != orphan.layout_path);
+        orphans.push(orphan);
+        self.save(&orphans)
+    }
+
+    /// Forget `layout_path`, once it was finalized after all or its tiles
+    /// were removed.
+    pub fn remove(&self, layout_path: &str) -> Result<(), ProcessError> {
+        let _lock = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut orphans = self.read()?;
+        let count = orphans.len();
+        orphans.retain(|orphan| orphan.layout_path != layout_path);
+        if orphans.len() == count {
+            return Ok(());
+        }
+        self.save(&orphans)
+    }
+
+    fn read(&self) -> Result<Vec<OrphanedLayout>, ProcessError> {
+        match fs::read(&self.path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| ProcessError::Manifest(format!("{}: {}", ORPHANS_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(ProcessError::io("Failed to read orphaned layouts", e)),
+        }
+    }
+
+    // Written next to the file and renamed over it, like the profiles
+    fn save(&self, orphans: &[OrphanedLayout]) -> Result<(), ProcessError> {
+        let contents = serde_json::to_vec_pretty(orphans)
+            .map_err(|e| ProcessError::Internal(format!("Failed to encode orphans: {}", e)))?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, contents)
+            .map_err(|e| ProcessError::io("Failed to write orphaned layouts", e))?;
+        fs::rename(&temp, &self.path)
+            .map_err(|e| ProcessError::io("Failed to write orphaned layouts", e))
+    }
+}
+
+/// Deal with the tiles a failed or cancelled run left on the server. With
+/// `config.rollback_on_failure` they are removed through the cleanup
+/// endpoint and the manifest goes with them; otherwise, or if that fails,
+/// the layout path is recorded in `orphans` and the manifest kept so the
+/// run can still be resumed. Problems are logged rather than returned, so
+/// the run's own error is what gets reported.
+pub async fn abandon(config: &ProcessConfig, manifest: JobManifest, orphans: &OrphanStore) {
+    // Exports and dry runs leave nothing on a server
+    let tiles_uploaded = manifest.uploaded_count();
+    if tiles_uploaded == 0 || config.dry_run || config.output != OutputTarget::Server {
+        return;
+    }
+
+    let layout_path = manifest.layout_path.clone();
+    if config.rollback_on_failure {
+        let discarded = async {
+            let sink = TileProcessor::new(config)?.sink_for(config)?;
+            sink.discard(&layout_path).await
+        };
+        match discarded.await {
+            Ok(true) => {
+                if let Err(e) = manifest.remove() {
+                    tracing::warn!(layout_path, "Failed to remove manifest: {}", e);
+                }
+                return;
+            }
+            Ok(false) => {
+                tracing::warn!(layout_path, "No cleanup endpoint set, keeping the tiles")
+            }
+            Err(e) => tracing::warn!(layout_path, "Rollback failed: {}", e),
+        }
+    }
+
+    let orphan = OrphanedLayout {
+        layout_path,
+        config: config.without_secrets(),
+        tiles_uploaded,
+        recorded_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+    };
+    if let Err(e) = orphans.record(orphan) {
+        tracing::warn!("Failed to record orphaned layout: {}", e);
+    }
+}
+
+impl OrphanCleanup {
+    /// `orphan` left in place because cleaning it up failed before the
+    /// server was asked.
+    pub fn failed(orphan: &OrphanedLayout, message: String) -> Self {
+        Self {
+            layout_path: orphan.layout_path.clone(),
+            server_address: orphan.config.server_address.clone(),
+            layout_key: orphan.config.layout_key.clone(),
+            removed: false,
+            message,
+        }
+    }
+}
+
+/// Ask the server to remove the tiles of `orphan`, with `config`, the
+/// orphan's config with its credentials filled in again. On success the
+/// record is dropped, along with the resume manifest of that layout path,
+/// whose tiles are gone now.
+pub async fn cleanup_orphan(
+    orphan: &OrphanedLayout,
+    config: ProcessConfig,
+    manifest_dir: &Path,
+    orphans: &OrphanStore,
+) -> OrphanCleanup {
+    let result = async {
+        let sink = TileProcessor::new(&config)?.sink_for(&config)?;
+        if !sink.discard(&orphan.layout_path).await? {
+            return Ok(false);
+        }
+        orphans.remove(&orphan.layout_path)?;
+        if let Ok(Some(manifest)) = JobManifest::load(manifest_dir, &config) {
+            if manifest.layout_path == orphan.layout_path {
+                manifest.remove()?;
+            }
+        }
+        Ok::<_, ProcessError>(true)
+    }
+    .await;
+
+    let (removed, message) = match result {
+        Ok(true) => (true, format!("Removed {} tiles", orphan.tiles_uploaded)),
+        Ok(false) => (
+            false,
+            "No cleanup endpoint is set for this server".to_string(),
+        ),
+        Err(e) => (false, e.to_string()),
+    };
+    OrphanCleanup {
+        layout_path: orphan.layout_path.clone(),
+        server_address: config.server_address,
+        layout_key: config.layout_key,
+        removed,
+        message,
+    }
+}
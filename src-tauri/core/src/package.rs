@@ -0,0 +1,134 @@
+use async_trait::async_trait;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::ProcessConfig;
+use crate::error::ProcessError;
+use crate::import::{finish_scan, number, tile_format, PyramidTile, ScannedPyramid};
+use crate::local::{LocalDirectorySink, PyramidManifest, PYRAMID_MANIFEST};
+use crate::sink::{Tile, TileSink, UploadManifest};
+
+/// Exports the pyramid as a single uncompressed TAR archive of the folder
+/// export, `{zoom}/{x}/{y}.{ext}` plus `layout.json`, for sites where the
+/// machine with the artwork can't reach the server. `scan_package` reads
+/// it back so the tiles can be uploaded from a machine that can.
+///
+/// Tiles collect in a `{name}.parts` folder next to the archive, so an
+/// interrupted export resumes like a folder export, and are packed once
+/// the export finishes.
+pub struct PackageSink {
+    path: PathBuf,
+    parts: PathBuf,
+    staging: LocalDirectorySink,
+}
+
+impl PackageSink {
+    pub fn new(path: PathBuf, config: &ProcessConfig) -> Self {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".parts");
+        let parts = path.with_file_name(name);
+        Self {
+            staging: LocalDirectorySink::new(parts.clone(), config),
+            path,
+            parts,
+        }
+    }
+}
+
+/// Pack the folder `parts` into the archive at `path`, then remove it.
+fn pack(parts: &Path, path: &Path) -> Result<(), ProcessError> {
+    let failed = |e| ProcessError::io("Failed to write package", e);
+    // Written aside and renamed, so a failed pack can't pass for a package
+    let temp = path.with_extension("partial");
+    let mut builder = tar::Builder::new(File::create(&temp).map_err(failed)?);
+    builder.append_dir_all(".", parts).map_err(failed)?;
+    builder
+        .into_inner()
+        .and_then(|file| file.sync_all())
+        .map_err(failed)?;
+    fs::rename(&temp, path).map_err(failed)?;
+    fs::remove_dir_all(parts).map_err(|e| ProcessError::io("Failed to remove package parts", e))
+}
+
+#[async_trait]
+impl TileSink for PackageSink {
+    async fn put_tile(&self, layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        self.staging.put_tile(layout_path, tile).await
+    }
+
+    async fn finish(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), ProcessError> {
+        self.staging.finish(layout_path, manifest).await?;
+
+        let (parts, path) = (self.parts.clone(), self.path.clone());
+        tokio::task::spawn_blocking(move || pack(&parts, &path))
+            .await
+            .map_err(|e| ProcessError::Internal(format!("Packing failed: {}", e)))?
+    }
+}
+
+/// The contents of a package written by `PackageSink`.
+#[derive(Debug, Clone)]
+pub struct ScannedPackage {
+    pub manifest: PyramidManifest,
+    pub pyramid: ScannedPyramid,
+}
+
+/// Index the tiles of the package at `path` without unpacking it: each
+/// tile points at its span of the archive, read when it is uploaded.
+pub fn scan_package(path: &Path) -> Result<ScannedPackage, ProcessError> {
+    let failed = |e| ProcessError::io("Failed to read package", e);
+    let mut archive = tar::Archive::new(File::open(path).map_err(failed)?);
+
+    let mut manifest = None;
+    let mut tiles = Vec::new();
+    for entry in archive.entries().map_err(failed)? {
+        let mut entry = entry.map_err(failed)?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        // Entries are stored under `./`
+        let name: PathBuf = entry
+            .path()
+            .map_err(failed)?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+
+        if name == Path::new(PYRAMID_MANIFEST) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json).map_err(failed)?;
+            let parsed: PyramidManifest = serde_json::from_str(&json)
+                .map_err(|e| ProcessError::Manifest(format!("{}: {}", PYRAMID_MANIFEST, e)))?;
+            manifest = Some(parsed);
+            continue;
+        }
+
+        let parts: Vec<&Path> = name.iter().map(Path::new).collect();
+        let [zoom, x, file] = parts[..] else {
+            continue;
+        };
+        if let (Some(zoom_level), Some(x), Some(y), Some(format)) =
+            (number(zoom), number(x), number(file), tile_format(file))
+        {
+            tiles.push(PyramidTile {
+                zoom_level,
+                x,
+                y,
+                format,
+                path: path.to_path_buf(),
+                range: Some((entry.raw_file_position(), entry.size())),
+            });
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| {
+        ProcessError::Manifest(format!("the package has no {}", PYRAMID_MANIFEST))
+    })?;
+    let pyramid = finish_scan(tiles, manifest.tile_size, manifest.overlap)?;
+    Ok(ScannedPackage { manifest, pyramid })
+}
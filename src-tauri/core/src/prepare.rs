@@ -0,0 +1,266 @@
+//! Changes made to the decoded image before it is tiled: rotation and
+//! flips, then the crop, then trimming uniform margins, then keying out a
+//! color, then tone adjustments. `decode::open_image`
+//! applies them, and `decode::read_header` reports the size they leave, so
+//! every path sees the same prepared image.
+
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+use crate::config::{Color, CropRect, ProcessConfig};
+use crate::error::ProcessError;
+
+/// The size a `width` by `height` image is once prepared. Finding the
+/// margins `auto_trim` takes off needs the pixels, so they're still
+/// counted.
+pub(crate) fn prepared_size(
+    config: &ProcessConfig,
+    width: u32,
+    height: u32,
+) -> Result<(u32, u32), ProcessError> {
+    let (width, height) = rotated_size(width, height, config.rotate);
+    match config.crop {
+        Some(crop) => {
+            check_crop(crop, width, height)?;
+            Ok((crop.width, crop.height))
+        }
+        None => Ok((width, height)),
+    }
+}
+
+/// Apply `config`'s preparation to a freshly decoded image.
+pub(crate) fn prepare(
+    image: DynamicImage,
+    config: &ProcessConfig,
+) -> Result<DynamicImage, ProcessError> {
+    let mut image = rotate(image, config.rotate);
+    if config.flip_horizontal {
+        image = image.fliph();
+    }
+    if config.flip_vertical {
+        image = image.flipv();
+    }
+    if let Some(crop) = config.crop {
+        check_crop(crop, image.width(), image.height())?;
+        image = image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+    }
+    if config.auto_trim {
+        image = trim(image, config.trim_tolerance, &config.image_path);
+    }
+    if let Some(key) = config.color_key {
+        image =
+            DynamicImage::ImageRgba8(key_out(image.into_rgba8(), key, config.color_key_tolerance));
+    }
+    // After the key, so it matches the colors as scanned
+    if config.tone_key().is_some() {
+        image = DynamicImage::ImageRgba8(adjust_tone(image.into_rgba8(), config));
+    }
+    Ok(image)
+}
+
+fn check_crop(crop: CropRect, width: u32, height: u32) -> Result<(), ProcessError> {
+    let inside = crop.width > 0
+        && crop.height > 0
+        && crop
+            .x
+            .checked_add(crop.width)
+            .is_some_and(|right| right <= width)
+        && crop
+            .y
+            .checked_add(crop.height)
+            .is_some_and(|bottom| bottom <= height);
+    if inside {
+        Ok(())
+    } else {
+        Err(ProcessError::InvalidConfig(format!(
+            "Crop {}x{} at {},{} doesn't fit in the {}x{} image",
+            crop.width, crop.height, crop.x, crop.y, width, height
+        )))
+    }
+}
+
+/// Whole quarter turns `degrees` comes to, if it is a multiple of 90.
+fn quarter_turns(degrees: f64) -> Option<u32> {
+    let turns = degrees.rem_euclid(360.0) / 90.0;
+    ((turns - turns.round()).abs() < 1e-9).then(|| turns.round() as u32 % 4)
+}
+
+/// The size of a `width` by `height` image turned `degrees` clockwise, on
+/// a canvas just large enough to hold all of it.
+fn rotated_size(width: u32, height: u32, degrees: f64) -> (u32, u32) {
+    match quarter_turns(degrees) {
+        Some(1 | 3) => (height, width),
+        Some(_) => (width, height),
+        None => {
+            let (sin, cos) = degrees.to_radians().sin_cos();
+            let (sin, cos) = (sin.abs(), cos.abs());
+            let (width, height) = (width as f64, height as f64);
+            // Rounding errors mustn't add a column
+            let side = |size: f64| ((size - 1e-6).ceil() as u32).max(1);
+            (
+                side(width * cos + height * sin),
+                side(width * sin + height * cos),
+            )
+        }
+    }
+}
+
+/// `image` turned `degrees` clockwise. Quarter turns move pixels exactly;
+/// other angles are resampled bilinearly onto a larger canvas whose
+/// corners are left transparent, so they take the background color.
+fn rotate(image: DynamicImage, degrees: f64) -> DynamicImage {
+    match quarter_turns(degrees) {
+        Some(0) => image,
+        Some(1) => image.rotate90(),
+        Some(2) => image.rotate180(),
+        Some(3) => image.rotate270(),
+        _ => DynamicImage::ImageRgba8(rotate_by(&image.into_rgba8(), degrees)),
+    }
+}
+
+fn rotate_by(source: &RgbaImage, degrees: f64) -> RgbaImage {
+    let (width, height) = rotated_size(source.width(), source.height(), degrees);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let source_center = (source.width() as f64 / 2.0, source.height() as f64 / 2.0);
+    let center = (width as f64 / 2.0, height as f64 / 2.0);
+    let pixel = |x: i64, y: i64| -> [f64; 4] {
+        if x < 0 || y < 0 || x >= source.width() as i64 || y >= source.height() as i64 {
+            return [0.0; 4];
+        }
+        let [r, g, b, a] = source.get_pixel(x as u32, y as u32).0;
+        [r as f64, g as f64, b as f64, a as f64]
+    };
+
+    let mut rotated = RgbaImage::new(width, height);
+    rotated
+        .par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, out) in row.chunks_exact_mut(4).enumerate() {
+                // Where the centre of this pixel comes from, turned back
+                let dx = x as f64 + 0.5 - center.0;
+                let dy = y as f64 + 0.5 - center.1;
+                let sx = dx * cos + dy * sin + source_center.0 - 0.5;
+                let sy = -dx * sin + dy * cos + source_center.1 - 0.5;
+                let (x0, y0) = (sx.floor(), sy.floor());
+                let (tx, ty) = (sx - x0, sy - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+
+                // Colors weighted by alpha, so transparent corners don't
+                // darken the edges
+                let mut sum = [0.0; 4];
+                for (px, py, weight) in [
+                    (x0, y0, (1.0 - tx) * (1.0 - ty)),
+                    (x0 + 1, y0, tx * (1.0 - ty)),
+                    (x0, y0 + 1, (1.0 - tx) * ty),
+                    (x0 + 1, y0 + 1, tx * ty),
+                ] {
+                    let [r, g, b, a] = pixel(px, py);
+                    let weight = weight * a;
+                    sum[0] += r * weight;
+                    sum[1] += g * weight;
+                    sum[2] += b * weight;
+                    sum[3] += weight;
+                }
+                if sum[3] > 0.0 {
+                    for channel in 0..3 {
+                        out[channel] = (sum[channel] / sum[3]).round().clamp(0.0, 255.0) as u8;
+                    }
+                    out[3] = sum[3].round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        });
+    rotated
+}
+
+/// `image` without the rows and columns along its edges that are all the
+/// color of its top-left corner, give or take `tolerance` per channel. An
+/// image that is that color throughout is kept whole.
+fn trim(image: DynamicImage, tolerance: u8, path: &str) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image;
+    }
+    let margin = image.get_pixel(0, 0);
+    let matches = |pixel: Rgba<u8>| {
+        pixel
+            .0
+            .iter()
+            .zip(margin.0)
+            .all(|(&channel, margin)| channel.abs_diff(margin) <= tolerance)
+    };
+    let blank_row =
+        |y: u32, left: u32, right: u32| (left..right).all(|x| matches(image.get_pixel(x, y)));
+    let blank_column =
+        |x: u32, top: u32, bottom: u32| (top..bottom).all(|y| matches(image.get_pixel(x, y)));
+
+    let Some(top) = (0..height).find(|&y| !blank_row(y, 0, width)) else {
+        return image;
+    };
+    let bottom = (top..height)
+        .rev()
+        .find(|&y| !blank_row(y, 0, width))
+        .unwrap_or(top)
+        + 1;
+    let left = (0..width)
+        .find(|&x| !blank_column(x, top, bottom))
+        .unwrap_or(0);
+    let right = (left..width)
+        .rev()
+        .find(|&x| !blank_column(x, top, bottom))
+        .unwrap_or(left)
+        + 1;
+    if (left, top, right, bottom) == (0, 0, width, height) {
+        return image;
+    }
+    tracing::info!(
+        path = %path,
+        left,
+        top,
+        right = width - right,
+        bottom = height - bottom,
+        "Trimmed uniform margins"
+    );
+    image.crop_imm(left, top, right - left, bottom - top)
+}
+
+/// `image` with the pixels within `tolerance` per channel of `key` made
+/// transparent. Their color is kept, so resizing doesn't darken the edges
+/// of what remains.
+fn key_out(mut image: RgbaImage, key: Color, tolerance: u8) -> RgbaImage {
+    let key = [key.r, key.g, key.b];
+    image.par_chunks_mut(4).for_each(|pixel| {
+        if pixel[..3]
+            .iter()
+            .zip(key)
+            .all(|(&channel, key)| channel.abs_diff(key) <= tolerance)
+        {
+            pixel[3] = 0;
+        }
+    });
+    image
+}
+
+/// `image` with `config`'s tone adjustments: turned gray first if asked,
+/// then gamma, contrast around mid-gray and brightness, in that order.
+fn adjust_tone(mut image: RgbaImage, config: &ProcessConfig) -> RgbaImage {
+    let table: Vec<u8> = (0..=255u8)
+        .map(|value| {
+            let value = (value as f32 / 255.0).powf(1.0 / config.gamma);
+            let value = (value - 0.5) * config.contrast + 0.5 + config.brightness;
+            (value * 255.0).round().clamp(0.0, 255.0) as u8
+        })
+        .collect();
+    image.par_chunks_mut(4).for_each(|pixel| {
+        if config.grayscale {
+            // Rec. 709 luma, as `imageops::grayscale` weighs the channels
+            let luma =
+                0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32;
+            pixel[..3].fill(luma.round().clamp(0.0, 255.0) as u8);
+        }
+        for channel in &mut pixel[..3] {
+            *channel = table[*channel as usize];
+        }
+    });
+    image
+}
@@ -0,0 +1,213 @@
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, ImageFormat, Rgba, RgbaImage};
+use serde::Serialize;
+use std::io::Cursor;
+
+use crate::background::run_background;
+use crate::config::{Color, ProcessConfig, TileFormat};
+use crate::debug::draw_overlay;
+use crate::decode::open_image;
+use crate::error::ProcessError;
+use crate::estimate::sample_tiles;
+use crate::processor::{is_background_tile, tile_bounds, TileEncoder, TileProcessor};
+
+/// Longest side of a preview when the caller doesn't ask for a size.
+pub const DEFAULT_PREVIEW_SIZE: u32 = 1024;
+
+/// `config.image_path` shrunk to fit `max_size` and flattened onto the
+/// run's background color, as PNG bytes.
+///
+/// Transparent areas show the background the padding tiles will have, so
+/// the preview looks like the uploaded layout. Decoding the image makes
+/// this blocking.
+pub fn render_preview(config: &ProcessConfig, max_size: u32) -> Result<Vec<u8>, ProcessError> {
+    let img = open_image(config)?;
+    let background = run_background(config, &img);
+    let max_size = max_size.max(1);
+    // Small images are shown as they are
+    let img = if img.width() > max_size || img.height() > max_size {
+        img.resize(max_size, max_size, FilterType::Triangle)
+    } else {
+        img
+    };
+
+    let mut preview: RgbaImage = ImageBuffer::from_pixel(
+        img.width(),
+        img.height(),
+        Rgba([background.r, background.g, background.b, 255]),
+    );
+    imageops::overlay(&mut preview, &img.to_rgba8(), 0, 0);
+
+    let mut png = Vec::new();
+    preview
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| ProcessError::image("Failed to encode preview", e))?;
+    Ok(png)
+}
+
+/// `image` flattened onto `background` as a JPEG, for the overview a run
+/// uploads to `thumbnail_endpoint`.
+pub(crate) fn encode_thumbnail(
+    image: &RgbaImage,
+    background: Color,
+    quality: u8,
+) -> Result<Vec<u8>, ProcessError> {
+    let mut flat: RgbaImage = ImageBuffer::from_pixel(
+        image.width(),
+        image.height(),
+        Rgba([background.r, background.g, background.b, 255]),
+    );
+    imageops::overlay(&mut flat, image, 0, 0);
+    let rgb = image::DynamicImage::ImageRgba8(flat).to_rgb8();
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode_image(&rgb)
+        .map_err(|e| ProcessError::image("Failed to encode thumbnail", e))?;
+    Ok(jpeg)
+}
+
+/// One tile exactly as a run would upload it, for spot checks.
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderedTile {
+    pub zoom_level: u32,
+    pub x: u32,
+    pub y: u32,
+    pub format: TileFormat,
+    pub mime_type: String,
+    // Set when the tile is all background, which `skip_background_tiles`
+    // leaves out of the upload
+    pub background: bool,
+    pub bytes: usize,
+    // The encoded tile, base64
+    pub data: String,
+}
+
+impl TileProcessor {
+    /// Render the tile at pixel offset `x`, `y` of `zoom_level`, the same
+    /// coordinates the server receives, exactly as `config` would upload it.
+    ///
+    /// Levels are resized one from another the way a run does, so getting
+    /// to a low zoom level takes the resizes of every level above it.
+    /// Decoding the image makes this blocking.
+    pub fn render_tile(
+        &self,
+        config: &ProcessConfig,
+        zoom_level: u32,
+        x: u32,
+        y: u32,
+    ) -> Result<RenderedTile, ProcessError> {
+        let img = open_image(config)?.into_rgba8();
+        let config = &ProcessConfig {
+            background_color: run_background(config, &img),
+            ..config.clone()
+        };
+        let (img_width, img_height) = img.dimensions();
+        let zoom_range = self.zoom_range(img_width, img_height);
+        if !zoom_range.contains(&zoom_level) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Zoom level {} is out of range, the image has levels {} to {}",
+                zoom_level,
+                zoom_range.start,
+                zoom_range.end - 1
+            )));
+        }
+        let size = self.level_size(zoom_level, img_width, img_height);
+        if !x.is_multiple_of(self.tile_size.width)
+            || !y.is_multiple_of(self.tile_size.height)
+            || x >= size.padded_width
+            || y >= size.padded_height
+        {
+            return Err(ProcessError::InvalidConfig(format!(
+                "No tile at {},{} of zoom level {}: offsets are multiples of {} and {} below {}x{}",
+                x,
+                y,
+                zoom_level,
+                self.tile_size.width,
+                self.tile_size.height,
+                size.padded_width,
+                size.padded_height
+            )));
+        }
+
+        let background = self.background(config);
+        let padded = self.padded_level(config, &img, zoom_level, background);
+        let (left, top, width, height) = tile_bounds(x, y, self.tile_size, self.overlap);
+        let tile = imageops::crop_imm(&padded, left, top, width, height);
+        let is_background = is_background_tile(&*tile, background);
+        let encoder = self.level_encoder(config, &img, zoom_level)?;
+        let mut tile = tile.to_image();
+        if config.debug_overlay {
+            let inset = (x - left, y - top);
+            draw_overlay(&mut tile, zoom_level, x, y, self.tile_size, inset);
+        }
+        let data = encoder.encode(tile)?;
+        Ok(RenderedTile {
+            zoom_level,
+            x,
+            y,
+            format: self.encoder.format,
+            mime_type: self.encoder.format.mime_type().to_string(),
+            background: is_background,
+            bytes: data.len(),
+            data: base64::engine::general_purpose::STANDARD.encode(&data),
+        })
+    }
+
+    /// Level `zoom_level` of `img`, padded, as a run renders it: resized one
+    /// level from another down from the deepest, unless `low_zoom_first`
+    /// resizes each from the source.
+    pub(crate) fn padded_level(
+        &self,
+        config: &ProcessConfig,
+        img: &RgbaImage,
+        zoom_level: u32,
+        background: Rgba<u8>,
+    ) -> RgbaImage {
+        let (img_width, img_height) = img.dimensions();
+        let zoom_range = self.zoom_range(img_width, img_height);
+        let levels: Vec<u32> = if config.low_zoom_first {
+            vec![zoom_level]
+        } else {
+            (zoom_level..zoom_range.end).rev().collect()
+        };
+        let mut previous: Option<RgbaImage> = None;
+        let mut padded = None;
+        for level in levels {
+            // An upscaled level is no better a source than the original
+            let source = match &previous {
+                Some(previous) if previous.width() < img_width => previous,
+                _ => img,
+            };
+            let size = self.level_size(level, img_width, img_height);
+            let (padded_img, scaled_img) = self.render_level(source, &size, background);
+            padded = Some(padded_img);
+            previous = Some(scaled_img);
+        }
+        padded.expect("the requested level is always rendered")
+    }
+
+    /// The encoder a run uses for the tiles of `zoom_level`, at the quality
+    /// a size budget and the quality ramp give it.
+    pub(crate) fn level_encoder(
+        &self,
+        config: &ProcessConfig,
+        img: &RgbaImage,
+        zoom_level: u32,
+    ) -> Result<TileEncoder, ProcessError> {
+        let (img_width, img_height) = img.dimensions();
+        let budget_quality = match config.size_budget_mb {
+            Some(_) => {
+                let samples = sample_tiles(img, self.tile_size);
+                self.budget_quality(config, &samples, self.count_tiles(img_width, img_height))?
+            }
+            None => None,
+        };
+        let deepest_zoom = self.zoom_range(img_width, img_height).end - 1;
+        Ok(self
+            .encoder
+            .with_quality(budget_quality)
+            .with_quality(config.ramp_quality(zoom_level, deepest_zoom)))
+    }
+}
@@ -0,0 +1,1755 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageBuffer, Rgba, RgbaImage};
+use rayon::prelude::*;
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use crate::blurhash::blurhash;
+use crate::breaker::CircuitBreaker;
+use crate::cache::{ImageCache, TileCache};
+use crate::config::{
+    CropRect, OutputTarget, PaddingAnchor, PaddingMode, ProcessConfig, ResizeFilter,
+    StitchPlacement, TileFormat, TileSize, UpscaleMode,
+};
+use crate::control::JobControl;
+use crate::debug::draw_overlay;
+use crate::dzi::DziSink;
+use crate::error::{FailedTile, ProcessError};
+use crate::incremental::{tile_hash, TileHashStore, TileHashes};
+use crate::layers::check_layer_name;
+use crate::linear::resize_linear;
+use crate::local::LocalDirectorySink;
+use crate::manifest::JobManifest;
+use crate::mbtiles::MbtilesSink;
+use crate::mirror::MirrorSink;
+use crate::package::PackageSink;
+use crate::preview::encode_thumbnail;
+use crate::progress::{ProgressReporter, StatusNotifier};
+use crate::s3::S3Sink;
+use crate::sftp::SftpSink;
+use crate::sink::{
+    validate_tile_url_template, DryRunSink, HttpSink, ImagePlacement, Tile, TileSink,
+};
+use crate::strips::LevelSource;
+use crate::summary::{save_summary, RunSummary, SummaryBuilder};
+
+pub(crate) const USER_AGENT: &str = "SDLayoutUploader-Tauri";
+
+/// Run `future` unless the job is cancelled first. Dropping the future
+/// aborts whatever request it had in flight.
+pub(crate) async fn until_cancelled<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = Result<T, ProcessError>>,
+) -> Result<T, ProcessError> {
+    tokio::select! {
+        result = future => result,
+        _ = cancel.cancelled() => Err(ProcessError::Cancelled),
+    }
+}
+
+/// Announce a cancellation, wherever in the run it was noticed.
+pub(crate) async fn report_cancelled<T>(
+    result: Result<T, ProcessError>,
+    reporter: &mut ProgressReporter,
+) -> Result<T, ProcessError> {
+    if let Err(ProcessError::Cancelled) = &result {
+        reporter.cancelled().await;
+    }
+    result
+}
+
+pub(crate) fn upload_result<T>(
+    result: Result<Result<T, ProcessError>, JoinError>,
+) -> Result<T, ProcessError> {
+    result.map_err(|e| ProcessError::Internal(format!("Upload task failed: {}", e)))?
+}
+
+// Waits for a 429 without `Retry-After`, and the longest one honoured
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+// A server still throttling after this many waits fails the tile
+const MAX_THROTTLED_ATTEMPTS: u32 = 30;
+
+/// A tile that made it into the sink.
+pub(crate) struct UploadedTile {
+    pub(crate) zoom_level: u32,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) bytes: u64,
+    pub(crate) checksum: String,
+    pub(crate) placeholder: Option<String>,
+}
+
+/// How one tile upload ended, short of stopping the run.
+pub(crate) enum UploadOutcome {
+    Uploaded(UploadedTile),
+    /// It failed on a continue-on-error run; kept for the retry pass
+    Failed(Tile),
+}
+
+/// Stores the tiles of one run in its sink, cloned into every upload task.
+#[derive(Clone)]
+pub(crate) struct Uploader {
+    sink: Arc<dyn TileSink>,
+    layout_path: String,
+    cancel: CancellationToken,
+    status: StatusNotifier,
+    breaker: Option<Arc<CircuitBreaker>>,
+    // Uploads sent again, counted across every clone
+    retries: Arc<AtomicU32>,
+}
+
+impl Uploader {
+    pub(crate) fn new(
+        sink: Arc<dyn TileSink>,
+        layout_path: String,
+        config: &ProcessConfig,
+        control: &JobControl,
+        reporter: &ProgressReporter,
+    ) -> Self {
+        Self {
+            sink,
+            layout_path,
+            cancel: control.cancel.clone(),
+            status: reporter.status_notifier(),
+            breaker: CircuitBreaker::new(config).map(Arc::new),
+            retries: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Store `tiles`, in one request if the sink can batch them, waiting as
+    /// long as the server asks whenever it answers 429. With the circuit
+    /// breaker on, tiles the server fails to take are tried again, and
+    /// uploads hold while the breaker is open; they only fail if they keep
+    /// failing while others go through.
+    pub(crate) async fn put(&self, tiles: Vec<Tile>) -> Result<(), ProcessError> {
+        let mut throttled = 0;
+        let mut failures = 0;
+        loop {
+            if let Some(breaker) = &self.breaker {
+                let waited = breaker
+                    .wait_until_closed(self.sink.as_ref(), &self.cancel, &self.status)
+                    .await?;
+                if waited {
+                    failures = 0;
+                }
+            }
+
+            let put = match tiles.as_slice() {
+                [tile] => self.sink.put_tile(&self.layout_path, tile.clone()),
+                _ => self.sink.put_tiles(&self.layout_path, tiles.clone()),
+            };
+            match until_cancelled(&self.cancel, put).await {
+                Ok(()) => {
+                    if let Some(breaker) = &self.breaker {
+                        breaker.record_success();
+                    }
+                    return Ok(());
+                }
+                Err(ProcessError::Throttled { retry_after, .. })
+                    if throttled < MAX_THROTTLED_ATTEMPTS =>
+                {
+                    throttled += 1;
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    let wait = retry_after
+                        .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs)
+                        .min(MAX_RETRY_AFTER);
+                    self.status.throttled(wait).await;
+                    until_cancelled(&self.cancel, async {
+                        tokio::time::sleep(wait).await;
+                        Ok(())
+                    })
+                    .await?;
+                }
+                Err(e) if e.is_unavailable() => {
+                    let Some(breaker) = &self.breaker else {
+                        return Err(e);
+                    };
+                    breaker.record_failure();
+                    failures += 1;
+                    if failures >= breaker.threshold() && !breaker.is_open() {
+                        return Err(e);
+                    }
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Store a batch of tiles from an upload task. A failure stops the run
+    /// unless `keep_failed` is set, in which case the batch's tiles are
+    /// handed back for the retry pass; a cancellation always stops it.
+    pub(crate) async fn send(
+        self,
+        tiles: Vec<Tile>,
+        keep_failed: bool,
+    ) -> Result<Vec<UploadOutcome>, ProcessError> {
+        let uploaded: Vec<UploadedTile> = tiles
+            .iter()
+            .map(|tile| UploadedTile {
+                zoom_level: tile.zoom_level,
+                x: tile.x,
+                y: tile.y,
+                bytes: tile.data.len() as u64,
+                checksum: tile.checksum(),
+                placeholder: tile.placeholder.clone(),
+            })
+            .collect();
+        let retry = keep_failed.then(|| tiles.clone());
+        match self.put(tiles).await {
+            Ok(()) => Ok(uploaded.into_iter().map(UploadOutcome::Uploaded).collect()),
+            Err(ProcessError::Cancelled) => Err(ProcessError::Cancelled),
+            Err(e) => match retry {
+                Some(tiles) => Ok(tiles.into_iter().map(UploadOutcome::Failed).collect()),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Send every tile that failed during a continue-on-error run once
+    /// more, one at a time, recording the ones that go through in
+    /// `manifest`.
+    pub(crate) async fn retry_failed(
+        &self,
+        tiles: Vec<Tile>,
+        manifest: &mut JobManifest,
+        reporter: &mut ProgressReporter,
+        control: &JobControl,
+    ) -> Result<RetryPass, ProcessError> {
+        let mut pass = RetryPass {
+            uploaded: Vec::new(),
+            failed: Vec::new(),
+        };
+        if !tiles.is_empty() {
+            tracing::info!(tiles = tiles.len(), "Retrying failed tiles");
+        }
+        for tile in tiles {
+            control.checkpoint(reporter).await?;
+
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            let (zoom_level, x, y) = (tile.zoom_level, tile.x, tile.y);
+            let bytes = tile.data.len() as u64;
+            let checksum = tile.checksum();
+            let placeholder = tile.placeholder.clone();
+            match self.put(vec![tile]).await {
+                Ok(()) => {
+                    manifest.record(zoom_level, x, y, &checksum, placeholder.as_deref())?;
+                    pass.uploaded.push(UploadedTile {
+                        zoom_level,
+                        x,
+                        y,
+                        bytes,
+                        checksum,
+                        placeholder,
+                    });
+                }
+                Err(ProcessError::Cancelled) => return Err(ProcessError::Cancelled),
+                Err(e) => pass.failed.push(FailedTile {
+                    zoom_level,
+                    x,
+                    y,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        Ok(pass)
+    }
+}
+
+/// Tiles the retry pass got through, and the ones it didn't.
+pub(crate) struct RetryPass {
+    pub(crate) uploaded: Vec<UploadedTile>,
+    pub(crate) failed: Vec<FailedTile>,
+}
+
+/// Encoding settings, copied onto the worker threads that produce tiles.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TileEncoder {
+    pub(crate) format: TileFormat,
+    jpeg_quality: u8,
+    webp_quality: u8,
+}
+
+impl TileEncoder {
+    pub(crate) fn new(config: &ProcessConfig) -> Self {
+        Self {
+            format: config.tile_format,
+            jpeg_quality: config.jpeg_quality,
+            webp_quality: config.webp_quality,
+        }
+    }
+
+    /// This encoder with its JPEG and WebP quality replaced by `quality`,
+    /// when given, for a level on the `quality_ramp`.
+    pub(crate) fn with_quality(mut self, quality: Option<u8>) -> Self {
+        if let Some(quality) = quality {
+            self.jpeg_quality = quality;
+            self.webp_quality = quality;
+        }
+        self
+    }
+
+    pub(crate) fn encode(&self, tile: RgbaImage) -> Result<Vec<u8>, ProcessError> {
+        let mut data = Vec::new();
+        match self.format {
+            TileFormat::Jpeg => {
+                let rgb_tile = image::DynamicImage::ImageRgba8(tile).to_rgb8();
+                JpegEncoder::new_with_quality(&mut data, self.jpeg_quality)
+                    .encode_image(&rgb_tile)
+                    .map_err(|e| ProcessError::image("Failed to encode JPEG", e))?;
+            }
+            TileFormat::Png => {
+                tile.write_with_encoder(PngEncoder::new(&mut data))
+                    .map_err(|e| ProcessError::image("Failed to encode PNG", e))?;
+            }
+            TileFormat::Webp => {
+                let (width, height) = tile.dimensions();
+                let encoded = webp::Encoder::from_rgba(tile.as_raw(), width, height)
+                    .encode(self.webp_quality as f32);
+                data.extend_from_slice(&encoded);
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Whether a tile is made up entirely of the background color.
+pub(crate) fn is_background_tile(
+    tile: &impl GenericImageView<Pixel = Rgba<u8>>,
+    background: Rgba<u8>,
+) -> bool {
+    tile.pixels().all(|(_, _, pixel)| pixel == background)
+}
+
+/// A tile cut from its zoom level and encoded, ready to upload.
+struct EncodedTile {
+    x: u32,
+    y: u32,
+    data: Vec<u8>,
+    // Content hash, when the run keeps tile hashes
+    hash: Option<String>,
+    // Blurhash, on `tile_placeholders` runs
+    placeholder: Option<String>,
+}
+
+/// A zoom level's resized image and the padded canvas of whole tiles it
+/// sits in, centred.
+pub(crate) struct LevelSize {
+    // Of the source image
+    pub(crate) scale_factor: f64,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) padded_width: u32,
+    pub(crate) padded_height: u32,
+    pub(crate) x_offset: u32,
+    pub(crate) y_offset: u32,
+}
+
+impl LevelSize {
+    /// Columns and rows of tiles covering the padded level, rounded up so
+    /// the last column and row are never dropped.
+    pub(crate) fn tiles(&self, tile_size: TileSize) -> (u32, u32) {
+        (
+            self.padded_width.div_ceil(tile_size.width),
+            self.padded_height.div_ceil(tile_size.height),
+        )
+    }
+}
+
+/// What the worker pool needs to produce the tiles of one zoom level.
+#[derive(Clone)]
+struct LevelTiles {
+    zoom_level: u32,
+    tile_size: TileSize,
+    overlap: u32,
+    // The padded level; `None` when the cache supplies every tile
+    image: Option<Arc<RgbaImage>>,
+    // Row of the level `image` starts at, below 0 for the bands of a
+    // low-memory run
+    image_y: u32,
+    background: Rgba<u8>,
+    skip_background: bool,
+    encoder: TileEncoder,
+    hash_tiles: bool,
+    placeholders: bool,
+    debug_overlay: bool,
+    // Tiles of the last upload on an incremental run
+    previous: Option<Arc<TileHashes>>,
+    cache: Option<ImageCache>,
+}
+
+impl LevelTiles {
+    /// Whether the tile had content on the last upload, in which case it is
+    /// sent even if it turned into background, to replace that content.
+    fn replaces(&self, x: u32, y: u32) -> bool {
+        self.previous
+            .as_ref()
+            .is_some_and(|previous| previous.contains(self.zoom_level, x, y))
+    }
+
+    fn is_cached(&self, x: u32, y: u32) -> bool {
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.is_background(self.zoom_level, x, y));
+        match cached {
+            Some(true) => !self.replaces(x, y),
+            Some(false) => true,
+            None => false,
+        }
+    }
+
+    /// Crop and encode one tile, or take it from the cache. Background
+    /// tiles are reported as `None` so they still count towards progress.
+    fn tile(&self, x: u32, y: u32) -> Result<Option<EncodedTile>, ProcessError> {
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(self.zoom_level, x, y));
+        let (data, placeholder) = match cached {
+            Some(Some(data)) => {
+                // The cache only has the encoded tile to go on
+                let placeholder = if self.placeholders {
+                    let tile = image::load_from_memory(&data)
+                        .map_err(|e| ProcessError::image("Failed to decode cached tile", e))?;
+                    Some(blurhash(&tile.to_rgba8()))
+                } else {
+                    None
+                };
+                (data, placeholder)
+            }
+            Some(None) if !self.replaces(x, y) => return Ok(None),
+            _ => {
+                let image = self.image.as_ref().ok_or_else(|| {
+                    ProcessError::Internal("The tile cache changed during the run".to_string())
+                })?;
+                let (left, top, width, height) = tile_bounds(x, y, self.tile_size, self.overlap);
+                let tile = image::imageops::crop_imm(
+                    image.as_ref(),
+                    left,
+                    top - self.image_y,
+                    width,
+                    height,
+                );
+                let is_background =
+                    self.skip_background && is_background_tile(&*tile, self.background);
+                if is_background {
+                    if let Some(cache) = &self.cache {
+                        cache.store(self.zoom_level, x, y, None);
+                    }
+                    if !self.replaces(x, y) {
+                        return Ok(None);
+                    }
+                }
+                let mut tile = tile.to_image();
+                if self.debug_overlay {
+                    let inset = (x - left, y - top);
+                    draw_overlay(&mut tile, self.zoom_level, x, y, self.tile_size, inset);
+                }
+                let placeholder = self.placeholders.then(|| blurhash(&tile));
+                let data = self.encoder.encode(tile)?;
+                if !is_background {
+                    if let Some(cache) = &self.cache {
+                        cache.store(self.zoom_level, x, y, Some(&data));
+                    }
+                }
+                (data, placeholder)
+            }
+        };
+        let hash = self.hash_tiles.then(|| tile_hash(&data));
+        Ok(Some(EncodedTile {
+            x,
+            y,
+            data,
+            hash,
+            placeholder,
+        }))
+    }
+}
+
+/// The part of a level the tile at `x`, `y` is cut from, as left, top,
+/// width and height: the tile and `overlap` pixels of its neighbours on
+/// every side that has one. Cropping stops at the level's edges.
+pub(crate) fn tile_bounds(
+    x: u32,
+    y: u32,
+    tile_size: TileSize,
+    overlap: u32,
+) -> (u32, u32, u32, u32) {
+    let left = x.saturating_sub(overlap);
+    let top = y.saturating_sub(overlap);
+    (
+        left,
+        top,
+        x - left + tile_size.width + overlap,
+        y - top + tile_size.height + overlap,
+    )
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Cuts images into tile pyramids. One processor can serve several runs
+/// that share the same tiling settings, such as the images of a batch.
+pub struct TileProcessor {
+    pub(crate) tile_size: TileSize,
+    pub(crate) overlap: u32,
+    pub(crate) padding: PaddingMode,
+    pub(crate) anchor: PaddingAnchor,
+    pub(crate) upscale: UpscaleMode,
+    pub(crate) resize_filter: ResizeFilter,
+    pub(crate) linear_resize: bool,
+    // Unsharp mask for downscaled levels, off at an amount of 0
+    pub(crate) sharpen_amount: f32,
+    pub(crate) sharpen_radius: f32,
+    // Deepest level generated, from `max_zoom_override`
+    pub(crate) max_zoom: Option<u32>,
+    // Shallowest level generated, from `min_zoom`
+    pub(crate) min_zoom: u32,
+    // Part of the image whose tiles a region update redoes
+    pub(crate) update_region: Option<CropRect>,
+    pub(crate) encoder: TileEncoder,
+    // Cropping and encoding run here so every core is busy while the async
+    // side handles uploads
+    pub(crate) encode_pool: Arc<rayon::ThreadPool>,
+    // Shared by every request in the run so connections and TLS sessions
+    // are pooled instead of being renegotiated per tile
+    pub(crate) client: reqwest::Client,
+    // Where the tile hashes of server uploads are kept for incremental runs
+    pub(crate) tile_hashes: Option<TileHashStore>,
+    // Encoded tiles kept between runs, for configs with `tile_cache`
+    tile_cache: Option<TileCache>,
+}
+
+impl TileProcessor {
+    pub fn new(config: &ProcessConfig) -> Result<Self, ProcessError> {
+        config.auth.validate()?;
+        if let Some(template) = &config.tile_url_template {
+            validate_tile_url_template(template)?;
+        }
+        if let Some(template) = &config.published_tile_url_template {
+            validate_tile_url_template(template)?;
+        }
+        if !(1..=100).contains(&config.jpeg_quality) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "JPEG quality must be between 1 and 100, got {}",
+                config.jpeg_quality
+            )));
+        }
+        if !(1..=100).contains(&config.webp_quality) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "WebP quality must be between 1 and 100, got {}",
+                config.webp_quality
+            )));
+        }
+        if let Some(step) = config
+            .quality_ramp
+            .iter()
+            .find(|step| !(1..=100).contains(&step.quality))
+        {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Quality ramp qualities must be between 1 and 100, got {}",
+                step.quality
+            )));
+        }
+        let mut ramp_levels: Vec<u32> = config
+            .quality_ramp
+            .iter()
+            .map(|step| step.levels_below_max)
+            .collect();
+        ramp_levels.sort_unstable();
+        if ramp_levels.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(ProcessError::InvalidConfig(
+                "Quality ramp steps must start at different levels".to_string(),
+            ));
+        }
+        if config.thumbnail_size == 0 {
+            return Err(ProcessError::InvalidConfig(
+                "The thumbnail size must be at least 1 pixel".to_string(),
+            ));
+        }
+        if let Some(budget) = config.size_budget_mb {
+            if budget == 0 {
+                return Err(ProcessError::InvalidConfig(
+                    "The size budget must be at least 1 MB".to_string(),
+                ));
+            }
+            if config.tile_format == TileFormat::Png {
+                return Err(ProcessError::InvalidConfig(
+                    "A size budget works by lowering the quality, so it needs JPEG or WebP tiles"
+                        .to_string(),
+                ));
+            }
+            if !config.quality_ramp.is_empty() {
+                return Err(ProcessError::InvalidConfig(
+                    "A size budget picks one quality for every level and can't be combined with a quality ramp"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(region) = config.update_region {
+            if region.width == 0 || region.height == 0 {
+                return Err(ProcessError::InvalidConfig(
+                    "The update region must be at least 1 pixel each way".to_string(),
+                ));
+            }
+            if config.size_budget_mb.is_some() {
+                return Err(ProcessError::InvalidConfig(
+                    "A size budget picks its quality from the whole layout, so it can't be combined with a region update"
+                        .to_string(),
+                ));
+            }
+        }
+        if config
+            .render_dpi
+            .is_some_and(|dpi| !(dpi > 0.0 && dpi.is_finite()))
+            || config.render_size == Some(0)
+        {
+            return Err(ProcessError::InvalidConfig(
+                "render_dpi and render_size must be positive".to_string(),
+            ));
+        }
+        if !config.rotate.is_finite() {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Rotation must be a number of degrees, got {}",
+                config.rotate
+            )));
+        }
+        if !(0.0..=10.0).contains(&config.sharpen_amount) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Sharpen amount must be between 0 and 10, got {}",
+                config.sharpen_amount
+            )));
+        }
+        if !(config.sharpen_radius > 0.0 && config.sharpen_radius <= 50.0) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Sharpen radius must be above 0 and at most 50 pixels, got {}",
+                config.sharpen_radius
+            )));
+        }
+        if !(-1.0..=1.0).contains(&config.brightness) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Brightness must be between -1 and 1, got {}",
+                config.brightness
+            )));
+        }
+        if !(0.0..=10.0).contains(&config.contrast) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Contrast must be between 0 and 10, got {}",
+                config.contrast
+            )));
+        }
+        if !(0.1..=10.0).contains(&config.gamma) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Gamma must be between 0.1 and 10, got {}",
+                config.gamma
+            )));
+        }
+        if config.color_key.is_some() && !config.tile_format.supports_alpha() {
+            return Err(ProcessError::InvalidConfig(
+                "A color key needs PNG or WebP tiles, JPEG has no transparency".to_string(),
+            ));
+        }
+        let tile_size = config.tile_size;
+        if tile_size.width == 0 || tile_size.height == 0 {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Tiles must be at least 1 pixel wide and high, got {}",
+                tile_size
+            )));
+        }
+        if config.overlap > 0 && config.overlap * 2 >= tile_size.width.min(tile_size.height) {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Overlap must be less than half the tile size, got {} for {} tiles",
+                config.overlap, tile_size
+            )));
+        }
+        // Both formats describe a pyramid by a single tile size
+        if !tile_size.is_square()
+            && matches!(
+                config.output,
+                OutputTarget::Dzi { .. } | OutputTarget::Mbtiles { .. }
+            )
+        {
+            return Err(ProcessError::InvalidConfig(format!(
+                "Deep Zoom and MBTiles exports need square tiles, got {}",
+                tile_size
+            )));
+        }
+        if config.overlap > 0 && matches!(config.output, OutputTarget::Mbtiles { .. }) {
+            return Err(ProcessError::InvalidConfig(
+                "MBTiles tiles can't overlap, set overlap to 0".to_string(),
+            ));
+        }
+        if config.pdf_page == 0 {
+            return Err(ProcessError::InvalidConfig(
+                "PDF pages are counted from 1".to_string(),
+            ));
+        }
+        if config.gif_frame == 0 {
+            return Err(ProcessError::InvalidConfig(
+                "GIF frames are counted from 1".to_string(),
+            ));
+        }
+        if let Some(layer) = &config.layer {
+            check_layer_name(layer)?;
+            if config.output != OutputTarget::Server {
+                return Err(ProcessError::InvalidConfig(
+                    "Layers are finalized on the server, so they need server output".to_string(),
+                ));
+            }
+        }
+        if let Some(stitch) = &config.stitch {
+            if stitch.images.is_empty() {
+                return Err(ProcessError::InvalidConfig(
+                    "A stitch needs at least one image besides image_path".to_string(),
+                ));
+            }
+            if stitch.placement == (StitchPlacement::Grid { columns: 0 }) {
+                return Err(ProcessError::InvalidConfig(
+                    "A stitch grid needs at least one column".to_string(),
+                ));
+            }
+        }
+
+        // Zero lets rayon pick one thread per core
+        let encode_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.encode_threads)
+            .thread_name(|i| format!("tile-encoder-{}", i))
+            .build()
+            .map_err(|e| ProcessError::Internal(format!("Failed to create encoder pool: {}", e)))?;
+
+        let keep_alive = Duration::from_secs(config.keep_alive_secs);
+        let client = config
+            .network
+            .client_builder()?
+            .pool_max_idle_per_host(config.pool_size)
+            .pool_idle_timeout(keep_alive)
+            .tcp_keepalive(keep_alive)
+            .build()
+            .map_err(|e| ProcessError::http("Failed to create HTTP client", e))?;
+
+        Ok(Self {
+            tile_size: config.tile_size,
+            overlap: config.overlap,
+            padding: config.effective_padding(),
+            anchor: config.anchor,
+            upscale: config.upscale,
+            resize_filter: config.resize_filter,
+            linear_resize: config.linear_resize,
+            sharpen_amount: config.sharpen_amount,
+            sharpen_radius: config.sharpen_radius,
+            max_zoom: config.max_zoom_override,
+            min_zoom: config.min_zoom,
+            update_region: config.update_region,
+            encoder: TileEncoder::new(config),
+            encode_pool: Arc::new(encode_pool),
+            client,
+            tile_hashes: None,
+            tile_cache: None,
+        })
+    }
+
+    /// Take encoded tiles from `cache` and add new ones to it on runs with
+    /// `config.tile_cache`.
+    pub fn with_tile_cache(mut self, cache: TileCache) -> Self {
+        self.tile_cache = Some(cache);
+        self
+    }
+
+    /// Keep the content hashes of every completed server upload in `store`,
+    /// which lets runs with `config.incremental` send only changed tiles.
+    pub fn with_tile_hashes(mut self, store: TileHashStore) -> Self {
+        self.tile_hashes = Some(store);
+        self
+    }
+
+    /// Scale at which `width` x `height` fits `2^zoom_level` tiles each way.
+    fn calc_zoom(&self, zoom_level: u32, width: u32, height: u32) -> f64 {
+        let tiles = 2_u32.pow(zoom_level) as f64;
+        let scale_x = tiles * self.tile_size.width as f64 / width as f64;
+        let scale_y = tiles * self.tile_size.height as f64 / height as f64;
+        scale_x.min(scale_y)
+    }
+
+    /// How big `zoom_level` of a `width` x `height` image is, resized and
+    /// padded out to whole tiles. The image sits where the deepest level
+    /// places it, scaled down to this level, so the levels stay aligned
+    /// with each other: anchoring each level on its own canvas would round
+    /// its offset separately, and the padding of whole tiles differs from
+    /// level to level.
+    pub(crate) fn level_size(&self, zoom_level: u32, width: u32, height: u32) -> LevelSize {
+        let mut size = self.level_canvas(zoom_level, width, height);
+        let deepest = self.get_max_zoom_levels(width, height) - 1;
+        if zoom_level < deepest {
+            let deep = self.level_canvas(deepest, width, height);
+            let ratio = size.scale_factor / deep.scale_factor;
+            let scaled =
+                |offset: u32, extra: u32| ((offset as f64 * ratio).round() as u32).min(extra);
+            size.x_offset = scaled(deep.x_offset, size.padded_width - size.width);
+            size.y_offset = scaled(deep.y_offset, size.padded_height - size.height);
+        }
+        size
+    }
+
+    /// `zoom_level` laid out on its own, with the image anchored on its
+    /// padded canvas.
+    fn level_canvas(&self, zoom_level: u32, width: u32, height: u32) -> LevelSize {
+        let scale_factor = match self.upscale {
+            UpscaleMode::None => self.calc_zoom(zoom_level, width, height).min(1.0),
+            _ => self.calc_zoom(zoom_level, width, height),
+        };
+
+        // Size of the resized image, never under a pixel: a thin image would
+        // otherwise shrink to nothing on the low levels and leave them blank
+        let new_width = ((width as f64 * scale_factor) as u32).max(1);
+        let new_height = ((height as f64 * scale_factor) as u32).max(1);
+
+        // Calculate padding: up to whole tiles on each side, to the square
+        // of every tile at this level, or none at all. Never smaller than
+        // the image, so no content is cut off.
+        let whole_tiles = |side: u32, tile: u32| side.div_ceil(tile).max(1) * tile;
+        let (tile_width, tile_height) = (self.tile_size.width, self.tile_size.height);
+        let (total_width, total_height) = match self.padding {
+            PaddingMode::Tiles => (
+                whole_tiles(new_width, tile_width),
+                whole_tiles(new_height, tile_height),
+            ),
+            PaddingMode::Square => {
+                let tiles = 2_u32.pow(zoom_level);
+                (
+                    (tiles * tile_width).max(whole_tiles(new_width, tile_width)),
+                    (tiles * tile_height).max(whole_tiles(new_height, tile_height)),
+                )
+            }
+            PaddingMode::None => (new_width, new_height),
+        };
+
+        let extra_width = total_width.saturating_sub(new_width);
+        let extra_height = total_height.saturating_sub(new_height);
+
+        let (anchor_x, anchor_y) = self.anchor.fractions();
+        LevelSize {
+            scale_factor,
+            width: new_width,
+            height: new_height,
+            padded_width: new_width + extra_width,
+            padded_height: new_height + extra_height,
+            x_offset: (extra_width as f64 * anchor_x).round() as u32,
+            y_offset: (extra_height as f64 * anchor_y).round() as u32,
+        }
+    }
+
+    /// The color of the padding around the image.
+    pub(crate) fn background(&self, config: &ProcessConfig) -> Rgba<u8> {
+        // Formats with an alpha channel keep transparency, so the padding is
+        // left clear unless the color gives an alpha
+        let color = config.background_color;
+        let background_alpha = if self.encoder.format.supports_alpha() {
+            color.a.unwrap_or(0)
+        } else {
+            255
+        };
+        Rgba([color.r, color.g, color.b, background_alpha])
+    }
+
+    /// Resize `source` to `size` and centre it on the padded level. Returns
+    /// the padded level and the resized image, the source for the next
+    /// level down.
+    pub(crate) fn render_level(
+        &self,
+        source: &RgbaImage,
+        size: &LevelSize,
+        background: Rgba<u8>,
+    ) -> (RgbaImage, RgbaImage) {
+        let scaled_img = self.resize_level(source, size);
+        let sharpened = self.sharpened(&scaled_img, size);
+
+        let mut padded_img: RgbaImage =
+            ImageBuffer::from_pixel(size.padded_width, size.padded_height, background);
+        self.paste(
+            &mut padded_img,
+            sharpened.as_ref().unwrap_or(&scaled_img),
+            size.x_offset,
+            size.y_offset,
+        );
+        (padded_img, scaled_img)
+    }
+
+    /// `source` resized to `size`'s image, with its filter and, with
+    /// `linear_resize`, in linear light.
+    pub(crate) fn resize_level(&self, source: &RgbaImage, size: &LevelSize) -> RgbaImage {
+        let filter = self.level_filter(size);
+        if self.resizes_linear(size) {
+            resize_linear(source, size.width, size.height, filter)
+        } else {
+            image::imageops::resize(source, size.width, size.height, filter)
+        }
+    }
+
+    /// `image`, resized for `size`, with the unsharp mask applied, or `None`
+    /// when sharpening is off or the level isn't downscaled. Only the tiles
+    /// get the sharpened pixels; the next level is resized from the plain
+    /// ones, so the sharpening doesn't build up from level to level.
+    pub(crate) fn sharpened(&self, image: &RgbaImage, size: &LevelSize) -> Option<RgbaImage> {
+        if self.sharpen_amount <= 0.0 || size.scale_factor >= 1.0 || image.is_empty() {
+            return None;
+        }
+        let blurred = image::imageops::blur(image, self.sharpen_radius);
+        let mut sharpened = image.clone();
+        sharpened
+            .par_chunks_mut(4)
+            .zip(blurred.par_chunks(4))
+            .for_each(|(pixel, blurred)| {
+                // Alpha is left as it is, so edges don't grow halos of it
+                for (channel, &blurred) in pixel[..3].iter_mut().zip(blurred) {
+                    let value = *channel as f32;
+                    let sharp = value + (value - blurred as f32) * self.sharpen_amount;
+                    *channel = sharp.round().clamp(0.0, 255.0) as u8;
+                }
+            });
+        Some(sharpened)
+    }
+
+    /// Rows above and below a band that sharpening it looks at: at least
+    /// half the blur kernel `imageops::blur` picks for the radius.
+    pub(crate) fn sharpen_margin(&self, size: &LevelSize) -> u32 {
+        if self.sharpen_amount <= 0.0 || size.scale_factor >= 1.0 {
+            return 0;
+        }
+        (self.sharpen_radius / 0.3).ceil() as u32 + 2
+    }
+
+    /// The filter `size` is resized with.
+    pub(crate) fn level_filter(&self, size: &LevelSize) -> FilterType {
+        if size.scale_factor > 1.0 && self.upscale == UpscaleMode::Nearest {
+            FilterType::Nearest
+        } else {
+            self.resize_filter.into()
+        }
+    }
+
+    /// Whether `size` is resized in linear light: with `linear_resize`,
+    /// unless its filter only copies pixels.
+    pub(crate) fn resizes_linear(&self, size: &LevelSize) -> bool {
+        self.linear_resize && self.level_filter(size) != FilterType::Nearest
+    }
+
+    /// Put `image` on the padded `canvas` at `x`, `y`.
+    pub(crate) fn paste(&self, canvas: &mut RgbaImage, image: &RgbaImage, x: u32, y: u32) {
+        if self.encoder.format.supports_alpha() {
+            // Copy pixels as-is so the source alpha survives
+            image::imageops::replace(canvas, image, x as i64, y as i64);
+        } else {
+            // Flatten any transparency onto the background color
+            image::imageops::overlay(canvas, image, x as i64, y as i64);
+        }
+    }
+
+    /// Levels down to the one covering the image at full resolution, or to
+    /// `max_zoom_override` if that stops sooner.
+    pub(crate) fn get_max_zoom_levels(&self, width: u32, height: u32) -> u32 {
+        let tiles_x = (width as f64 / self.tile_size.width as f64).ceil();
+        let tiles_y = (height as f64 / self.tile_size.height as f64).ceil();
+        let tiles = tiles_x.max(tiles_y);
+        let zoom_levels = (tiles.log2().ceil() as u32) + 1;
+        match self.max_zoom {
+            Some(max_zoom) => zoom_levels.min(max_zoom + 1),
+            None => zoom_levels,
+        }
+    }
+
+    /// Whether a `width` x `height` image fits inside a single tile, making
+    /// its pyramid one zoom level of one tile.
+    pub(crate) fn fits_one_tile(&self, width: u32, height: u32) -> bool {
+        width <= self.tile_size.width && height <= self.tile_size.height
+    }
+
+    /// The zoom levels a run generates: from `min_zoom`, or the deepest
+    /// level if the image doesn't go that deep, down to the deepest.
+    pub(crate) fn zoom_range(&self, width: u32, height: u32) -> Range<u32> {
+        let zoom_levels = self.get_max_zoom_levels(width, height);
+        self.min_zoom.min(zoom_levels - 1)..zoom_levels
+    }
+
+    /// Tiles of `zoom_level`, the same grid the run walks.
+    pub(crate) fn level_tiles(&self, zoom_level: u32, width: u32, height: u32) -> u32 {
+        if self.update_region.is_some() {
+            return self.level_grid(zoom_level, width, height).len() as u32;
+        }
+        let (tiles_x, tiles_y) = self
+            .level_size(zoom_level, width, height)
+            .tiles(self.tile_size);
+        tiles_x * tiles_y
+    }
+
+    /// Pixel offsets of the tiles of `zoom_level` a run walks: every tile,
+    /// or on a region update those showing the region.
+    pub(crate) fn level_grid(&self, zoom_level: u32, width: u32, height: u32) -> Vec<(u32, u32)> {
+        let size = self.level_size(zoom_level, width, height);
+        let (tiles_x, tiles_y) = size.tiles(self.tile_size);
+        let tile_size = self.tile_size;
+        (0..tiles_x)
+            .flat_map(|tile_x| (0..tiles_y).map(move |tile_y| (tile_x, tile_y)))
+            .map(|(tile_x, tile_y)| (tile_x * tile_size.width, tile_y * tile_size.height))
+            .filter(|&(x, y)| match self.update_region {
+                Some(region) => self.shows_region(&size, region, x, y),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Whether the tile at `x`, `y` of a level laid out as `size` shows any
+    /// of `region` of the image, or pixels that change with it.
+    fn shows_region(&self, size: &LevelSize, region: CropRect, x: u32, y: u32) -> bool {
+        // The resize filters reach a few pixels past a changed one, of the
+        // larger image when enlarging, and sharpening about three radii
+        let scale = size.scale_factor;
+        let mut margin = 4.0 + 4.0 * scale.max(1.0);
+        if self.sharpen_amount > 0.0 {
+            margin += 3.0 * self.sharpen_radius as f64;
+        }
+        let left = region.x as f64 * scale + size.x_offset as f64 - margin;
+        let top = region.y as f64 * scale + size.y_offset as f64 - margin;
+        let right = (region.x + region.width) as f64 * scale + size.x_offset as f64 + margin;
+        let bottom = (region.y + region.height) as f64 * scale + size.y_offset as f64 + margin;
+
+        let tile_left = x.saturating_sub(self.overlap) as f64;
+        let tile_top = y.saturating_sub(self.overlap) as f64;
+        let tile_right = (x + self.tile_size.width + self.overlap) as f64;
+        let tile_bottom = (y + self.tile_size.height + self.overlap) as f64;
+        tile_left < right && left < tile_right && tile_top < bottom && top < tile_bottom
+    }
+
+    /// Tiles a run generates, every level counted from its actual grid so
+    /// progress adds up however the levels are padded.
+    pub fn count_tiles(&self, width: u32, height: u32) -> u32 {
+        self.zoom_range(width, height)
+            .map(|zoom_level| self.level_tiles(zoom_level, width, height))
+            .sum()
+    }
+
+    /// The sink `config` asks for: its output target, or a stand-in on a
+    /// dry run.
+    pub(crate) fn sink_for(
+        &self,
+        config: &ProcessConfig,
+    ) -> Result<Arc<dyn TileSink>, ProcessError> {
+        let sink: Arc<dyn TileSink> = if config.dry_run {
+            Arc::new(DryRunSink::new(
+                config.dry_run_output.as_ref().map(PathBuf::from),
+            ))
+        } else {
+            match &config.output {
+                OutputTarget::Server if !config.mirrors.is_empty() => {
+                    Arc::new(MirrorSink::new(self.client.clone(), config))
+                }
+                OutputTarget::Server => Arc::new(HttpSink::new(self.client.clone(), config)),
+                OutputTarget::Directory { path } => {
+                    Arc::new(LocalDirectorySink::new(PathBuf::from(path), config))
+                }
+                OutputTarget::Dzi { path } => Arc::new(DziSink::new(PathBuf::from(path), config)),
+                OutputTarget::Mbtiles { path } => {
+                    Arc::new(MbtilesSink::open(Path::new(path), config)?)
+                }
+                OutputTarget::Package { path } => {
+                    Arc::new(PackageSink::new(PathBuf::from(path), config))
+                }
+                OutputTarget::Sftp(settings) => Arc::new(SftpSink::new(settings, config)?),
+                OutputTarget::S3(settings) => {
+                    Arc::new(S3Sink::new(self.client.clone(), settings, config)?)
+                }
+            }
+        };
+        Ok(sink)
+    }
+
+    /// Tile `config.image_path` into `config.output`, or only go through
+    /// the motions when `config.dry_run` is set.
+    pub async fn process_tiles(
+        &self,
+        config: &ProcessConfig,
+        manifest: &mut JobManifest,
+        reporter: &mut ProgressReporter,
+        control: JobControl,
+    ) -> Result<RunSummary, ProcessError> {
+        let sink = self.sink_for(config)?;
+        self.process_tiles_to(config, sink, manifest, reporter, control)
+            .await
+    }
+
+    /// Tile `config.image_path` into `sink`. Tiles already in `manifest` are
+    /// skipped, and every stored tile is recorded there.
+    pub async fn process_tiles_to(
+        &self,
+        config: &ProcessConfig,
+        sink: Arc<dyn TileSink>,
+        manifest: &mut JobManifest,
+        reporter: &mut ProgressReporter,
+        control: JobControl,
+    ) -> Result<RunSummary, ProcessError> {
+        let result = self
+            .tile_into(config, sink, manifest, reporter, &control)
+            .await;
+        save_summary(&result, config);
+        report_cancelled(result, reporter).await
+    }
+
+    async fn tile_into(
+        &self,
+        config: &ProcessConfig,
+        sink: Arc<dyn TileSink>,
+        manifest: &mut JobManifest,
+        reporter: &mut ProgressReporter,
+        control: &JobControl,
+    ) -> Result<RunSummary, ProcessError> {
+        let mut summary = SummaryBuilder::new();
+
+        // Load the image, into a temp file on low-memory runs
+        let low_memory = self.check_memory(config, &mut summary)?;
+        let (mut source, background_color) = LevelSource::open(config, low_memory)?;
+        let config = &ProcessConfig {
+            background_color,
+            ..config.clone()
+        };
+        summary.phases().load_secs = summary.lap();
+        let (img_width, img_height) = source.dimensions();
+        let zoom_range = self.zoom_range(img_width, img_height);
+        let deepest_zoom = zoom_range.end - 1;
+        if let Some(region) = config.update_region {
+            let inside = region
+                .x
+                .checked_add(region.width)
+                .is_some_and(|right| right <= img_width)
+                && region
+                    .y
+                    .checked_add(region.height)
+                    .is_some_and(|bottom| bottom <= img_height);
+            if !inside {
+                return Err(ProcessError::InvalidConfig(format!(
+                    "The update region {}x{} at {},{} doesn't fit in the {}x{} image",
+                    region.width, region.height, region.x, region.y, img_width, img_height
+                )));
+            }
+        }
+        let total_tiles = self.count_tiles(img_width, img_height);
+        if self.fits_one_tile(img_width, img_height) {
+            summary.warn(format!(
+                "The image is only {}x{}, smaller than one {}x{} tile; uploaded as a single tile",
+                img_width, img_height, self.tile_size.width, self.tile_size.height
+            ));
+        }
+
+        // A size budget sets the quality from tiles sampled off the source.
+        // The config carries it from here on, so cached tiles are keyed by
+        // the quality they were encoded at.
+        let budget_quality = match config.size_budget_mb {
+            Some(_) => {
+                let samples = source.sample_tiles(self.tile_size)?;
+                self.budget_quality(config, &samples, total_tiles)?
+            }
+            None => None,
+        };
+        summary.set_budget_quality(budget_quality);
+        let config = &ProcessConfig {
+            jpeg_quality: budget_quality.unwrap_or(config.jpeg_quality),
+            webp_quality: budget_quality.unwrap_or(config.webp_quality),
+            ..config.clone()
+        };
+        let encoder = self.encoder.with_quality(budget_quality);
+
+        let layout_path = manifest.layout_path.clone();
+        let mut current_tile = 0;
+        let min_zoom = zoom_range.start;
+        let mut max_zoom = 0;
+        let mut tiles_skipped = 0;
+        let mut bytes_uploaded = 0;
+
+        // Hashes are kept for server uploads, and an incremental run or a
+        // region update compares against those of the last upload to the same
+        // layout path
+        let tile_hashes = self
+            .tile_hashes
+            .as_ref()
+            .filter(|_| !config.dry_run && config.output == OutputTarget::Server);
+        let previous = match tile_hashes {
+            Some(store) if config.incremental || config.update_region.is_some() => store
+                .load(config)?
+                .filter(|previous| previous.layout_path == layout_path)
+                .map(Arc::new),
+            _ => None,
+        };
+        // The tiles left alone only fit a layout with the same levels
+        let resized = previous
+            .as_ref()
+            .filter(|previous| config.update_region.is_some() && previous.max_zoom != deepest_zoom);
+        if let Some(previous) = resized {
+            return Err(ProcessError::InvalidConfig(format!(
+                "The image now has zoom levels down to {} but the layout down to {}; upload it in full",
+                deepest_zoom, previous.max_zoom
+            )));
+        }
+        let mut hashes = tile_hashes.map(|_| match &previous {
+            Some(previous) => previous.as_ref().clone(),
+            None => TileHashes::new(layout_path.clone()),
+        });
+
+        // Uploads run on their own tasks so the next tiles can be generated
+        // while earlier ones are still in flight. Dropping the set on an early
+        // return aborts whatever is still uploading.
+        let concurrency = config.concurrency.max(1);
+        let mut uploads: JoinSet<Result<Vec<UploadOutcome>, ProcessError>> = JoinSet::new();
+        let uploader = Uploader::new(sink.clone(), layout_path.clone(), config, control, reporter);
+        let batch_size = config.upload_batch_size();
+        let mut batch = Vec::new();
+        // Tiles that failed on a continue-on-error run
+        let mut failed = Vec::new();
+
+        let background = self.background(config);
+
+        let image_cache = match &self.tile_cache {
+            Some(cache) if config.tile_cache => Some(cache.for_image(config)?),
+            _ => None,
+        };
+
+        // Levels are generated largest first, and each one is downscaled from
+        // the level before it rather than from the full-resolution source.
+        // Low-zoom-first runs go the other way and resize every level from
+        // the source, trading resize work for an early overview.
+        let levels: Vec<u32> = if config.low_zoom_first {
+            zoom_range.collect()
+        } else {
+            zoom_range.rev().collect()
+        };
+
+        // Process each zoom level
+        for zoom_level in levels {
+            control.checkpoint(reporter).await?;
+
+            max_zoom = max_zoom.max(zoom_level);
+            let size = self.level_size(zoom_level, img_width, img_height);
+
+            // Generate tiles
+            let (_, tiles_y) = size.tiles(self.tile_size);
+
+            // Tiles already uploaded by an earlier, interrupted run are skipped
+            let tile_size = self.tile_size;
+            let (done, pending): (Vec<_>, Vec<_>) = self
+                .level_grid(zoom_level, img_width, img_height)
+                .into_iter()
+                .partition(|&(x, y)| manifest.contains(zoom_level, x, y));
+            current_tile += done.len() as u32;
+            // What the interrupted run stored there isn't known any more
+            if let Some(hashes) = hashes.as_mut() {
+                for (x, y) in done {
+                    hashes.forget(zoom_level, x, y);
+                }
+            }
+
+            let level = LevelTiles {
+                zoom_level,
+                tile_size,
+                overlap: self.overlap,
+                image: None,
+                image_y: 0,
+                background,
+                skip_background: config.skip_background_tiles,
+                encoder: encoder.with_quality(config.ramp_quality(zoom_level, deepest_zoom)),
+                hash_tiles: hashes.is_some(),
+                placeholders: config.tile_placeholders,
+                debug_overlay: config.debug_overlay,
+                previous: previous.clone(),
+                cache: image_cache.clone(),
+            };
+
+            // The level is only resized when the cache can't supply every
+            // tile still to be sent
+            let fully_cached =
+                level.cache.is_some() && pending.iter().all(|&(x, y)| level.is_cached(x, y));
+            if fully_cached {
+                source.skip_level();
+            }
+
+            // Low-memory runs render the level a row of tiles at a time
+            let bands: Vec<(u32, u32)> = if source.is_banded() && !fully_cached {
+                (0..tiles_y)
+                    .map(|row| row * tile_size.height)
+                    .map(|y| (y, tile_size.height.min(size.padded_height - y)))
+                    .collect()
+            } else {
+                vec![(0, size.padded_height)]
+            };
+            let mut pending = pending;
+            for (band_y, band_height) in bands {
+                let band_pending;
+                (band_pending, pending) = pending
+                    .into_iter()
+                    .partition(|&(_, y)| y < band_y + band_height);
+
+                let mut band = level.clone();
+                if !fully_cached {
+                    control.checkpoint(reporter).await?;
+                    let resize_started = Instant::now();
+                    // Overlapping tiles reach into the bands around theirs
+                    let render_y = band_y.saturating_sub(self.overlap);
+                    let render_end = (band_y + band_height + self.overlap).min(size.padded_height);
+                    // A smaller level is no source for the larger ones after it
+                    let image = source.render(
+                        self,
+                        &size,
+                        render_y,
+                        render_end - render_y,
+                        background,
+                        !config.low_zoom_first,
+                    )?;
+                    band.image = Some(Arc::new(image));
+                    band.image_y = render_y;
+                    summary.phases().resize_secs += resize_started.elapsed().as_secs_f64();
+                }
+
+                // Crop and encode on the worker pool. The channel is bounded so
+                // encoding can't run too far ahead of the uploads, and once the
+                // receiver is dropped the workers stop at their next send.
+                let (tile_tx, mut tile_rx) = mpsc::channel(concurrency * 2);
+                self.encode_pool.spawn(move || {
+                    let _ = band_pending
+                        .into_par_iter()
+                        .try_for_each_with(tile_tx, |tile_tx, (x, y)| {
+                            tile_tx.blocking_send(band.tile(x, y))
+                        });
+                });
+
+                while let Some(encoded) = tile_rx.recv().await {
+                    // A paused job holds here; the encoder blocks once the
+                    // channel fills up
+                    control.checkpoint(reporter).await?;
+
+                    let Some(EncodedTile {
+                        x,
+                        y,
+                        data,
+                        hash,
+                        placeholder,
+                    }) = encoded?
+                    else {
+                        current_tile += 1;
+                        tiles_skipped += 1;
+                        summary.skipped(zoom_level);
+                        reporter
+                            .update(
+                                current_tile,
+                                total_tiles,
+                                zoom_level,
+                                tiles_skipped,
+                                bytes_uploaded,
+                            )
+                            .await;
+                        continue;
+                    };
+
+                    if let (Some(hashes), Some(hash)) = (hashes.as_mut(), hash) {
+                        let unchanged = previous
+                            .as_ref()
+                            .is_some_and(|previous| previous.matches(zoom_level, x, y, &hash));
+                        // The server already has this exact tile
+                        if unchanged {
+                            manifest.record(zoom_level, x, y, &hash, placeholder.as_deref())?;
+                            hashes.insert(zoom_level, x, y, hash);
+                            current_tile += 1;
+                            summary.unchanged(zoom_level);
+                            reporter
+                                .update(
+                                    current_tile,
+                                    total_tiles,
+                                    zoom_level,
+                                    tiles_skipped,
+                                    bytes_uploaded,
+                                )
+                                .await;
+                            continue;
+                        }
+                        hashes.insert(zoom_level, x, y, hash);
+                    }
+
+                    batch.push(Tile {
+                        zoom_level,
+                        x,
+                        y,
+                        format: self.encoder.format,
+                        data,
+                        placeholder,
+                    });
+                    if batch.len() < batch_size {
+                        continue;
+                    }
+
+                    // Wait for a free slot before queueing another upload
+                    if uploads.len() >= concurrency {
+                        if let Some(result) = uploads.join_next().await {
+                            for outcome in upload_result(result)? {
+                                match outcome {
+                                    UploadOutcome::Uploaded(done) => {
+                                        manifest.record(
+                                            done.zoom_level,
+                                            done.x,
+                                            done.y,
+                                            &done.checksum,
+                                            done.placeholder.as_deref(),
+                                        )?;
+                                        summary.uploaded(
+                                            done.zoom_level,
+                                            done.bytes,
+                                            &done.checksum,
+                                        );
+                                        bytes_uploaded += done.bytes;
+                                    }
+                                    UploadOutcome::Failed(tile) => failed.push(tile),
+                                }
+                                current_tile += 1;
+                            }
+                            reporter
+                                .update(
+                                    current_tile,
+                                    total_tiles,
+                                    zoom_level,
+                                    tiles_skipped,
+                                    bytes_uploaded,
+                                )
+                                .await;
+                        }
+                    }
+
+                    let tiles = std::mem::take(&mut batch);
+                    uploads.spawn(uploader.clone().send(tiles, config.continue_on_error));
+                }
+            }
+            source.finish_level();
+        }
+        // The last, partly filled batch
+        if !batch.is_empty() {
+            uploads.spawn(uploader.clone().send(batch, config.continue_on_error));
+        }
+
+        // Drain the remaining in-flight uploads
+        while let Some(result) = uploads.join_next().await {
+            let mut zoom_level = 0;
+            for outcome in upload_result(result)? {
+                zoom_level = match outcome {
+                    UploadOutcome::Uploaded(done) => {
+                        manifest.record(
+                            done.zoom_level,
+                            done.x,
+                            done.y,
+                            &done.checksum,
+                            done.placeholder.as_deref(),
+                        )?;
+                        summary.uploaded(done.zoom_level, done.bytes, &done.checksum);
+                        bytes_uploaded += done.bytes;
+                        done.zoom_level
+                    }
+                    UploadOutcome::Failed(tile) => {
+                        let zoom_level = tile.zoom_level;
+                        failed.push(tile);
+                        zoom_level
+                    }
+                };
+                current_tile += 1;
+            }
+            reporter
+                .update(
+                    current_tile,
+                    total_tiles,
+                    zoom_level,
+                    tiles_skipped,
+                    bytes_uploaded,
+                )
+                .await;
+        }
+
+        // Resizing happened in between the tiles, and is counted on its own
+        let lap = summary.lap();
+        let phases = summary.phases();
+        phases.tiles_secs = (lap - phases.resize_secs).max(0.0);
+
+        // Give the tiles that failed one more go; if any fail again the
+        // layout isn't finalized and the manifest is kept for a resume
+        let retry = uploader
+            .retry_failed(failed, manifest, reporter, control)
+            .await?;
+        for tile in &retry.uploaded {
+            summary.uploaded(tile.zoom_level, tile.bytes, &tile.checksum);
+        }
+        summary.phases().retry_secs = summary.lap();
+        if !retry.failed.is_empty() {
+            return Err(ProcessError::TilesFailed {
+                tiles: retry.failed,
+            });
+        }
+
+        // Final cancellation check before finalize
+        if control.cancel.is_cancelled() {
+            return Err(ProcessError::Cancelled);
+        }
+
+        let placement = self.level_size(max_zoom, img_width, img_height);
+        let level_offsets = (min_zoom..=max_zoom)
+            .map(|zoom_level| {
+                let size = self.level_size(zoom_level, img_width, img_height);
+                (zoom_level, (size.x_offset, size.y_offset))
+            })
+            .collect();
+        let mut upload = manifest.upload_manifest(
+            config,
+            min_zoom,
+            max_zoom,
+            self.tile_size,
+            self.overlap,
+            Some(ImagePlacement {
+                width: img_width,
+                height: img_height,
+                offset_x: placement.x_offset,
+                offset_y: placement.y_offset,
+                level_offsets,
+            }),
+        );
+        if config.thumbnail_endpoint.is_some() && !config.dry_run {
+            let overview = source.overview(self, config.thumbnail_size)?;
+            upload.thumbnail = Some(encode_thumbnail(
+                &overview,
+                config.background_color,
+                config.jpeg_quality,
+            )?);
+        }
+        until_cancelled(&control.cancel, sink.finish(&layout_path, &upload)).await?;
+        summary.phases().finalize_secs = summary.lap();
+
+        if let (Some(store), Some(mut hashes)) = (tile_hashes, hashes) {
+            hashes.max_zoom = max_zoom;
+            store.save(config, &hashes)?;
+        }
+
+        Ok(summary.finish(layout_path, min_zoom, max_zoom, uploader.retries(), config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processor(tile_size: TileSize, padding: PaddingMode) -> TileProcessor {
+        let mut config: ProcessConfig = serde_json::from_value(serde_json::json!({
+            "image_path": "plan.png",
+            "server_address": "http://localhost",
+            "layout_key": "plan",
+            "secret": "",
+            "background_color": "#ffffff",
+            "tile_size": tile_size,
+        }))
+        .unwrap();
+        config.padding = padding;
+        TileProcessor::new(&config).unwrap()
+    }
+
+    // Each level as (width, height, padded width, padded height, columns, rows)
+    fn levels(processor: &TileProcessor, width: u32, height: u32) -> Vec<[u32; 6]> {
+        processor
+            .zoom_range(width, height)
+            .map(|zoom_level| {
+                let size = processor.level_size(zoom_level, width, height);
+                let (tiles_x, tiles_y) = size.tiles(processor.tile_size);
+                let grid = processor.level_grid(zoom_level, width, height);
+                assert_eq!(grid.len() as u32, tiles_x * tiles_y);
+                assert_eq!(
+                    processor.level_tiles(zoom_level, width, height),
+                    tiles_x * tiles_y
+                );
+                [
+                    size.width,
+                    size.height,
+                    size.padded_width,
+                    size.padded_height,
+                    tiles_x,
+                    tiles_y,
+                ]
+            })
+            .collect()
+    }
+
+    fn count(levels: &[[u32; 6]]) -> u32 {
+        levels.iter().map(|level| level[4] * level[5]).sum()
+    }
+
+    #[test]
+    fn panoramic_image_padded_to_tiles() {
+        let processor = processor(TileSize::square(256), PaddingMode::Tiles);
+        let levels = levels(&processor, 10000, 10);
+        assert_eq!(
+            levels,
+            [
+                [256, 1, 256, 256, 1, 1],
+                [512, 1, 512, 256, 2, 1],
+                [1024, 1, 1024, 256, 4, 1],
+                [2048, 2, 2048, 256, 8, 1],
+                [4096, 4, 4096, 256, 16, 1],
+                [8192, 8, 8192, 256, 32, 1],
+                [16384, 16, 16384, 256, 64, 1],
+            ]
+        );
+        assert_eq!(processor.count_tiles(10000, 10), count(&levels));
+    }
+
+    #[test]
+    fn tall_image_padded_to_tiles() {
+        let processor = processor(TileSize::square(256), PaddingMode::Tiles);
+        let levels = levels(&processor, 10, 10000);
+        assert_eq!(
+            levels,
+            [
+                [1, 256, 256, 256, 1, 1],
+                [1, 512, 256, 512, 1, 2],
+                [1, 1024, 256, 1024, 1, 4],
+                [2, 2048, 256, 2048, 1, 8],
+                [4, 4096, 256, 4096, 1, 16],
+                [8, 8192, 256, 8192, 1, 32],
+                [16, 16384, 256, 16384, 1, 64],
+            ]
+        );
+        assert_eq!(processor.count_tiles(10, 10000), count(&levels));
+    }
+
+    #[test]
+    fn panoramic_image_padded_to_square() {
+        let processor = processor(TileSize::square(256), PaddingMode::Square);
+        let levels = levels(&processor, 10000, 10);
+        assert_eq!(
+            levels,
+            [
+                [256, 1, 256, 256, 1, 1],
+                [512, 1, 512, 512, 2, 2],
+                [1024, 1, 1024, 1024, 4, 4],
+                [2048, 2, 2048, 2048, 8, 8],
+                [4096, 4, 4096, 4096, 16, 16],
+                [8192, 8, 8192, 8192, 32, 32],
+                [16384, 16, 16384, 16384, 64, 64],
+            ]
+        );
+        assert_eq!(processor.count_tiles(10000, 10), count(&levels));
+    }
+
+    #[test]
+    fn levels_share_the_deepest_placement() {
+        let tiles = processor(TileSize::square(256), PaddingMode::Tiles);
+        let offsets: Vec<_> = tiles
+            .zoom_range(10000, 10)
+            .map(|zoom_level| {
+                let size = tiles.level_size(zoom_level, 10000, 10);
+                (size.x_offset, size.y_offset)
+            })
+            .collect();
+        assert_eq!(
+            offsets,
+            [(0, 2), (0, 4), (0, 8), (0, 15), (0, 30), (0, 60), (0, 120)]
+        );
+
+        // Every level is the deepest one scaled down, offsets included
+        for padding in [PaddingMode::Tiles, PaddingMode::Square] {
+            for (width, height) in [(10000, 10), (10, 10000), (3000, 1000), (777, 5000)] {
+                let padded = processor(TileSize::square(256), padding);
+                let deepest = padded.zoom_range(width, height).end - 1;
+                let deep = padded.level_size(deepest, width, height);
+                for zoom_level in padded.zoom_range(width, height) {
+                    let size = padded.level_size(zoom_level, width, height);
+                    let ratio = size.scale_factor / deep.scale_factor;
+                    assert!((size.x_offset as f64 - deep.x_offset as f64 * ratio).abs() <= 0.5);
+                    assert!((size.y_offset as f64 - deep.y_offset as f64 * ratio).abs() <= 0.5);
+                    assert!(size.x_offset + size.width <= size.padded_width);
+                    assert!(size.y_offset + size.height <= size.padded_height);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tall_image_unpadded() {
+        let processor = processor(TileSize::square(256), PaddingMode::None);
+        let levels = levels(&processor, 10, 10000);
+        assert_eq!(
+            levels,
+            [
+                [1, 256, 1, 256, 1, 1],
+                [1, 512, 1, 512, 1, 2],
+                [1, 1024, 1, 1024, 1, 4],
+                [2, 2048, 2, 2048, 1, 8],
+                [4, 4096, 4, 4096, 1, 16],
+                [8, 8192, 8, 8192, 1, 32],
+                [16, 16384, 16, 16384, 1, 64],
+            ]
+        );
+        assert_eq!(processor.count_tiles(10, 10000), count(&levels));
+    }
+
+    #[test]
+    fn wide_tiles_in_every_padding_mode() {
+        let tile_size = TileSize {
+            width: 512,
+            height: 256,
+        };
+        let tiles = levels(&processor(tile_size, PaddingMode::Tiles), 3000, 1000);
+        assert_eq!(
+            tiles,
+            [
+                [512, 170, 512, 256, 1, 1],
+                [1024, 341, 1024, 512, 2, 2],
+                [2048, 682, 2048, 768, 4, 3],
+                [4096, 1365, 4096, 1536, 8, 6],
+            ]
+        );
+        let square = levels(&processor(tile_size, PaddingMode::Square), 3000, 1000);
+        assert_eq!(
+            square,
+            [
+                [512, 170, 512, 256, 1, 1],
+                [1024, 341, 1024, 512, 2, 2],
+                [2048, 682, 2048, 1024, 4, 4],
+                [4096, 1365, 4096, 2048, 8, 8],
+            ]
+        );
+        let unpadded = levels(&processor(tile_size, PaddingMode::None), 3000, 1000);
+        assert_eq!(
+            unpadded,
+            [
+                [512, 170, 512, 170, 1, 1],
+                [1024, 341, 1024, 341, 2, 2],
+                [2048, 682, 2048, 682, 4, 3],
+                [4096, 1365, 4096, 1365, 8, 6],
+            ]
+        );
+
+        // The grid steps the tile width across and the height down
+        let grid = processor(tile_size, PaddingMode::Tiles).level_grid(2, 3000, 1000);
+        assert_eq!(grid.first(), Some(&(0, 0)));
+        assert_eq!(grid.last(), Some(&(1536, 512)));
+    }
+}
@@ -0,0 +1,400 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::error::{FailedTile, ProcessError};
+use crate::summary::RunSummary;
+
+// Per-tile updates are coalesced so a fast run doesn't flood the listener
+const EMIT_INTERVAL: Duration = Duration::from_millis(100);
+// Rates are sampled this often and smoothed with weight `RATE_SMOOTHING`
+// on the newest sample, so the ETA doesn't jump with every slow tile
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressUpdate {
+    pub job_id: String,
+    pub current: u32,
+    pub total: u32,
+    pub zoom_level: u32,
+    pub percentage: u32,
+    pub status: String,
+    // Background-only tiles that were left out of the upload
+    pub skipped: u32,
+    // Set while the job is held by `pause_processing`
+    #[serde(default)]
+    pub paused: bool,
+    // Set while uploads wait out a 429 from the server
+    #[serde(default)]
+    pub throttled: bool,
+    // Set while the circuit breaker holds uploads for a failing server
+    #[serde(default)]
+    pub waiting_for_server: bool,
+    // Encoded bytes stored so far this run
+    #[serde(default)]
+    pub bytes_uploaded: u64,
+    // Smoothed rates, 0 until the first second has passed
+    #[serde(default)]
+    pub tiles_per_second: f64,
+    // Kilobits per second
+    #[serde(default)]
+    pub upload_kbps: f64,
+    // Unknown until there is a rate to go by
+    #[serde(default)]
+    pub eta_seconds: Option<u64>,
+    #[serde(default)]
+    pub elapsed_seconds: u64,
+}
+
+impl ProgressUpdate {
+    pub fn with_status(job_id: &str, status: &str) -> Self {
+        Self {
+            job_id: job_id.to_string(),
+            current: 0,
+            total: 0,
+            zoom_level: 0,
+            percentage: 0,
+            status: status.to_string(),
+            skipped: 0,
+            paused: false,
+            throttled: false,
+            waiting_for_server: false,
+            bytes_uploaded: 0,
+            tiles_per_second: 0.0,
+            upload_kbps: 0.0,
+            eta_seconds: None,
+            elapsed_seconds: 0,
+        }
+    }
+}
+
+/// Smoothed tile and byte rates of a run.
+struct Throughput {
+    started: Instant,
+    // Time and counts of the last sample; unset until the first update,
+    // and again after a pause so the time spent paused isn't counted
+    sample: Option<(Instant, u32, u64)>,
+    // Bytes seen across every image of a batch, and the last count of the
+    // current one
+    bytes_total: u64,
+    bytes_seen: u64,
+    tiles_per_second: Option<f64>,
+    bytes_per_second: Option<f64>,
+}
+
+impl Throughput {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            sample: None,
+            bytes_total: 0,
+            bytes_seen: 0,
+            tiles_per_second: None,
+            bytes_per_second: None,
+        }
+    }
+
+    /// Take in the counts of an update: tiles done and the bytes the current
+    /// image has stored.
+    fn record(&mut self, current: u32, bytes_uploaded: u64) {
+        // Each image of a batch counts its bytes from zero
+        self.bytes_total += bytes_uploaded
+            .checked_sub(self.bytes_seen)
+            .unwrap_or(bytes_uploaded);
+        self.bytes_seen = bytes_uploaded;
+
+        let now = Instant::now();
+        let Some((at, tiles, bytes)) = self.sample else {
+            self.sample = Some((now, current, self.bytes_total));
+            return;
+        };
+        let elapsed = now.duration_since(at);
+        if elapsed < RATE_SAMPLE_INTERVAL {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        let smooth = |previous: Option<f64>, sample: f64| {
+            Some(previous.map_or(sample, |previous| {
+                previous + RATE_SMOOTHING * (sample - previous)
+            }))
+        };
+        self.tiles_per_second = smooth(
+            self.tiles_per_second,
+            current.saturating_sub(tiles) as f64 / secs,
+        );
+        self.bytes_per_second = smooth(
+            self.bytes_per_second,
+            self.bytes_total.saturating_sub(bytes) as f64 / secs,
+        );
+        self.sample = Some((now, current, self.bytes_total));
+    }
+
+    fn apply(&self, update: &mut ProgressUpdate) {
+        update.bytes_uploaded = self.bytes_total;
+        update.tiles_per_second = self.tiles_per_second.unwrap_or_default();
+        update.upload_kbps = self.bytes_per_second.unwrap_or_default() * 8.0 / 1000.0;
+        update.elapsed_seconds = self.started.elapsed().as_secs();
+        update.eta_seconds = self
+            .tiles_per_second
+            .filter(|&rate| rate > 0.0)
+            .map(|rate| (update.total.saturating_sub(update.current) as f64 / rate).ceil() as u64);
+    }
+}
+
+pub type ProgressState = Arc<Mutex<Option<ProgressUpdate>>>;
+
+/// Payload of the completion and failure events.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobMessage {
+    pub job_id: String,
+    pub message: String,
+    // `ProcessError::code` of a failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    // Tiles that still failed after the retry pass of a continue-on-error run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_tiles: Vec<FailedTile>,
+    // What a finished single-image run produced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RunSummary>,
+}
+
+/// Where the current image sits within a batch, so its tile counts can be
+/// reported as progress through the whole batch.
+#[derive(Debug, Clone)]
+pub struct BatchPosition {
+    pub index: usize,
+    pub count: usize,
+    pub tiles_before: u32,
+    pub tiles_total: u32,
+    // Tiles of the current image, counted from its header until the image
+    // is prepared; `tiles_total` follows when trimming changes the count
+    pub tiles: u32,
+    // The layer the image is, for the layers of a layered upload
+    pub layer: Option<String>,
+}
+
+/// Something a run reports to as it goes.
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    Started(ProgressUpdate),
+    Progress(ProgressUpdate),
+    Completed(JobMessage),
+    Failed(JobMessage),
+    Cancelled(ProgressUpdate),
+}
+
+/// Receives the events of a run: the desktop app forwards them to the
+/// frontend, the CLI prints them.
+pub trait ProgressListener: Send + Sync {
+    fn on_event(&self, event: ProgressEvent);
+}
+
+/// Keeps the latest progress snapshot in `ProgressState` for `get_progress`
+/// and passes each event on to a `ProgressListener`.
+pub struct ProgressReporter {
+    listener: Arc<dyn ProgressListener>,
+    job_id: String,
+    state: ProgressState,
+    last_emit: Option<Instant>,
+    batch: Option<BatchPosition>,
+    throughput: Throughput,
+}
+
+impl ProgressReporter {
+    pub fn new(listener: Arc<dyn ProgressListener>, job_id: String, state: ProgressState) -> Self {
+        Self {
+            listener,
+            job_id,
+            state,
+            last_emit: None,
+            batch: None,
+            throughput: Throughput::new(),
+        }
+    }
+
+    pub fn set_batch_position(&mut self, position: BatchPosition) {
+        self.batch = Some(position);
+    }
+
+    /// The batch position, with the counts corrected once the current
+    /// image was prepared.
+    pub fn batch_position(&self) -> Option<&BatchPosition> {
+        self.batch.as_ref()
+    }
+
+    pub async fn started(&mut self, update: ProgressUpdate) {
+        *self.state.lock().await = Some(update.clone());
+        self.listener.on_event(ProgressEvent::Started(update));
+    }
+
+    /// Record a finished tile, and the bytes the image has stored so far.
+    /// Events are throttled, except for the last tile so the frontend
+    /// always sees 100%.
+    pub async fn update(
+        &mut self,
+        current_tile: u32,
+        total_tiles: u32,
+        zoom_level: u32,
+        tiles_skipped: u32,
+        bytes_uploaded: u64,
+    ) {
+        // The header doesn't show what `auto_trim` takes off, so the run's
+        // own count of the prepared image replaces the one from the header
+        if let Some(batch) = &mut self.batch {
+            if batch.tiles != total_tiles {
+                batch.tiles_total = batch.tiles_total - batch.tiles + total_tiles;
+                batch.tiles = total_tiles;
+            }
+        }
+        let (current, total, prefix) = match &self.batch {
+            Some(batch) => (
+                batch.tiles_before + current_tile,
+                batch.tiles_total,
+                match &batch.layer {
+                    Some(layer) => {
+                        format!("Layer {} ({}/{}): ", layer, batch.index + 1, batch.count)
+                    }
+                    None => format!("Image {}/{}: ", batch.index + 1, batch.count),
+                },
+            ),
+            None => (current_tile, total_tiles, String::new()),
+        };
+        let percentage = (current * 100) / total;
+        self.throughput.record(current, bytes_uploaded);
+        let mut update = ProgressUpdate {
+            job_id: self.job_id.clone(),
+            current,
+            total,
+            zoom_level,
+            percentage,
+            status: format!(
+                "{}Processing zoom level {} ({}/{})",
+                prefix, zoom_level, current_tile, total_tiles
+            ),
+            skipped: tiles_skipped,
+            ..ProgressUpdate::with_status(&self.job_id, "")
+        };
+        self.throughput.apply(&mut update);
+        *self.state.lock().await = Some(update.clone());
+
+        let due = self
+            .last_emit
+            .is_none_or(|last| last.elapsed() >= EMIT_INTERVAL);
+        if due || current >= total {
+            self.last_emit = Some(Instant::now());
+            self.listener.on_event(ProgressEvent::Progress(update));
+        }
+    }
+
+    /// Mark the job as paused, keeping the counts of the last snapshot.
+    pub async fn paused(&mut self) {
+        let update = {
+            let mut state = self.state.lock().await;
+            let update = ProgressUpdate {
+                status: "Paused".to_string(),
+                paused: true,
+                throttled: false,
+                waiting_for_server: false,
+                ..state
+                    .clone()
+                    .unwrap_or_else(|| ProgressUpdate::with_status(&self.job_id, "Paused"))
+            };
+            *state = Some(update.clone());
+            update
+        };
+        // Let the first update after resuming through straight away, and
+        // measure rates afresh from there
+        self.last_emit = None;
+        self.throughput.sample = None;
+        self.listener.on_event(ProgressEvent::Progress(update));
+    }
+
+    /// A handle the upload tasks use to report a stalled run.
+    pub fn status_notifier(&self) -> StatusNotifier {
+        StatusNotifier {
+            listener: self.listener.clone(),
+            job_id: self.job_id.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    pub async fn cancelled(&mut self) {
+        let update = ProgressUpdate::with_status(&self.job_id, "Cancelled");
+        *self.state.lock().await = Some(update.clone());
+        self.listener.on_event(ProgressEvent::Cancelled(update));
+    }
+
+    pub fn completed(&self, message: &str, summary: Option<&RunSummary>) {
+        self.listener.on_event(ProgressEvent::Completed(JobMessage {
+            job_id: self.job_id.clone(),
+            message: message.to_string(),
+            code: None,
+            failed_tiles: Vec::new(),
+            summary: summary.cloned(),
+        }));
+    }
+
+    pub fn failed(&self, error: &ProcessError) {
+        self.listener.on_event(ProgressEvent::Failed(JobMessage {
+            job_id: self.job_id.clone(),
+            message: error.to_string(),
+            code: Some(error.code().to_string()),
+            failed_tiles: match error {
+                ProcessError::TilesFailed { tiles } => tiles.clone(),
+                _ => Vec::new(),
+            },
+            summary: None,
+        }));
+    }
+}
+
+/// Lets the upload tasks of a run report why it stalled: throttled by the
+/// server, or waiting for it to come back. The next tile that completes
+/// clears the flag again.
+#[derive(Clone)]
+pub struct StatusNotifier {
+    listener: Arc<dyn ProgressListener>,
+    job_id: String,
+    state: ProgressState,
+}
+
+impl StatusNotifier {
+    pub async fn throttled(&self, wait: Duration) {
+        let status = format!("Throttled by server, retrying in {}s", wait.as_secs());
+        self.emit(status, true, false).await;
+    }
+
+    pub async fn waiting_for_server(&self, probe_in: Duration) {
+        let status = format!(
+            "Waiting for server, checking again in {}s",
+            probe_in.as_secs()
+        );
+        self.emit(status, false, true).await;
+    }
+
+    pub async fn server_back(&self) {
+        self.emit("Server is back, resuming".to_string(), false, false)
+            .await;
+    }
+
+    // Keeps the counts of the last snapshot
+    async fn emit(&self, status: String, throttled: bool, waiting_for_server: bool) {
+        let update = {
+            let mut state = self.state.lock().await;
+            let update = ProgressUpdate {
+                status: status.clone(),
+                throttled,
+                waiting_for_server,
+                ..state
+                    .clone()
+                    .unwrap_or_else(|| ProgressUpdate::with_status(&self.job_id, &status))
+            };
+            *state = Some(update.clone());
+            update
+        };
+        self.listener.on_event(ProgressEvent::Progress(update));
+    }
+}
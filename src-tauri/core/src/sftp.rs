@@ -0,0 +1,374 @@
+use async_trait::async_trait;
+use base64::Engine;
+use ssh2::{CheckResult, HashType, KnownHostFileKind, Session, Sftp};
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{ProcessConfig, SftpAuth, SftpSettings, TileFormat, TileSize};
+use crate::error::ProcessError;
+use crate::local::{PyramidManifest, PYRAMID_MANIFEST};
+use crate::sink::{Tile, TileSink, UploadManifest};
+
+/// Writes the pyramid into a folder on an SFTP server, as
+/// `{zoom}/{x}/{y}.{ext}` plus `layout.json` like a folder export, for
+/// sites that only take tiles through an SFTP drop.
+///
+/// libssh2 is blocking, so each tile is written on the blocking pool over
+/// a connection taken from a small pool; a connection that fails is
+/// dropped and the next tile opens a fresh one.
+pub struct SftpSink {
+    inner: Arc<Inner>,
+    layout_key: String,
+    tile_size: TileSize,
+    tile_format: TileFormat,
+}
+
+struct Inner {
+    settings: SftpSettings,
+    // Remote folder with no trailing slash, empty for the login folder
+    root: String,
+    timeout: Duration,
+    idle: Mutex<Vec<Connection>>,
+    // Folders known to exist, shared so each is only checked once a run
+    created: Mutex<HashSet<String>>,
+}
+
+struct Connection {
+    // Kept alive for `sftp`, which runs over it
+    _session: Session,
+    sftp: Sftp,
+}
+
+impl SftpSink {
+    pub fn new(settings: &SftpSettings, config: &ProcessConfig) -> Result<Self, ProcessError> {
+        if settings.host.trim().is_empty() || settings.username.is_empty() {
+            return Err(ProcessError::InvalidConfig(
+                "Invalid SFTP settings: a host and username are required".to_string(),
+            ));
+        }
+        let root = settings.remote_dir.trim();
+        let root = match root.trim_end_matches('/') {
+            "" if root.starts_with('/') => "/".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+        Ok(Self {
+            inner: Arc::new(Inner {
+                settings: settings.clone(),
+                root,
+                timeout: Duration::from_secs(config.network.request_timeout_secs.max(1)),
+                idle: Mutex::new(Vec::new()),
+                created: Mutex::new(HashSet::new()),
+            }),
+            layout_key: config.layout_key.clone(),
+            tile_size: config.tile_size,
+            tile_format: config.tile_format,
+        })
+    }
+
+    /// Run `task` on the blocking pool over a pooled connection.
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        task: impl FnOnce(&Inner, &Connection) -> Result<T, ProcessError> + Send + 'static,
+    ) -> Result<T, ProcessError> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let idle = inner.idle.lock().unwrap().pop();
+            let connection = match idle {
+                Some(connection) => connection,
+                None => inner.connect()?,
+            };
+            let result = task(&inner, &connection);
+            // A failed connection may be broken; it is not reused
+            if result.is_ok() {
+                inner.idle.lock().unwrap().push(connection);
+            }
+            result
+        })
+        .await
+        .map_err(|e| ProcessError::Internal(format!("SFTP task failed: {}", e)))?
+    }
+}
+
+impl Inner {
+    fn connect(&self) -> Result<Connection, ProcessError> {
+        let settings = &self.settings;
+        let unreachable =
+            |e| ProcessError::io(format!("Failed to connect to {}", settings.host), e);
+        let address = (settings.host.trim(), settings.port)
+            .to_socket_addrs()
+            .map_err(unreachable)?
+            .next()
+            .ok_or_else(|| {
+                unreachable(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "host name did not resolve",
+                ))
+            })?;
+        let tcp = TcpStream::connect_timeout(&address, self.timeout).map_err(unreachable)?;
+
+        let failed = |e| ProcessError::sftp("SFTP connection failed", e);
+        let mut session = Session::new().map_err(failed)?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(self.timeout.as_millis() as u32);
+        session.handshake().map_err(failed)?;
+        self.check_host_key(&session)?;
+
+        let refused = |e| ProcessError::sftp("SFTP login failed", e);
+        match &settings.auth {
+            SftpAuth::Password { password } => session
+                .userauth_password(&settings.username, password)
+                .map_err(refused)?,
+            SftpAuth::Key { path, passphrase } => session
+                .userauth_pubkey_file(
+                    &settings.username,
+                    None,
+                    Path::new(path),
+                    passphrase.as_deref(),
+                )
+                .map_err(refused)?,
+        }
+
+        let sftp = session.sftp().map_err(failed)?;
+        tracing::debug!(host = %settings.host, port = settings.port, "SFTP connection opened");
+        Ok(Connection {
+            _session: session,
+            sftp,
+        })
+    }
+
+    /// Make sure the server is the one expected: its host key is the
+    /// pinned one, or otherwise the one `~/.ssh/known_hosts` has for it,
+    /// unless the settings accept any key.
+    fn check_host_key(&self, session: &Session) -> Result<(), ProcessError> {
+        let settings = &self.settings;
+        let hash = session.host_key_hash(HashType::Sha256).unwrap_or_default();
+        let fingerprint = format!(
+            "SHA256:{}",
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash)
+        );
+        if let Some(expected) = &settings.host_key_sha256 {
+            if !fingerprint_matches(expected, hash) {
+                return Err(ProcessError::InvalidConfig(format!(
+                    "The host key of {} ({}) does not match the expected key",
+                    settings.host, fingerprint
+                )));
+            }
+            return Ok(());
+        }
+        if settings.accept_any_host_key {
+            tracing::warn!(host = %settings.host, %fingerprint, "SFTP host key not verified");
+            return Ok(());
+        }
+
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| ProcessError::Internal(format!("{} sent no host key", settings.host)))?;
+        let mut known_hosts = session
+            .known_hosts()
+            .map_err(|e| ProcessError::sftp("Failed to read known hosts", e))?;
+        // A missing or unreadable file knows no hosts
+        if let Some(path) = known_hosts_path() {
+            let _ = known_hosts.read_file(&path, KnownHostFileKind::OpenSSH);
+        }
+        let host = settings.host.trim();
+        // OpenSSH lists servers on other ports as `[host]:port`
+        let checked = match settings.port {
+            22 => known_hosts.check(host, key),
+            port => known_hosts.check_port(host, port, key),
+        };
+        match checked {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(ProcessError::InvalidConfig(format!(
+                "The host key of {} ({}) does not match the one in ~/.ssh/known_hosts; the server may have been replaced or the connection intercepted",
+                settings.host, fingerprint
+            ))),
+            CheckResult::NotFound | CheckResult::Failure => {
+                Err(ProcessError::InvalidConfig(format!(
+                    "{} ({}) is not in ~/.ssh/known_hosts; connect to it once with ssh or pin its host key",
+                    settings.host, fingerprint
+                )))
+            }
+        }
+    }
+
+    /// `relative` under the remote folder.
+    fn path(&self, relative: &str) -> String {
+        match self.root.as_str() {
+            "" => relative.to_string(),
+            "/" => format!("/{}", relative),
+            root => format!("{}/{}", root, relative),
+        }
+    }
+
+    /// Create `dir` and any missing parents.
+    fn create_dir_all(&self, sftp: &Sftp, dir: &str) -> Result<(), ProcessError> {
+        if dir.is_empty() || dir == "/" || self.created.lock().unwrap().contains(dir) {
+            return Ok(());
+        }
+        if let Some((parent, _)) = dir.rsplit_once('/') {
+            self.create_dir_all(sftp, parent)?;
+        }
+        if sftp.stat(Path::new(dir)).is_err() {
+            // Another connection may have created it in the meantime
+            if let Err(e) = sftp.mkdir(Path::new(dir), 0o755) {
+                if sftp.stat(Path::new(dir)).is_err() {
+                    return Err(ProcessError::sftp(
+                        format!("Failed to create remote folder {}", dir),
+                        e,
+                    ));
+                }
+            }
+        }
+        self.created.lock().unwrap().insert(dir.to_string());
+        Ok(())
+    }
+
+    fn write(&self, sftp: &Sftp, path: &str, data: &[u8]) -> Result<(), ProcessError> {
+        let failed = |e| ProcessError::io(format!("Failed to write {}", path), e);
+        let mut file = sftp
+            .create(Path::new(path))
+            .map_err(|e| ProcessError::sftp(format!("Failed to create {}", path), e))?;
+        file.write_all(data).map_err(failed)?;
+        file.flush().map_err(failed)
+    }
+}
+
+/// Whether `expected` is the SHA-256 `hash` of a host key, given as hex or
+/// as the `SHA256:` base64 form `ssh-keygen -lf` prints.
+fn fingerprint_matches(expected: &str, hash: &[u8]) -> bool {
+    let expected = expected.trim();
+    match expected.strip_prefix("SHA256:") {
+        Some(encoded) => {
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash)
+                == encoded.trim_end_matches('=')
+        }
+        None => {
+            let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+            hex.eq_ignore_ascii_case(&expected.replace(':', ""))
+        }
+    }
+}
+
+/// The user's OpenSSH known hosts file.
+fn known_hosts_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+#[async_trait]
+impl TileSink for SftpSink {
+    async fn put_tile(&self, _layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        let (zoom_level, x, y, bytes) = (tile.zoom_level, tile.x, tile.y, tile.data.len());
+        let started = Instant::now();
+        let result = self
+            .with_connection(move |inner, connection| {
+                let dir = inner.path(&format!("{}/{}", tile.zoom_level, tile.x));
+                inner.create_dir_all(&connection.sftp, &dir)?;
+                let path = format!("{}/{}.{}", dir, tile.y, tile.format.extension());
+                inner.write(&connection.sftp, &path, &tile.data)
+            })
+            .await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+
+        match &result {
+            Ok(()) => tracing::debug!(
+                zoom_level,
+                x,
+                y,
+                bytes,
+                elapsed_ms,
+                "Tile written over SFTP"
+            ),
+            Err(e) => tracing::warn!(
+                zoom_level,
+                x,
+                y,
+                bytes,
+                elapsed_ms,
+                error = %e,
+                "SFTP tile write failed"
+            ),
+        }
+        result
+    }
+
+    async fn finish(&self, layout_path: &str, upload: &UploadManifest) -> Result<(), ProcessError> {
+        let manifest = PyramidManifest {
+            layout_key: self.layout_key.clone(),
+            layout_path: layout_path.to_string(),
+            min_zoom: upload.min_zoom,
+            max_zoom: upload.max_zoom,
+            tile_size: self.tile_size,
+            overlap: upload.overlap,
+            tile_format: self.tile_format,
+            placeholders: upload.placeholders.clone().unwrap_or_default(),
+        };
+        let json = serde_json::to_string_pretty(&manifest)
+            .map_err(|e| ProcessError::Internal(format!("Failed to serialize manifest: {}", e)))?;
+        self.with_connection(move |inner, connection| {
+            let root = inner.root.clone();
+            inner.create_dir_all(&connection.sftp, &root)?;
+            inner.write(
+                &connection.sftp,
+                &inner.path(PYRAMID_MANIFEST),
+                json.as_bytes(),
+            )
+        })
+        .await
+    }
+
+    async fn probe(&self) -> Result<(), ProcessError> {
+        // A fresh connection, as pooled ones may have gone stale
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = inner.connect()?;
+            inner.idle.lock().unwrap().push(connection);
+            Ok(())
+        })
+        .await
+        .map_err(|e| ProcessError::Internal(format!("SFTP task failed: {}", e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn matches_fingerprints_in_either_form() {
+        let hash = Sha256::digest(b"host key");
+        for fingerprint in [
+            "SHA256:+xspGuW/PEF/5qpDMzUSFm9CUN7eQpfJk+E1hh4AsL0",
+            // With the padding OpenSSH leaves off
+            "SHA256:+xspGuW/PEF/5qpDMzUSFm9CUN7eQpfJk+E1hh4AsL0=",
+            "fb1b291ae5bf3c417fe6aa43333512166f4250dede4297c993e135861e00b0bd",
+            "FB:1B:29:1A:E5:BF:3C:41:7F:E6:AA:43:33:35:12:16:6F:42:50:DE:DE:42:97:C9:93:E1:35:86:1E:00:B0:BD",
+            "  fb1b291ae5bf3c417fe6aa43333512166f4250dede4297c993e135861e00b0bd\n",
+        ] {
+            assert!(fingerprint_matches(fingerprint, &hash), "{}", fingerprint);
+        }
+    }
+
+    #[test]
+    fn rejects_other_fingerprints() {
+        let hash = Sha256::digest(b"host key");
+        for fingerprint in [
+            "",
+            "SHA256:",
+            "SHA256:+xspGuW/PEF/5qpDMzUSFm9CUN7eQpfJk+E1hh4AsL1",
+            // The base64 form is case-sensitive
+            "SHA256:+XSPGUW/PEF/5QPDMZUSFM9CUN7EQPFJK+E1HH4ASL0",
+            "SHA256:fb1b291ae5bf3c417fe6aa43333512166f4250dede4297c993e135861e00b0bd",
+            "fb1b291ae5bf3c417fe6aa43333512166f4250dede4297c993e135861e00b0",
+            "MD5:fb1b291ae5bf3c417fe6aa43333512166f4250dede4297c993e135861e00b0bd",
+        ] {
+            assert!(!fingerprint_matches(fingerprint, &hash), "{}", fingerprint);
+        }
+    }
+}
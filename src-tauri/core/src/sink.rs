@@ -0,0 +1,921 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::config::{
+    AuthMode, FinalizeMethod, OutputTarget, ProcessConfig, TileFormat, TileSize, TileUploadMethod,
+};
+use crate::error::ProcessError;
+use crate::incremental::tile_hash;
+use crate::local::write_tile;
+
+/// Header carrying a tile's SHA-256 (hex) on upload, and echoed back by
+/// servers that verify it.
+pub const CHECKSUM_HEADER: &str = "X-Checksum-SHA256";
+
+/// Route tiles are uploaded to unless `tile_url_template` names another.
+/// `{layout_key}`, `{path}` (the layout path), `{zoom}`, `{x}` and `{y}` are
+/// filled in per tile.
+pub const DEFAULT_TILE_URL_TEMPLATE: &str =
+    "/LayoutUtil/UploadTile/{layout_key}/{path}/{zoom}/{x}/{y}";
+
+/// Route that points the layout at its tiles unless `finalize_endpoint`
+/// names another.
+pub const DEFAULT_FINALIZE_ENDPOINT: &str = "/api/Location/LocationLayout/UpdatePath";
+
+/// Route that points one layer of a layout at its tiles unless
+/// `layer_endpoint` names another.
+pub const DEFAULT_LAYER_ENDPOINT: &str = "/api/Location/LocationLayout/UpdateLayerPath";
+
+/// Page showing a finalized layout unless `viewer_url_template` names
+/// another. `{layout_key}` and `{path}` (the layout path) are filled in.
+pub const DEFAULT_VIEWER_URL_TEMPLATE: &str = "/Location/LocationLayout/{layout_key}";
+
+const TILE_URL_PLACEHOLDERS: &[&str] = &["layout_key", "path", "zoom", "x", "y"];
+
+/// Where the layout can be viewed once finalized, relative to the server
+/// address unless the template is a full URL. `None` for runs that don't
+/// finalize a layout on the server.
+pub fn viewer_url(config: &ProcessConfig, layout_path: &str) -> Option<String> {
+    if config.dry_run || !matches!(config.output, OutputTarget::Server | OutputTarget::S3(_)) {
+        return None;
+    }
+    let url = config
+        .viewer_url_template
+        .as_deref()
+        .unwrap_or(DEFAULT_VIEWER_URL_TEMPLATE)
+        .replace("{layout_key}", &config.layout_key)
+        .replace("{path}", layout_path);
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Some(url)
+    } else {
+        Some(format!(
+            "{}/{}",
+            config.server_address.trim_end_matches('/'),
+            url.trim_start_matches('/')
+        ))
+    }
+}
+
+/// Check that a tile URL template only uses known placeholders and has the
+/// coordinates that keep tiles apart.
+pub(crate) fn validate_tile_url_template(template: &str) -> Result<(), ProcessError> {
+    let invalid = |reason: String| {
+        ProcessError::InvalidConfig(format!(
+            "Invalid tile URL template '{}': {}",
+            template, reason
+        ))
+    };
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| invalid("unclosed '{'".to_string()))?;
+        let name = &rest[start + 1..start + end];
+        if !TILE_URL_PLACEHOLDERS.contains(&name) {
+            return Err(invalid(format!("unknown placeholder {{{}}}", name)));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    for required in ["{zoom}", "{x}", "{y}"] {
+        if !template.contains(required) {
+            return Err(invalid(format!("{} is missing", required)));
+        }
+    }
+    Ok(())
+}
+
+/// An encoded tile and where it sits in the pyramid.
+#[derive(Clone)]
+pub struct Tile {
+    pub zoom_level: u32,
+    pub x: u32,
+    pub y: u32,
+    pub format: TileFormat,
+    pub data: Vec<u8>,
+    // Blurhash of the tile on `tile_placeholders` runs
+    pub placeholder: Option<String>,
+}
+
+impl Tile {
+    /// SHA-256 of the encoded data, as hex.
+    pub fn checksum(&self) -> String {
+        tile_hash(&self.data)
+    }
+}
+
+/// Body of a request storing a tile as a copy of an earlier one of the
+/// layout with the same content, sent to `tile_ref_endpoint`.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct TileReference<'a> {
+    zoom: u32,
+    x: u32,
+    y: u32,
+    source_zoom: u32,
+    source_x: u32,
+    source_y: u32,
+    checksum: &'a str,
+}
+
+/// What a finished upload consists of, handed to `TileSink::finish` so the
+/// destination can check that every tile arrived.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadManifest {
+    pub layout_key: String,
+    pub layout_path: String,
+    // Shallowest zoom level uploaded, above zero when `min_zoom` left the
+    // smallest levels out
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub tile_size: TileSize,
+    // Pixels each tile shares with its neighbours, 0 for edge-to-edge tiles
+    pub overlap: u32,
+    pub tile_format: TileFormat,
+    // Size of the source image; unknown for pyramids generated elsewhere
+    pub image_width: Option<u32>,
+    pub image_height: Option<u32>,
+    // Pixel offset of the image on the padded canvas of the deepest level,
+    // for mapping image coordinates onto the layout
+    pub image_offset_x: Option<u32>,
+    pub image_offset_y: Option<u32>,
+    // The offset on the canvas of each level, keyed by zoom, as `[x, y]`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub image_offsets: BTreeMap<u32, (u32, u32)>,
+    // Tiles stored, background tiles left out of the upload not included
+    pub tile_count: u32,
+    pub tiles_per_zoom: BTreeMap<u32, u32>,
+    // SHA-256 over the sorted `zoom/x/y:sha256` lines of every tile
+    pub checksums_digest: String,
+    // Blurhash of each tile keyed by `zoom/x/y`, on `tile_placeholders` runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placeholders: Option<BTreeMap<String, String>>,
+    // JPEG overview of the layout, uploaded to `thumbnail_endpoint` on
+    // finalize rather than sent with the manifest
+    #[serde(skip)]
+    pub thumbnail: Option<Vec<u8>>,
+    pub app_version: String,
+}
+
+/// The source image's size and where it sits on the deepest level, and on
+/// each of the levels.
+#[derive(Debug, Clone)]
+pub struct ImagePlacement {
+    pub width: u32,
+    pub height: u32,
+    pub offset_x: u32,
+    pub offset_y: u32,
+    pub level_offsets: BTreeMap<u32, (u32, u32)>,
+}
+
+/// Destination for the tiles of a run.
+///
+/// Tiles arrive from several tasks at once, in no particular order. `finish`
+/// is called once after every tile has been stored.
+#[async_trait]
+pub trait TileSink: Send + Sync {
+    /// Store one tile of the layout at `layout_path`.
+    async fn put_tile(&self, layout_path: &str, tile: Tile) -> Result<(), ProcessError>;
+
+    /// Store several tiles at once. Sinks without a way to batch them store
+    /// them one by one.
+    async fn put_tiles(&self, layout_path: &str, tiles: Vec<Tile>) -> Result<(), ProcessError> {
+        for tile in tiles {
+            self.put_tile(layout_path, tile).await?;
+        }
+        Ok(())
+    }
+
+    /// Complete the layout once all of its tiles are stored.
+    async fn finish(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), ProcessError>;
+
+    /// Remove the tiles stored under an abandoned `layout_path`. Returns
+    /// `false` if the sink has no way to, leaving them in place.
+    async fn discard(&self, _layout_path: &str) -> Result<bool, ProcessError> {
+        Ok(false)
+    }
+
+    /// Check that the destination is reachable again after repeated
+    /// failures. Sinks that can't be down have nothing to check.
+    async fn probe(&self) -> Result<(), ProcessError> {
+        Ok(())
+    }
+}
+
+/// Uploads tiles to an iviva server and points the layout at them when done.
+pub struct HttpSink {
+    client: reqwest::Client,
+    server_address: String,
+    layout_key: String,
+    secret: String,
+    auth: AuthMode,
+    cleanup_endpoint: Option<String>,
+    verify_checksums: bool,
+    manifest_endpoint: Option<String>,
+    thumbnail_endpoint: Option<String>,
+    batch_endpoint: Option<String>,
+    // Cleared once the server turns the batch endpoint down, after which
+    // tiles go one per request
+    batch_supported: AtomicBool,
+    tile_ref_endpoint: Option<String>,
+    // Cleared once the server turns the reference endpoint down, after
+    // which duplicate tiles are uploaded in full
+    refs_supported: AtomicBool,
+    // Where each tile content was first stored, by checksum; only kept
+    // with a reference endpoint
+    stored: Mutex<HashMap<String, (u32, u32, u32)>>,
+    // Absolute, with the per-tile placeholders left in
+    tile_url_template: String,
+    tile_upload_method: TileUploadMethod,
+    // The layer endpoint for a layer, sent its name as `Layer`
+    finalize_endpoint: String,
+    finalize_method: FinalizeMethod,
+    finalize_params: BTreeMap<String, String>,
+    layer: Option<String>,
+}
+
+/// `config`'s tile URL template made absolute.
+fn tile_url_template(config: &ProcessConfig) -> String {
+    let template = config
+        .tile_url_template
+        .as_deref()
+        .unwrap_or(DEFAULT_TILE_URL_TEMPLATE);
+    absolute_template(config, template)
+}
+
+/// `template` under `config`'s server address, unless it is a full URL.
+pub(crate) fn absolute_template(config: &ProcessConfig, template: &str) -> String {
+    if template.contains("://") {
+        return template.to_string();
+    }
+    format!(
+        "{}/{}",
+        config.server_address.trim_end_matches('/'),
+        template.trim_start_matches('/')
+    )
+}
+
+impl HttpSink {
+    pub fn new(client: reqwest::Client, config: &ProcessConfig) -> Self {
+        Self {
+            client,
+            server_address: config.server_address.trim_end_matches('/').to_string(),
+            layout_key: config.layout_key.clone(),
+            secret: config.secret.clone(),
+            auth: config.auth.clone(),
+            cleanup_endpoint: config.cleanup_endpoint.clone(),
+            verify_checksums: config.verify_checksums,
+            manifest_endpoint: config.manifest_endpoint.clone(),
+            thumbnail_endpoint: config.thumbnail_endpoint.clone(),
+            batch_endpoint: config.batch_endpoint.clone(),
+            batch_supported: AtomicBool::new(true),
+            tile_ref_endpoint: config.tile_ref_endpoint.clone(),
+            refs_supported: AtomicBool::new(true),
+            stored: Mutex::new(HashMap::new()),
+            tile_url_template: tile_url_template(config),
+            tile_upload_method: config.tile_upload_method,
+            finalize_endpoint: match &config.layer {
+                Some(_) => config
+                    .layer_endpoint
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_LAYER_ENDPOINT.to_string()),
+                None => config
+                    .finalize_endpoint
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_FINALIZE_ENDPOINT.to_string()),
+            },
+            finalize_method: config.finalize_method,
+            finalize_params: config.finalize_params.clone(),
+            layer: config.layer.clone(),
+        }
+    }
+
+    fn tile_url(&self, layout_path: &str, tile: &Tile) -> String {
+        self.tile_url_template
+            .replace("{layout_key}", &self.layout_key)
+            .replace("{path}", layout_path)
+            .replace("{zoom}", &tile.zoom_level.to_string())
+            .replace("{x}", &tile.x.to_string())
+            .replace("{y}", &tile.y.to_string())
+    }
+
+    /// Post `tiles` to the batch endpoint in one multipart request, each
+    /// as a `tile` part named `{zoom}_{x}_{y}.{ext}` carrying its checksum
+    /// header. Returns `false` if the server has no such route.
+    async fn upload_batch(
+        &self,
+        endpoint: &str,
+        layout_path: &str,
+        tiles: &[Tile],
+    ) -> Result<bool, ProcessError> {
+        let url = format!(
+            "{}/{}/{}/{}",
+            self.server_address,
+            endpoint.trim_matches('/'),
+            self.layout_key,
+            layout_path
+        );
+
+        let mut form = reqwest::multipart::Form::new();
+        for tile in tiles {
+            let mut headers = reqwest::header::HeaderMap::new();
+            if let Ok(checksum) = tile.checksum().parse() {
+                headers.insert(CHECKSUM_HEADER, checksum);
+            }
+            let part = reqwest::multipart::Part::bytes(tile.data.clone())
+                .file_name(format!(
+                    "{}_{}_{}.{}",
+                    tile.zoom_level,
+                    tile.x,
+                    tile.y,
+                    tile.format.extension()
+                ))
+                .mime_str(tile.format.mime_type())
+                .unwrap()
+                .headers(headers);
+            form = form.part("tile", part);
+        }
+
+        let started = Instant::now();
+        let response = self
+            .auth
+            .authorize(self.client.post(&url), "__sc__", &self.secret)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| ProcessError::http("Batch upload failed", e))?;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        let status = response.status();
+
+        match status {
+            StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED => return Ok(false),
+            StatusCode::TOO_MANY_REQUESTS => {
+                return Err(ProcessError::Throttled {
+                    context: "Batch upload failed".to_string(),
+                    retry_after: Self::retry_after(&response),
+                })
+            }
+            _ => {}
+        }
+        match response.error_for_status() {
+            Ok(_) => {
+                tracing::debug!(
+                    tiles = tiles.len(),
+                    status = status.as_u16(),
+                    elapsed_ms,
+                    "Tile batch uploaded"
+                );
+                Ok(true)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    tiles = tiles.len(),
+                    status = status.as_u16(),
+                    elapsed_ms,
+                    error = %e,
+                    "Tile batch upload failed"
+                );
+                Err(ProcessError::http("Batch upload failed", e))
+            }
+        }
+    }
+
+    /// Note that tiles with `checksum` can refer to the one stored at
+    /// zoom level, x and y `place`, unless one was stored before it.
+    fn remember(&self, checksum: String, place: (u32, u32, u32)) {
+        if self.tile_ref_endpoint.is_some() {
+            self.stored.lock().unwrap().entry(checksum).or_insert(place);
+        }
+    }
+
+    /// Store `tile` as a reference to an earlier tile of the run with the
+    /// same content, through the reference endpoint. Returns `false` if it
+    /// has to be uploaded in full: there is no endpoint or no such tile
+    /// stored yet, or the server has no such route.
+    async fn put_reference(
+        &self,
+        layout_path: &str,
+        tile: &Tile,
+        checksum: &str,
+    ) -> Result<bool, ProcessError> {
+        let endpoint = self
+            .tile_ref_endpoint
+            .as_deref()
+            .filter(|_| self.refs_supported.load(Ordering::Relaxed));
+        let Some(endpoint) = endpoint else {
+            return Ok(false);
+        };
+        let Some((source_zoom, source_x, source_y)) =
+            self.stored.lock().unwrap().get(checksum).copied()
+        else {
+            return Ok(false);
+        };
+        let url = format!(
+            "{}/{}/{}/{}",
+            self.server_address,
+            endpoint.trim_matches('/'),
+            self.layout_key,
+            layout_path
+        );
+        let reference = TileReference {
+            zoom: tile.zoom_level,
+            x: tile.x,
+            y: tile.y,
+            source_zoom,
+            source_x,
+            source_y,
+            checksum,
+        };
+
+        let response = self
+            .auth
+            .authorize(self.client.post(&url), "__sc__", &self.secret)
+            .json(&reference)
+            .send()
+            .await
+            .map_err(|e| ProcessError::http("Tile reference failed", e))?;
+        match response.status() {
+            StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED => {
+                if self.refs_supported.swap(false, Ordering::Relaxed) {
+                    tracing::warn!(
+                        "The server has no tile reference endpoint, uploading duplicate tiles in full"
+                    );
+                }
+                return Ok(false);
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                return Err(ProcessError::Throttled {
+                    context: "Tile reference failed".to_string(),
+                    retry_after: Self::retry_after(&response),
+                })
+            }
+            _ => {}
+        }
+        response
+            .error_for_status()
+            .map_err(|e| ProcessError::http("Tile reference failed", e))?;
+        tracing::debug!(
+            zoom_level = tile.zoom_level,
+            x = tile.x,
+            y = tile.y,
+            source_zoom,
+            source_x,
+            source_y,
+            "Tile stored as a reference"
+        );
+        Ok(true)
+    }
+
+    /// Send one tile with the configured method. A 429 comes back as
+    /// `Ok(Err(retry_after))` rather than as an error, so the caller can
+    /// wait it out.
+    async fn upload_tile(
+        &self,
+        layout_path: &str,
+        tile: Tile,
+        checksum: &str,
+    ) -> Result<Result<reqwest::Response, Option<u64>>, reqwest::Error> {
+        let url = self.tile_url(layout_path, &tile);
+
+        let request = match self.tile_upload_method {
+            TileUploadMethod::Post => {
+                let part = reqwest::multipart::Part::bytes(tile.data)
+                    .file_name(format!("tile.{}", tile.format.extension()))
+                    .mime_str(tile.format.mime_type())
+                    .unwrap();
+                let form = reqwest::multipart::Form::new().part("file", part);
+                self.client.post(&url).multipart(form)
+            }
+            TileUploadMethod::Put => self
+                .client
+                .put(&url)
+                .header(reqwest::header::CONTENT_TYPE, tile.format.mime_type())
+                .body(tile.data),
+        };
+
+        let response = self
+            .auth
+            .authorize(request, "__sc__", &self.secret)
+            .header(CHECKSUM_HEADER, checksum)
+            .send()
+            .await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Ok(Err(Self::retry_after(&response)));
+        }
+        response.error_for_status().map(Ok)
+    }
+
+    /// The wait a 429 asks for, in seconds. Only the delay form of
+    /// `Retry-After` is understood; a date falls back to the default.
+    fn retry_after(response: &reqwest::Response) -> Option<u64> {
+        response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The checksum the server reports for what it stored: its echo of
+    /// `X-Checksum-SHA256`, or else the ETag.
+    fn received_checksum(response: &reqwest::Response) -> Option<String> {
+        let headers = response.headers();
+        let value = headers
+            .get(CHECKSUM_HEADER)
+            .or_else(|| headers.get(reqwest::header::ETAG))?
+            .to_str()
+            .ok()?;
+        Some(
+            value
+                .trim_start_matches("W/")
+                .trim_matches('"')
+                .to_ascii_lowercase(),
+        )
+    }
+
+    /// Post `manifest` to the manifest endpoint, if there is one, so the
+    /// server can refuse an incomplete upload before the layout is switched.
+    async fn send_manifest(&self, manifest: &UploadManifest) -> Result<(), reqwest::Error> {
+        let Some(endpoint) = &self.manifest_endpoint else {
+            return Ok(());
+        };
+        let url = format!(
+            "{}/{}",
+            self.server_address,
+            endpoint.trim_start_matches('/')
+        );
+        self.auth
+            .authorize(self.client.post(&url), "apikey", &self.secret)
+            .json(manifest)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Post the layout's overview to the thumbnail endpoint as a multipart
+    /// `file` part, if there is an endpoint and an overview to send.
+    async fn send_thumbnail(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), reqwest::Error> {
+        let (Some(endpoint), Some(thumbnail)) = (&self.thumbnail_endpoint, &manifest.thumbnail)
+        else {
+            return Ok(());
+        };
+        let url = format!(
+            "{}/{}/{}/{}",
+            self.server_address,
+            endpoint.trim_matches('/'),
+            self.layout_key,
+            layout_path
+        );
+        let part = reqwest::multipart::Part::bytes(thumbnail.clone())
+            .file_name("thumbnail.jpg")
+            .mime_str(TileFormat::Jpeg.mime_type())
+            .unwrap();
+        let form = reqwest::multipart::Form::new().part("file", part);
+        self.auth
+            .authorize(self.client.post(&url), "__sc__", &self.secret)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?;
+        tracing::debug!(layout_path, bytes = thumbnail.len(), "Thumbnail uploaded");
+        Ok(())
+    }
+
+    /// Point the layout at its tiles. `base_url`, when the tiles are stored
+    /// somewhere other than the server, is sent along as `BaseUrl`.
+    pub(crate) async fn finalize(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+        base_url: Option<&str>,
+    ) -> Result<(), ProcessError> {
+        let max_zoom = manifest.max_zoom;
+        let started = Instant::now();
+        let result = self.finalize_upload(layout_path, manifest, base_url).await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+
+        match &result {
+            Ok(()) => tracing::info!(layout_path, max_zoom, elapsed_ms, "Layout finalized"),
+            Err(e) => tracing::error!(
+                layout_path,
+                max_zoom,
+                status = ?e.status().map(|status| status.as_u16()),
+                elapsed_ms,
+                error = %e,
+                "Finalize failed"
+            ),
+        }
+        result.map_err(|e| ProcessError::http("Failed to finalize upload", e))
+    }
+
+    async fn finalize_upload(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+        base_url: Option<&str>,
+    ) -> Result<(), reqwest::Error> {
+        self.send_manifest(manifest).await?;
+        self.send_thumbnail(layout_path, manifest).await?;
+
+        let url = format!(
+            "{}/{}",
+            self.server_address,
+            self.finalize_endpoint.trim_start_matches('/')
+        );
+
+        // The extra parameters can't override the ones the server relies on
+        let mut params = self.finalize_params.clone();
+        params.insert("LayoutKey".to_string(), self.layout_key.clone());
+        params.insert("LayoutPath".to_string(), layout_path.to_string());
+        params.insert("MaxZoom".to_string(), manifest.max_zoom.to_string());
+        if let Some(layer) = &self.layer {
+            params.insert("Layer".to_string(), layer.clone());
+        }
+        // Only sent when levels were left out, so full pyramids finalize as before
+        let min_zoom = Some(manifest.min_zoom).filter(|&min_zoom| min_zoom > 0);
+        if let Some(min_zoom) = min_zoom {
+            params.insert("MinZoom".to_string(), min_zoom.to_string());
+        }
+        if let Some(base_url) = base_url {
+            params.insert("BaseUrl".to_string(), base_url.to_string());
+        }
+        // Only sent for tiles that aren't square, whose size the viewer can't
+        // take from one number
+        let tile_size = Some(manifest.tile_size).filter(|tile_size| !tile_size.is_square());
+        if let Some(tile_size) = tile_size {
+            params.insert("TileWidth".to_string(), tile_size.width.to_string());
+            params.insert("TileHeight".to_string(), tile_size.height.to_string());
+        }
+        // Only sent for overlapping tiles, so other layouts finalize as before
+        let overlap = Some(manifest.overlap).filter(|&overlap| overlap > 0);
+        if let Some(overlap) = overlap {
+            params.insert("Overlap".to_string(), overlap.to_string());
+        }
+        let offset = manifest.image_offset_x.zip(manifest.image_offset_y);
+        if let Some((offset_x, offset_y)) = offset {
+            params.insert("OffsetX".to_string(), offset_x.to_string());
+            params.insert("OffsetY".to_string(), offset_y.to_string());
+        }
+        // Every level's offset as `zoom:x,y` separated by `;`, since they are
+        // rounded level by level rather than halving exactly
+        if !manifest.image_offsets.is_empty() {
+            let level_offsets: Vec<_> = manifest
+                .image_offsets
+                .iter()
+                .map(|(zoom_level, (x, y))| format!("{}:{},{}", zoom_level, x, y))
+                .collect();
+            params.insert("LevelOffsets".to_string(), level_offsets.join(";"));
+        }
+
+        let request = match self.finalize_method {
+            FinalizeMethod::Get => self.client.get(&url).query(&params),
+            FinalizeMethod::Post => {
+                let mut body: serde_json::Map<String, serde_json::Value> = params
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect();
+                body.insert("MaxZoom".to_string(), manifest.max_zoom.into());
+                if let Some(min_zoom) = min_zoom {
+                    body.insert("MinZoom".to_string(), min_zoom.into());
+                }
+                if let Some(overlap) = overlap {
+                    body.insert("Overlap".to_string(), overlap.into());
+                }
+                if let Some(tile_size) = tile_size {
+                    body.insert("TileWidth".to_string(), tile_size.width.into());
+                    body.insert("TileHeight".to_string(), tile_size.height.into());
+                }
+                if let Some((offset_x, offset_y)) = offset {
+                    body.insert("OffsetX".to_string(), offset_x.into());
+                    body.insert("OffsetY".to_string(), offset_y.into());
+                }
+                if !manifest.image_offsets.is_empty() {
+                    body.insert(
+                        "LevelOffsets".to_string(),
+                        serde_json::json!(manifest.image_offsets),
+                    );
+                }
+                self.client.post(&url).json(&body)
+            }
+        };
+        self.auth
+            .authorize(request, "apikey", &self.secret)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TileSink for HttpSink {
+    async fn put_tiles(&self, layout_path: &str, mut tiles: Vec<Tile>) -> Result<(), ProcessError> {
+        let endpoint = self
+            .batch_endpoint
+            .as_deref()
+            .filter(|_| self.batch_supported.load(Ordering::Relaxed));
+        if let Some(endpoint) = endpoint {
+            // Duplicates of tiles already stored go as references instead
+            let mut rest = Vec::new();
+            for tile in tiles {
+                if !self
+                    .put_reference(layout_path, &tile, &tile.checksum())
+                    .await?
+                {
+                    rest.push(tile);
+                }
+            }
+            tiles = rest;
+            if tiles.is_empty() {
+                return Ok(());
+            }
+            if self.upload_batch(endpoint, layout_path, &tiles).await? {
+                for tile in &tiles {
+                    self.remember(tile.checksum(), (tile.zoom_level, tile.x, tile.y));
+                }
+                return Ok(());
+            }
+            if self.batch_supported.swap(false, Ordering::Relaxed) {
+                tracing::warn!("The server has no batch endpoint, uploading tiles one by one");
+            }
+        }
+        for tile in tiles {
+            self.put_tile(layout_path, tile).await?;
+        }
+        Ok(())
+    }
+
+    async fn put_tile(&self, layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        let (zoom_level, x, y, bytes) = (tile.zoom_level, tile.x, tile.y, tile.data.len());
+        let checksum = tile.checksum();
+        if self.put_reference(layout_path, &tile, &checksum).await? {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let result = self.upload_tile(layout_path, tile, &checksum).await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+
+        match result {
+            Ok(Err(retry_after)) => {
+                tracing::warn!(
+                    zoom_level,
+                    x,
+                    y,
+                    retry_after,
+                    elapsed_ms,
+                    "Tile upload throttled"
+                );
+                Err(ProcessError::Throttled {
+                    context: "Upload failed".to_string(),
+                    retry_after,
+                })
+            }
+            Ok(Ok(response)) => {
+                let status = response.status().as_u16();
+                if self.verify_checksums {
+                    let received = Self::received_checksum(&response);
+                    if received.as_deref() != Some(checksum.as_str()) {
+                        tracing::warn!(
+                            zoom_level,
+                            x,
+                            y,
+                            status,
+                            expected = %checksum,
+                            received = ?received,
+                            "Tile checksum mismatch"
+                        );
+                        return Err(ProcessError::ChecksumMismatch {
+                            context: format!("Tile {}/{}/{}", zoom_level, x, y),
+                            expected: checksum,
+                            received,
+                        });
+                    }
+                }
+                tracing::debug!(zoom_level, x, y, bytes, status, elapsed_ms, "Tile uploaded");
+                self.remember(checksum, (zoom_level, x, y));
+                Ok(())
+            }
+            Err(e) => {
+                tracing::warn!(
+                    zoom_level,
+                    x,
+                    y,
+                    bytes,
+                    status = ?e.status().map(|status| status.as_u16()),
+                    elapsed_ms,
+                    error = %e,
+                    "Tile upload failed"
+                );
+                Err(ProcessError::http("Upload failed", e))
+            }
+        }
+    }
+
+    async fn finish(
+        &self,
+        layout_path: &str,
+        manifest: &UploadManifest,
+    ) -> Result<(), ProcessError> {
+        self.finalize(layout_path, manifest, None).await
+    }
+
+    async fn discard(&self, layout_path: &str) -> Result<bool, ProcessError> {
+        let Some(endpoint) = &self.cleanup_endpoint else {
+            return Ok(false);
+        };
+        let url = format!(
+            "{}/{}",
+            self.server_address,
+            endpoint.trim_start_matches('/')
+        );
+        let params = [
+            ("LayoutKey", self.layout_key.as_str()),
+            ("LayoutPath", layout_path),
+        ];
+
+        let result = self
+            .auth
+            .authorize(self.client.delete(&url), "apikey", &self.secret)
+            .query(&params)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                tracing::info!(layout_path, "Abandoned layout path removed");
+                Ok(true)
+            }
+            Err(e) => {
+                tracing::warn!(layout_path, error = %e, "Cleanup failed");
+                Err(ProcessError::http("Failed to remove abandoned tiles", e))
+            }
+        }
+    }
+
+    // Any answer short of a 5xx means the server is back
+    async fn probe(&self) -> Result<(), ProcessError> {
+        let response = self
+            .client
+            .get(&self.server_address)
+            .send()
+            .await
+            .map_err(|e| ProcessError::http("Server unreachable", e))?;
+        match response.error_for_status() {
+            Err(e) if e.status().is_some_and(|status| status.is_server_error()) => {
+                Err(ProcessError::http("Server unavailable", e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Stands in for the server on a dry run. Tiles are written under `output`
+/// as `{layout_path}/{zoom}/{x}/{y}.{ext}`, or dropped when there is none.
+pub struct DryRunSink {
+    output: Option<PathBuf>,
+}
+
+impl DryRunSink {
+    pub fn new(output: Option<PathBuf>) -> Self {
+        Self { output }
+    }
+}
+
+#[async_trait]
+impl TileSink for DryRunSink {
+    async fn put_tile(&self, layout_path: &str, tile: Tile) -> Result<(), ProcessError> {
+        match &self.output {
+            Some(output) => write_tile(&output.join(layout_path), &tile).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn finish(
+        &self,
+        _layout_path: &str,
+        _manifest: &UploadManifest,
+    ) -> Result<(), ProcessError> {
+        Ok(())
+    }
+}
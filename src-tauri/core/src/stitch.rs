@@ -0,0 +1,93 @@
+//! Stitching: `image_path` and the further images of `config.stitch` laid
+//! out on one canvas, which `decode` hands on as the source image. The
+//! layout only needs the images' sizes, so headers and decoding agree on
+//! it.
+
+use std::iter;
+
+use crate::config::{ProcessConfig, Stitch, StitchPlacement};
+use crate::error::ProcessError;
+
+/// A config for reading each image of `stitch` on its own, `image_path`
+/// first.
+pub(crate) fn parts(config: &ProcessConfig, stitch: &Stitch) -> Vec<ProcessConfig> {
+    iter::once(config.image_path.clone())
+        .chain(stitch.images.iter().map(|image| image.path.clone()))
+        .map(|image_path| ProcessConfig {
+            image_path,
+            stitch: None,
+            ..config.clone()
+        })
+        .collect()
+}
+
+/// The canvas the images of a stitch make and where each goes on it.
+pub(crate) struct Arrangement {
+    pub width: u32,
+    pub height: u32,
+    // Top-left corner of each image, in the order of `parts`
+    pub positions: Vec<(u32, u32)>,
+}
+
+/// Lay out images of `sizes`, in the order of `parts`, as `stitch` says.
+pub(crate) fn arrange(stitch: &Stitch, sizes: &[(u32, u32)]) -> Result<Arrangement, ProcessError> {
+    let origins: Vec<(i64, i64)> = match stitch.placement {
+        StitchPlacement::Grid { columns } => {
+            let columns = columns.max(1) as usize;
+            let mut widths = vec![0i64; columns];
+            let mut heights = vec![0i64; sizes.len().div_ceil(columns)];
+            for (i, &(width, height)) in sizes.iter().enumerate() {
+                widths[i % columns] = widths[i % columns].max(width as i64);
+                heights[i / columns] = heights[i / columns].max(height as i64);
+            }
+            let starts = |sides: &[i64]| -> Vec<i64> {
+                sides
+                    .iter()
+                    .scan(0, |start, side| {
+                        let this = *start;
+                        *start += side;
+                        Some(this)
+                    })
+                    .collect()
+            };
+            let (lefts, tops) = (starts(&widths), starts(&heights));
+            (0..sizes.len())
+                .map(|i| (lefts[i % columns], tops[i / columns]))
+                .collect()
+        }
+        StitchPlacement::Offsets => iter::once((0, 0))
+            .chain(stitch.images.iter().map(|image| (image.x, image.y)))
+            .collect(),
+    };
+
+    // The canvas is the bounding box of all the images
+    let bounds = origins.iter().zip(sizes);
+    let left = bounds.clone().map(|((x, _), _)| *x).min().unwrap_or(0);
+    let top = bounds.clone().map(|((_, y), _)| *y).min().unwrap_or(0);
+    let right = bounds
+        .clone()
+        .map(|((x, _), (width, _))| x + *width as i64)
+        .max()
+        .unwrap_or(0);
+    let bottom = bounds
+        .map(|((_, y), (_, height))| y + *height as i64)
+        .max()
+        .unwrap_or(0);
+    let (Ok(width), Ok(height)) = (u32::try_from(right - left), u32::try_from(bottom - top)) else {
+        return Err(ProcessError::InvalidConfig(format!(
+            "The stitched images span {}x{} pixels, too large for one canvas",
+            right - left,
+            bottom - top
+        )));
+    };
+
+    let positions = origins
+        .iter()
+        .map(|(x, y)| ((x - left) as u32, (y - top) as u32))
+        .collect();
+    Ok(Arrangement {
+        width,
+        height,
+        positions,
+    })
+}
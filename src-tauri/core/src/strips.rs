@@ -0,0 +1,544 @@
+//! The low-memory pipeline: the decoded image is moved to a temp file and
+//! each level is rendered a row of tiles at a time from strips of it, so
+//! no RGBA copy of the source or whole level is held while tiling. Decoding
+//! isn't incremental: the image is decoded and prepared whole first, and
+//! that decode is the run's peak.
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::background::run_background;
+use crate::config::{Color, ProcessConfig, TileSize};
+use crate::decode::open_image;
+use crate::error::ProcessError;
+use crate::estimate::{sample_origins, sample_tiles};
+use crate::linear::{alpha_byte, linear_table, to_srgb};
+use crate::processor::{LevelSize, TileProcessor};
+
+/// Roughly the most source rows read at once.
+pub(crate) const STRIP_ROWS: u32 = 512;
+
+/// Raw RGBA pixels in a temp file, read and written by whole rows. The
+/// file is removed when this is dropped.
+pub(crate) struct DiskImage {
+    path: PathBuf,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl DiskImage {
+    pub(crate) fn create(width: u32, height: u32) -> Result<Self, ProcessError> {
+        let path =
+            std::env::temp_dir().join(format!("layout-uploader-{}.rgba", uuid::Uuid::new_v4()));
+        File::create(&path)
+            .and_then(|file| file.set_len(width as u64 * height as u64 * 4))
+            .map_err(|e| ProcessError::io("Failed to create low-memory image", e))?;
+        Ok(Self {
+            path,
+            width,
+            height,
+        })
+    }
+
+    /// Spill `image` to a temp file a strip at a time, converting it to
+    /// RGBA on the way.
+    pub(crate) fn from_image(image: &DynamicImage) -> Result<Self, ProcessError> {
+        let disk = Self::create(image.width(), image.height())?;
+        for y in (0..image.height()).step_by(STRIP_ROWS as usize) {
+            let rows = STRIP_ROWS.min(image.height() - y);
+            disk.write_rows(y, &image.crop_imm(0, y, image.width(), rows).to_rgba8())?;
+        }
+        Ok(disk)
+    }
+
+    fn offset(&self, y: u32) -> u64 {
+        y as u64 * self.width as u64 * 4
+    }
+
+    /// Store `rows`, as wide as the image, starting at row `y`.
+    pub(crate) fn write_rows(&self, y: u32, rows: &RgbaImage) -> Result<(), ProcessError> {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(self.offset(y)))?;
+                file.write_all(rows.as_raw())
+            })
+            .map_err(|e| ProcessError::io("Failed to write low-memory image", e))
+    }
+
+    /// `rows` rows starting at row `y`.
+    pub(crate) fn read_rows(&self, y: u32, rows: u32) -> Result<RgbaImage, ProcessError> {
+        let mut data = vec![0; rows as usize * self.width as usize * 4];
+        File::open(&self.path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(self.offset(y)))?;
+                file.read_exact(&mut data)
+            })
+            .map_err(|e| ProcessError::io("Failed to read low-memory image", e))?;
+        ImageBuffer::from_raw(self.width, rows, data).ok_or_else(|| {
+            ProcessError::Internal("Low-memory strip has the wrong size".to_string())
+        })
+    }
+}
+
+impl Drop for DiskImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// What a run resizes its levels from: the decoded image, and the last
+/// level generated while each level is resized from the one before it.
+pub(crate) enum LevelSource {
+    Memory {
+        image: RgbaImage,
+        previous: Option<RgbaImage>,
+    },
+    /// The same in temp files, for `low_memory` runs. `next` collects the
+    /// level being rendered, a band at a time.
+    Disk {
+        image: DiskImage,
+        previous: Option<DiskImage>,
+        next: Option<DiskImage>,
+    },
+}
+
+impl LevelSource {
+    /// Decode `config.image_path`, and with `low_memory` move it to a temp
+    /// file. The decoder still needs the whole image once, in its own pixel
+    /// format and through `prepare`'s steps, so this is where a low-memory
+    /// run peaks; the pixels are freed once they are on disk. Also returns
+    /// the run's background color, which `auto_background` takes from the
+    /// image while it is at hand.
+    pub(crate) fn open(
+        config: &ProcessConfig,
+        low_memory: bool,
+    ) -> Result<(Self, Color), ProcessError> {
+        let img = open_image(config)?;
+        let background = run_background(config, &img);
+        let source = if low_memory {
+            Self::Disk {
+                image: DiskImage::from_image(&img)?,
+                previous: None,
+                next: None,
+            }
+        } else {
+            Self::Memory {
+                image: img.into_rgba8(),
+                previous: None,
+            }
+        };
+        Ok((source, background))
+    }
+
+    pub(crate) fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Self::Memory { image, .. } => image.dimensions(),
+            Self::Disk { image, .. } => (image.width, image.height),
+        }
+    }
+
+    /// Full-resolution tiles from around the source, see
+    /// `estimate::sample_tiles`; from disk only their rows are read.
+    pub(crate) fn sample_tiles(&self, tile_size: TileSize) -> Result<Vec<RgbaImage>, ProcessError> {
+        match self {
+            Self::Memory { image, .. } => Ok(sample_tiles(image, tile_size)),
+            Self::Disk { image, .. } => sample_origins(image.width, image.height, tile_size)
+                .map(|(x, y)| {
+                    let rows = image.read_rows(y, tile_size.height.min(image.height - y))?;
+                    Ok(imageops::crop_imm(&rows, x, 0, tile_size.width, rows.height()).to_image())
+                })
+                .collect(),
+        }
+    }
+
+    /// The source shrunk to fit `max_size` on its longer side, resized as
+    /// a level would be; smaller images are left as they are. From disk it
+    /// is resized a strip at a time.
+    pub(crate) fn overview(
+        &self,
+        processor: &TileProcessor,
+        max_size: u32,
+    ) -> Result<RgbaImage, ProcessError> {
+        let (width, height) = self.dimensions();
+        let scale_factor = (max_size as f64 / width.max(height) as f64).min(1.0);
+        let (width, height) = (
+            ((width as f64 * scale_factor).round() as u32).max(1),
+            ((height as f64 * scale_factor).round() as u32).max(1),
+        );
+        // A level of just the image, without padding
+        let size = LevelSize {
+            scale_factor,
+            width,
+            height,
+            padded_width: width,
+            padded_height: height,
+            x_offset: 0,
+            y_offset: 0,
+        };
+        match self {
+            Self::Memory { image, .. } => Ok(processor.resize_level(image, &size)),
+            Self::Disk { image, .. } => processor.resize_rows(image, &size, 0, size.height),
+        }
+    }
+
+    /// Whether levels are rendered a band of rows at a time rather than
+    /// whole.
+    pub(crate) fn is_banded(&self) -> bool {
+        matches!(self, Self::Disk { .. })
+    }
+
+    /// Rows `band_y..band_y + band_height` of the padded level `size`; in
+    /// memory the whole level is rendered whatever the band. With `chain`
+    /// the next level is resized from this one.
+    pub(crate) fn render(
+        &mut self,
+        processor: &TileProcessor,
+        size: &LevelSize,
+        band_y: u32,
+        band_height: u32,
+        background: Rgba<u8>,
+        chain: bool,
+    ) -> Result<RgbaImage, ProcessError> {
+        match self {
+            Self::Memory { image, previous } => {
+                // An upscaled level is no better a source than the original
+                let source = match previous.as_ref() {
+                    Some(previous) if previous.width() < image.width() => previous,
+                    _ => &*image,
+                };
+                let (padded, scaled) = processor.render_level(source, size, background);
+                if chain {
+                    *previous = Some(scaled);
+                }
+                Ok(padded)
+            }
+            Self::Disk {
+                image,
+                previous,
+                next,
+            } => {
+                let source = match previous.as_ref() {
+                    Some(previous) if previous.width < image.width => previous,
+                    _ => &*image,
+                };
+                let mut band = ImageBuffer::from_pixel(size.padded_width, band_height, background);
+                // Rows of the resized image inside the band
+                let top = band_y.max(size.y_offset);
+                let bottom = (band_y + band_height).min(size.y_offset + size.height);
+                if top < bottom && size.width > 0 {
+                    // Sharpening needs the rows around the band too, so it
+                    // comes out as it would on the whole level
+                    let (first, last) = (top - size.y_offset, bottom - size.y_offset);
+                    let margin = processor.sharpen_margin(size);
+                    let from = first.saturating_sub(margin);
+                    let to = (last + margin).min(size.height);
+                    let resized = processor.resize_rows(source, size, from, to)?;
+                    let inner = |image: &RgbaImage| {
+                        imageops::crop_imm(image, 0, first - from, size.width, last - first)
+                            .to_image()
+                    };
+                    let sharpened = processor
+                        .sharpened(&resized, size)
+                        .map(|sharpened| inner(&sharpened));
+                    let rows = if (from, to) == (first, last) {
+                        resized
+                    } else {
+                        inner(&resized)
+                    };
+                    processor.paste(
+                        &mut band,
+                        sharpened.as_ref().unwrap_or(&rows),
+                        size.x_offset,
+                        top - band_y,
+                    );
+                    if chain {
+                        if next.is_none() {
+                            *next = Some(DiskImage::create(size.width, size.height)?);
+                        }
+                        if let Some(next) = next {
+                            next.write_rows(top - size.y_offset, &rows)?;
+                        }
+                    }
+                }
+                Ok(band)
+            }
+        }
+    }
+
+    /// Every band of the level has been rendered.
+    pub(crate) fn finish_level(&mut self) {
+        if let Self::Disk { previous, next, .. } = self {
+            if let Some(next) = next.take() {
+                *previous = Some(next);
+            }
+        }
+    }
+
+    /// The level came from the tile cache without being rendered, so the
+    /// next one is resized from the source.
+    pub(crate) fn skip_level(&mut self) {
+        match self {
+            Self::Memory { previous, .. } => *previous = None,
+            Self::Disk { previous, next, .. } => {
+                *previous = None;
+                *next = None;
+            }
+        }
+    }
+}
+
+impl TileProcessor {
+    /// Rows `top..bottom` of the resized image of `size`, resized from
+    /// `source` a strip at a time. The pixels are weighed exactly as
+    /// `imageops::resize` weighs them, so they match a level resized whole.
+    fn resize_rows(
+        &self,
+        source: &DiskImage,
+        size: &LevelSize,
+        top: u32,
+        bottom: u32,
+    ) -> Result<RgbaImage, ProcessError> {
+        // `imageops::resize` copies an image it isn't asked to resize
+        if (source.width, source.height) == (size.width, size.height) {
+            return source.read_rows(top, bottom - top);
+        }
+
+        let filter = self.level_filter(size);
+        let linear = self.resizes_linear(size);
+        // What each channel's bytes are resized as: themselves, or with
+        // `linear_resize` linear light and alpha from 0 to 1
+        let decode: [[f32; 256]; 4] = if linear {
+            let table = linear_table();
+            [
+                table,
+                table,
+                table,
+                std::array::from_fn(|a| a as f32 / 255.0),
+            ]
+        } else {
+            [std::array::from_fn(|value| value as f32); 4]
+        };
+        let columns: Vec<Taps> = (0..size.width)
+            .map(|x| Taps::new(filter, source.width, size.width, x))
+            .collect();
+        let ratio = source.height as f64 / size.height as f64;
+        let piece = ((STRIP_ROWS as f64 / ratio) as u32).max(1);
+        let source_stride = source.width as usize * 4;
+        let stride = size.width as usize * 4;
+
+        let mut rows = RgbaImage::new(size.width, bottom - top);
+        // One row resized vertically, not yet horizontally or rounded
+        let mut line = vec![0.0_f32; source_stride];
+        let mut y = top;
+        while y < bottom {
+            let end = (y + piece).min(bottom);
+            let row_taps: Vec<Taps> = (y..end)
+                .map(|row| Taps::new(filter, source.height, size.height, row))
+                .collect();
+            let strip_top = row_taps.iter().map(|taps| taps.first).min().unwrap_or(0);
+            let strip_bottom = row_taps.iter().map(Taps::end).max().unwrap_or(0);
+            let strip = source.read_rows(strip_top, strip_bottom - strip_top)?;
+
+            for (row, taps) in (y..end).zip(&row_taps) {
+                line.fill(0.0);
+                for (i, weight) in taps.weights.iter().enumerate() {
+                    let start =
+                        (taps.first - strip_top) as usize * source_stride + i * source_stride;
+                    for (i, (value, &byte)) in line
+                        .iter_mut()
+                        .zip(&strip.as_raw()[start..start + source_stride])
+                        .enumerate()
+                    {
+                        *value += decode[i % 4][byte as usize] * weight;
+                    }
+                }
+
+                let start = (row - top) as usize * stride;
+                let out = &mut (*rows)[start..start + stride];
+                for (pixel, taps) in out.chunks_exact_mut(4).zip(&columns) {
+                    let mut sum = [0.0_f32; 4];
+                    for (i, weight) in taps.weights.iter().enumerate() {
+                        let x = (taps.first as usize + i) * 4;
+                        for (channel, value) in sum.iter_mut().zip(&line[x..x + 4]) {
+                            *channel += value * weight;
+                        }
+                    }
+                    for (i, (byte, channel)) in pixel.iter_mut().zip(sum).enumerate() {
+                        *byte = match (linear, i) {
+                            (false, _) => channel.clamp(0.0, 255.0).round() as u8,
+                            (true, 3) => alpha_byte(channel),
+                            (true, _) => to_srgb(channel),
+                        };
+                    }
+                }
+            }
+            y = end;
+        }
+        Ok(rows)
+    }
+}
+
+/// The source pixels one resized pixel is made from along an axis, and
+/// their normalized weights.
+struct Taps {
+    first: u32,
+    weights: Vec<f32>,
+}
+
+impl Taps {
+    /// Taps of pixel `out` when `source_len` pixels are resized to
+    /// `target_len`, worked out the way `imageops::resize` does.
+    fn new(filter: FilterType, source_len: u32, target_len: u32, out: u32) -> Self {
+        let (kernel, support): (fn(f32) -> f32, f32) = match filter {
+            FilterType::Nearest => (|_| 1.0, 0.0),
+            FilterType::Triangle => (triangle, 1.0),
+            FilterType::CatmullRom => (catmull_rom, 2.0),
+            FilterType::Gaussian => (gaussian, 3.0),
+            FilterType::Lanczos3 => (lanczos3, 3.0),
+        };
+        let ratio = source_len as f32 / target_len as f32;
+        let sratio = ratio.max(1.0);
+        let source_support = support * sratio;
+
+        let centre = (out as f32 + 0.5) * ratio;
+        let first = ((centre - source_support).floor() as i64).clamp(0, source_len as i64 - 1);
+        let end = ((centre + source_support).ceil() as i64).clamp(first + 1, source_len as i64);
+        let mut weights: Vec<f32> = (first..end)
+            .map(|i| kernel((i as f32 - (centre - 0.5)) / sratio))
+            .collect();
+        let sum: f32 = weights.iter().sum();
+        for weight in &mut weights {
+            *weight /= sum;
+        }
+        Self {
+            first: first as u32,
+            weights,
+        }
+    }
+
+    fn end(&self) -> u32 {
+        self.first + self.weights.len() as u32
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let a = x * std::f32::consts::PI;
+        a.sin() / a
+    }
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() < 3.0 {
+        sinc(x) * sinc(x / 3.0)
+    } else {
+        0.0
+    }
+}
+
+/// Gaussian with a standard deviation of 0.5.
+fn gaussian(x: f32) -> f32 {
+    let r = 0.5_f32;
+    ((2.0 * std::f32::consts::PI).sqrt() * r).recip() * (-x.powi(2) / (2.0 * r.powi(2))).exp()
+}
+
+/// The Catmull-Rom cubic spline.
+fn catmull_rom(x: f32) -> f32 {
+    let a = x.abs();
+    if a < 1.0 {
+        (9.0 * a.powi(3) - 15.0 * a.powi(2) + 6.0) / 6.0
+    } else if a < 2.0 {
+        (-3.0 * a.powi(3) + 15.0 * a.powi(2) - 24.0 * a + 12.0) / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn triangle(x: f32) -> f32 {
+    (1.0 - x.abs()).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ResizeFilter;
+
+    fn processor(filter: ResizeFilter, linear_resize: bool) -> TileProcessor {
+        let mut config = ProcessConfig::new(
+            "plan.png".to_string(),
+            "http://localhost".to_string(),
+            "plan".to_string(),
+            String::new(),
+        );
+        config.resize_filter = filter;
+        config.linear_resize = linear_resize;
+        TileProcessor::new(&config).unwrap()
+    }
+
+    fn size(width: u32, height: u32, scale_factor: f64) -> LevelSize {
+        LevelSize {
+            scale_factor,
+            width,
+            height,
+            padded_width: width,
+            padded_height: height,
+            x_offset: 0,
+            y_offset: 0,
+        }
+    }
+
+    #[test]
+    fn strips_resize_like_imageops() {
+        // Sharp edges, gradients and partly transparent pixels
+        let source = RgbaImage::from_fn(61, 47, |x, y| {
+            Rgba([
+                (x * 37 + y * 11) as u8,
+                if (x / 5 + y / 3) % 2 == 0 { 255 } else { 0 },
+                (x * y) as u8,
+                (255 - y * 3) as u8,
+            ])
+        });
+        let disk = DiskImage::from_image(&DynamicImage::ImageRgba8(source.clone())).unwrap();
+
+        for filter in [
+            ResizeFilter::Nearest,
+            ResizeFilter::Triangle,
+            ResizeFilter::CatmullRom,
+            ResizeFilter::Gaussian,
+            ResizeFilter::Lanczos3,
+        ] {
+            for linear_resize in [false, true] {
+                let processor = processor(filter, linear_resize);
+                for size in [size(23, 17, 0.38), size(150, 113, 2.4)] {
+                    let whole = processor.resize_level(&source, &size);
+                    // In pieces, so rows are also weighed across strip edges
+                    let mut top = 0;
+                    while top < size.height {
+                        let bottom = (top + 7).min(size.height);
+                        let rows = processor.resize_rows(&disk, &size, top, bottom).unwrap();
+                        let expected =
+                            imageops::crop_imm(&whole, 0, top, size.width, bottom - top).to_image();
+                        assert!(
+                            rows == expected,
+                            "{:?}, linear {}, {}x{} rows {}..{}",
+                            filter,
+                            linear_resize,
+                            size.width,
+                            size.height,
+                            top,
+                            bottom
+                        );
+                        top = bottom;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::config::{OutputTarget, ProcessConfig};
+use crate::error::ProcessError;
+use crate::sink::viewer_url;
+
+/// What a finished run produced: returned to the caller, sent with the
+/// completion event, and written to `summary_path` when one is set.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub layout_path: String,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub zoom_levels: u32,
+    pub tiles_uploaded: u32,
+    pub tiles_skipped: u32,
+    // Tiles an incremental run left alone because they hadn't changed
+    pub tiles_unchanged: u32,
+    pub bytes_uploaded: u64,
+    pub average_tile_kb: f64,
+    // Tiles identical to one uploaded earlier in the run, and their bytes;
+    // with `tile_ref_endpoint` they were sent as references
+    pub duplicate_tiles: u32,
+    pub duplicate_bytes: u64,
+    // JPEG or WebP quality picked to keep within `size_budget_mb`
+    pub budget_quality: Option<u8>,
+    pub tiles_per_zoom: BTreeMap<u32, ZoomSummary>,
+    // Uploads sent again: throttled, waited out on the circuit breaker, or
+    // on the retry pass
+    pub retries: u32,
+    pub phases: PhaseTimings,
+    // Settings that weakened the run's security, see `NetworkSettings::warnings`,
+    // and settings the run overrode
+    pub warnings: Vec<String>,
+    // Where the finalized layout can be viewed, see `sink::viewer_url`
+    pub viewer_url: Option<String>,
+}
+
+/// The tiles of one zoom level. Tiles stored by an earlier, interrupted
+/// run aren't counted.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ZoomSummary {
+    pub tiles_uploaded: u32,
+    pub tiles_skipped: u32,
+    pub tiles_unchanged: u32,
+    pub bytes_uploaded: u64,
+}
+
+/// Wall-clock seconds spent in each phase of a run. Encoding and uploading
+/// overlap, so they are timed together.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PhaseTimings {
+    pub load_secs: f64,
+    pub resize_secs: f64,
+    pub tiles_secs: f64,
+    pub retry_secs: f64,
+    pub finalize_secs: f64,
+    pub total_secs: f64,
+}
+
+impl RunSummary {
+    /// Write the summary as JSON to `path`, or into it as
+    /// `{layout_path}.summary.json` if it is a folder, so the images of a
+    /// batch each get their own.
+    pub fn write(&self, path: &Path) -> Result<(), ProcessError> {
+        let path = if path.is_dir() {
+            path.join(format!("{}.summary.json", self.layout_path))
+        } else {
+            path.to_path_buf()
+        };
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| ProcessError::Internal(format!("Failed to serialize summary: {}", e)))?;
+        std::fs::write(&path, json).map_err(|e| ProcessError::io("Failed to write run summary", e))
+    }
+}
+
+/// Write a finished run's summary to `config.summary_path`, if set. The
+/// layout is already live by then, so a failure is only logged.
+pub(crate) fn save_summary(result: &Result<RunSummary, ProcessError>, config: &ProcessConfig) {
+    if let (Ok(summary), Some(path)) = (result, &config.summary_path) {
+        if let Err(e) = summary.write(Path::new(path)) {
+            tracing::warn!(path = %path, error = %e, "Failed to write run summary");
+        }
+    }
+}
+
+/// Collects a run's numbers as it goes.
+pub(crate) struct SummaryBuilder {
+    started: Instant,
+    phase_started: Instant,
+    per_zoom: BTreeMap<u32, ZoomSummary>,
+    phases: PhaseTimings,
+    warnings: Vec<String>,
+    budget_quality: Option<u8>,
+    // Checksums of the tiles uploaded so far, to spot repeats
+    checksums: HashSet<String>,
+    duplicate_tiles: u32,
+    duplicate_bytes: u64,
+}
+
+impl SummaryBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            phase_started: Instant::now(),
+            per_zoom: BTreeMap::new(),
+            phases: PhaseTimings::default(),
+            warnings: Vec::new(),
+            budget_quality: None,
+            checksums: HashSet::new(),
+            duplicate_tiles: 0,
+            duplicate_bytes: 0,
+        }
+    }
+
+    /// Seconds since the last call, or since the start.
+    pub(crate) fn lap(&mut self) -> f64 {
+        let now = Instant::now();
+        let secs = now.duration_since(self.phase_started).as_secs_f64();
+        self.phase_started = now;
+        secs
+    }
+
+    pub(crate) fn phases(&mut self) -> &mut PhaseTimings {
+        &mut self.phases
+    }
+
+    pub(crate) fn uploaded(&mut self, zoom_level: u32, bytes: u64, checksum: &str) {
+        let zoom = self.per_zoom.entry(zoom_level).or_default();
+        zoom.tiles_uploaded += 1;
+        zoom.bytes_uploaded += bytes;
+        if !self.checksums.insert(checksum.to_string()) {
+            self.duplicate_tiles += 1;
+            self.duplicate_bytes += bytes;
+        }
+    }
+
+    pub(crate) fn skipped(&mut self, zoom_level: u32) {
+        self.per_zoom.entry(zoom_level).or_default().tiles_skipped += 1;
+    }
+
+    /// Note something about the run that the user should know, such as a
+    /// setting it overrode.
+    pub(crate) fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    pub(crate) fn set_budget_quality(&mut self, quality: Option<u8>) {
+        self.budget_quality = quality;
+    }
+
+    pub(crate) fn unchanged(&mut self, zoom_level: u32) {
+        self.per_zoom.entry(zoom_level).or_default().tiles_unchanged += 1;
+    }
+
+    pub(crate) fn finish(
+        mut self,
+        layout_path: String,
+        min_zoom: u32,
+        max_zoom: u32,
+        retries: u32,
+        config: &ProcessConfig,
+    ) -> RunSummary {
+        let totals = self
+            .per_zoom
+            .values()
+            .fold(ZoomSummary::default(), |total, zoom| ZoomSummary {
+                tiles_uploaded: total.tiles_uploaded + zoom.tiles_uploaded,
+                tiles_skipped: total.tiles_skipped + zoom.tiles_skipped,
+                tiles_unchanged: total.tiles_unchanged + zoom.tiles_unchanged,
+                bytes_uploaded: total.bytes_uploaded + zoom.bytes_uploaded,
+            });
+        self.phases.total_secs = self.started.elapsed().as_secs_f64();
+        let average_tile_kb = if totals.tiles_uploaded == 0 {
+            0.0
+        } else {
+            totals.bytes_uploaded as f64 / totals.tiles_uploaded as f64 / 1024.0
+        };
+        RunSummary {
+            viewer_url: viewer_url(config, &layout_path),
+            layout_path,
+            min_zoom,
+            max_zoom,
+            zoom_levels: max_zoom - min_zoom + 1,
+            tiles_uploaded: totals.tiles_uploaded,
+            tiles_skipped: totals.tiles_skipped,
+            tiles_unchanged: totals.tiles_unchanged,
+            bytes_uploaded: totals.bytes_uploaded,
+            average_tile_kb,
+            duplicate_tiles: self.duplicate_tiles,
+            duplicate_bytes: self.duplicate_bytes,
+            budget_quality: self.budget_quality,
+            tiles_per_zoom: self.per_zoom,
+            retries,
+            phases: self.phases,
+            warnings: config
+                .network
+                .warnings()
+                .into_iter()
+                .chain(match &config.output {
+                    OutputTarget::Sftp(sftp) => sftp.warnings(),
+                    _ => Vec::new(),
+                })
+                .chain(self.warnings)
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,1276 @@
+//! Headless uploader for scripted and CI use, running the same pipeline as
+//! the desktop app.
+//!
+//! ```text
+//! layout-uploader upload --image plan.png --server https://host --layout-key KEY
+//! ```
+
+use clap::{Args, Parser, Subcommand};
+use layout_uploader_core::cache::TileCache;
+use layout_uploader_core::config::{
+    AuthMode, Color, CropRect, FinalizeMethod, LayerImage, OutputTarget, PaddingAnchor,
+    PaddingMode, ProcessConfig, QualityStep, ResizeFilter, S3Settings, ServerTarget, SftpAuth,
+    SftpSettings, Stitch, StitchImage, StitchPlacement, TileFormat, TileSize, TileUploadMethod,
+    UpscaleMode,
+};
+use layout_uploader_core::control::JobControl;
+use layout_uploader_core::decode::read_header;
+use layout_uploader_core::error::ProcessError;
+use layout_uploader_core::incremental::TileHashStore;
+use layout_uploader_core::layers::layer_runs;
+use layout_uploader_core::manifest::JobManifest;
+use layout_uploader_core::network::{ClientCertificate, ProxySettings};
+use layout_uploader_core::orphans::{self, OrphanStore};
+use layout_uploader_core::package::scan_package;
+use layout_uploader_core::preview::DEFAULT_PREVIEW_SIZE;
+use layout_uploader_core::processor::TileProcessor;
+use layout_uploader_core::progress::{
+    BatchPosition, ProgressEvent, ProgressListener, ProgressReporter, ProgressState, ProgressUpdate,
+};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(
+    name = "layout-uploader",
+    version,
+    about = "Tile and upload layout images"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Tile an image and upload it to a layout
+    Upload(Box<UploadArgs>),
+    /// Upload a package written with `upload --package`
+    UploadPackage(PackageArgs),
+    /// Remove the tiles failed or cancelled uploads left on their servers
+    CleanupOrphans(CleanupArgs),
+}
+
+#[derive(Args)]
+struct PackageArgs {
+    /// Package file to upload
+    #[arg(long)]
+    package: PathBuf,
+    /// Server base address, e.g. https://host
+    #[arg(long)]
+    server: String,
+    /// Layout to upload to, instead of the one the package was made for
+    #[arg(long)]
+    layout_key: Option<String>,
+    /// API secret
+    #[arg(long, env = "LAYOUT_UPLOADER_SECRET", hide_env_values = true)]
+    secret: String,
+    /// Send the secret as `Authorization: Bearer` instead of a query parameter
+    #[arg(long, conflicts_with = "auth_header")]
+    bearer: bool,
+    /// Send the secret in this header instead of a query parameter
+    #[arg(long)]
+    auth_header: Option<String>,
+    /// Tiles uploaded at the same time
+    #[arg(long)]
+    concurrency: Option<usize>,
+    /// Continue an interrupted upload of the same package and layout
+    #[arg(long)]
+    resume: bool,
+    /// Where resume manifests are kept
+    #[arg(long)]
+    manifest_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct CleanupArgs {
+    /// API secret of the servers and their mirrors
+    #[arg(long, env = "LAYOUT_UPLOADER_SECRET", hide_env_values = true)]
+    secret: String,
+    /// Password of the proxy the uploads went through; orphan records
+    /// don't keep it
+    #[arg(long, env = "LAYOUT_UPLOADER_PROXY_PASSWORD", hide_env_values = true)]
+    proxy_password: Option<String>,
+    /// Password of the .p12/.pfx client certificate the uploads used
+    #[arg(
+        long,
+        env = "LAYOUT_UPLOADER_CLIENT_CERT_PASSWORD",
+        hide_env_values = true
+    )]
+    client_cert_password: Option<String>,
+    /// Where resume manifests are kept
+    #[arg(long)]
+    manifest_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct UploadArgs {
+    /// Image to tile
+    #[arg(long)]
+    image: String,
+    /// Server base address, e.g. https://host
+    #[arg(long, required_unless_present_any = ["output_dir", "mbtiles", "package", "sftp_host"])]
+    server: Option<String>,
+    #[arg(long)]
+    layout_key: String,
+    /// Upload under this layout path instead of a fresh UUID, so re-runs
+    /// overwrite the same tiles
+    #[arg(long)]
+    layout_path: Option<String>,
+    /// Upload --image as this layer of the layout, finalized through the
+    /// layer endpoint; "base" when --layer is given without it
+    #[arg(long, value_name = "NAME")]
+    layer_name: Option<String>,
+    /// Further image aligned with --image to upload as a layer of the same
+    /// layout, as NAME=PATH; may be repeated
+    #[arg(long = "layer", value_name = "NAME=PATH", value_parser = parse_param)]
+    layers: Vec<(String, String)>,
+    /// API secret
+    #[arg(
+        long,
+        env = "LAYOUT_UPLOADER_SECRET",
+        hide_env_values = true,
+        required_unless_present_any = ["output_dir", "mbtiles", "package", "sftp_host"]
+    )]
+    secret: Option<String>,
+    /// Tile size in pixels, e.g. 256, or WIDTHxHEIGHT for tiles that aren't
+    /// square, e.g. 512x256
+    #[arg(long, default_value = "256", value_parser = parse_tile_size)]
+    tile_size: TileSize,
+    /// Pixels each tile shares with its neighbours on every side, for Deep
+    /// Zoom viewers that blend tile seams
+    #[arg(long, default_value_t = 0)]
+    overlap: u32,
+    /// Padding color as #RRGGBB, #RRGGBBAA, a color name or r,g,b; the
+    /// alpha is used by png and webp tiles, which are otherwise padded
+    /// transparent
+    #[arg(long, default_value = "#000000")]
+    background: Color,
+    /// Send the secret as `Authorization: Bearer` instead of a query parameter
+    #[arg(long, conflicts_with = "auth_header")]
+    bearer: bool,
+    /// Send the secret in this header instead of a query parameter
+    #[arg(long)]
+    auth_header: Option<String>,
+    /// Tile encoding: jpeg, png or webp
+    #[arg(long, default_value = "jpeg", value_parser = parse_format)]
+    format: TileFormat,
+    #[arg(long)]
+    jpeg_quality: Option<u8>,
+    #[arg(long)]
+    webp_quality: Option<u8>,
+    /// JPEG and WebP quality from some levels out from the deepest zoom,
+    /// as LEVELS:QUALITY; repeat or separate with commas, e.g. 1:70,3:55
+    #[arg(long = "quality-step", value_delimiter = ',', value_parser = parse_quality_step)]
+    quality_ramp: Vec<QualityStep>,
+    /// Keep the whole layout under this many MB by encoding at the highest
+    /// JPEG or WebP quality that fits
+    #[arg(long = "size-budget")]
+    size_budget_mb: Option<u64>,
+    /// Tiles uploaded at the same time
+    #[arg(long)]
+    concurrency: Option<usize>,
+    /// Leave out tiles that are entirely background
+    #[arg(long)]
+    skip_background_tiles: bool,
+    /// Pad each zoom level to whole tiles, to a square of 2^zoom tiles, or
+    /// not at all (none), leaving the edge tiles smaller
+    #[arg(long, default_value = "tiles", value_parser = parse_padding)]
+    padding: PaddingMode,
+    /// Where the image sits on the padded canvas: center, topleft, or X,Y
+    /// as the share (0 to 1) of the padding left of and above it
+    #[arg(long, default_value = "center", value_parser = parse_anchor)]
+    anchor: PaddingAnchor,
+    /// How levels larger than the image are made: lanczos, nearest, or none
+    /// to keep the deepest level at native resolution
+    #[arg(long, default_value = "lanczos", value_parser = parse_upscale)]
+    upscale: UpscaleMode,
+    /// Resampling filter for the zoom levels: nearest (fastest, for draft
+    /// runs), triangle, catmullrom, gaussian or lanczos3
+    #[arg(long, default_value = "lanczos3", value_parser = parse_resize_filter)]
+    resize_filter: ResizeFilter,
+    /// Resize the zoom levels in linear light, so hatching and text keep
+    /// their density on the low levels instead of darkening
+    #[arg(long)]
+    linear_resize: bool,
+    /// Sharpen each downscaled zoom level with an unsharp mask of this
+    /// strength, e.g. 0.5 to keep thin lines crisp; 0 turns it off
+    #[arg(long, value_name = "AMOUNT", default_value_t = 0.0)]
+    sharpen: f32,
+    /// Radius in pixels the unsharp mask works at
+    #[arg(long, value_name = "PIXELS", default_value_t = 1.0)]
+    sharpen_radius: f32,
+    /// Rasterize SVG and PDF input at this resolution; 96 keeps an SVG's
+    /// own size, higher values keep lines sharp on the deepest zoom levels
+    #[arg(long, value_name = "DPI")]
+    render_dpi: Option<f32>,
+    /// Rasterize SVG and PDF input to this many pixels on its longer side,
+    /// instead of --render-dpi
+    #[arg(long, value_name = "PIXELS", conflicts_with = "render_dpi")]
+    render_size: Option<u32>,
+    /// Tile photos as stored, ignoring their EXIF orientation
+    #[arg(long)]
+    no_auto_orient: bool,
+    /// Tile the colors as stored, ignoring an embedded ICC profile
+    #[arg(long)]
+    no_convert_to_srgb: bool,
+    /// Page of a PDF to tile, counted from 1
+    #[arg(long, default_value_t = 1)]
+    pdf_page: u32,
+    /// Frame of an animated GIF to tile, counted from 1
+    #[arg(long, default_value_t = 1)]
+    gif_frame: u32,
+    /// Further image to stitch onto one canvas with --image, repeatable;
+    /// without --stitch-columns it goes X,Y pixels from --image's top-left
+    /// corner
+    #[arg(long = "stitch", value_name = "PATH[@X,Y]", value_parser = parse_stitch_image)]
+    stitch: Vec<StitchImage>,
+    /// Lay --image and the --stitch images out in rows of this many,
+    /// instead of at their offsets
+    #[arg(long, value_name = "COLUMNS", requires = "stitch")]
+    stitch_columns: Option<u32>,
+    /// Turn the image this many degrees clockwise before tiling; angles
+    /// other than multiples of 90 straighten skewed scans
+    #[arg(
+        long,
+        value_name = "DEGREES",
+        default_value_t = 0.0,
+        allow_negative_numbers = true
+    )]
+    rotate: f64,
+    /// Mirror the image left to right, after rotating it
+    #[arg(long)]
+    flip_horizontal: bool,
+    /// Mirror the image top to bottom, after rotating it
+    #[arg(long)]
+    flip_vertical: bool,
+    /// Tile only this part of the image, in pixels from its top-left corner
+    #[arg(long, value_name = "X,Y,WIDTH,HEIGHT", value_parser = parse_crop)]
+    crop: Option<CropRect>,
+    /// Make pixels of this color transparent (#RRGGBB, a color name or
+    /// r,g,b); needs --format png or webp
+    #[arg(long, value_name = "COLOR")]
+    color_key: Option<Color>,
+    /// How far, per channel (0-255), a pixel may be from the color key and
+    /// still be made transparent
+    #[arg(long, default_value_t = 16, requires = "color_key")]
+    color_key_tolerance: u8,
+    /// Lighten (up to 1) or darken (down to -1) the image before tiling
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    brightness: f32,
+    /// Stretch (above 1) or flatten (below 1) the image's contrast
+    #[arg(long, default_value_t = 1.0)]
+    contrast: f32,
+    /// Lighten (above 1) or darken (below 1) the midtones
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f32,
+    /// Tile the image in shades of gray
+    #[arg(long)]
+    grayscale: bool,
+    /// Use the paper color along the image's edges as the background,
+    /// instead of --background
+    #[arg(long)]
+    auto_background: bool,
+    /// Strip borders of the margin color (the top-left pixel's) around the
+    /// drawing before tiling
+    #[arg(long)]
+    auto_trim: bool,
+    /// How far, per channel (0-255), a margin pixel may be from the margin
+    /// color
+    #[arg(long, default_value_t = 16, requires = "auto_trim")]
+    trim_tolerance: u8,
+    /// Stop the pyramid at this zoom level, for huge images whose deepest
+    /// levels nobody views
+    #[arg(long)]
+    max_zoom: Option<u32>,
+    /// Leave out the zoom levels above this one, for viewers that never
+    /// zoom out fully
+    #[arg(long, default_value_t = 0)]
+    min_zoom: u32,
+    /// Export the pyramid to this folder instead of uploading it
+    #[arg(long)]
+    output_dir: Option<String>,
+    /// Export in Deep Zoom format (`.dzi` plus `_files` folder)
+    #[arg(long, requires = "output_dir")]
+    dzi: bool,
+    /// Export into this MBTiles file instead of uploading
+    #[arg(long, conflicts_with = "output_dir")]
+    mbtiles: Option<String>,
+    /// Pack the tiles into this TAR file, to be sent later with
+    /// `upload-package` from a machine that can reach the server
+    #[arg(long, conflicts_with_all = ["output_dir", "mbtiles"])]
+    package: Option<String>,
+    /// Upload the tiles to this S3-compatible bucket instead of the server,
+    /// which is then pointed at them
+    #[arg(
+        long,
+        conflicts_with_all = ["output_dir", "mbtiles", "package"],
+        requires_all = ["s3_endpoint", "s3_region", "s3_access_key", "s3_secret_key"]
+    )]
+    s3_bucket: Option<String>,
+    /// Object store address, e.g. https://s3.eu-west-1.amazonaws.com
+    #[arg(long, requires = "s3_bucket")]
+    s3_endpoint: Option<String>,
+    #[arg(long, requires = "s3_bucket")]
+    s3_region: Option<String>,
+    /// Key prefix the tiles are stored under
+    #[arg(long, requires = "s3_bucket")]
+    s3_prefix: Option<String>,
+    #[arg(long, requires = "s3_bucket")]
+    s3_access_key: Option<String>,
+    #[arg(long, env = "LAYOUT_UPLOADER_S3_SECRET_KEY", hide_env_values = true)]
+    s3_secret_key: Option<String>,
+    /// Address the tiles are served from, if not the bucket itself
+    #[arg(long, requires = "s3_bucket")]
+    s3_public_url: Option<String>,
+    /// Write the tiles into a folder on this SFTP server instead of
+    /// uploading them
+    #[arg(
+        long,
+        conflicts_with_all = ["output_dir", "mbtiles", "package", "s3_bucket"],
+        requires = "sftp_user"
+    )]
+    sftp_host: Option<String>,
+    #[arg(long, default_value_t = 22, requires = "sftp_host")]
+    sftp_port: u16,
+    #[arg(long, requires = "sftp_host")]
+    sftp_user: Option<String>,
+    #[arg(long, env = "LAYOUT_UPLOADER_SFTP_PASSWORD", hide_env_values = true)]
+    sftp_password: Option<String>,
+    /// Private key to log in with instead of a password
+    #[arg(long, requires = "sftp_host")]
+    sftp_key: Option<String>,
+    #[arg(
+        long,
+        env = "LAYOUT_UPLOADER_SFTP_KEY_PASSPHRASE",
+        hide_env_values = true
+    )]
+    sftp_key_passphrase: Option<String>,
+    /// Remote folder the pyramid is written into
+    #[arg(long, requires = "sftp_host")]
+    sftp_dir: Option<String>,
+    /// Expected SHA-256 of the server's host key, as hex or as the
+    /// `SHA256:...` fingerprint `ssh-keygen -lf` prints; without it the key
+    /// has to be in ~/.ssh/known_hosts
+    #[arg(long, requires = "sftp_host")]
+    sftp_host_key: Option<String>,
+    /// Accept any host key instead of checking ~/.ssh/known_hosts; use
+    /// with care
+    #[arg(long, requires = "sftp_host", conflicts_with = "sftp_host_key")]
+    sftp_accept_any_host_key: bool,
+    /// Run the full pipeline without uploading anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Folder a dry run writes its tiles to, instead of discarding them
+    #[arg(long, requires = "dry_run")]
+    dry_run_output: Option<String>,
+    /// Continue an interrupted upload of the same image and layout
+    #[arg(long)]
+    resume: bool,
+    /// Keep encoded tiles in this folder and reuse them on later runs of
+    /// the same image and settings
+    #[arg(long)]
+    tile_cache: Option<PathBuf>,
+    /// Tile and upload the coarse zoom levels first, so the layout shows an
+    /// overview early; costs extra resizing
+    #[arg(long)]
+    low_zoom_first: bool,
+    /// Move the decoded image to disk and render each zoom level a row of
+    /// tiles at a time, for gigapixel scans whose levels don't fit in
+    /// memory (the image itself is still decoded whole once)
+    #[arg(long)]
+    low_memory: bool,
+    /// Switch to low-memory mode when the run is estimated to need more
+    /// than this many MB, and refuse it if even that needs more
+    #[arg(long, value_name = "MB")]
+    memory_limit: Option<u64>,
+    /// Write the run summary (tile counts per zoom, timings, retries) to
+    /// this JSON file, or into this folder as `<layout path>.summary.json`
+    #[arg(long, value_name = "PATH")]
+    summary: Option<PathBuf>,
+    /// Hold uploads after this many consecutive server failures until the
+    /// server answers again
+    #[arg(long)]
+    breaker_threshold: Option<u32>,
+    /// Seconds between checks whether a failing server is back
+    #[arg(long, requires = "breaker_threshold")]
+    breaker_probe_secs: Option<u64>,
+    /// Where each tile is sent, relative to the server unless a full URL,
+    /// with {layout_key}, {path}, {zoom}, {x} and {y} filled in
+    #[arg(long)]
+    tile_url_template: Option<String>,
+    /// How tiles are sent: post (multipart) or put (raw body)
+    #[arg(long, default_value = "post", value_parser = parse_upload_method)]
+    tile_upload_method: TileUploadMethod,
+    /// Server path that points the layout at the uploaded tiles
+    #[arg(long)]
+    finalize_endpoint: Option<String>,
+    /// How finalize is sent: get (query string) or post (JSON body)
+    #[arg(long, default_value = "get", value_parser = parse_finalize_method)]
+    finalize_method: FinalizeMethod,
+    /// Server path that points one layer of the layout at its tiles
+    #[arg(long)]
+    layer_endpoint: Option<String>,
+    /// Extra finalize parameter as NAME=VALUE, may be repeated
+    #[arg(long = "finalize-param", value_parser = parse_param)]
+    finalize_params: Vec<(String, String)>,
+    /// Page showing the finalized layout, with {layout_key} and {path}
+    /// placeholders; printed once the upload completes
+    #[arg(long, value_name = "TEMPLATE")]
+    viewer_url: Option<String>,
+    /// Where the live layout's tiles are fetched from for --diff, relative
+    /// to the server unless a full URL, with the --tile-url-template
+    /// placeholders
+    #[arg(long, value_name = "TEMPLATE")]
+    published_tile_url: Option<String>,
+    /// Compare the new rendering with the published layout instead of
+    /// uploading, and write a heatmap of what changed to this PNG
+    #[arg(long, value_name = "PATH", requires = "published_tile_url")]
+    diff: Option<PathBuf>,
+    /// Zoom level --diff compares, instead of a low one of a few dozen
+    /// tiles
+    #[arg(long, requires = "diff")]
+    diff_zoom: Option<u32>,
+    /// Also upload to and finalize on this server, as
+    /// SERVER,LAYOUT_KEY[,SECRET] (the secret defaults to --secret); may
+    /// be repeated
+    #[arg(long = "mirror", value_parser = parse_mirror)]
+    mirrors: Vec<(String, String, Option<String>)>,
+    /// Server path taking several tiles per request, e.g.
+    /// /LayoutUtil/UploadTiles
+    #[arg(long)]
+    batch_endpoint: Option<String>,
+    /// Tiles sent per request to the batch endpoint
+    #[arg(long, requires = "batch_endpoint")]
+    batch_size: Option<usize>,
+    /// Server path storing a tile as a copy of an earlier one with the same
+    /// content, so repeated tiles are uploaded once
+    #[arg(long)]
+    tile_ref_endpoint: Option<String>,
+    /// Server path the upload manifest is POSTed to before finalizing
+    #[arg(long)]
+    manifest_endpoint: Option<String>,
+    /// Add a blurhash of every tile to the upload manifest (and layout.json),
+    /// for viewers to show while tiles load
+    #[arg(long)]
+    tile_placeholders: bool,
+    /// Draw each tile's boundary and zoom/x/y label onto it, to check the
+    /// server's coordinate mapping in the viewer
+    #[arg(long)]
+    debug_overlay: bool,
+    /// Server path a JPEG overview of the layout is uploaded to on finalize
+    #[arg(long)]
+    thumbnail_endpoint: Option<String>,
+    /// Longest side of that overview in pixels
+    #[arg(long, requires = "thumbnail_endpoint")]
+    thumbnail_size: Option<u32>,
+    /// Fail tiles the server doesn't confirm with the same SHA-256
+    #[arg(long)]
+    verify_checksums: bool,
+    /// Upload only the tiles that changed since the last upload to this
+    /// layout key
+    #[arg(long)]
+    incremental: bool,
+    /// Redo only the tiles showing this part of the image, in pixels of
+    /// the image as tiled, over the layout's existing layout path
+    #[arg(long, value_name = "X,Y,WIDTH,HEIGHT", value_parser = parse_region)]
+    update_region: Option<CropRect>,
+    /// Keep going when tiles fail, retry them once at the end and list
+    /// the ones that still failed
+    #[arg(long)]
+    continue_on_error: bool,
+    /// Where resume manifests are kept
+    #[arg(long)]
+    manifest_dir: Option<PathBuf>,
+    /// Server path that deletes an unfinished layout path's tiles
+    #[arg(long)]
+    cleanup_endpoint: Option<String>,
+    /// Remove the uploaded tiles through --cleanup-endpoint if the upload
+    /// fails or is cancelled, instead of keeping them for --resume
+    #[arg(long, requires = "cleanup_endpoint")]
+    rollback_on_failure: bool,
+    /// Send requests through this proxy (http://, https://, socks5:// or
+    /// socks5h://) instead of the system one
+    #[arg(long)]
+    proxy: Option<String>,
+    #[arg(long, requires = "proxy")]
+    proxy_user: Option<String>,
+    #[arg(
+        long,
+        env = "LAYOUT_UPLOADER_PROXY_PASSWORD",
+        hide_env_values = true,
+        requires = "proxy_user"
+    )]
+    proxy_password: Option<String>,
+    /// Ignore HTTP_PROXY / HTTPS_PROXY from the environment
+    #[arg(long, conflicts_with = "proxy")]
+    no_system_proxy: bool,
+    /// PEM file of extra CA certificates to trust
+    #[arg(long)]
+    ca_bundle: Option<String>,
+    /// Skip certificate checks (self-signed servers); use with care
+    #[arg(long)]
+    insecure: bool,
+    /// Client certificate for mutual TLS: a .p12/.pfx archive, or a PEM
+    /// certificate together with --client-key
+    #[arg(long)]
+    client_cert: Option<String>,
+    /// PKCS#8 PEM private key for a PEM --client-cert
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<String>,
+    /// Password of a .p12/.pfx --client-cert
+    #[arg(
+        long,
+        env = "LAYOUT_UPLOADER_CLIENT_CERT_PASSWORD",
+        hide_env_values = true,
+        requires = "client_cert",
+        conflicts_with = "client_key"
+    )]
+    client_cert_password: Option<String>,
+}
+
+impl UploadArgs {
+    fn config(&self) -> ProcessConfig {
+        let mut config = ProcessConfig::new(
+            self.image.clone(),
+            self.server.clone().unwrap_or_default(),
+            self.layout_key.clone(),
+            self.secret.clone().unwrap_or_default(),
+        );
+        config.layout_path = self.layout_path.clone();
+        config.layer = self.layer_name.clone();
+        config.layers = self
+            .layers
+            .iter()
+            .map(|(name, image_path)| LayerImage {
+                name: name.clone(),
+                image_path: image_path.clone(),
+            })
+            .collect();
+        config.tile_size = self.tile_size;
+        config.overlap = self.overlap;
+        if self.bearer {
+            config.auth = AuthMode::Bearer;
+        } else if let Some(name) = self.auth_header.clone() {
+            config.auth = AuthMode::Header { name };
+        }
+        config.background_color = self.background;
+        config.tile_format = self.format;
+        config.skip_background_tiles = self.skip_background_tiles;
+        config.padding = self.padding;
+        config.anchor = self.anchor;
+        config.upscale = self.upscale;
+        config.resize_filter = self.resize_filter;
+        config.linear_resize = self.linear_resize;
+        config.sharpen_amount = self.sharpen;
+        config.sharpen_radius = self.sharpen_radius;
+        config.render_dpi = self.render_dpi;
+        config.render_size = self.render_size;
+        config.auto_orient = !self.no_auto_orient;
+        config.convert_to_srgb = !self.no_convert_to_srgb;
+        config.pdf_page = self.pdf_page;
+        config.gif_frame = self.gif_frame;
+        if !self.stitch.is_empty() {
+            config.stitch = Some(Stitch {
+                images: self.stitch.clone(),
+                placement: match self.stitch_columns {
+                    Some(columns) => StitchPlacement::Grid { columns },
+                    None => StitchPlacement::Offsets,
+                },
+            });
+        }
+        config.rotate = self.rotate;
+        config.flip_horizontal = self.flip_horizontal;
+        config.flip_vertical = self.flip_vertical;
+        config.crop = self.crop;
+        config.auto_background = self.auto_background;
+        config.auto_trim = self.auto_trim;
+        config.color_key = self.color_key;
+        config.color_key_tolerance = self.color_key_tolerance;
+        config.trim_tolerance = self.trim_tolerance;
+        config.brightness = self.brightness;
+        config.contrast = self.contrast;
+        config.gamma = self.gamma;
+        config.grayscale = self.grayscale;
+        config.max_zoom_override = self.max_zoom;
+        config.min_zoom = self.min_zoom;
+        if let Some(path) = self.mbtiles.clone() {
+            config.output = OutputTarget::Mbtiles { path };
+        }
+        if let Some(path) = self.package.clone() {
+            config.output = OutputTarget::Package { path };
+        }
+        if let Some(host) = self.sftp_host.clone() {
+            let auth = match self.sftp_key.clone() {
+                Some(path) => SftpAuth::Key {
+                    path,
+                    passphrase: self.sftp_key_passphrase.clone(),
+                },
+                None => SftpAuth::Password {
+                    password: self.sftp_password.clone().unwrap_or_default(),
+                },
+            };
+            config.output = OutputTarget::Sftp(SftpSettings {
+                host,
+                port: self.sftp_port,
+                username: self.sftp_user.clone().unwrap_or_default(),
+                auth,
+                remote_dir: self.sftp_dir.clone().unwrap_or_default(),
+                host_key_sha256: self.sftp_host_key.clone(),
+                accept_any_host_key: self.sftp_accept_any_host_key,
+            });
+        }
+        if let Some(bucket) = self.s3_bucket.clone() {
+            config.output = OutputTarget::S3(S3Settings {
+                endpoint: self.s3_endpoint.clone().unwrap_or_default(),
+                bucket,
+                prefix: self.s3_prefix.clone().unwrap_or_default(),
+                region: self.s3_region.clone().unwrap_or_default(),
+                access_key_id: self.s3_access_key.clone().unwrap_or_default(),
+                secret_access_key: self.s3_secret_key.clone().unwrap_or_default(),
+                public_url: self.s3_public_url.clone(),
+            });
+        }
+        if let Some(path) = self.output_dir.clone() {
+            config.output = if self.dzi {
+                OutputTarget::Dzi { path }
+            } else {
+                OutputTarget::Directory { path }
+            };
+        }
+        config.dry_run = self.dry_run;
+        config.dry_run_output = self.dry_run_output.clone();
+        config.continue_on_error = self.continue_on_error;
+        config.incremental = self.incremental;
+        config.update_region = self.update_region;
+        config.verify_checksums = self.verify_checksums;
+        config.manifest_endpoint = self.manifest_endpoint.clone();
+        config.tile_placeholders = self.tile_placeholders;
+        config.debug_overlay = self.debug_overlay;
+        config.thumbnail_endpoint = self.thumbnail_endpoint.clone();
+        if let Some(size) = self.thumbnail_size {
+            config.thumbnail_size = size;
+        }
+        config.tile_url_template = self.tile_url_template.clone();
+        config.tile_upload_method = self.tile_upload_method;
+        config.finalize_endpoint = self.finalize_endpoint.clone();
+        config.viewer_url_template = self.viewer_url.clone();
+        config.published_tile_url_template = self.published_tile_url.clone();
+        config.finalize_method = self.finalize_method;
+        config.layer_endpoint = self.layer_endpoint.clone();
+        config.finalize_params = self.finalize_params.iter().cloned().collect();
+        config.mirrors = self
+            .mirrors
+            .iter()
+            .map(|(server_address, layout_key, secret)| ServerTarget {
+                server_address: server_address.clone(),
+                layout_key: layout_key.clone(),
+                secret: secret.clone().unwrap_or_else(|| config.secret.clone()),
+                auth: config.auth.clone(),
+            })
+            .collect();
+        config.batch_endpoint = self.batch_endpoint.clone();
+        config.tile_ref_endpoint = self.tile_ref_endpoint.clone();
+        if let Some(batch_size) = self.batch_size {
+            config.batch_size = batch_size;
+        }
+        config.tile_cache = self.tile_cache.is_some();
+        config.low_zoom_first = self.low_zoom_first;
+        config.low_memory = self.low_memory;
+        config.memory_limit_mb = self.memory_limit;
+        config.summary_path = self
+            .summary
+            .as_ref()
+            .map(|path| path.to_string_lossy().into_owned());
+        config.cleanup_endpoint = self.cleanup_endpoint.clone();
+        config.rollback_on_failure = self.rollback_on_failure;
+        if let Some(quality) = self.jpeg_quality {
+            config.jpeg_quality = quality;
+        }
+        if let Some(quality) = self.webp_quality {
+            config.webp_quality = quality;
+        }
+        config.quality_ramp = self.quality_ramp.clone();
+        config.size_budget_mb = self.size_budget_mb;
+        if let Some(concurrency) = self.concurrency {
+            config.concurrency = concurrency;
+        }
+        if let Some(threshold) = self.breaker_threshold {
+            config.breaker_threshold = threshold;
+        }
+        if let Some(secs) = self.breaker_probe_secs {
+            config.breaker_probe_secs = secs;
+        }
+        config.network.proxy = self.proxy.clone().map(|url| ProxySettings {
+            url,
+            username: self.proxy_user.clone(),
+            password: self.proxy_password.clone(),
+        });
+        config.network.use_system_proxy = !self.no_system_proxy;
+        config.network.ca_bundle = self.ca_bundle.clone();
+        config.network.accept_invalid_certs = self.insecure;
+        config.network.client_certificate =
+            self.client_cert
+                .clone()
+                .map(|path| match self.client_key.clone() {
+                    Some(key_path) => ClientCertificate::Pem {
+                        cert_path: path,
+                        key_path,
+                    },
+                    None => ClientCertificate::Pkcs12 {
+                        path,
+                        password: self.client_cert_password.clone(),
+                    },
+                });
+        config
+    }
+}
+
+fn parse_format(value: &str) -> Result<TileFormat, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase()))
+        .map_err(|_| format!("unknown tile format '{}'", value))
+}
+
+fn parse_upload_method(value: &str) -> Result<TileUploadMethod, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase()))
+        .map_err(|_| format!("unknown upload method '{}'", value))
+}
+
+fn parse_finalize_method(value: &str) -> Result<FinalizeMethod, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase()))
+        .map_err(|_| format!("unknown finalize method '{}'", value))
+}
+
+fn parse_padding(value: &str) -> Result<PaddingMode, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase())).map_err(|_| {
+        format!(
+            "unknown padding '{}', expected tiles, square or none",
+            value
+        )
+    })
+}
+
+fn parse_resize_filter(value: &str) -> Result<ResizeFilter, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase())).map_err(|_| {
+        format!(
+            "unknown resize filter '{}', expected nearest, triangle, catmullrom, gaussian or lanczos3",
+            value
+        )
+    })
+}
+
+fn parse_upscale(value: &str) -> Result<UpscaleMode, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_lowercase())).map_err(|_| {
+        format!(
+            "unknown upscale mode '{}', expected lanczos, nearest or none",
+            value
+        )
+    })
+}
+
+fn parse_anchor(value: &str) -> Result<PaddingAnchor, String> {
+    match value.to_lowercase().as_str() {
+        "center" => Ok(PaddingAnchor::Center),
+        "topleft" | "top-left" => Ok(PaddingAnchor::TopLeft),
+        custom => {
+            let share = |part: &str| {
+                part.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|share| (0.0..=1.0).contains(share))
+            };
+            match custom.split_once(',') {
+                Some((x, y)) => match (share(x), share(y)) {
+                    (Some(x), Some(y)) => Ok(PaddingAnchor::Custom { x, y }),
+                    _ => Err(format!(
+                        "anchor shares must be between 0 and 1, got '{}'",
+                        value
+                    )),
+                },
+                None => Err(format!(
+                    "unknown anchor '{}', expected center, topleft or X,Y",
+                    value
+                )),
+            }
+        }
+    }
+}
+
+fn parse_tile_size(value: &str) -> Result<TileSize, String> {
+    let invalid = || {
+        format!(
+            "tile size must be a number of pixels or WIDTHxHEIGHT, got '{}'",
+            value
+        )
+    };
+    let size = match value.split_once(['x', 'X']) {
+        Some((width, height)) => TileSize {
+            width: width.trim().parse().map_err(|_| invalid())?,
+            height: height.trim().parse().map_err(|_| invalid())?,
+        },
+        None => TileSize::square(value.trim().parse().map_err(|_| invalid())?),
+    };
+    if size.width == 0 || size.height == 0 {
+        return Err(invalid());
+    }
+    Ok(size)
+}
+
+fn parse_quality_step(value: &str) -> Result<QualityStep, String> {
+    let invalid = || format!("quality step must be LEVELS:QUALITY, got '{}'", value);
+    let (levels, quality) = value.split_once(':').ok_or_else(invalid)?;
+    Ok(QualityStep {
+        levels_below_max: levels.trim().parse().map_err(|_| invalid())?,
+        quality: quality.trim().parse().map_err(|_| invalid())?,
+    })
+}
+
+fn parse_crop(value: &str) -> Result<CropRect, String> {
+    let parts: Vec<u32> = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("crop must be X,Y,WIDTH,HEIGHT in pixels, got '{}'", value))?;
+    match parts[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok(CropRect {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err(format!(
+            "crop must be X,Y,WIDTH,HEIGHT in pixels, got '{}'",
+            value
+        )),
+    }
+}
+
+fn parse_region(value: &str) -> Result<CropRect, String> {
+    parse_crop(value)
+        .map_err(|_| format!("region must be X,Y,WIDTH,HEIGHT in pixels, got '{}'", value))
+}
+
+fn parse_stitch_image(value: &str) -> Result<StitchImage, String> {
+    // A path can hold an `@` itself, so only a trailing X,Y is an offset
+    let offset = value.rsplit_once('@').and_then(|(path, offset)| {
+        let (x, y) = offset.split_once(',')?;
+        Some((path, x.trim().parse().ok()?, y.trim().parse().ok()?))
+    });
+    let (path, x, y) = offset.unwrap_or((value, 0, 0));
+    if path.is_empty() {
+        return Err(format!("expected PATH[@X,Y], got '{}'", value));
+    }
+    Ok(StitchImage {
+        path: path.to_string(),
+        x,
+        y,
+    })
+}
+
+fn parse_param(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got '{}'", value)),
+    }
+}
+
+fn parse_mirror(value: &str) -> Result<(String, String, Option<String>), String> {
+    let mut parts = value.splitn(3, ',');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(server), Some(layout_key), secret)
+            if !server.trim().is_empty() && !layout_key.trim().is_empty() =>
+        {
+            Ok((
+                server.trim().to_string(),
+                layout_key.trim().to_string(),
+                secret.map(str::to_string),
+            ))
+        }
+        _ => Err(format!(
+            "expected SERVER,LAYOUT_KEY[,SECRET], got '{}'",
+            value
+        )),
+    }
+}
+
+/// Prints progress to stderr, one line per percent.
+#[derive(Default)]
+struct ConsoleProgress {
+    last_percentage: AtomicU32,
+}
+
+impl ProgressListener for ConsoleProgress {
+    fn on_event(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Started(update) => eprintln!("{}", update.status),
+            ProgressEvent::Progress(update) => {
+                let last = self
+                    .last_percentage
+                    .swap(update.percentage, Ordering::Relaxed);
+                // A stalled run keeps the percentage but is always news
+                if update.percentage != last || update.throttled || update.waiting_for_server {
+                    match update.eta_seconds {
+                        Some(eta) => eprintln!(
+                            "[{:>3}%] {} - {:.1} tiles/s, {:.0} kbps, ETA {}s",
+                            update.percentage,
+                            update.status,
+                            update.tiles_per_second,
+                            update.upload_kbps,
+                            eta
+                        ),
+                        None => eprintln!("[{:>3}%] {}", update.percentage, update.status),
+                    }
+                }
+            }
+            ProgressEvent::Cancelled(update) => eprintln!("{}", update.status),
+            // The outcome is printed once by `main`
+            ProgressEvent::Completed(_) | ProgressEvent::Failed(_) => {}
+        }
+    }
+}
+
+fn manifest_dir(dir: Option<PathBuf>) -> Result<PathBuf, ProcessError> {
+    let dir = dir.unwrap_or_else(|| std::env::temp_dir().join("layout-uploader").join("jobs"));
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| ProcessError::io("Failed to create manifest directory", e))?;
+    Ok(dir)
+}
+
+/// A job control cancelled by Ctrl-C.
+fn ctrl_c_control() -> JobControl {
+    let cancel = CancellationToken::new();
+    let ctrl_c_cancel = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Cancelling...");
+            ctrl_c_cancel.cancel();
+        }
+    });
+    JobControl {
+        cancel,
+        ..JobControl::default()
+    }
+}
+
+/// A reporter printing to stderr, started with the tiles `manifest` has.
+async fn console_reporter(manifest: &JobManifest) -> ProgressReporter {
+    let job_id = Uuid::new_v4().to_string();
+    let mut reporter = ProgressReporter::new(
+        Arc::new(ConsoleProgress::default()),
+        job_id.clone(),
+        ProgressState::new(Mutex::new(None)),
+    );
+    reporter
+        .started(ProgressUpdate {
+            current: manifest.uploaded_count(),
+            ..ProgressUpdate::with_status(&job_id, "Starting...")
+        })
+        .await;
+    reporter
+}
+
+/// Upload the image, or each layer in turn with progress through them all.
+/// The first layer that fails stops the rest.
+async fn upload(args: UploadArgs) -> Result<String, ProcessError> {
+    let runs = layer_runs(&args.config())?;
+    let manifest_dir = manifest_dir(args.manifest_dir.clone())?;
+    let tile_hashes = TileHashStore::new(&manifest_dir.join("tile-hashes"));
+    let control = ctrl_c_control();
+
+    // Every layer shares the settings, so one processor serves all
+    let mut processor = TileProcessor::new(&runs[0])?.with_tile_hashes(tile_hashes);
+    if let Some(dir) = &args.tile_cache {
+        processor = processor.with_tile_cache(TileCache::new(dir));
+    }
+    if let Some(path) = &args.diff {
+        return diff(&processor, &runs[0], args.diff_zoom, path).await;
+    }
+    if runs.len() == 1 {
+        return upload_run(&args, &processor, &runs[0], None, control).await;
+    }
+
+    // Layers are the same size, so each makes as many tiles
+    let header = read_header(&runs[0])?;
+    let layer_tiles = processor.count_tiles(header.width, header.height);
+    let mut position = BatchPosition {
+        index: 0,
+        count: runs.len(),
+        tiles_before: 0,
+        tiles_total: layer_tiles * runs.len() as u32,
+        tiles: layer_tiles,
+        layer: None,
+    };
+    let mut messages = Vec::with_capacity(runs.len());
+    for (index, config) in runs.iter().enumerate() {
+        position.index = index;
+        position.layer = config.layer.clone();
+        let layer = config.layer.as_deref().unwrap_or_default();
+        let result = upload_run(
+            &args,
+            &processor,
+            config,
+            Some(&mut position),
+            control.clone(),
+        )
+        .await;
+        // The count is corrected once a layer is prepared, and the layers
+        // left make as many tiles as this one did
+        position.tiles_before += position.tiles;
+        position.tiles_total =
+            position.tiles_before + position.tiles * (runs.len() - index - 1) as u32;
+        match result {
+            Ok(message) => messages.push(format!("Layer {}: {}", layer, message)),
+            Err(e) => {
+                eprintln!("Layer {} failed", layer);
+                return Err(e);
+            }
+        }
+    }
+    Ok(messages.join("\n"))
+}
+
+/// Upload one image, as one layer at `position` of a layered upload.
+async fn upload_run(
+    args: &UploadArgs,
+    processor: &TileProcessor,
+    config: &ProcessConfig,
+    position: Option<&mut BatchPosition>,
+    control: JobControl,
+) -> Result<String, ProcessError> {
+    let manifest_dir = manifest_dir(args.manifest_dir.clone())?;
+    let orphans = OrphanStore::new(&manifest_dir);
+    let tile_hashes = TileHashStore::new(&manifest_dir.join("tile-hashes"));
+
+    let resumed = match args.resume {
+        true => JobManifest::load(&manifest_dir, config)?,
+        false => None,
+    };
+    let mut manifest = match resumed {
+        Some(manifest) => manifest,
+        // A layer that already finished has no manifest left, and is
+        // uploaded again
+        None if args.resume && position.is_none() => return Err(ProcessError::NoResumableUpload),
+        None => JobManifest::create(&manifest_dir, config, tile_hashes.layout_path(config)?)?,
+    };
+
+    let mut reporter = console_reporter(&manifest).await;
+    if let Some(position) = &position {
+        reporter.set_batch_position((*position).clone());
+    }
+    let result = processor
+        .process_tiles(config, &mut manifest, &mut reporter, control)
+        .await;
+    if let (Some(position), Some(corrected)) = (position, reporter.batch_position()) {
+        *position = corrected.clone();
+    }
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            orphans::abandon(config, manifest, &orphans).await;
+            return Err(e);
+        }
+    };
+    orphans.remove(&manifest.layout_path)?;
+    manifest.remove()?;
+
+    for warning in &summary.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    let verb = if config.dry_run {
+        "Dry run complete (nothing uploaded)"
+    } else {
+        "Upload complete"
+    };
+    let mut message = format!(
+        "{}. Max zoom level: {}, tiles uploaded: {}, average tile size: {:.1} KB, background tiles skipped: {}, unchanged tiles: {}",
+        verb,
+        summary.max_zoom,
+        summary.tiles_uploaded,
+        summary.average_tile_kb,
+        summary.tiles_skipped,
+        summary.tiles_unchanged
+    );
+    if summary.duplicate_tiles > 0 {
+        message.push_str(&format!(
+            ", duplicate tiles: {} ({:.1} MB)",
+            summary.duplicate_tiles,
+            summary.duplicate_bytes as f64 / (1024.0 * 1024.0)
+        ));
+    }
+    if let Some(quality) = summary.budget_quality {
+        message.push_str(&format!(", quality for the size budget: {}", quality));
+    }
+    for warning in &summary.warnings {
+        message.push_str(&format!(". Warning: {}", warning));
+    }
+    if let Some(url) = &summary.viewer_url {
+        message.push_str(&format!(". View it at {}", url));
+    }
+    Ok(message)
+}
+
+/// Compare `config`'s rendering with the published layout, the first
+/// layer's on a layered upload, and write the heatmap to `path`.
+async fn diff(
+    processor: &TileProcessor,
+    config: &ProcessConfig,
+    zoom_level: Option<u32>,
+    path: &Path,
+) -> Result<String, ProcessError> {
+    let diff = processor
+        .diff_published(config, zoom_level, DEFAULT_PREVIEW_SIZE)
+        .await?;
+    std::fs::write(path, diff.heatmap_png()?)
+        .map_err(|e| ProcessError::io("Failed to write heatmap", e))?;
+    let mut message = format!(
+        "Compared zoom level {} of {}: {} of {} tiles changed ({:.1}% of pixels)",
+        diff.zoom_level,
+        diff.layout_path,
+        diff.tiles_changed,
+        diff.tiles_compared,
+        diff.changed_fraction * 100.0
+    );
+    if diff.tiles_missing > 0 {
+        message.push_str(&format!(", {} not published", diff.tiles_missing));
+    }
+    message.push_str(&format!(". Heatmap written to {}", path.display()));
+    Ok(message)
+}
+
+async fn upload_package(args: PackageArgs) -> Result<String, ProcessError> {
+    let scan_path = args.package.clone();
+    let package = tokio::task::spawn_blocking(move || scan_package(&scan_path))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Package scan failed: {}", e)))??;
+
+    let mut config = ProcessConfig::new(
+        args.package.to_string_lossy().to_string(),
+        args.server,
+        args.layout_key
+            .unwrap_or_else(|| package.manifest.layout_key.clone()),
+        args.secret,
+    );
+    config.tile_size = package.pyramid.tile_size;
+    config.overlap = package.pyramid.overlap;
+    config.tile_format = package.manifest.tile_format;
+    if args.bearer {
+        config.auth = AuthMode::Bearer;
+    } else if let Some(name) = args.auth_header {
+        config.auth = AuthMode::Header { name };
+    }
+    if let Some(concurrency) = args.concurrency {
+        config.concurrency = concurrency;
+    }
+
+    let manifest_dir = manifest_dir(args.manifest_dir)?;
+    let orphans = OrphanStore::new(&manifest_dir);
+    let mut manifest = if args.resume {
+        JobManifest::load(&manifest_dir, &config)?.ok_or(ProcessError::NoResumableUpload)?
+    } else {
+        JobManifest::create(&manifest_dir, &config, config.resolve_layout_path()?)?
+    };
+
+    let control = ctrl_c_control();
+    let mut reporter = console_reporter(&manifest).await;
+    let result = TileProcessor::new(&config)?
+        .upload_pyramid(
+            package.pyramid,
+            &config,
+            &mut manifest,
+            &mut reporter,
+            control,
+        )
+        .await;
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            orphans::abandon(&config, manifest, &orphans).await;
+            return Err(e);
+        }
+    };
+    orphans.remove(&manifest.layout_path)?;
+    manifest.remove()?;
+
+    Ok(format!(
+        "Package uploaded to layout {}. Max zoom level: {}, tiles uploaded: {}",
+        config.layout_key, summary.max_zoom, summary.tiles_uploaded
+    ))
+}
+
+async fn cleanup_orphans(args: CleanupArgs) -> Result<String, ProcessError> {
+    let manifest_dir = manifest_dir(args.manifest_dir)?;
+    let store = OrphanStore::new(&manifest_dir);
+
+    let mut removed = 0;
+    let orphaned = store.list()?;
+    for orphan in &orphaned {
+        // Records are saved without credentials, so they come from the
+        // command line
+        let mut config = ProcessConfig {
+            secret: args.secret.clone(),
+            ..orphan.config.clone()
+        };
+        for mirror in &mut config.mirrors {
+            mirror.secret = args.secret.clone();
+        }
+        if let Some(proxy) = &mut config.network.proxy {
+            proxy.password = args.proxy_password.clone();
+        }
+        if let Some(ClientCertificate::Pkcs12 { password, .. }) =
+            &mut config.network.client_certificate
+        {
+            *password = args.client_cert_password.clone();
+        }
+        let cleanup = orphans::cleanup_orphan(orphan, config, &manifest_dir, &store).await;
+        eprintln!(
+            "{} {} ({}): {}",
+            cleanup.server_address, cleanup.layout_key, cleanup.layout_path, cleanup.message
+        );
+        if cleanup.removed {
+            removed += 1;
+        }
+    }
+    Ok(format!(
+        "Removed {} of {} orphaned layout paths",
+        removed,
+        orphaned.len()
+    ))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Upload(args) => upload(*args).await,
+        Command::UploadPackage(args) => upload_package(args).await,
+        Command::CleanupOrphans(args) => cleanup_orphans(args).await,
+    };
+
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            if let ProcessError::TilesFailed { tiles } = &e {
+                for tile in tiles {
+                    eprintln!(
+                        "  zoom {} x {} y {}: {}",
+                        tile.zoom_level, tile.x, tile.y, tile.error
+                    );
+                }
+                eprintln!("Run again with --resume to retry just these tiles");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
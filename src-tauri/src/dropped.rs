@@ -0,0 +1,100 @@
+//! Files dropped onto the window: checked against the supported image
+//! types and described, so the frontend can fill in a job from them.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Emitter};
+
+use layout_uploader_core::config::{ProcessConfig, IMAGE_EXTENSIONS};
+use layout_uploader_core::decode::read_header;
+
+pub const EVENT_DROPPED: &str = "files://dropped";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DroppedImage {
+    pub path: String,
+    pub file_name: String,
+    pub width: u32,
+    pub height: u32,
+    // Detected from the file's contents, e.g. "Png"
+    pub format: String,
+    pub file_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectedFile {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Payload of `files://dropped`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DroppedFiles {
+    pub images: Vec<DroppedImage>,
+    pub rejected: Vec<RejectedFile>,
+}
+
+/// Describe `paths` off the UI thread and emit them as `files://dropped`.
+pub fn handle(app: &AppHandle, paths: Vec<PathBuf>) {
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let dropped = describe(&paths);
+        tracing::debug!(
+            images = dropped.images.len(),
+            rejected = dropped.rejected.len(),
+            "Files dropped"
+        );
+        let _ = app.emit(EVENT_DROPPED, dropped);
+    });
+}
+
+fn describe(paths: &[PathBuf]) -> DroppedFiles {
+    let mut dropped = DroppedFiles::default();
+    for path in paths {
+        match read_image(path) {
+            Ok(image) => dropped.images.push(image),
+            Err(reason) => dropped.rejected.push(RejectedFile {
+                path: path.to_string_lossy().to_string(),
+                reason,
+            }),
+        }
+    }
+    dropped
+}
+
+fn read_image(path: &Path) -> Result<DroppedImage, String> {
+    if path.is_dir() {
+        return Err("Folders can only be uploaded as a batch".to_string());
+    }
+    if !crate::is_supported_image(path) {
+        return Err(format!(
+            "Unsupported file type, expected one of: {}",
+            IMAGE_EXTENSIONS.join(", ")
+        ));
+    }
+
+    let file_size = std::fs::metadata(path)
+        .map_err(|e| format!("Failed to read file: {}", e))?
+        .len();
+    // Measured the way a run with default settings would see it, so an
+    // SVG reports its rasterized size
+    let config = ProcessConfig::new(
+        path.to_string_lossy().to_string(),
+        String::new(),
+        String::new(),
+        String::new(),
+    );
+    let header = read_header(&config).map_err(|e| e.to_string())?;
+
+    Ok(DroppedImage {
+        path: path.to_string_lossy().to_string(),
+        file_name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        width: header.width,
+        height: header.height,
+        format: header.format,
+        file_size,
+    })
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+use layout_uploader_core::error::ProcessError;
+
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// One finished run, as shown by `get_history` and written by
+/// `export_history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub job_id: String,
+    pub image_path: String,
+    pub server_address: String,
+    pub layout_key: String,
+    // Seconds since the Unix epoch
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub tiles_uploaded: u32,
+    pub tiles_skipped: u32,
+    pub bytes_uploaded: u64,
+    // "completed", "failed" or "cancelled"
+    pub result: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+const CSV_HEADER: &str = "job_id,image_path,server_address,layout_key,started_at,duration_secs,tiles_uploaded,tiles_skipped,bytes_uploaded,result,message";
+
+/// Every run on this machine, appended to `history.jsonl` in the app data
+/// directory as each one ends.
+pub struct HistoryStore {
+    path: PathBuf,
+    entries: Mutex<Vec<HistoryEntry>>,
+}
+
+impl HistoryStore {
+    /// Read the history at `path`. Lines that don't parse, such as one cut
+    /// short by a crash, are skipped.
+    pub fn load(path: PathBuf) -> Result<Self, ProcessError> {
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(ProcessError::io("Failed to read history", e)),
+        };
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub async fn record(&self, entry: HistoryEntry) -> Result<(), ProcessError> {
+        let mut entries = self.entries.lock().await;
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| ProcessError::io("Failed to create history directory", e))?;
+        }
+        let line = serde_json::to_string(&entry)
+            .map_err(|e| ProcessError::Internal(format!("Failed to encode history: {}", e)))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| ProcessError::io("Failed to open history", e))?;
+        writeln!(file, "{}", line).map_err(|e| ProcessError::io("Failed to write history", e))?;
+
+        entries.push(entry);
+        Ok(())
+    }
+
+    /// Runs newest first.
+    pub async fn list(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().await.iter().rev().cloned().collect()
+    }
+
+    /// Write the whole history to `path`, oldest run first.
+    pub async fn export(&self, path: &Path, format: ExportFormat) -> Result<(), ProcessError> {
+        let entries = self.entries.lock().await.clone();
+        let contents = match format {
+            ExportFormat::Json => serde_json::to_string_pretty(&entries)
+                .map_err(|e| ProcessError::Internal(format!("Failed to encode history: {}", e)))?,
+            ExportFormat::Csv => {
+                let mut csv = format!("{}\n", CSV_HEADER);
+                for entry in &entries {
+                    csv.push_str(&csv_row(entry));
+                    csv.push('\n');
+                }
+                csv
+            }
+        };
+        std::fs::write(path, contents).map_err(|e| ProcessError::io("Failed to export history", e))
+    }
+}
+
+fn csv_row(entry: &HistoryEntry) -> String {
+    [
+        csv_field(&entry.job_id),
+        csv_field(&entry.image_path),
+        csv_field(&entry.server_address),
+        csv_field(&entry.layout_key),
+        entry.started_at.to_string(),
+        format!("{:.1}", entry.duration_secs),
+        entry.tiles_uploaded.to_string(),
+        entry.tiles_skipped.to_string(),
+        entry.bytes_uploaded.to_string(),
+        csv_field(&entry.result),
+        csv_field(&entry.message),
+    ]
+    .join(",")
+}
+
+// Quoted only when needed, with embedded quotes doubled (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
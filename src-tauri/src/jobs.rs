@@ -0,0 +1,293 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use layout_uploader_core::config::ProcessConfig;
+use layout_uploader_core::control::{JobControl, PauseToken};
+use layout_uploader_core::error::ProcessError;
+use layout_uploader_core::progress::{ProgressState, ProgressUpdate};
+use layout_uploader_core::summary::RunSummary;
+
+use crate::history::HistoryEntry;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", content = "message", rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Paused,
+    // Running, but the circuit breaker holds uploads until the server is back
+    #[serde(rename = "waiting_for_server")]
+    WaitingForServer,
+    Completed(String),
+    Failed(String),
+    Cancelled,
+}
+
+/// One upload run and the state the commands need to observe or stop it.
+pub struct Job {
+    pub id: String,
+    pub image_path: String,
+    pub server_address: String,
+    pub layout_key: String,
+    pub progress: ProgressState,
+    pub cancel: CancellationToken,
+    pub pause: PauseToken,
+    status: Mutex<JobStatus>,
+    // Summed over every image of a batch
+    totals: Mutex<RunTotals>,
+    // Where the finalized layout can be viewed, once it is
+    viewer_url: Mutex<Option<String>>,
+    started: Instant,
+    started_at: SystemTime,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct RunTotals {
+    tiles_uploaded: u32,
+    tiles_skipped: u32,
+    bytes_uploaded: u64,
+}
+
+impl Job {
+    /// Whether the run is still going, paused or not.
+    pub async fn is_running(&self) -> bool {
+        matches!(
+            *self.status.lock().await,
+            JobStatus::Running | JobStatus::Paused
+        )
+    }
+
+    /// What the pipeline polls to stop or hold this run.
+    pub fn control(&self) -> JobControl {
+        JobControl {
+            cancel: self.cancel.clone(),
+            pause: self.pause.clone(),
+        }
+    }
+
+    /// Hold the run after the uploads already in flight. Returns whether
+    /// there was a running job to pause.
+    pub async fn pause(&self) -> bool {
+        let mut status = self.status.lock().await;
+        if !matches!(*status, JobStatus::Running) {
+            return false;
+        }
+        self.pause.pause();
+        *status = JobStatus::Paused;
+        true
+    }
+
+    /// Let a paused run carry on. Returns whether it was paused.
+    pub async fn resume(&self) -> bool {
+        let mut status = self.status.lock().await;
+        if !matches!(*status, JobStatus::Paused) {
+            return false;
+        }
+        self.pause.resume();
+        *status = JobStatus::Running;
+        true
+    }
+
+    /// Count the tiles of a finished image towards the job.
+    pub async fn add_summary(&self, summary: &RunSummary) {
+        let mut totals = self.totals.lock().await;
+        totals.tiles_uploaded += summary.tiles_uploaded;
+        totals.tiles_skipped += summary.tiles_skipped;
+        totals.bytes_uploaded += summary.bytes_uploaded;
+        if summary.viewer_url.is_some() {
+            *self.viewer_url.lock().await = summary.viewer_url.clone();
+        }
+    }
+
+    /// The history record of a run that ended with `result`.
+    pub async fn history_entry(&self, result: &Result<String, ProcessError>) -> HistoryEntry {
+        let totals = *self.totals.lock().await;
+        let (outcome, message) = match result {
+            Ok(message) => ("completed", message.clone()),
+            Err(_) if self.cancel.is_cancelled() => ("cancelled", "Cancelled".to_string()),
+            Err(e) => ("failed", e.to_string()),
+        };
+        HistoryEntry {
+            job_id: self.id.clone(),
+            image_path: self.image_path.clone(),
+            server_address: self.server_address.clone(),
+            layout_key: self.layout_key.clone(),
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            tiles_uploaded: totals.tiles_uploaded,
+            tiles_skipped: totals.tiles_skipped,
+            bytes_uploaded: totals.bytes_uploaded,
+            result: outcome.to_string(),
+            message,
+        }
+    }
+
+    /// Record how the run ended.
+    pub async fn finish(&self, result: &Result<String, ProcessError>) {
+        let status = if self.cancel.is_cancelled() {
+            JobStatus::Cancelled
+        } else {
+            match result {
+                Ok(message) => JobStatus::Completed(message.clone()),
+                Err(e) => JobStatus::Failed(e.to_string()),
+            }
+        };
+        *self.status.lock().await = status;
+    }
+
+    /// Record that the job failed before its run started.
+    pub async fn fail(&self, error: &ProcessError) {
+        *self.status.lock().await = JobStatus::Failed(error.to_string());
+    }
+
+    async fn info(&self) -> JobInfo {
+        let progress = self.progress.lock().await.clone();
+        let status = match self.status.lock().await.clone() {
+            JobStatus::Running if progress.as_ref().is_some_and(|p| p.waiting_for_server) => {
+                JobStatus::WaitingForServer
+            }
+            status => status,
+        };
+        JobInfo {
+            id: self.id.clone(),
+            image_path: self.image_path.clone(),
+            server_address: self.server_address.clone(),
+            layout_key: self.layout_key.clone(),
+            status,
+            progress,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub image_path: String,
+    pub server_address: String,
+    pub layout_key: String,
+    pub status: JobStatus,
+    pub progress: Option<ProgressUpdate>,
+}
+
+/// Every job started this session, keyed by job id.
+#[derive(Default)]
+pub struct JobManager {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+}
+
+impl JobManager {
+    /// Register a new running job for `config`. Two jobs uploading the same
+    /// image to the same layout would fight over one manifest, so that is
+    /// refused.
+    pub async fn register(&self, config: &ProcessConfig) -> Result<Arc<Job>, ProcessError> {
+        let mut jobs = self.jobs.lock().await;
+
+        if running_for(&jobs, config).await {
+            return Err(ProcessError::AlreadyRunning(config.layout_key.clone()));
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let job = Arc::new(Job {
+            id: id.clone(),
+            image_path: config.image_path.clone(),
+            server_address: config.server_address.clone(),
+            layout_key: config.layout_key.clone(),
+            progress: ProgressState::new(Mutex::new(None)),
+            cancel: CancellationToken::new(),
+            pause: PauseToken::default(),
+            status: Mutex::new(JobStatus::Running),
+            totals: Mutex::new(RunTotals::default()),
+            viewer_url: Mutex::new(None),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        });
+        jobs.insert(id, job.clone());
+        tracing::info!(
+            job_id = %job.id,
+            image_path = %job.image_path,
+            server_address = %job.server_address,
+            layout_key = %job.layout_key,
+            "Job started"
+        );
+
+        Ok(job)
+    }
+
+    pub async fn get(&self, job_id: &str) -> Result<Arc<Job>, ProcessError> {
+        self.jobs
+            .lock()
+            .await
+            .get(job_id)
+            .cloned()
+            .ok_or_else(|| ProcessError::JobNotFound(job_id.to_string()))
+    }
+
+    /// Whether a job for the same image, server and layout is still going.
+    pub async fn is_running_for(&self, config: &ProcessConfig) -> bool {
+        running_for(&*self.jobs.lock().await, config).await
+    }
+
+    /// Jobs still going, paused or not.
+    pub async fn active(&self) -> Vec<Arc<Job>> {
+        let jobs: Vec<Arc<Job>> = self.jobs.lock().await.values().cloned().collect();
+        let mut active = Vec::new();
+        for job in jobs {
+            if job.is_running().await {
+                active.push(job);
+            }
+        }
+        active
+    }
+
+    /// Where the most recently started job that finalized `layout_key` can
+    /// be viewed.
+    pub async fn viewer_url(&self, layout_key: &str) -> Option<String> {
+        let mut jobs: Vec<Arc<Job>> = self
+            .jobs
+            .lock()
+            .await
+            .values()
+            .filter(|job| job.layout_key == layout_key)
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|job| std::cmp::Reverse(job.started));
+        for job in jobs {
+            if let Some(url) = job.viewer_url.lock().await.clone() {
+                return Some(url);
+            }
+        }
+        None
+    }
+
+    pub async fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<Arc<Job>> = self.jobs.lock().await.values().cloned().collect();
+        jobs.sort_by_key(|job| job.started);
+
+        let mut infos = Vec::with_capacity(jobs.len());
+        for job in jobs {
+            infos.push(job.info().await);
+        }
+        infos
+    }
+}
+
+async fn running_for(jobs: &HashMap<String, Arc<Job>>, config: &ProcessConfig) -> bool {
+    for job in jobs.values() {
+        if job.image_path == config.image_path
+            && job.server_address == config.server_address
+            && job.layout_key == config.layout_key
+            && job.is_running().await
+        {
+            return true;
+        }
+    }
+    false
+}
@@ -0,0 +1,79 @@
+//! Diagnostics written to a daily log file in the app's log directory, so
+//! users can attach them to support tickets.
+
+use std::path::{Path, PathBuf};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+use layout_uploader_core::error::ProcessError;
+
+const LOG_PREFIX: &str = "layout-uploader";
+const LOG_SUFFIX: &str = "log";
+// Days of logs kept before the oldest file is deleted
+const MAX_LOG_FILES: usize = 14;
+// Tile-level detail from our own crates, warnings from everything else;
+// RUST_LOG overrides it
+const DEFAULT_FILTER: &str = "warn,iviva_layout_uploader=debug,layout_uploader_core=debug";
+
+/// Flushes buffered log lines when dropped, so it lives as long as the app.
+pub struct LogGuard(#[allow(dead_code)] WorkerGuard);
+
+/// Send `tracing` events to `{dir}/layout-uploader.{date}.log`.
+pub fn init(dir: &Path) -> Result<LogGuard, ProcessError> {
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_PREFIX)
+        .filename_suffix(LOG_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(dir)
+        .map_err(|e| ProcessError::Internal(format!("Failed to create log file: {}", e)))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(false)
+        .try_init()
+        .map_err(|e| ProcessError::Internal(format!("Failed to start logging: {}", e)))?;
+
+    Ok(LogGuard(guard))
+}
+
+/// The most recent log file; the date in the name makes it sort last.
+fn latest_log(dir: &Path) -> Result<Option<PathBuf>, ProcessError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ProcessError::io("Failed to read log directory", e)),
+    };
+
+    let mut latest: Option<PathBuf> = None;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| ProcessError::io("Failed to read log directory", e))?
+            .path();
+        let is_log = path.file_name().is_some_and(|name| {
+            let name = name.to_string_lossy();
+            name.starts_with(LOG_PREFIX) && name.ends_with(LOG_SUFFIX)
+        });
+        if is_log && latest.as_ref().is_none_or(|latest| path > *latest) {
+            latest = Some(path);
+        }
+    }
+    Ok(latest)
+}
+
+/// The last `lines` lines of the current log file.
+pub fn tail(dir: &Path, lines: usize) -> Result<Vec<String>, ProcessError> {
+    let Some(path) = latest_log(dir)? else {
+        return Ok(Vec::new());
+    };
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| ProcessError::io("Failed to read log", e))?;
+    let all: Vec<&str> = contents.lines().collect();
+    let start = all.len().saturating_sub(lines);
+    Ok(all[start..].iter().map(|line| line.to_string()).collect())
+}
@@ -1,368 +1,1057 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use image::imageops::FilterType;
-use image::{ImageBuffer, ImageFormat, Rgba, RgbaImage};
-use reqwest;
-use serde::{Deserialize, Serialize};
+mod dropped;
+mod history;
+mod jobs;
+mod logging;
+mod notify;
+mod profiles;
+mod secrets;
+mod tray;
+
+use history::{ExportFormat, HistoryEntry, HistoryStore, HISTORY_FILE};
+use jobs::{Job, JobInfo, JobManager};
+use layout_uploader_core::background::suggest_background;
+use layout_uploader_core::cache::TileCache;
+use layout_uploader_core::config::{
+    AuthMode, Color, OutputTarget, ProcessConfig, IMAGE_EXTENSIONS,
+};
+use layout_uploader_core::connection::{self, ConnectionReport};
+use layout_uploader_core::decode::{gif_frames, pdf_pages, read_header, PdfPageInfo};
+use layout_uploader_core::diff::LayoutDiff;
+use layout_uploader_core::error::ProcessError;
+use layout_uploader_core::estimate::{ImageInfo, JobEstimate};
+use layout_uploader_core::import::{scan_pyramid, ScannedPyramid};
+use layout_uploader_core::incremental::TileHashStore;
+use layout_uploader_core::layers::layer_runs;
+use layout_uploader_core::manifest::{JobManifest, ResumableJob};
+use layout_uploader_core::network::NetworkSettings;
+use layout_uploader_core::orphans::{self, OrphanCleanup, OrphanStore, OrphanedLayout};
+use layout_uploader_core::package::scan_package;
+use layout_uploader_core::preview::{render_preview, RenderedTile, DEFAULT_PREVIEW_SIZE};
+use layout_uploader_core::processor::TileProcessor;
+use layout_uploader_core::progress::{
+    BatchPosition, ProgressEvent, ProgressListener, ProgressReporter, ProgressUpdate,
+};
+use profiles::{ProfileStore, ServerProfile, PROFILES_FILE};
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tauri::State;
-use tokio::sync::Mutex;
-use uuid::Uuid;
+use tauri::{AppHandle, DragDropEvent, Emitter, Manager, State, WindowEvent};
+use tauri_plugin_opener::OpenerExt;
+use tray::Tray;
+
+const EVENT_STARTED: &str = "processing://started";
+const EVENT_PROGRESS: &str = "processing://progress";
+const EVENT_COMPLETED: &str = "processing://completed";
+const EVENT_FAILED: &str = "processing://failed";
+const EVENT_CANCELLED: &str = "processing://cancelled";
+
+/// Forwards a job's progress to the frontend as `processing://*` events and
+/// the tray, and notifies the OS when it finishes.
+struct TauriEvents {
+    app: AppHandle,
+    layout: String,
+}
+
+impl ProgressListener for TauriEvents {
+    fn on_event(&self, event: ProgressEvent) {
+        let app = &self.app;
+        let tray = app.try_state::<Tray>();
+        let _ = match event {
+            ProgressEvent::Started(update) => {
+                if let Some(tray) = &tray {
+                    tray.progress(&self.layout, &update);
+                }
+                app.emit(EVENT_STARTED, update)
+            }
+            ProgressEvent::Progress(update) => {
+                if let Some(tray) = &tray {
+                    tray.progress(&self.layout, &update);
+                }
+                app.emit(EVENT_PROGRESS, update)
+            }
+            ProgressEvent::Completed(message) => {
+                if let Some(tray) = &tray {
+                    tray.finished(&message.job_id);
+                }
+                notify::completed(app, &self.layout, &message);
+                app.emit(EVENT_COMPLETED, message)
+            }
+            ProgressEvent::Failed(message) => {
+                if let Some(tray) = &tray {
+                    tray.finished(&message.job_id);
+                }
+                notify::failed(app, &self.layout, &message);
+                app.emit(EVENT_FAILED, message)
+            }
+            ProgressEvent::Cancelled(update) => {
+                if let Some(tray) = &tray {
+                    tray.finished(&update.job_id);
+                }
+                app.emit(EVENT_CANCELLED, update)
+            }
+        };
+    }
+}
+
+fn reporter(app: &AppHandle, job: &Job) -> ProgressReporter {
+    ProgressReporter::new(
+        Arc::new(TauriEvents {
+            app: app.clone(),
+            layout: job.layout_key.clone(),
+        }),
+        job.id.clone(),
+        job.progress.clone(),
+    )
+}
+
+#[tauri::command]
+async fn select_image_file() -> Result<Option<String>, ProcessError> {
+    use rfd::AsyncFileDialog;
+
+    let file = AsyncFileDialog::new()
+        .add_filter("Images", IMAGE_EXTENSIONS)
+        .set_title("Select Image File")
+        .pick_file()
+        .await;
+
+    match file {
+        Some(file_handle) => Ok(Some(file_handle.path().to_string_lossy().to_string())),
+        None => Ok(None),
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ProcessConfig {
-    image_path: String,
+/// Pick the folder a layout is exported to.
+#[tauri::command]
+async fn select_output_folder() -> Result<Option<String>, ProcessError> {
+    use rfd::AsyncFileDialog;
+
+    let folder = AsyncFileDialog::new()
+        .set_title("Select Export Folder")
+        .pick_folder()
+        .await;
+
+    Ok(folder.map(|handle| handle.path().to_string_lossy().to_string()))
+}
+
+/// Directory holding the manifests of uploads that haven't finished yet.
+fn manifest_dir(app: &AppHandle) -> Result<PathBuf, ProcessError> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| {
+            ProcessError::Internal(format!("Failed to resolve app data directory: {}", e))
+        })?
+        .join("jobs");
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| ProcessError::io("Failed to create manifest directory", e))?;
+    Ok(dir)
+}
+
+/// Content hashes of the last upload to each layout key, for incremental
+/// runs.
+fn tile_hash_store(app: &AppHandle) -> Result<TileHashStore, ProcessError> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| {
+            ProcessError::Internal(format!("Failed to resolve app data directory: {}", e))
+        })?
+        .join("tile-hashes");
+    Ok(TileHashStore::new(&dir))
+}
+
+/// Encoded tiles kept for runs with `tile_cache`, in the app cache directory.
+fn tile_cache(app: &AppHandle) -> Result<TileCache, ProcessError> {
+    let dir = app
+        .path()
+        .app_cache_dir()
+        .map_err(|e| {
+            ProcessError::Internal(format!("Failed to resolve app cache directory: {}", e))
+        })?
+        .join("tiles");
+    Ok(TileCache::new(&dir))
+}
+
+/// Run one job: tile `config.image_path`, or send `pyramid` when the tiles
+/// were generated elsewhere.
+async fn run_processing(
+    app: AppHandle,
+    job: &Job,
+    config: ProcessConfig,
+    mut manifest: JobManifest,
+    pyramid: Option<ScannedPyramid>,
+) -> Result<String, ProcessError> {
+    let mut reporter = reporter(&app, job);
+
+    reporter
+        .started(ProgressUpdate {
+            current: manifest.uploaded_count(),
+            ..ProgressUpdate::with_status(&job.id, "Starting...")
+        })
+        .await;
+
+    let processor = TileProcessor::new(&config).and_then(|processor| {
+        Ok(processor
+            .with_tile_hashes(tile_hash_store(&app)?)
+            .with_tile_cache(tile_cache(&app)?))
+    });
+    let result = match processor {
+        Ok(processor) => match pyramid {
+            Some(pyramid) => {
+                processor
+                    .upload_pyramid(
+                        pyramid,
+                        &config,
+                        &mut manifest,
+                        &mut reporter,
+                        job.control(),
+                    )
+                    .await
+            }
+            None => {
+                processor
+                    .process_tiles(&config, &mut manifest, &mut reporter, job.control())
+                    .await
+            }
+        },
+        Err(e) => Err(e),
+    };
+
+    let orphans = OrphanStore::new(&manifest_dir(&app)?);
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            // A cancelled run has already announced itself
+            if !job.cancel.is_cancelled() {
+                reporter.failed(&e);
+            }
+            orphans::abandon(&config, manifest, &orphans).await;
+            return Err(e);
+        }
+    };
+    job.add_summary(&summary).await;
+
+    // The layout is finalized, nothing is left to resume or clean up
+    orphans.remove(&manifest.layout_path)?;
+    manifest.remove()?;
+
+    let mut message = if config.dry_run {
+        format!(
+            "Dry run completed! {} tiles ({:.1} MB) would have been uploaded. Max zoom level: {}, average tile size: {:.1} KB, background tiles skipped: {}",
+            summary.tiles_uploaded,
+            summary.bytes_uploaded as f64 / (1024.0 * 1024.0),
+            summary.max_zoom,
+            summary.average_tile_kb,
+            summary.tiles_skipped
+        )
+    } else {
+        format!(
+            "Processing completed successfully! Max zoom level: {}, average tile size: {:.1} KB, background tiles skipped: {}",
+            summary.max_zoom,
+            summary.average_tile_kb,
+            summary.tiles_skipped
+        )
+    };
+    if summary.tiles_unchanged > 0 {
+        message.push_str(&format!(
+            ". {} unchanged tiles left in place",
+            summary.tiles_unchanged
+        ));
+    }
+    if let Some(output) = config.dry_run_output.as_ref().filter(|_| config.dry_run) {
+        message.push_str(&format!(". Tiles written to {}", output));
+    } else if let OutputTarget::Directory { path }
+    | OutputTarget::Dzi { path }
+    | OutputTarget::Mbtiles { path }
+    | OutputTarget::Package { path } = &config.output
+    {
+        message.push_str(&format!(". Tiles exported to {}", path));
+    } else if let OutputTarget::S3(s3) = &config.output {
+        message.push_str(&format!(". Tiles stored in bucket {}", s3.bucket));
+    } else if let OutputTarget::Sftp(sftp) = &config.output {
+        message.push_str(&format!(". Tiles written to {} over SFTP", sftp.host));
+    }
+    for warning in &summary.warnings {
+        message.push_str(&format!(". Warning: {}", warning));
+    }
+    if let Some(url) = &summary.viewer_url {
+        message.push_str(&format!(". View it at {}", url));
+    }
+    reporter.completed(&message, Some(&summary));
+
+    Ok(message)
+}
+
+/// Check the server details before committing to a long upload. `auth` and
+/// `network` carry the auth mode, proxy and timeouts the upload will use.
+#[tauri::command]
+async fn test_connection(
     server_address: String,
     layout_key: String,
-    secret: String,
-    background_color: (u8, u8, u8),
-    tile_size: u32,
+    mut secret: String,
+    auth: Option<AuthMode>,
+    network: Option<NetworkSettings>,
+) -> Result<ConnectionReport, ProcessError> {
+    if secret.is_empty() {
+        secret = secrets::load(server_address.clone())
+            .await?
+            .unwrap_or_default();
+    }
+    connection::test_connection(
+        &server_address,
+        &layout_key,
+        &secret,
+        &auth.unwrap_or_default(),
+        &network.unwrap_or_default(),
+    )
+    .await
+}
+
+/// Work out the size and duration of a job before starting it.
+#[tauri::command]
+async fn estimate_job(config: ProcessConfig) -> Result<JobEstimate, ProcessError> {
+    tokio::task::spawn_blocking(move || TileProcessor::new(&config)?.estimate(&config))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Estimate task failed: {}", e)))?
+}
+
+/// Describe the selected image and the tiles it would make, without
+/// starting a job.
+#[tauri::command]
+async fn get_image_info(config: ProcessConfig) -> Result<ImageInfo, ProcessError> {
+    tokio::task::spawn_blocking(move || TileProcessor::new(&config)?.image_info(&config))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Image info task failed: {}", e)))?
+}
+
+/// The paper color along the selected image's edges, to offer as the
+/// background color. `None` if the edges are transparent.
+#[tauri::command]
+async fn detect_background(config: ProcessConfig) -> Result<Option<Color>, ProcessError> {
+    tokio::task::spawn_blocking(move || suggest_background(&config))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Background task failed: {}", e)))?
+}
+
+/// The pages of a PDF and their sizes in points, for picking the page to
+/// tile.
+#[tauri::command]
+async fn get_pdf_pages(path: String) -> Result<Vec<PdfPageInfo>, ProcessError> {
+    tokio::task::spawn_blocking(move || pdf_pages(&path))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("PDF pages task failed: {}", e)))?
+}
+
+/// How many frames a GIF has, for picking the frame of an animated one to
+/// tile.
+#[tauri::command]
+async fn get_gif_frames(path: String) -> Result<u32, ProcessError> {
+    tokio::task::spawn_blocking(move || gif_frames(&path))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("GIF frames task failed: {}", e)))?
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ProgressUpdate {
-    current: u32,
-    total: u32,
-    zoom_level: u32,
-    percentage: u32,
-    status: String,
+/// A downsized PNG of the selected image on its background color, for the
+/// preview, `max_size` pixels on its longest side.
+#[tauri::command]
+async fn generate_preview(
+    config: ProcessConfig,
+    max_size: Option<u32>,
+) -> Result<Vec<u8>, ProcessError> {
+    tokio::task::spawn_blocking(move || {
+        render_preview(&config, max_size.unwrap_or(DEFAULT_PREVIEW_SIZE))
+    })
+    .await
+    .map_err(|e| ProcessError::Internal(format!("Preview task failed: {}", e)))?
 }
 
-type ProgressState = Arc<Mutex<Option<ProgressUpdate>>>;
-type CancelState = Arc<Mutex<bool>>;
+/// Encode one tile exactly as `config` would upload it, for checking
+/// quality and padding before a full run. `x` and `y` are pixel offsets
+/// within the zoom level, as the server receives them.
+#[tauri::command]
+async fn render_tile(
+    config: ProcessConfig,
+    zoom: u32,
+    x: u32,
+    y: u32,
+) -> Result<RenderedTile, ProcessError> {
+    tokio::task::spawn_blocking(move || {
+        TileProcessor::new(&config)?.render_tile(&config, zoom, x, y)
+    })
+    .await
+    .map_err(|e| ProcessError::Internal(format!("Tile render task failed: {}", e)))?
+}
 
-struct TileProcessor {
-    tile_size: u32,
+/// Compare `config`'s rendering with the tiles the server publishes for
+/// the layout, at `zoom_level` or a low level by default, so changes can
+/// be reviewed before overwriting the live layout. The heatmap fits within
+/// `max_size`.
+#[tauri::command]
+async fn diff_published_layout(
+    app: AppHandle,
+    config: ProcessConfig,
+    zoom_level: Option<u32>,
+    max_size: Option<u32>,
+) -> Result<LayoutDiff, ProcessError> {
+    TileProcessor::new(&config)?
+        .with_tile_hashes(tile_hash_store(&app)?)
+        .diff_published(
+            &config,
+            zoom_level,
+            max_size.unwrap_or(DEFAULT_PREVIEW_SIZE),
+        )
+        .await
 }
 
-impl TileProcessor {
-    fn new(tile_size: u32) -> Self {
-        Self { tile_size }
+/// Record how a job ended, on the job for `list_jobs` and in the history.
+async fn finish_job(app: &AppHandle, job: &Job, result: &Result<String, ProcessError>) {
+    job.finish(result).await;
+    let entry = job.history_entry(result).await;
+    tracing::info!(
+        job_id = %job.id,
+        result = %entry.result,
+        tiles_uploaded = entry.tiles_uploaded,
+        bytes_uploaded = entry.bytes_uploaded,
+        duration_secs = entry.duration_secs,
+        "Job finished: {}",
+        entry.message
+    );
+    if let Err(e) = app.state::<HistoryStore>().record(entry).await {
+        tracing::error!(job_id = %job.id, "Failed to record job history: {}", e);
     }
+}
+
+/// Run a registered job in the background; its outcome is reported through
+/// events and kept on the job for `list_jobs`.
+fn spawn_job(
+    app: AppHandle,
+    job: Arc<Job>,
+    config: ProcessConfig,
+    manifest: JobManifest,
+    pyramid: Option<ScannedPyramid>,
+) {
+    tauri::async_runtime::spawn(async move {
+        let result = run_processing(app.clone(), &job, config, manifest, pyramid).await;
+        finish_job(&app, &job, &result).await;
+    });
+}
 
-    fn calc_zoom(&self, zoom_level: u32, width: u32, height: u32) -> f64 {
-        let ts = (2_u32.pow(zoom_level)) * self.tile_size;
-        let max_dimension = width.max(height);
-        ts as f64 / max_dimension as f64
+/// Start uploading a layout and return the new job's id. A layout with
+/// further `layers` uploads each of them in turn under the one job.
+#[tauri::command]
+async fn start_processing(
+    app: AppHandle,
+    mut config: ProcessConfig,
+    jobs: State<'_, JobManager>,
+) -> Result<String, ProcessError> {
+    secrets::fill_in(&mut config).await?;
+    secrets::remember(&config).await;
+    if !config.layers.is_empty() {
+        let runs = layer_runs(&config)?;
+        let job = jobs.register(&config).await?;
+        let job_id = job.id.clone();
+        tauri::async_runtime::spawn(async move {
+            let result = run_batch(app.clone(), &job, runs, Vec::new()).await;
+            finish_job(&app, &job, &result).await;
+        });
+        return Ok(job_id);
     }
+    let layout_path = tile_hash_store(&app)?.layout_path(&config)?;
+    let dir = manifest_dir(&app)?;
+    // Registered first, so a run already going for the same layout keeps
+    // its manifest
+    let job = jobs.register(&config).await?;
+    let manifest = setup_step(&job, JobManifest::create(&dir, &config, layout_path)).await?;
 
-    fn get_max_zoom_levels(&self, width: u32, height: u32) -> u32 {
-        let max_dimension = width.max(height);
-        let tiles = (max_dimension as f64 / self.tile_size as f64).ceil();
-        (tiles.log2().ceil() as u32) + 1
+    let job_id = job.id.clone();
+    spawn_job(app, job, config, manifest, None);
+    Ok(job_id)
+}
+
+/// `result` of a setup step run once `job` is registered; on failure the
+/// job is finished as failed, so it doesn't stay running and block retries.
+async fn setup_step<T>(job: &Job, result: Result<T, ProcessError>) -> Result<T, ProcessError> {
+    if let Err(e) = &result {
+        job.fail(e).await;
     }
+    result
+}
+
+/// Continue an interrupted upload of the same image to the same layout,
+/// reusing its layout path and skipping tiles that already made it.
+#[tauri::command]
+async fn resume_interrupted_upload(
+    app: AppHandle,
+    mut config: ProcessConfig,
+    jobs: State<'_, JobManager>,
+) -> Result<String, ProcessError> {
+    secrets::fill_in(&mut config).await?;
+    let manifest =
+        JobManifest::load(&manifest_dir(&app)?, &config)?.ok_or(ProcessError::NoResumableUpload)?;
+    let job = jobs.register(&config).await?;
+
+    let job_id = job.id.clone();
+    spawn_job(app, job, config, manifest, None);
+    Ok(job_id)
+}
+
+/// Uploads interrupted by a crash or by closing the app, which can be
+/// continued by passing their `config`, credentials filled in or left empty
+/// for the stored ones, to `resume_interrupted_upload`.
+#[tauri::command]
+async fn list_resumable_jobs(
+    app: AppHandle,
+    jobs: State<'_, JobManager>,
+) -> Result<Vec<ResumableJob>, ProcessError> {
+    let dir = manifest_dir(&app)?;
+    let resumable = tokio::task::spawn_blocking(move || JobManifest::list(&dir))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Manifest scan failed: {}", e)))??;
 
-    async fn process_tiles(
-        &self,
-        config: &ProcessConfig,
-        progress_state: ProgressState,
-        cancel_state: CancelState,
-    ) -> Result<u32, String> {
-        // Reset cancel state
-        *cancel_state.lock().await = false;
-
-        // Load and convert image
-        let img =
-            image::open(&config.image_path).map_err(|e| format!("Failed to open image: {}", e))?;
-
-        let img = img.to_rgba8();
-        let (img_width, img_height) = img.dimensions();
-        let zoom_levels = self.get_max_zoom_levels(img_width, img_height);
-
-        // Calculate total tiles
-        let mut total_tiles = 0;
-        for i in 0..zoom_levels {
-            total_tiles += 4_u32.pow(i);
+    // Jobs running in this session are still writing to their manifests
+    let mut idle = Vec::with_capacity(resumable.len());
+    for job in resumable {
+        if !jobs.is_running_for(&job.config).await {
+            idle.push(job);
         }
+    }
+    Ok(idle)
+}
 
-        let layout_path = Uuid::new_v4().to_string();
-        let mut current_tile = 0;
-        let mut max_zoom = 0;
-
-        // Process each zoom level
-        for zoom_level in (0..zoom_levels).rev() {
-            if *cancel_state.lock().await {
-                *progress_state.lock().await = Some(ProgressUpdate {
-                    current: 0,
-                    total: 0,
-                    zoom_level: 0,
-                    percentage: 0,
-                    status: "Cancelled".to_string(),
-                });
-                return Err("Processing cancelled".to_string());
+/// Upload a pyramid generated elsewhere, such as our own folder export, an
+/// XYZ folder from gdal2tiles or vips, or a Deep Zoom export, and return the
+/// new job's id.
+#[tauri::command]
+async fn upload_existing_pyramid(
+    app: AppHandle,
+    dir: String,
+    mut config: ProcessConfig,
+    jobs: State<'_, JobManager>,
+) -> Result<String, ProcessError> {
+    secrets::fill_in(&mut config).await?;
+    secrets::remember(&config).await;
+    let scan_dir = PathBuf::from(&dir);
+    let tile_size = config.tile_size;
+    let pyramid = tokio::task::spawn_blocking(move || scan_pyramid(&scan_dir, tile_size))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Pyramid scan failed: {}", e)))??;
+
+    // The folder stands in for the image, so the job and its manifest are
+    // keyed on it
+    let config = ProcessConfig {
+        image_path: dir,
+        tile_size: pyramid.tile_size,
+        overlap: pyramid.overlap,
+        ..config
+    };
+    let layout_path = config.resolve_layout_path()?;
+    let dir = manifest_dir(&app)?;
+    let job = jobs.register(&config).await?;
+    let manifest = setup_step(&job, JobManifest::create(&dir, &config, layout_path)).await?;
+
+    let job_id = job.id.clone();
+    spawn_job(app, job, config, manifest, Some(pyramid));
+    Ok(job_id)
+}
+
+/// Upload a package exported on a machine without access to the server,
+/// and return the new job's id. The package's layout key is used unless
+/// `config` names another.
+#[tauri::command]
+async fn upload_package(
+    app: AppHandle,
+    path: String,
+    mut config: ProcessConfig,
+    jobs: State<'_, JobManager>,
+) -> Result<String, ProcessError> {
+    secrets::fill_in(&mut config).await?;
+    secrets::remember(&config).await;
+    let scan_path = PathBuf::from(&path);
+    let package = tokio::task::spawn_blocking(move || scan_package(&scan_path))
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Package scan failed: {}", e)))??;
+
+    // The package stands in for the image, like a pyramid folder
+    let layout_key = match config.layout_key.trim() {
+        "" => package.manifest.layout_key.clone(),
+        key => key.to_string(),
+    };
+    let config = ProcessConfig {
+        image_path: path,
+        layout_key,
+        tile_size: package.pyramid.tile_size,
+        overlap: package.pyramid.overlap,
+        tile_format: package.manifest.tile_format,
+        ..config
+    };
+    let layout_path = config.resolve_layout_path()?;
+    let dir = manifest_dir(&app)?;
+    let job = jobs.register(&config).await?;
+    let manifest = setup_step(&job, JobManifest::create(&dir, &config, layout_path)).await?;
+
+    let job_id = job.id.clone();
+    spawn_job(app, job, config, manifest, Some(package.pyramid));
+    Ok(job_id)
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchConfig {
+    // Either a folder to scan for images, explicit image paths, or both
+    #[serde(default)]
+    directory: Option<String>,
+    #[serde(default)]
+    image_paths: Vec<String>,
+    // File name, with or without its extension, to the layout it goes to
+    layout_keys: HashMap<String, String>,
+    // Shared settings; `image_path` and `layout_key` are set per image
+    settings: ProcessConfig,
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Expand a batch into one config per image. Images without a layout key
+/// are returned by name so the final report can mention them.
+fn batch_images(batch: &BatchConfig) -> Result<(Vec<ProcessConfig>, Vec<String>), ProcessError> {
+    let mut paths: Vec<PathBuf> = batch.image_paths.iter().map(PathBuf::from).collect();
+
+    if let Some(directory) = &batch.directory {
+        let entries = std::fs::read_dir(directory)
+            .map_err(|e| ProcessError::io("Failed to read directory", e))?;
+
+        let mut found = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| ProcessError::io("Failed to read directory", e))?
+                .path();
+            if path.is_file() && is_supported_image(&path) {
+                found.push(path);
             }
+        }
+        found.sort();
+        paths.extend(found);
+    }
 
-            max_zoom = max_zoom.max(zoom_level);
-            let scale_factor = self.calc_zoom(zoom_level, img_width, img_height);
-
-            // Resize image
-            let new_width = (img_width as f64 * scale_factor) as u32;
-            let new_height = (img_height as f64 * scale_factor) as u32;
-
-            let scaled_img =
-                image::imageops::resize(&img, new_width, new_height, FilterType::Lanczos3);
-
-            // Calculate padding
-            let tile_count = 2_u32.pow(zoom_level);
-            let total_width = tile_count * self.tile_size;
-
-            let extra_width = total_width.saturating_sub(new_width);
-            let extra_height = total_width.saturating_sub(new_height);
-
-            // Create padded image
-            let padded_width = new_width + extra_width;
-            let padded_height = new_height + extra_height;
-
-            let mut padded_img: RgbaImage = ImageBuffer::from_pixel(
-                padded_width,
-                padded_height,
-                Rgba([
-                    config.background_color.0,
-                    config.background_color.1,
-                    config.background_color.2,
-                    255,
-                ]),
-            );
-
-            // Paste scaled image
-            let x_offset = extra_width / 2;
-            let y_offset = extra_height / 2;
-
-            image::imageops::overlay(
-                &mut padded_img,
-                &scaled_img,
-                x_offset as i64,
-                y_offset as i64,
-            );
-
-            // Generate tiles
-            let tiles_x = padded_width / self.tile_size;
-            let tiles_y = padded_height / self.tile_size;
-
-            for tile_x in 0..tiles_x {
-                for tile_y in 0..tiles_y {
-                    if *cancel_state.lock().await {
-                        *progress_state.lock().await = Some(ProgressUpdate {
-                            current: 0,
-                            total: 0,
-                            zoom_level: 0,
-                            percentage: 0,
-                            status: "Cancelled".to_string(),
-                        });
-                        return Err("Processing cancelled".to_string());
-                    }
+    let mut images = Vec::new();
+    let mut unmapped = Vec::new();
+    for path in paths {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file_stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
 
-                    let x = tile_x * self.tile_size;
-                    let y = tile_y * self.tile_size;
+        let layout_key = batch
+            .layout_keys
+            .get(&file_name)
+            .or_else(|| batch.layout_keys.get(&file_stem));
 
-                    // Extract tile and convert to RGB
-                    let tile = image::imageops::crop_imm(
-                        &padded_img,
-                        x,
-                        y,
-                        self.tile_size,
-                        self.tile_size,
-                    );
-                    let rgb_tile = image::DynamicImage::ImageRgba8(tile.to_image()).to_rgb8();
-
-                    // Convert to JPEG
-                    let mut jpeg_data = Vec::new();
-                    rgb_tile
-                        .write_to(&mut std::io::Cursor::new(&mut jpeg_data), ImageFormat::Jpeg)
-                        .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
-
-                    // Upload tile
-                    let url = format!(
-                        "{}/LayoutUtil/UploadTile/{}/{}/{}/{}/{}?__sc__={}",
-                        config.server_address.trim_end_matches('/'),
-                        config.layout_key,
-                        layout_path,
-                        zoom_level,
-                        x,
-                        y,
-                        config.secret
-                    );
+        match layout_key {
+            Some(layout_key) => images.push(ProcessConfig {
+                image_path: path.to_string_lossy().to_string(),
+                layout_key: layout_key.clone(),
+                ..batch.settings.clone()
+            }),
+            None => unmapped.push(file_name),
+        }
+    }
+
+    if images.is_empty() {
+        return Err(ProcessError::InvalidConfig(
+            "None of the batch images have a layout key".to_string(),
+        ));
+    }
+
+    Ok((images, unmapped))
+}
+
+/// Upload each image of a batch, or each layer of a layered upload, in turn
+/// under one job. A failed image doesn't stop the rest; failures are
+/// collected into the final result.
+async fn run_batch(
+    app: AppHandle,
+    job: &Job,
+    images: Vec<ProcessConfig>,
+    unmapped: Vec<String>,
+) -> Result<String, ProcessError> {
+    let layered = images[0].layer.is_some();
+    let mut reporter = reporter(&app, job);
+    let status = if layered {
+        "Starting layers..."
+    } else {
+        "Starting batch..."
+    };
+    reporter
+        .started(ProgressUpdate::with_status(&job.id, status))
+        .await;
+
+    let result = async {
+        // Every image shares the batch settings, so one processor serves all
+        let tile_hashes = tile_hash_store(&app)?;
+        let processor = TileProcessor::new(&images[0])?
+            .with_tile_hashes(tile_hashes.clone())
+            .with_tile_cache(tile_cache(&app)?);
+        let manifest_dir = manifest_dir(&app)?;
+        let orphans = OrphanStore::new(&manifest_dir);
+
+        // Tile counts only need the image headers, so the whole batch total is
+        // known before anything is decoded; each image's count is corrected
+        // once it is prepared, when trimming makes it smaller
+        let mut tile_counts = Vec::with_capacity(images.len());
+        for config in &images {
+            let header = read_header(config)?;
+            tile_counts.push(processor.count_tiles(header.width, header.height));
+        }
+        let mut tiles_total = tile_counts.iter().sum();
+
+        let mut tiles_before = 0;
+        let mut failed = Vec::new();
+        for (index, config) in images.iter().enumerate() {
+            if job.cancel.is_cancelled() {
+                reporter.cancelled().await;
+                return Err(ProcessError::Cancelled);
+            }
+
+            reporter.set_batch_position(BatchPosition {
+                index,
+                count: images.len(),
+                tiles_before,
+                tiles_total,
+                tiles: tile_counts[index],
+                layer: config.layer.clone(),
+            });
+
+            let image_result = async {
+                let mut manifest =
+                    JobManifest::create(&manifest_dir, config, tile_hashes.layout_path(config)?)?;
+                let result = processor
+                    .process_tiles(config, &mut manifest, &mut reporter, job.control())
+                    .await;
+                match result {
+                    Ok(summary) => {
+                        job.add_summary(&summary).await;
+                        orphans.remove(&manifest.layout_path)?;
+                        manifest.remove()
+                    }
+                    Err(e) => {
+                        orphans::abandon(config, manifest, &orphans).await;
+                        Err(e)
+                    }
+                }
+            }
+            .await;
 
-                    self.upload_tile(&url, &jpeg_data)
-                        .await
-                        .map_err(|e| format!("Upload failed: {}", e))?;
-
-                    current_tile += 1;
-
-                    // Update progress
-                    let percentage = (current_tile * 100) / total_tiles;
-                    let progress = ProgressUpdate {
-                        current: current_tile,
-                        total: total_tiles,
-                        zoom_level,
-                        percentage,
-                        status: format!(
-                            "Processing zoom level {} ({}/{})",
-                            zoom_level, current_tile, total_tiles
-                        ),
-                    };
-
-                    *progress_state.lock().await = Some(progress);
+            if let Err(e) = image_result {
+                if job.cancel.is_cancelled() {
+                    return Err(e);
                 }
+                let name = config.layer.as_ref().unwrap_or(&config.layout_key);
+                failed.push(format!("{}: {}", name, e));
+            }
+
+            // As corrected once the image was prepared
+            if let Some(position) = reporter.batch_position() {
+                tiles_before = position.tiles_before + position.tiles;
+                tiles_total = position.tiles_total;
             }
         }
 
-        // Final cancellation check before finalize
-        if *cancel_state.lock().await {
-            *progress_state.lock().await = Some(ProgressUpdate {
-                current: 0,
-                total: 0,
-                zoom_level: 0,
-                percentage: 0,
-                status: "Cancelled".to_string(),
+        let mut message = if layered {
+            format!(
+                "Layers completed successfully! {} of {} layers uploaded",
+                images.len() - failed.len(),
+                images.len()
+            )
+        } else {
+            format!(
+                "Batch completed successfully! {} of {} layouts uploaded",
+                images.len() - failed.len(),
+                images.len()
+            )
+        };
+        if !unmapped.is_empty() {
+            message.push_str(&format!(". No layout key for: {}", unmapped.join(", ")));
+        }
+        if !failed.is_empty() {
+            return Err(ProcessError::Batch {
+                failed: failed.len(),
+                total: images.len(),
+                errors: failed,
             });
-            return Err("Processing cancelled".to_string());
         }
+        Ok(message)
+    }
+    .await;
 
-        // Finalize upload
-        self.finalize_upload(
-            &config.server_address,
-            &config.layout_key,
-            &layout_path,
-            &config.secret,
-            max_zoom,
-        )
+    match &result {
+        Ok(message) => reporter.completed(message, None),
+        // A cancelled run has already announced itself
+        Err(e) if !job.cancel.is_cancelled() => reporter.failed(e),
+        Err(_) => {}
+    }
+    result
+}
+
+/// Upload a folder or list of images, each to its own layout, as one job.
+#[tauri::command]
+async fn start_batch_processing(
+    app: AppHandle,
+    mut batch: BatchConfig,
+    jobs: State<'_, JobManager>,
+) -> Result<String, ProcessError> {
+    secrets::fill_in(&mut batch.settings).await?;
+    secrets::remember(&batch.settings).await;
+    let (images, unmapped) = batch_images(&batch)?;
+
+    // Shown by `list_jobs`, and keeps the same batch from running twice
+    let label = ProcessConfig {
+        image_path: batch
+            .directory
+            .clone()
+            .unwrap_or_else(|| format!("{} images", images.len())),
+        layout_key: images
+            .iter()
+            .map(|image| image.layout_key.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        ..batch.settings.clone()
+    };
+    let job = jobs.register(&label).await?;
+
+    let job_id = job.id.clone();
+    tauri::async_runtime::spawn(async move {
+        let result = run_batch(app.clone(), &job, images, unmapped).await;
+        finish_job(&app, &job, &result).await;
+    });
+    Ok(job_id)
+}
+
+/// Layout paths that got tiles from a failed or cancelled run but were
+/// never finalized, oldest first.
+#[tauri::command]
+async fn list_orphans(app: AppHandle) -> Result<Vec<OrphanedLayout>, ProcessError> {
+    let store = OrphanStore::new(&manifest_dir(&app)?);
+    tokio::task::spawn_blocking(move || store.list())
         .await
-        .map_err(|e| format!("Failed to finalize upload: {}", e))?;
+        .map_err(|e| ProcessError::Internal(format!("Orphan scan failed: {}", e)))?
+}
+
+/// Remove the tiles of every orphaned layout path through its server's
+/// cleanup endpoint, using the stored credentials. Layout paths a running
+/// job is resuming are left alone, and one whose credentials can't be read
+/// is reported as not removed.
+#[tauri::command]
+async fn cleanup_orphans(
+    app: AppHandle,
+    jobs: State<'_, JobManager>,
+) -> Result<Vec<OrphanCleanup>, ProcessError> {
+    let dir = manifest_dir(&app)?;
+    let store = OrphanStore::new(&dir);
 
-        Ok(max_zoom)
+    let mut results = Vec::new();
+    for orphan in store.list()? {
+        if jobs.is_running_for(&orphan.config).await {
+            continue;
+        }
+        // A credential store that fails for one orphan fails only its
+        // cleanup
+        let mut config = orphan.config.clone();
+        if let Err(e) = secrets::fill_in(&mut config).await {
+            results.push(OrphanCleanup::failed(&orphan, e.to_string()));
+            continue;
+        }
+        results.push(orphans::cleanup_orphan(&orphan, config, &dir, &store).await);
     }
+    Ok(results)
+}
 
-    async fn upload_tile(&self, url: &str, data: &[u8]) -> Result<(), reqwest::Error> {
-        let client = reqwest::Client::new();
-        let part = reqwest::multipart::Part::bytes(data.to_vec())
-            .file_name("tile.jpg")
-            .mime_str("image/jpeg")
-            .unwrap();
+/// Delete every cached tile.
+#[tauri::command]
+async fn clear_tile_cache(app: AppHandle) -> Result<(), ProcessError> {
+    let cache = tile_cache(&app)?;
+    tokio::task::spawn_blocking(move || cache.clear())
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Cache cleanup failed: {}", e)))?
+}
 
-        let form = reqwest::multipart::Form::new().part("file", part);
+/// Latest progress snapshot of a job, for a window that missed the events.
+#[tauri::command]
+async fn get_progress(
+    job_id: String,
+    jobs: State<'_, JobManager>,
+) -> Result<Option<ProgressUpdate>, ProcessError> {
+    let job = jobs.get(&job_id).await?;
+    let progress = job.progress.lock().await.clone();
+    Ok(progress)
+}
 
-        client
-            .post(url)
-            .header("User-Agent", "SDLayoutUploader-Tauri")
-            .multipart(form)
-            .send()
-            .await?
-            .error_for_status()?;
+#[tauri::command]
+async fn cancel_processing(
+    job_id: String,
+    jobs: State<'_, JobManager>,
+) -> Result<(), ProcessError> {
+    let job = jobs.get(&job_id).await?;
+    job.cancel.cancel();
+    *job.progress.lock().await = Some(ProgressUpdate::with_status(&job_id, "Cancelling..."));
+    Ok(())
+}
 
-        Ok(())
+/// Hold a running job once its in-flight uploads finish; tiles generated so
+/// far are kept and nothing is lost until it is resumed or cancelled.
+#[tauri::command]
+async fn pause_processing(job_id: String, jobs: State<'_, JobManager>) -> Result<(), ProcessError> {
+    let job = jobs.get(&job_id).await?;
+    if !job.pause().await {
+        return Err(ProcessError::InvalidState(
+            "Only a running job can be paused".to_string(),
+        ));
     }
+    Ok(())
+}
 
-    async fn finalize_upload(
-        &self,
-        server: &str,
-        layout_key: &str,
-        layout_path: &str,
-        secret: &str,
-        max_zoom: u32,
-    ) -> Result<(), reqwest::Error> {
-        let url = format!(
-            "{}/api/Location/LocationLayout/UpdatePath",
-            server.trim_end_matches('/')
-        );
-
-        let max_zoom_str = max_zoom.to_string();
-        let mut params = HashMap::new();
-        params.insert("LayoutKey", layout_key);
-        params.insert("LayoutPath", layout_path);
-        params.insert("apikey", secret);
-        params.insert("MaxZoom", &max_zoom_str);
-
-        let client = reqwest::Client::new();
-        client
-            .get(&url)
-            .header("User-Agent", "SDLayoutUploader-Tauri")
-            .query(&params)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        Ok(())
+/// Carry on with a job held by `pause_processing`.
+#[tauri::command]
+async fn resume_processing(
+    job_id: String,
+    jobs: State<'_, JobManager>,
+) -> Result<(), ProcessError> {
+    let job = jobs.get(&job_id).await?;
+    if !job.resume().await {
+        return Err(ProcessError::InvalidState(
+            "Only a paused job can be resumed".to_string(),
+        ));
     }
+    Ok(())
 }
 
 #[tauri::command]
-async fn select_image_file() -> Result<Option<String>, String> {
-    use rfd::AsyncFileDialog;
+async fn list_jobs(jobs: State<'_, JobManager>) -> Result<Vec<JobInfo>, ProcessError> {
+    Ok(jobs.list().await)
+}
 
-    let file = AsyncFileDialog::new()
-        .add_filter("Images", &["png", "jpg", "jpeg", "gif", "bmp", "webp"])
-        .set_title("Select Image File")
-        .pick_file()
-        .await;
+/// Keep `secret` in the OS credential store for `server_address`. Jobs
+/// started with an empty secret use it from then on.
+#[tauri::command]
+async fn save_secret(server_address: String, secret: String) -> Result<(), ProcessError> {
+    secrets::save(server_address, secret).await
+}
 
-    match file {
-        Some(file_handle) => Ok(Some(file_handle.path().to_string_lossy().to_string())),
-        None => Ok(None),
+#[tauri::command]
+async fn load_secret(server_address: String) -> Result<Option<String>, ProcessError> {
+    secrets::load(server_address).await
+}
+
+#[tauri::command]
+async fn delete_secret(server_address: String) -> Result<(), ProcessError> {
+    secrets::delete(server_address).await
+}
+
+/// Every server profile, with its passwords filled in from the credential
+/// store.
+#[tauri::command]
+async fn list_profiles(
+    profiles: State<'_, ProfileStore>,
+) -> Result<Vec<ServerProfile>, ProcessError> {
+    let mut profiles = profiles.list().await;
+    for profile in &mut profiles {
+        secrets::fill_in_profile(profile).await;
     }
+    Ok(profiles)
 }
 
+/// Save a new server profile and return it with its id.
 #[tauri::command]
-async fn start_processing(
-    config: ProcessConfig,
-    progress_state: State<'_, ProgressState>,
-    cancel_state: State<'_, CancelState>,
-) -> Result<String, String> {
-    // Clear any previous state before starting new processing
-    *cancel_state.lock().await = false;
-    *progress_state.lock().await = Some(ProgressUpdate {
-        current: 0,
-        total: 0,
-        zoom_level: 0,
-        percentage: 0,
-        status: "Starting...".to_string(),
-    });
-    
-    let processor = TileProcessor::new(config.tile_size);
+async fn create_profile(
+    profile: ServerProfile,
+    profiles: State<'_, ProfileStore>,
+) -> Result<ServerProfile, ProcessError> {
+    profiles.create(profile).await
+}
 
-    match processor
-        .process_tiles(
-            &config,
-            progress_state.inner().clone(),
-            cancel_state.inner().clone(),
-        )
+#[tauri::command]
+async fn update_profile(
+    profile: ServerProfile,
+    profiles: State<'_, ProfileStore>,
+) -> Result<ServerProfile, ProcessError> {
+    profiles.update(profile).await
+}
+
+#[tauri::command]
+async fn delete_profile(id: String, profiles: State<'_, ProfileStore>) -> Result<(), ProcessError> {
+    profiles.delete(&id).await
+}
+
+/// Every run recorded on this machine, newest first.
+#[tauri::command]
+async fn get_history(history: State<'_, HistoryStore>) -> Result<Vec<HistoryEntry>, ProcessError> {
+    Ok(history.list().await)
+}
+
+/// Write the run history to `path` as `json` or `csv`, for audits.
+#[tauri::command]
+async fn export_history(
+    path: String,
+    format: ExportFormat,
+    history: State<'_, HistoryStore>,
+) -> Result<(), ProcessError> {
+    history.export(Path::new(&path), format).await
+}
+
+fn log_dir(app: &AppHandle) -> Result<PathBuf, ProcessError> {
+    app.path()
+        .app_log_dir()
+        .map_err(|e| ProcessError::Internal(format!("Failed to resolve log directory: {}", e)))
+}
+
+/// The last `lines` lines of today's log, for support tickets.
+#[tauri::command]
+async fn get_log_tail(app: AppHandle, lines: usize) -> Result<Vec<String>, ProcessError> {
+    let dir = log_dir(&app)?;
+    tokio::task::spawn_blocking(move || logging::tail(&dir, lines))
         .await
-    {
-        Ok(max_zoom) => Ok(format!(
-            "Processing completed successfully! Max zoom level: {}",
-            max_zoom
-        )),
-        Err(e) => Err(e),
-    }
+        .map_err(|e| ProcessError::Internal(format!("Log read failed: {}", e)))?
 }
 
+/// Open the finalized layout in the browser, at the viewer URL of the last
+/// job that uploaded it this session.
 #[tauri::command]
-async fn get_progress(
-    progress_state: State<'_, ProgressState>,
-) -> Result<Option<ProgressUpdate>, ()> {
-    Ok(progress_state.lock().await.clone())
+async fn open_layout(
+    app: AppHandle,
+    layout_key: String,
+    jobs: State<'_, JobManager>,
+) -> Result<(), ProcessError> {
+    let url = jobs.viewer_url(&layout_key).await.ok_or_else(|| {
+        ProcessError::InvalidState(format!(
+            "Layout {} hasn't been published in this session",
+            layout_key
+        ))
+    })?;
+    app.opener()
+        .open_url(url, None::<&str>)
+        .map_err(|e| ProcessError::Internal(format!("Failed to open layout: {}", e)))
 }
 
+/// Show the log folder in the file manager.
 #[tauri::command]
-async fn cancel_processing(
-    progress_state: State<'_, ProgressState>,
-    cancel_state: State<'_, CancelState>,
-) -> Result<(), ()> {
-    *cancel_state.lock().await = true;
-    *progress_state.lock().await = Some(ProgressUpdate {
-        current: 0,
-        total: 0,
-        zoom_level: 0,
-        percentage: 0,
-        status: "Cancelling...".to_string(),
-    });
-    Ok(())
+async fn open_log_folder(app: AppHandle) -> Result<(), ProcessError> {
+    let dir = log_dir(&app)?;
+    app.opener()
+        .open_path(dir.to_string_lossy(), None::<&str>)
+        .map_err(|e| ProcessError::Internal(format!("Failed to open log folder: {}", e)))
 }
 
 #[tauri::command]
-async fn read_file_as_bytes(path: String) -> Result<Vec<u8>, String> {
-    std::fs::read(&path).map_err(|e| format!("Failed to read file: {}", e))
+async fn read_file_as_bytes(path: String) -> Result<Vec<u8>, ProcessError> {
+    std::fs::read(&path).map_err(|e| ProcessError::io("Failed to read file", e))
 }
 
 fn main() {
@@ -370,13 +1059,86 @@ fn main() {
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_http::init())
-        .manage(ProgressState::new(Mutex::new(None)))
-        .manage(CancelState::new(Mutex::new(false)))
+        .plugin(tauri_plugin_notification::init())
+        .manage(JobManager::default())
+        .setup(|app| {
+            let log_guard = logging::init(&app.path().app_log_dir()?)?;
+            app.manage(log_guard);
+            let path = app.path().app_config_dir()?.join(PROFILES_FILE);
+            app.manage(ProfileStore::load(path)?);
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let profiles = handle.state::<ProfileStore>();
+                if let Err(e) = profiles.move_passwords_to_store().await {
+                    tracing::warn!(
+                        "Failed to move profile passwords to the credential store: {}",
+                        e
+                    );
+                }
+            });
+            let path = app.path().app_data_dir()?.join(HISTORY_FILE);
+            app.manage(HistoryStore::load(path)?);
+            tray::init(app.handle())?;
+            Ok(())
+        })
+        .on_window_event(|window, event| {
+            match event {
+                // Closing the window while jobs run only hides it; the tray
+                // brings it back or quits
+                WindowEvent::CloseRequested { api } => {
+                    let busy = window
+                        .app_handle()
+                        .try_state::<Tray>()
+                        .is_some_and(|tray| tray.is_busy());
+                    if busy {
+                        let _ = window.hide();
+                        api.prevent_close();
+                    }
+                }
+                WindowEvent::DragDrop(DragDropEvent::Drop { paths, .. }) => {
+                    dropped::handle(window.app_handle(), paths.clone());
+                }
+                _ => {}
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             select_image_file,
+            select_output_folder,
+            test_connection,
+            estimate_job,
+            get_image_info,
+            get_pdf_pages,
+            get_gif_frames,
+            detect_background,
+            generate_preview,
+            render_tile,
+            diff_published_layout,
             start_processing,
+            resume_interrupted_upload,
+            list_resumable_jobs,
+            list_orphans,
+            cleanup_orphans,
+            clear_tile_cache,
+            start_batch_processing,
+            upload_existing_pyramid,
+            upload_package,
             get_progress,
+            pause_processing,
+            resume_processing,
             cancel_processing,
+            list_jobs,
+            save_secret,
+            load_secret,
+            delete_secret,
+            list_profiles,
+            create_profile,
+            update_profile,
+            delete_profile,
+            get_history,
+            export_history,
+            get_log_tail,
+            open_log_folder,
+            open_layout,
             read_file_as_bytes
         ])
         .run(tauri::generate_context!())
@@ -0,0 +1,67 @@
+//! OS notifications for finished jobs, so a long upload running behind
+//! other windows doesn't need watching.
+
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+use layout_uploader_core::progress::JobMessage;
+
+/// Announce a completed job, unless the user is looking at the window.
+pub fn completed(app: &AppHandle, layout: &str, message: &JobMessage) {
+    let body = match &message.summary {
+        Some(summary) => format!(
+            "Layout '{}' uploaded, {} tiles in {}",
+            layout,
+            thousands(summary.tiles_uploaded),
+            duration(summary.phases.total_secs)
+        ),
+        None => message.message.clone(),
+    };
+    show(app, "Upload complete", &body);
+}
+
+/// Announce a failed job with its error code, unless the user is looking
+/// at the window.
+pub fn failed(app: &AppHandle, layout: &str, message: &JobMessage) {
+    let body = match &message.code {
+        Some(code) => format!("Layout '{}' failed [{}]: {}", layout, code, message.message),
+        None => format!("Layout '{}' failed: {}", layout, message.message),
+    };
+    show(app, "Upload failed", &body);
+}
+
+fn show(app: &AppHandle, title: &str, body: &str) {
+    let focused = app
+        .get_webview_window("main")
+        .and_then(|window| window.is_focused().ok())
+        .unwrap_or(false);
+    if focused {
+        return;
+    }
+    if let Err(e) = app.notification().builder().title(title).body(body).show() {
+        tracing::warn!(error = %e, "Failed to show notification");
+    }
+}
+
+/// `14322` as `14,322`.
+fn thousands(count: u32) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// A run's length the way a person would say it: `40 s`, `22 min`, `1 h 5 min`.
+fn duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match secs {
+        0..=59 => format!("{} s", secs),
+        60..=3_599 => format!("{} min", secs / 60),
+        _ => format!("{} h {} min", secs / 3_600, secs % 3_600 / 60),
+    }
+}
@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use layout_uploader_core::config::{AuthMode, ProcessConfig, QualityStep, TileFormat, TileSize};
+use layout_uploader_core::error::ProcessError;
+use layout_uploader_core::network::{ClientCertificate, NetworkSettings};
+
+use crate::secrets;
+
+pub const PROFILES_FILE: &str = "profiles.json";
+
+/// A server the user uploads to, with the settings they use for it. The
+/// secret is not part of it; it lives in the credential store, as do the
+/// proxy and client certificate passwords of `network`, kept under the
+/// profile's id and only filled in for `list_profiles`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerProfile {
+    // Assigned by `ProfileStore::create`
+    pub id: String,
+    pub name: String,
+    pub server_address: String,
+    // Layouts uploaded to on this server, offered as choices
+    pub layout_keys: Vec<String>,
+    pub tile_size: TileSize,
+    pub tile_format: TileFormat,
+    pub jpeg_quality: u8,
+    pub webp_quality: u8,
+    pub quality_ramp: Vec<QualityStep>,
+    pub auth: AuthMode,
+    pub network: NetworkSettings,
+}
+
+impl Default for ServerProfile {
+    fn default() -> Self {
+        let defaults =
+            ProcessConfig::new(String::new(), String::new(), String::new(), String::new());
+        Self {
+            id: String::new(),
+            name: String::new(),
+            server_address: String::new(),
+            layout_keys: Vec::new(),
+            tile_size: defaults.tile_size,
+            tile_format: defaults.tile_format,
+            jpeg_quality: defaults.jpeg_quality,
+            webp_quality: defaults.webp_quality,
+            quality_ramp: defaults.quality_ramp,
+            auth: defaults.auth,
+            network: defaults.network,
+        }
+    }
+}
+
+/// Server profiles, kept in `profiles.json` in the app config directory and
+/// rewritten on every change.
+pub struct ProfileStore {
+    path: PathBuf,
+    profiles: Mutex<Vec<ServerProfile>>,
+}
+
+impl ProfileStore {
+    /// Read the profiles at `path`; a missing file means there are none yet.
+    pub fn load(path: PathBuf) -> Result<Self, ProcessError> {
+        let profiles = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+                ProcessError::InvalidConfig(format!("Invalid profiles file: {}", e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(ProcessError::io("Failed to read profiles", e)),
+        };
+        Ok(Self {
+            path,
+            profiles: Mutex::new(profiles),
+        })
+    }
+
+    pub async fn list(&self) -> Vec<ServerProfile> {
+        self.profiles.lock().await.clone()
+    }
+
+    pub async fn create(&self, mut profile: ServerProfile) -> Result<ServerProfile, ProcessError> {
+        let mut profiles = self.profiles.lock().await;
+        profile.id = Uuid::new_v4().to_string();
+        validate(&profile, &profiles)?;
+
+        secrets::save_profile_passwords(&profile).await?;
+        profiles.push(without_passwords(&profile));
+        self.save(&profiles)?;
+        Ok(profile)
+    }
+
+    pub async fn update(&self, profile: ServerProfile) -> Result<ServerProfile, ProcessError> {
+        let mut profiles = self.profiles.lock().await;
+        validate(&profile, &profiles)?;
+        let slot = profiles
+            .iter_mut()
+            .find(|existing| existing.id == profile.id)
+            .ok_or_else(|| ProcessError::ProfileNotFound(profile.id.clone()))?;
+
+        secrets::save_profile_passwords(&profile).await?;
+        *slot = without_passwords(&profile);
+        self.save(&profiles)?;
+        Ok(profile)
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), ProcessError> {
+        let mut profiles = self.profiles.lock().await;
+        let index = profiles
+            .iter()
+            .position(|profile| profile.id == id)
+            .ok_or_else(|| ProcessError::ProfileNotFound(id.to_string()))?;
+
+        profiles.remove(index);
+        self.save(&profiles)?;
+        secrets::delete_profile_passwords(id).await
+    }
+
+    /// Move the passwords of profiles saved before the credential store
+    /// kept them into the store, and rewrite the file without them.
+    pub async fn move_passwords_to_store(&self) -> Result<(), ProcessError> {
+        let mut profiles = self.profiles.lock().await;
+        let mut moved = false;
+        for profile in profiles.iter_mut() {
+            // Moved passwords are left empty in the file
+            let set =
+                |password: &Option<String>| password.as_deref().is_some_and(|p| !p.is_empty());
+            let network = &profile.network;
+            let has_passwords = network
+                .proxy
+                .as_ref()
+                .is_some_and(|proxy| set(&proxy.password))
+                || matches!(
+                    &network.client_certificate,
+                    Some(ClientCertificate::Pkcs12 { password, .. }) if set(password)
+                );
+            if has_passwords {
+                secrets::save_profile_passwords(profile).await?;
+                *profile = without_passwords(profile);
+                moved = true;
+            }
+        }
+        if moved {
+            self.save(&profiles)?;
+        }
+        Ok(())
+    }
+
+    // Written next to the file and renamed over it, so a crash mid-write
+    // can't lose every profile
+    fn save(&self, profiles: &[ServerProfile]) -> Result<(), ProcessError> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| ProcessError::io("Failed to create config directory", e))?;
+        }
+        let contents = serde_json::to_vec_pretty(profiles)
+            .map_err(|e| ProcessError::Internal(format!("Failed to encode profiles: {}", e)))?;
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(&temp, contents)
+            .map_err(|e| ProcessError::io("Failed to write profiles", e))?;
+        std::fs::rename(&temp, &self.path)
+            .map_err(|e| ProcessError::io("Failed to write profiles", e))
+    }
+}
+
+fn without_passwords(profile: &ServerProfile) -> ServerProfile {
+    ServerProfile {
+        network: profile.network.without_secrets(),
+        ..profile.clone()
+    }
+}
+
+fn validate(profile: &ServerProfile, profiles: &[ServerProfile]) -> Result<(), ProcessError> {
+    if profile.name.trim().is_empty() || profile.server_address.trim().is_empty() {
+        return Err(ProcessError::InvalidConfig(
+            "A profile needs a name and a server address".to_string(),
+        ));
+    }
+    let taken = profiles
+        .iter()
+        .any(|other| other.id != profile.id && other.name.eq_ignore_ascii_case(&profile.name));
+    if taken {
+        return Err(ProcessError::InvalidConfig(format!(
+            "A profile named '{}' already exists",
+            profile.name
+        )));
+    }
+    Ok(())
+}
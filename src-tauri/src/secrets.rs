@@ -0,0 +1,301 @@
+//! Server secrets kept in the platform credential store (Windows Credential
+//! Manager, macOS Keychain, Secret Service on Linux) rather than by the
+//! frontend.
+
+use keyring::Entry;
+use layout_uploader_core::config::{OutputTarget, ProcessConfig, SftpAuth};
+use layout_uploader_core::error::ProcessError;
+use layout_uploader_core::network::{ClientCertificate, NetworkSettings};
+
+use crate::profiles::ServerProfile;
+
+const SERVICE: &str = "iviva-layout-uploader";
+
+/// One secret per server, so every layout on it shares the key.
+fn entry(server_address: &str) -> Result<Entry, ProcessError> {
+    let account = server_address.trim().trim_end_matches('/');
+    if account.is_empty() {
+        return Err(ProcessError::InvalidConfig(
+            "A server address is required to store its secret".to_string(),
+        ));
+    }
+    Entry::new(SERVICE, account).map_err(store_error)
+}
+
+/// The other credentials a run can carry are each kept under what they
+/// unlock, such as `proxy:user@host`, so a config written to disk without
+/// them can be filled in again.
+fn credential_entry(account: &str) -> Result<Entry, ProcessError> {
+    Entry::new(SERVICE, account).map_err(store_error)
+}
+
+fn store_error(error: keyring::Error) -> ProcessError {
+    ProcessError::SecretStore(error.to_string())
+}
+
+// The store may go over D-Bus or show a system prompt, so it is kept off
+// the async runtime
+async fn blocking<T: Send + 'static>(
+    task: impl FnOnce() -> Result<T, ProcessError> + Send + 'static,
+) -> Result<T, ProcessError> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| ProcessError::Internal(format!("Credential store task failed: {}", e)))?
+}
+
+pub async fn save(server_address: String, secret: String) -> Result<(), ProcessError> {
+    blocking(move || {
+        entry(&server_address)?
+            .set_password(&secret)
+            .map_err(store_error)
+    })
+    .await
+}
+
+pub async fn load(server_address: String) -> Result<Option<String>, ProcessError> {
+    blocking(move || match entry(&server_address)?.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(store_error(e)),
+    })
+    .await
+}
+
+pub async fn delete(server_address: String) -> Result<(), ProcessError> {
+    blocking(move || match entry(&server_address)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(store_error(e)),
+    })
+    .await
+}
+
+/// Use the stored secrets for `config.server_address` and each mirror
+/// where the frontend left the secret empty, and the stored proxy, client
+/// certificate, S3 and SFTP credentials where those were taken out, as they
+/// are in configs read back from disk. A credential the store can't give is
+/// left out with a warning, so runs that don't need it go ahead.
+pub async fn fill_in(config: &mut ProcessConfig) -> Result<(), ProcessError> {
+    fill_in_secret(&config.server_address, &mut config.secret).await?;
+    for mirror in &mut config.mirrors {
+        fill_in_secret(&mirror.server_address, &mut mirror.secret).await?;
+    }
+    for (account, mut credential) in credentials(config) {
+        if !credential.is_missing() {
+            continue;
+        }
+        match load_credential(account).await {
+            Ok(Some(stored)) => credential.set(stored),
+            Ok(None) => credential.clear(),
+            Err(e) => {
+                tracing::warn!("Failed to load a stored credential: {}", e);
+                credential.clear();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keep the proxy, client certificate, S3 and SFTP credentials of `config`
+/// in the store, so a run resumed or cleaned up from its config on disk,
+/// which has none, can fill them in again. Without a store the run goes
+/// ahead, and those credentials have to be given again to resume it.
+pub async fn remember(config: &ProcessConfig) {
+    let mut config = config.clone();
+    for (account, mut credential) in credentials(&mut config) {
+        let Some(credential) = credential.take() else {
+            continue;
+        };
+        let stored = blocking(move || {
+            credential_entry(&account)?
+                .set_password(&credential)
+                .map_err(store_error)
+        })
+        .await;
+        if let Err(e) = stored {
+            tracing::warn!("Failed to store a credential for resuming: {}", e);
+        }
+    }
+}
+
+/// Keep the proxy and client certificate passwords of `profile` in the
+/// store under its id, and drop the ones it no longer has.
+pub async fn save_profile_passwords(profile: &ServerProfile) -> Result<(), ProcessError> {
+    let mut network = profile.network.clone();
+    for (account, credential) in profile_passwords(&profile.id, &mut network) {
+        let password = credential.and_then(|mut credential| credential.take());
+        blocking(move || {
+            let entry = credential_entry(&account)?;
+            match password {
+                Some(password) => entry.set_password(&password).map_err(store_error),
+                None => match entry.delete_credential() {
+                    Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                    Err(e) => Err(store_error(e)),
+                },
+            }
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// Fill in the passwords stored for `profile`, which its file leaves out.
+/// A password the store can't give is left out with a warning.
+pub async fn fill_in_profile(profile: &mut ServerProfile) {
+    let id = profile.id.clone();
+    for (account, credential) in profile_passwords(&id, &mut profile.network) {
+        let Some(mut credential) = credential.filter(Credential::is_missing) else {
+            continue;
+        };
+        match load_credential(account).await {
+            Ok(Some(stored)) => credential.set(stored),
+            Ok(None) => credential.clear(),
+            Err(e) => {
+                tracing::warn!(profile = %id, "Failed to load a stored profile password: {}", e);
+                credential.clear();
+            }
+        }
+    }
+}
+
+/// Forget the passwords stored for the profile `id`.
+pub async fn delete_profile_passwords(id: &str) -> Result<(), ProcessError> {
+    let mut network = NetworkSettings::default();
+    for (account, _) in profile_passwords(id, &mut network) {
+        blocking(
+            move || match credential_entry(&account)?.delete_credential() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(store_error(e)),
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The accounts a profile's proxy and client certificate passwords are
+/// stored as, with the password fields of `network` where it has them.
+fn profile_passwords<'a>(
+    id: &str,
+    network: &'a mut NetworkSettings,
+) -> [(String, Option<Credential<'a>>); 2] {
+    let proxy = network
+        .proxy
+        .as_mut()
+        .map(|proxy| Credential::Optional(&mut proxy.password));
+    let client_certificate = match &mut network.client_certificate {
+        Some(ClientCertificate::Pkcs12 { password, .. }) => Some(Credential::Optional(password)),
+        _ => None,
+    };
+    [
+        (format!("profile:{}:proxy", id), proxy),
+        (
+            format!("profile:{}:client-certificate", id),
+            client_certificate,
+        ),
+    ]
+}
+
+async fn load_credential(account: String) -> Result<Option<String>, ProcessError> {
+    blocking(move || match credential_entry(&account)?.get_password() {
+        Ok(stored) => Ok(Some(stored)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(store_error(e)),
+    })
+    .await
+}
+
+/// The credentials of `config` besides the server secrets, each under the
+/// account it is stored as.
+fn credentials(config: &mut ProcessConfig) -> Vec<(String, Credential<'_>)> {
+    let mut credentials = Vec::new();
+    // A proxy password means nothing without a username
+    if let Some(proxy) = config.network.proxy.as_mut().filter(|proxy| {
+        proxy
+            .username
+            .as_deref()
+            .is_some_and(|name| !name.is_empty())
+    }) {
+        let account = format!(
+            "proxy:{}@{}",
+            proxy.username.as_deref().unwrap_or_default(),
+            proxy.url
+        );
+        credentials.push((account, Credential::Optional(&mut proxy.password)));
+    }
+    if let Some(ClientCertificate::Pkcs12 { path, password }) =
+        &mut config.network.client_certificate
+    {
+        let account = format!("client-certificate:{}", path);
+        credentials.push((account, Credential::Optional(password)));
+    }
+    match &mut config.output {
+        OutputTarget::S3(s3) => {
+            let account = format!("s3:{}@{}", s3.access_key_id, s3.endpoint);
+            credentials.push((account, Credential::Required(&mut s3.secret_access_key)));
+        }
+        OutputTarget::Sftp(sftp) => match &mut sftp.auth {
+            SftpAuth::Password { password } => {
+                let account = format!("sftp:{}@{}:{}", sftp.username, sftp.host, sftp.port);
+                credentials.push((account, Credential::Required(password)));
+            }
+            SftpAuth::Key { path, passphrase } => {
+                let account = format!("sftp-key:{}", path);
+                credentials.push((account, Credential::Optional(passphrase)));
+            }
+        },
+        _ => {}
+    }
+    credentials
+}
+
+/// A credential field, whether the config models a missing one as `None`
+/// or as an empty string.
+enum Credential<'a> {
+    /// `None` when there is none, and empty once taken out of a config
+    /// written to disk
+    Optional(&'a mut Option<String>),
+    Required(&'a mut String),
+}
+
+impl Credential<'_> {
+    /// Whether the credential was taken out and should come from the store.
+    fn is_missing(&self) -> bool {
+        match self {
+            Credential::Optional(value) => value.as_deref() == Some(""),
+            Credential::Required(value) => value.is_empty(),
+        }
+    }
+
+    /// Leave an optional credential the store doesn't have unset.
+    fn clear(&mut self) {
+        if let Credential::Optional(value) = self {
+            **value = None;
+        }
+    }
+
+    /// The credential, left empty in its place; `None` if there is none.
+    fn take(&mut self) -> Option<String> {
+        let value = match self {
+            Credential::Optional(value) => value.take().unwrap_or_default(),
+            Credential::Required(value) => std::mem::take(*value),
+        };
+        (!value.is_empty()).then_some(value)
+    }
+
+    fn set(&mut self, credential: String) {
+        match self {
+            Credential::Optional(value) => **value = Some(credential),
+            Credential::Required(value) => **value = credential,
+        }
+    }
+}
+
+async fn fill_in_secret(server_address: &str, secret: &mut String) -> Result<(), ProcessError> {
+    if !secret.is_empty() || server_address.trim().is_empty() {
+        return Ok(());
+    }
+    if let Some(stored) = load(server_address.to_string()).await? {
+        *secret = stored;
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! The tray icon: progress of the running jobs in its tooltip and menu,
+//! Pause and Cancel for all of them, and a way back to the window once it
+//! has been closed while jobs run on.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tauri::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+use tauri::tray::{MouseButton, MouseButtonState, TrayIcon, TrayIconBuilder, TrayIconEvent};
+use tauri::{AppHandle, Manager};
+
+use layout_uploader_core::progress::ProgressUpdate;
+
+use crate::jobs::JobManager;
+
+const TRAY_ID: &str = "main";
+const IDLE: &str = "No uploads running";
+
+const MENU_SHOW: &str = "show";
+const MENU_PAUSE: &str = "pause";
+const MENU_CANCEL: &str = "cancel";
+const MENU_QUIT: &str = "quit";
+
+/// The tray's menu items, and how far each running job has got.
+pub struct Tray {
+    icon: TrayIcon,
+    status: MenuItem<tauri::Wry>,
+    pause: MenuItem<tauri::Wry>,
+    cancel: MenuItem<tauri::Wry>,
+    // Layout and progress of each running job, by job id
+    running: Mutex<HashMap<String, (String, ProgressUpdate)>>,
+}
+
+/// Put the icon in the tray and manage it as app state.
+pub fn init(app: &AppHandle) -> tauri::Result<()> {
+    let status = MenuItem::with_id(app, "status", IDLE, false, None::<&str>)?;
+    let show = MenuItem::with_id(app, MENU_SHOW, "Show window", true, None::<&str>)?;
+    let pause = MenuItem::with_id(app, MENU_PAUSE, "Pause", false, None::<&str>)?;
+    let cancel = MenuItem::with_id(app, MENU_CANCEL, "Cancel", false, None::<&str>)?;
+    let quit = MenuItem::with_id(app, MENU_QUIT, "Quit", true, None::<&str>)?;
+    let menu = Menu::with_items(
+        app,
+        &[
+            &status,
+            &PredefinedMenuItem::separator(app)?,
+            &show,
+            &pause,
+            &cancel,
+            &PredefinedMenuItem::separator(app)?,
+            &quit,
+        ],
+    )?;
+
+    let mut builder = TrayIconBuilder::with_id(TRAY_ID)
+        .tooltip(IDLE)
+        .menu(&menu)
+        .show_menu_on_left_click(false)
+        .on_menu_event(on_menu_event)
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                show_window(tray.app_handle());
+            }
+        });
+    if let Some(icon) = app.default_window_icon() {
+        builder = builder.icon(icon.clone());
+    }
+    let icon = builder.build(app)?;
+
+    app.manage(Tray {
+        icon,
+        status,
+        pause,
+        cancel,
+        running: Mutex::new(HashMap::new()),
+    });
+    Ok(())
+}
+
+impl Tray {
+    /// Show how far the job of `update` has got.
+    pub fn progress(&self, layout: &str, update: &ProgressUpdate) {
+        self.running
+            .lock()
+            .unwrap()
+            .insert(update.job_id.clone(), (layout.to_string(), update.clone()));
+        self.refresh();
+    }
+
+    /// Drop a job that completed, failed or was cancelled.
+    pub fn finished(&self, job_id: &str) {
+        self.running.lock().unwrap().remove(job_id);
+        self.refresh();
+    }
+
+    /// Whether any job is still going, so closing the window should only
+    /// hide it.
+    pub fn is_busy(&self) -> bool {
+        !self.running.lock().unwrap().is_empty()
+    }
+
+    fn refresh(&self) {
+        let running = self.running.lock().unwrap();
+        let all_paused = running.values().all(|(_, update)| update.paused);
+        let status = match running.len() {
+            0 => IDLE.to_string(),
+            1 => {
+                let (layout, update) = running.values().next().unwrap();
+                let state = if update.paused { ", paused" } else { "" };
+                format!("'{}': {}%{}", layout, update.percentage, state)
+            }
+            count => {
+                let percentage = running
+                    .values()
+                    .map(|(_, update)| update.percentage)
+                    .sum::<u32>()
+                    / count as u32;
+                format!("{} uploads: {}%", count, percentage)
+            }
+        };
+        let _ = self.status.set_text(&status);
+        let _ = self.icon.set_tooltip(Some(&status));
+        let _ = self.pause.set_enabled(!running.is_empty());
+        let _ = self.pause.set_text(if !running.is_empty() && all_paused {
+            "Resume"
+        } else {
+            "Pause"
+        });
+        let _ = self.cancel.set_enabled(!running.is_empty());
+    }
+}
+
+fn on_menu_event(app: &AppHandle, event: MenuEvent) {
+    match event.id().as_ref() {
+        MENU_SHOW => show_window(app),
+        MENU_PAUSE => {
+            let app = app.clone();
+            tauri::async_runtime::spawn(async move { toggle_pause(&app).await });
+        }
+        MENU_CANCEL => {
+            let app = app.clone();
+            tauri::async_runtime::spawn(async move { cancel_all(&app).await });
+        }
+        // Running jobs keep their manifests and can be resumed next time
+        MENU_QUIT => app.exit(0),
+        _ => {}
+    }
+}
+
+/// Pause every running job, or if all are paused, resume them.
+async fn toggle_pause(app: &AppHandle) {
+    let jobs = app.state::<JobManager>().active().await;
+    let mut paused = false;
+    for job in &jobs {
+        paused |= job.pause().await;
+    }
+    if !paused {
+        for job in &jobs {
+            job.resume().await;
+        }
+    }
+}
+
+async fn cancel_all(app: &AppHandle) {
+    for job in app.state::<JobManager>().active().await {
+        job.cancel.cancel();
+        *job.progress.lock().await = Some(ProgressUpdate::with_status(&job.id, "Cancelling..."));
+    }
+}
+
+pub fn show_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+}
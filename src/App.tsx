@@ -8,24 +8,94 @@ interface ProcessConfig {
     server_address: string;
     layout_key: string;
     secret: string;
-    background_color: [number, number, number];
+    // #RRGGBB, #RRGGBBAA or a CSS color name
+    background_color: string;
     tile_size: number;
+    // Page of a PDF to tile, counted from 1
+    pdf_page?: number;
+    // Frame of an animated GIF to tile, counted from 1
+    gif_frame?: number;
 }
 
 interface ProgressUpdate {
+    job_id: string;
     current: number;
     total: number;
     zoom_level: number;
     percentage: number;
     status: string;
+    skipped: number;
+    paused: boolean;
+    throttled?: boolean;
+    waiting_for_server?: boolean;
+    bytes_uploaded?: number;
+    tiles_per_second?: number;
+    upload_kbps?: number;
+    eta_seconds?: number | null;
+    elapsed_seconds?: number;
 }
 
+interface ResumableJob {
+    id: string;
+    layout_path: string;
+    config: ProcessConfig;
+    tiles_uploaded: number;
+    started_at: number;
+    updated_at: number;
+}
+
+interface JobMessage {
+    job_id: string;
+    message: string;
+    code?: string;
+    // Tiles still failing after the retry pass of a continue-on-error run
+    failed_tiles?: { zoom_level: number; x: number; y: number; error: string }[];
+    // Run summary of a completed single-image job
+    summary?: { viewer_url?: string | null };
+}
+
+// Header details of the selected image, from `get_image_info`
+interface ImageInfo {
+    width: number;
+    height: number;
+    format: string;
+    color_type: string;
+    file_size: number;
+    zoom_levels: number;
+    total_tiles: number;
+    // Estimated peak memory in bytes
+    memory: { normal_bytes: number; low_memory_bytes: number };
+}
+
+// Payload of `files://dropped`: dropped files checked by the backend
+interface DroppedFiles {
+    images: { path: string; file_name: string; width: number; height: number; format: string; file_size: number }[];
+    rejected: { path: string; reason: string }[];
+}
+
+// Error returned by every backend command
+interface ProcessError {
+    code: string;
+    message: string;
+    context: string | null;
+    status: number | null;
+}
+
+const errorMessage = (error: unknown) =>
+    (error as ProcessError)?.message ?? String(error);
+
+// e.g. 75 -> "1m 15s"
+const formatDuration = (seconds: number) => {
+    const minutes = Math.floor(seconds / 60);
+    return minutes > 0 ? `${minutes}m ${seconds % 60}s` : `${seconds}s`;
+};
+
 const DefaultConfig: ProcessConfig = {
     image_path: '',
     server_address: '',
     layout_key: '',
     secret: '',
-    background_color: [0, 0, 0],
+    background_color: '#000000',
     tile_size: 256,
 }
 
@@ -39,57 +109,89 @@ function App() {
     const [serverInput, setServerInput] = useState('');
     const [dragActive, setDragActive] = useState(false);
     const [imagePreview, setImagePreview] = useState<string | null>(null);
+    const [imageInfo, setImageInfo] = useState<ImageInfo | null>(null);
+    // Paper color along the selected image's edges, from `detect_background`
+    const [paperColor, setPaperColor] = useState<string | null>(null);
+    // Pages of the selected PDF, from `get_pdf_pages`
+    const [pdfPages, setPdfPages] = useState<{ number: number; width_pt: number; height_pt: number }[]>([]);
+    // Frames of the selected GIF, from `get_gif_frames`
+    const [gifFrames, setGifFrames] = useState(0);
+    const [viewerUrl, setViewerUrl] = useState<string | null>(null);
+    // Uploads an earlier session didn't finish
+    const [resumableJobs, setResumableJobs] = useState<ResumableJob[]>([]);
 
     const dropRef = useRef<HTMLDivElement>(null);
+    // Events from other jobs are ignored
+    const jobIdRef = useRef<string | null>(null);
 
     useEffect(() => {
-        let interval: NodeJS.Timeout;
-
-        if (appState === 'processing') {
-            interval = setInterval(async () => {
-                try {
-                    const progressData = await invoke<ProgressUpdate | null>('get_progress');
-                    if (progressData) {
-                        setProgress(progressData);
-                        // Only handle cancellation from progress if we're still in processing state
-                        // This prevents overriding manual cancellation state changes
-                        if (progressData.status === 'Cancelled' && appState === 'processing') {
-                            setAppState('idle');
-                            setMessage('Processing was cancelled.');
-                        }
-                    }
-                } catch (error) {
-                    console.error('Failed to get progress:', error);
-                }
-            }, 500);
-        }
+        invoke<ResumableJob[]>('list_resumable_jobs')
+            .then(setResumableJobs)
+            .catch(error => console.error('Failed to list resumable jobs:', error));
+    }, []);
+
+    useEffect(() => {
+        const isCurrentJob = (jobId: string) => jobId === jobIdRef.current;
+
+        const unlistenStarted = listen<ProgressUpdate>('processing://started', (event) => {
+            if (isCurrentJob(event.payload.job_id)) setProgress(event.payload);
+        });
+
+        const unlistenProgress = listen<ProgressUpdate>('processing://progress', (event) => {
+            if (isCurrentJob(event.payload.job_id)) setProgress(event.payload);
+        });
+
+        const unlistenCompleted = listen<JobMessage>('processing://completed', (event) => {
+            if (!isCurrentJob(event.payload.job_id)) return;
+            setMessage(event.payload.message);
+            setViewerUrl(event.payload.summary?.viewer_url ?? null);
+            setAppState('completed');
+        });
+
+        const unlistenFailed = listen<JobMessage>('processing://failed', (event) => {
+            if (!isCurrentJob(event.payload.job_id)) return;
+            const failedTiles = event.payload.failed_tiles ?? [];
+            setMessage(failedTiles.length > 0
+                ? `Error: ${event.payload.message} (first: zoom ${failedTiles[0].zoom_level}, ${failedTiles[0].x},${failedTiles[0].y}: ${failedTiles[0].error}). Resume the upload to retry them.`
+                : `Error: ${event.payload.message}`);
+            setAppState('error');
+        });
+
+        const unlistenCancelled = listen<ProgressUpdate>('processing://cancelled', (event) => {
+            if (!isCurrentJob(event.payload.job_id)) return;
+            setAppState('idle');
+            setMessage('Processing was cancelled.');
+            setProgress(null);
+        });
 
         return () => {
-            if (interval) clearInterval(interval);
+            unlistenStarted.then(f => f());
+            unlistenProgress.then(f => f());
+            unlistenCompleted.then(f => f());
+            unlistenFailed.then(f => f());
+            unlistenCancelled.then(f => f());
         };
-    }, [appState]);
+    }, []);
 
     useEffect(() => {
 
-        const unlistenDrop = listen('tauri://drag-drop', (event) => {
+        const unlistenDrop = listen('tauri://drag-drop', () => {
             setDragActive(false)
-            const dropData = event.payload as { paths: string[], position: { x: number, y: number } };
-
-            if (dropData && dropData.paths && dropData.paths.length > 0) {
-                const filePath = dropData.paths[0];
-                setConfig(prev => ({ ...prev, image_path: filePath }));
-
-                invoke<number[]>('read_file_as_bytes', { path: filePath })
-                    .then(fileData => {
-                        const uint8Array = new Uint8Array(fileData);
-                        const blob = new Blob([uint8Array]);
-                        const dataUrl = URL.createObjectURL(blob);
-                        setImagePreview(dataUrl);
-                    })
-                    .catch(() => {
-                        setImagePreview(null);
-                    });
+        });
+
+        const unlistenDropped = listen<DroppedFiles>('files://dropped', (event) => {
+            const { images, rejected } = event.payload;
+            if (images.length === 0) {
+                if (rejected.length > 0) {
+                    setMessage(`Error: can't use ${getFileName(rejected[0].path)}: ${rejected[0].reason}`);
+                }
+                return;
             }
+
+            const image = images[0];
+            const filePath = image.path;
+            setConfig(prev => ({ ...prev, image_path: filePath }));
+            setMessage('');
         });
 
         const unlistenDragEnter = listen('tauri://drag-enter', () => {
@@ -103,27 +205,90 @@ function App() {
         return () => {
             console.log('Cleaning up Tauri file drag &drop listener');
             unlistenDrop.then(f => f());
+            unlistenDropped.then(f => f());
             unlistenDragEnter.then(f => f());
             unlistenDragLeave.then(f => f());
         };
     }, []);
 
+    useEffect(() => {
+        if (!config.image_path) {
+            setImageInfo(null);
+            return;
+        }
+        invoke<ImageInfo>('get_image_info', { config })
+            .then(setImageInfo)
+            .catch(() => setImageInfo(null));
+    }, [config.image_path, config.tile_size, config.pdf_page, config.gif_frame]);
+
+    useEffect(() => {
+        setPaperColor(null);
+        if (!config.image_path) {
+            return;
+        }
+        invoke<string | null>('detect_background', { config })
+            .then(setPaperColor)
+            .catch(() => setPaperColor(null));
+    }, [config.image_path, config.pdf_page, config.gif_frame]);
+
+    useEffect(() => {
+        setPdfPages([]);
+        setConfig(prev => ({ ...prev, pdf_page: 1 }));
+        if (!config.image_path.toLowerCase().endsWith('.pdf')) {
+            return;
+        }
+        invoke<{ number: number; width_pt: number; height_pt: number }[]>('get_pdf_pages', { path: config.image_path })
+            .then(setPdfPages)
+            .catch(() => setPdfPages([]));
+    }, [config.image_path]);
+
+    useEffect(() => {
+        setGifFrames(0);
+        setConfig(prev => ({ ...prev, gif_frame: 1 }));
+        if (!config.image_path.toLowerCase().endsWith('.gif')) {
+            return;
+        }
+        invoke<number>('get_gif_frames', { path: config.image_path })
+            .then(setGifFrames)
+            .catch(() => setGifFrames(0));
+    }, [config.image_path]);
+
+    // Rendered by the backend on the chosen background color
+    useEffect(() => {
+        if (!config.image_path) {
+            setImagePreview(null);
+            return;
+        }
+        let previewUrl: string | null = null;
+        invoke<number[]>('generate_preview', { config })
+            .then(png => {
+                const blob = new Blob([new Uint8Array(png)], { type: 'image/png' });
+                previewUrl = URL.createObjectURL(blob);
+                setImagePreview(previewUrl);
+            })
+            .catch(error => {
+                console.error('Failed to generate preview:', error);
+                setImagePreview(null);
+            });
+        return () => {
+            if (previewUrl) URL.revokeObjectURL(previewUrl);
+        };
+    }, [config.image_path, config.background_color, config.pdf_page, config.gif_frame]);
+
+    const openLayout = async () => {
+        try {
+            await invoke('open_layout', { layoutKey: config.layout_key });
+        } catch (error) {
+            console.error('Failed to open layout:', error);
+        }
+    };
+
     const selectImage = async () => {
         try {
             const selected = await invoke<string | null>('select_image_file');
             if (selected) {
                 setConfig(prev => ({ ...prev, image_path: selected }));
-                try {
-                    const fileData = await invoke<number[]>('read_file_as_bytes', { path: selected });
-                    const uint8Array = new Uint8Array(fileData);
-                    const blob = new Blob([uint8Array]);
-                    const dataUrl = URL.createObjectURL(blob);
-                    setImagePreview(dataUrl);
-                    setMessage('');
-                } catch (readError) {
-                    console.error('Failed to read file for preview:', readError);
-                    setImagePreview(null);
-                }
+                setMessage('');
             }
         } catch (error) {
             console.error('Failed to select image:', error);
@@ -134,52 +299,81 @@ function App() {
     const parseServerAddress = (address: string) => {
         setServerInput(address);
         const parts = address.split('|');
-        if (parts.length === 3) {
+        // Without a secret the backend uses the one saved for the server
+        if (parts.length === 2 || parts.length === 3) {
             setConfig(prev => ({
                 ...prev,
                 server_address: parts[0],
                 layout_key: parts[1],
-                secret: parts[2],
+                secret: parts[2] ?? '',
             }));
         }
     };
 
     const handleColorChange = (event: React.ChangeEvent<HTMLInputElement>) => {
-        const hex = event.target.value;
-        const r = parseInt(hex.slice(1, 3), 16);
-        const g = parseInt(hex.slice(3, 5), 16);
-        const b = parseInt(hex.slice(5, 7), 16);
-        setConfig(prev => ({ ...prev, background_color: [r, g, b] }));
-    };
-
-    const rgbToHex = (r: number, g: number, b: number) => {
-        return "#" + ((1 << 24) + (r << 16) + (g << 8) + b).toString(16).slice(1);
+        setConfig(prev => ({ ...prev, background_color: event.target.value }));
     };
 
-    const startProcessing = async () => {
-        if (!config.image_path || !config.server_address || !config.layout_key || !config.secret) {
+    const startProcessing = async (command: 'start_processing' | 'resume_interrupted_upload' = 'start_processing') => {
+        if (!config.image_path || !config.server_address || !config.layout_key) {
             setMessage('Please fill in all required fields.');
             return;
         }
 
         // Clear any previous state
         setAppState('processing');
-        setProgress({ current: 0, total: 0, zoom_level: 0, percentage: 0, status: 'Starting...' });
+        setProgress({ job_id: '', current: 0, total: 0, zoom_level: 0, percentage: 0, status: 'Starting...', skipped: 0, paused: false });
         setMessage('');
 
+        // The job runs in the background; completion arrives as an event
         try {
-            const result = await invoke<string>('start_processing', { config });
-            setMessage(result);
-            setAppState(result.toLowerCase().includes('success') ? 'completed' : 'error');
+            jobIdRef.current = await invoke<string>(command, { config });
+            // Remembered in the OS credential store, so next time
+            // `server|layout_key` is enough
+            if (config.secret) {
+                invoke('save_secret', { serverAddress: config.server_address, secret: config.secret })
+                    .catch(error => console.error('Failed to save secret:', error));
+            }
         } catch (error) {
-            setMessage(`Error: ${error}`);
+            setMessage(`Error: ${errorMessage(error)}`);
             setAppState('error');
         }
     };
 
+    // The stored config has no secret; the backend uses the saved one
+    const resumeJob = async (job: ResumableJob) => {
+        setResumableJobs(jobs => jobs.filter(other => other.id !== job.id));
+        setAppState('processing');
+        setProgress({ job_id: '', current: job.tiles_uploaded, total: 0, zoom_level: 0, percentage: 0, status: 'Resuming...', skipped: 0, paused: false });
+        setMessage('');
+        try {
+            jobIdRef.current = await invoke<string>('resume_interrupted_upload', { config: job.config });
+        } catch (error) {
+            setMessage(`Error: ${errorMessage(error)}`);
+            setAppState('error');
+        }
+    };
+
+    // Pausing takes effect once the uploads in flight finish, when the
+    // backend reports `paused`; the button flips straight away
+    const togglePause = async () => {
+        if (!progress) return;
+        const command = progress.paused ? 'resume_processing' : 'pause_processing';
+        try {
+            await invoke(command, { jobId: jobIdRef.current });
+            setProgress({
+                ...progress,
+                paused: !progress.paused,
+                status: progress.paused ? 'Resuming...' : 'Pausing...',
+            });
+        } catch (error) {
+            console.error(`Failed to ${command}:`, error);
+        }
+    };
+
     const cancelProcessing = async () => {
         try {
-            await invoke('cancel_processing');
+            await invoke('cancel_processing', { jobId: jobIdRef.current });
             // Immediately update the UI state to show cancellation
             setAppState('idle');
             setMessage('Processing was cancelled.');
@@ -202,7 +396,7 @@ function App() {
             server_address: '',
             layout_key: '',
             secret: '',
-            background_color: [0, 0, 0],
+            background_color: '#000000',
             tile_size: 256,
         });
         setServerInput('');
@@ -225,7 +419,7 @@ function App() {
                 {/* Top Controls */}
                 <div className="top-controls">
                     <div className="input-group">
-                        <label className="input-label">Server Details (server|layout_key|secret)</label>
+                        <label className="input-label">Server Details (server|layout_key|secret, secret optional once saved)</label>
                         <input
                             type="text"
                             className="text-input"
@@ -245,7 +439,7 @@ function App() {
                         >
                             <div
                                 className="color-preview"
-                                style={{ backgroundColor: rgbToHex(...config.background_color) }}
+                                style={{ backgroundColor: config.background_color }}
                             />
                             Color
                         </button>
@@ -253,9 +447,23 @@ function App() {
                             id="colorPicker"
                             type="color"
                             className="hidden-color-input"
-                            value={rgbToHex(...config.background_color)}
+                            value={config.background_color.slice(0, 7)}
                             onChange={handleColorChange}
                         />
+                        {paperColor && paperColor !== config.background_color && (
+                            <button
+                                className="color-picker-btn"
+                                onClick={() => setConfig(prev => ({ ...prev, background_color: paperColor }))}
+                                disabled={appState === 'processing'}
+                                title="Use the color along the image's edges"
+                            >
+                                <div
+                                    className="color-preview"
+                                    style={{ backgroundColor: paperColor }}
+                                />
+                                Match paper
+                            </button>
+                        )}
                     </div>
 
                     <div className="input-group">
@@ -289,6 +497,11 @@ function App() {
                                         </div>
                                         <div className="image-overlay">
                                             <div className="image-name">{getFileName(config.image_path)}</div>
+                                            {imageInfo && (
+                                                <div className="image-info">
+                                                    {imageInfo.width} × {imageInfo.height} {imageInfo.format}, {(imageInfo.file_size / (1024 * 1024)).toFixed(1)} MB · {imageInfo.zoom_levels} zoom levels, {imageInfo.total_tiles.toLocaleString()} tiles · ~{Math.ceil(imageInfo.memory.normal_bytes / (1024 * 1024)).toLocaleString()} MB memory
+                                                </div>
+                                            )}
                                             <div className="drop-hint">Click to change or drop new image</div>
                                         </div>
                                     </div>
@@ -304,13 +517,49 @@ function App() {
                             </div>
                         </div>
                     </div>
+
+                    {pdfPages.length > 1 && (
+                        <div className="input-group">
+                            <label className="input-label">PDF Page</label>
+                            <select
+                                className="text-input"
+                                value={config.pdf_page ?? 1}
+                                onChange={(e) => setConfig(prev => ({ ...prev, pdf_page: parseInt(e.target.value) }))}
+                                disabled={appState === 'processing'}
+                            >
+                                {pdfPages.map(page => (
+                                    <option key={page.number} value={page.number}>
+                                        Page {page.number} ({Math.round(page.width_pt / 72 * 25.4)} × {Math.round(page.height_pt / 72 * 25.4)} mm)
+                                    </option>
+                                ))}
+                            </select>
+                        </div>
+                    )}
+
+                    {gifFrames > 1 && (
+                        <div className="input-group">
+                            <label className="input-label">GIF Frame</label>
+                            <select
+                                className="text-input"
+                                value={config.gif_frame ?? 1}
+                                onChange={(e) => setConfig(prev => ({ ...prev, gif_frame: parseInt(e.target.value) }))}
+                                disabled={appState === 'processing'}
+                            >
+                                {Array.from({ length: gifFrames }, (_, i) => i + 1).map(frame => (
+                                    <option key={frame} value={frame}>
+                                        Frame {frame} of {gifFrames}
+                                    </option>
+                                ))}
+                            </select>
+                        </div>
+                    )}
                 </div>
 
                 {/* Action Button */}
                 <div className="action-section">
                     <button
                         className="main-btn"
-                        onClick={startProcessing}
+                        onClick={() => startProcessing()}
                         disabled={appState === 'processing' || !config.image_path || !config.server_address || !config.secret || !config.layout_key || !config.background_color || !config.tile_size}
                     >
                         Start Processing
@@ -320,6 +569,22 @@ function App() {
                     </button>
                 </div>
 
+                {/* Unfinished uploads from an earlier session */}
+                {appState === 'idle' && resumableJobs.map(job => (
+                    <div key={job.id} className="message info">
+                        Unfinished upload of {getFileName(job.config.image_path)} to {job.config.layout_key} ({job.tiles_uploaded} tiles done)
+                        <button className="main-btn" onClick={() => resumeJob(job)}>
+                            Resume
+                        </button>
+                        <button
+                            className="secondary-btn"
+                            onClick={() => setResumableJobs(jobs => jobs.filter(other => other.id !== job.id))}
+                        >
+                            Dismiss
+                        </button>
+                    </div>
+                ))}
+
                 {/* Message display */}
                 {message && (
                     <div className={`message ${message.includes('Error') || message.includes('Failed') ? 'error' : 'info'}`}>
@@ -343,15 +608,32 @@ function App() {
                                         />
                                     </div>
                                     <div className="progress-details">
-                                        <div>{progress.status}</div>
+                                        <div className={progress.throttled || progress.waiting_for_server ? 'progress-throttled' : undefined}>{progress.status}</div>
                                         <div>Tiles: {progress.current}/{progress.total}</div>
+                                        {progress.skipped > 0 && <div>Background tiles skipped: {progress.skipped}</div>}
+                                        {!!progress.tiles_per_second && (
+                                            <div>
+                                                {progress.tiles_per_second.toFixed(1)} tiles/s, {((progress.upload_kbps ?? 0) / 1000).toFixed(2)} Mbps, {((progress.bytes_uploaded ?? 0) / (1024 * 1024)).toFixed(1)} MB sent
+                                            </div>
+                                        )}
+                                        {progress.elapsed_seconds !== undefined && (
+                                            <div>
+                                                Elapsed: {formatDuration(progress.elapsed_seconds)}
+                                                {progress.eta_seconds != null && `, about ${formatDuration(progress.eta_seconds)} left`}
+                                            </div>
+                                        )}
                                     </div>
                                 </>
                             )}
 
-                            <button className="cancel-btn" onClick={cancelProcessing}>
-                                Cancel
-                            </button>
+                            <div className="progress-actions">
+                                <button className="secondary-btn" onClick={togglePause} disabled={!progress}>
+                                    {progress?.paused ? 'Resume' : 'Pause'}
+                                </button>
+                                <button className="cancel-btn" onClick={cancelProcessing}>
+                                    Cancel
+                                </button>
+                            </div>
                         </div>
                     </div>
                 )}
@@ -366,13 +648,25 @@ function App() {
                             <div className="completion-message">{message}</div>
                             <div className="completion-actions">
                                 {appState === 'error' ? (
-                                    <button className="main-btn" onClick={resetApp}>
-                                        Go Back
-                                    </button>
+                                    <>
+                                        <button className="main-btn" onClick={() => startProcessing('resume_interrupted_upload')}>
+                                            Resume
+                                        </button>
+                                        <button className="secondary-btn" onClick={resetApp}>
+                                            Go Back
+                                        </button>
+                                    </>
                                 ) : (
-                                    <button className="main-btn" onClick={startFresh}>
-                                        Done
-                                    </button>
+                                    <>
+                                        {viewerUrl && (
+                                            <button className="secondary-btn" onClick={openLayout}>
+                                                Open Layout
+                                            </button>
+                                        )}
+                                        <button className="main-btn" onClick={startFresh}>
+                                            Done
+                                        </button>
+                                    </>
                                 )}
                             </div>
                         </div>